@@ -20,7 +20,9 @@ use core::{
     ptr::NonNull,
 };
 
-use allocator::{AllocResult, BaseAllocator, BitmapPageAllocator, ByteAllocator, PageAllocator};
+use allocator::{
+    AllocError, AllocResult, BaseAllocator, BitmapPageAllocator, ByteAllocator, PageAllocator,
+};
 use kspin::SpinNoIrq;
 
 const PAGE_SIZE: usize = 0x1000;
@@ -70,6 +72,11 @@ impl UsageStats {
         Self([0; ALL_KINDS.len()])
     }
 
+    /// Returns the number of bytes allocated for `kind`.
+    pub fn get(&self, kind: UsageKind) -> usize {
+        self.0[kind as usize]
+    }
+
     fn alloc(&mut self, kind: UsageKind, size: usize) {
         self.0[kind as usize] += size;
     }
@@ -99,6 +106,13 @@ impl fmt::Debug for UsageStats {
     }
 }
 
+/// A function freeing memory on demand, e.g. by dropping caches.
+///
+/// It's given the number of pages wanted, and returns the number of pages it
+/// freed. It's called with no lock of the allocator held, but possibly with
+/// other locks held by the caller, so it must not block.
+pub type Shrinker = fn(usize) -> usize;
+
 /// The global allocator used by ArceOS.
 ///
 /// It combines a [`ByteAllocator`] and a [`PageAllocator`] into a simple
@@ -114,6 +128,7 @@ pub struct GlobalAllocator {
     balloc: SpinNoIrq<DefaultByteAllocator>,
     palloc: SpinNoIrq<BitmapPageAllocator<PAGE_SIZE>>,
     stats: SpinNoIrq<UsageStats>,
+    shrinker: SpinNoIrq<Option<Shrinker>>,
 }
 
 impl GlobalAllocator {
@@ -123,6 +138,7 @@ impl GlobalAllocator {
             balloc: SpinNoIrq::new(DefaultByteAllocator::new()),
             palloc: SpinNoIrq::new(BitmapPageAllocator::new()),
             stats: SpinNoIrq::new(UsageStats::new()),
+            shrinker: SpinNoIrq::new(None),
         }
     }
 
@@ -154,6 +170,12 @@ impl GlobalAllocator {
         self.balloc.lock().init(heap_ptr, init_heap_size);
     }
 
+    /// Sets the function called when the page allocator runs out of memory,
+    /// before giving up.
+    pub fn set_shrinker(&self, shrinker: Shrinker) {
+        *self.shrinker.lock() = Some(shrinker);
+    }
+
     /// Add the given region to the allocator.
     ///
     /// It will add the whole region to the byte allocator.
@@ -170,13 +192,16 @@ impl GlobalAllocator {
     fn alloc(&self, layout: Layout) -> AllocResult<NonNull<u8>> {
         // simple two-level allocator: if no heap memory, allocate from the page
         // allocator.
-        let mut balloc = self.balloc.lock();
         loop {
+            let mut balloc = self.balloc.lock();
             if let Ok(ptr) = balloc.alloc(layout) {
                 self.stats.lock().alloc(UsageKind::RustHeap, layout.size());
                 return Ok(ptr);
             } else {
                 let old_size = balloc.total_bytes();
+                // Don't hold the lock while allocating pages, as the shrinker
+                // may use the heap.
+                drop(balloc);
                 let expand_size = old_size
                     .max(layout.size())
                     .next_power_of_two()
@@ -204,7 +229,7 @@ impl GlobalAllocator {
                         heap_ptr,
                         heap_ptr + try_size
                     );
-                    balloc.add_memory(heap_ptr, try_size)?;
+                    self.balloc.lock().add_memory(heap_ptr, try_size)?;
                     break;
                 }
             }
@@ -231,16 +256,25 @@ impl GlobalAllocator {
     ///
     /// `align_pow2` must be a power of 2, and the returned region bound will be
     /// aligned to it.
+    /// If there is no memory, the [shrinker](Self::set_shrinker) is asked to
+    /// free some, and the allocation is retried once.
     pub fn alloc_pages(
         &self,
         num_pages: usize,
         align_pow2: usize,
         kind: UsageKind,
     ) -> AllocResult<usize> {
-        if !matches!(kind, UsageKind::RustHeap) {
+        let mut result = self.palloc.lock().alloc_pages(num_pages, align_pow2);
+        if let Err(AllocError::NoMemory) = result {
+            let shrinker = *self.shrinker.lock();
+            if shrinker.is_some_and(|shrink| shrink(num_pages) > 0) {
+                result = self.palloc.lock().alloc_pages(num_pages, align_pow2);
+            }
+        }
+        if result.is_ok() && !matches!(kind, UsageKind::RustHeap) {
             self.stats.lock().alloc(kind, num_pages * PAGE_SIZE);
         }
-        self.palloc.lock().alloc_pages(num_pages, align_pow2)
+        result
     }
 
     /// Allocates contiguous pages starting from the given address.
@@ -0,0 +1,220 @@
+//! A write-back cache of disk blocks.
+//!
+//! Filesystems that do small, scattered I/O, like FAT on its tables and
+//! directories, would otherwise hit the device on every access. The cache is
+//! shared by all partitions of a disk, and kept coherent with the uncached
+//! accesses to it.
+
+use alloc::{boxed::Box, vec, vec::Vec};
+use core::num::NonZeroUsize;
+
+use axdriver::{AxBlockDevice, prelude::*};
+use log::error;
+use lru::LruCache;
+
+use crate::loop_device::LoopDevice;
+
+/// Memory used by the cache of a disk, in bytes.
+const CACHE_SIZE: usize = 256 * 1024;
+
+/// Counters of the block cache of a disk.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheStats {
+    /// Number of accesses to blocks found in the cache.
+    pub hits: u64,
+    /// Number of accesses to blocks not found in the cache.
+    pub misses: u64,
+    /// Number of dirty blocks written back to the disk.
+    pub writebacks: u64,
+}
+
+struct CachedBlock {
+    data: Box<[u8]>,
+    dirty: bool,
+}
+
+/// A device a disk is made of.
+pub(crate) enum BlockDevice {
+    /// A device of the platform.
+    Native(AxBlockDevice),
+    /// A file, through a loop device.
+    Loop(LoopDevice),
+}
+
+impl BaseDriverOps for BlockDevice {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Block
+    }
+
+    fn device_name(&self) -> &str {
+        match self {
+            Self::Native(dev) => dev.device_name(),
+            Self::Loop(dev) => dev.device_name(),
+        }
+    }
+}
+
+impl BlockDriverOps for BlockDevice {
+    fn num_blocks(&self) -> u64 {
+        match self {
+            Self::Native(dev) => dev.num_blocks(),
+            Self::Loop(dev) => dev.num_blocks(),
+        }
+    }
+
+    fn block_size(&self) -> usize {
+        match self {
+            Self::Native(dev) => dev.block_size(),
+            Self::Loop(dev) => dev.block_size(),
+        }
+    }
+
+    fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        match self {
+            Self::Native(dev) => dev.read_block(block_id, buf),
+            Self::Loop(dev) => dev.read_block(block_id, buf),
+        }
+    }
+
+    fn write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        match self {
+            Self::Native(dev) => dev.write_block(block_id, buf),
+            Self::Loop(dev) => dev.write_block(block_id, buf),
+        }
+    }
+
+    fn flush(&mut self) -> DevResult {
+        match self {
+            Self::Native(dev) => dev.flush(),
+            Self::Loop(dev) => dev.flush(),
+        }
+    }
+}
+
+/// A disk along with the cache of its blocks.
+///
+/// Dirty blocks are written back when they are evicted, on
+/// [`flush`](Self::flush), and when the disk is dropped.
+pub(crate) struct CachedDisk {
+    dev: BlockDevice,
+    blocks: LruCache<u64, CachedBlock>,
+    block_size: usize,
+    stats: CacheStats,
+}
+
+impl CachedDisk {
+    pub fn new(dev: BlockDevice) -> Self {
+        let block_size = dev.block_size();
+        let capacity = NonZeroUsize::new((CACHE_SIZE / block_size).max(1)).unwrap();
+        Self {
+            dev,
+            blocks: LruCache::new(capacity),
+            block_size,
+            stats: CacheStats::default(),
+        }
+    }
+
+    pub fn num_blocks(&self) -> u64 {
+        self.dev.num_blocks()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    /// Returns the cached block, bringing it in if needed. Its content is
+    /// only read from the device if `load` is set.
+    fn block(&mut self, block_id: u64, load: bool) -> DevResult<&mut CachedBlock> {
+        if self.blocks.contains(&block_id) {
+            self.stats.hits += 1;
+            return Ok(self.blocks.get_mut(&block_id).unwrap());
+        }
+        self.stats.misses += 1;
+
+        let mut data = if self.blocks.len() < self.blocks.cap().get() {
+            vec![0; self.block_size].into_boxed_slice()
+        } else {
+            // Write back before popping, so that nothing is lost on failure.
+            let (&id, lru) = self.blocks.peek_lru().unwrap();
+            if lru.dirty {
+                self.dev.write_block(id, &lru.data)?;
+                self.stats.writebacks += 1;
+            }
+            self.blocks.pop_lru().unwrap().1.data
+        };
+        if load {
+            self.dev.read_block(block_id, &mut data)?;
+        }
+        self.blocks
+            .put(block_id, CachedBlock { data, dirty: false });
+        Ok(self.blocks.get_mut(&block_id).unwrap())
+    }
+
+    /// Reads part of a block through the cache.
+    pub fn read_cached(&mut self, block_id: u64, offset: usize, buf: &mut [u8]) -> DevResult {
+        let block = self.block(block_id, true)?;
+        buf.copy_from_slice(&block.data[offset..offset + buf.len()]);
+        Ok(())
+    }
+
+    /// Writes part of a block through the cache. The write reaches the
+    /// device once the block is written back.
+    pub fn write_cached(&mut self, block_id: u64, offset: usize, buf: &[u8]) -> DevResult {
+        let block = self.block(block_id, buf.len() != self.block_size)?;
+        block.data[offset..offset + buf.len()].copy_from_slice(buf);
+        block.dirty = true;
+        Ok(())
+    }
+
+    /// Reads whole blocks from the device, seeing the pending writes of the
+    /// cache.
+    pub fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        self.dev.read_block(block_id, buf)?;
+        for (id, chunk) in (block_id..).zip(buf.chunks_mut(self.block_size)) {
+            match self.blocks.peek(&id) {
+                Some(block) if block.dirty => chunk.copy_from_slice(&block.data),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes whole blocks to the device, updating the cached copies.
+    pub fn write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        self.dev.write_block(block_id, buf)?;
+        for (id, chunk) in (block_id..).zip(buf.chunks(self.block_size)) {
+            if let Some(block) = self.blocks.peek_mut(&id) {
+                block.data.copy_from_slice(chunk);
+                block.dirty = false;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes back all dirty blocks, in ascending order, and flushes the
+    /// device.
+    pub fn flush(&mut self) -> DevResult {
+        let mut dirty = self
+            .blocks
+            .iter()
+            .filter(|(_, block)| block.dirty)
+            .map(|(&id, _)| id)
+            .collect::<Vec<_>>();
+        dirty.sort_unstable();
+        for id in dirty {
+            let block = self.blocks.peek_mut(&id).unwrap();
+            self.dev.write_block(id, &block.data)?;
+            block.dirty = false;
+            self.stats.writebacks += 1;
+        }
+        self.dev.flush()
+    }
+}
+
+impl Drop for CachedDisk {
+    fn drop(&mut self) {
+        if let Err(err) = self.flush() {
+            error!("Failed to write back the block cache: {err:?}");
+        }
+    }
+}
@@ -1,7 +1,35 @@
-use alloc::{boxed::Box, vec};
 use core::mem;
 
 use axdriver::prelude::*;
+use axfs_ng_vfs::VfsError;
+
+use crate::partition::Partition;
+
+/// Converts a device error into the corresponding filesystem error.
+pub fn into_vfs_err(err: DevError) -> VfsError {
+    match err {
+        DevError::AlreadyExists => VfsError::AlreadyExists,
+        DevError::Again => VfsError::WouldBlock,
+        DevError::InvalidParam => VfsError::InvalidInput,
+        DevError::NoMemory => VfsError::NoMemory,
+        DevError::ResourceBusy => VfsError::ResourceBusy,
+        DevError::Unsupported => VfsError::Unsupported,
+        _ => VfsError::Io,
+    }
+}
+
+/// Converts a filesystem error into the corresponding device error.
+pub fn into_dev_err(err: VfsError) -> DevError {
+    match err {
+        VfsError::AlreadyExists => DevError::AlreadyExists,
+        VfsError::WouldBlock => DevError::Again,
+        VfsError::InvalidInput => DevError::InvalidParam,
+        VfsError::NoMemory => DevError::NoMemory,
+        VfsError::ResourceBusy => DevError::ResourceBusy,
+        VfsError::Unsupported => DevError::Unsupported,
+        _ => DevError::Io,
+    }
+}
 
 fn take<'a>(buf: &mut &'a [u8], cnt: usize) -> &'a [u8] {
     let (first, rem) = buf.split_at(cnt);
@@ -17,38 +45,29 @@ fn take_mut<'a>(buf: &mut &'a mut [u8], cnt: usize) -> &'a mut [u8] {
 }
 
 /// A disk device with a cursor.
+///
+/// Accesses to parts of blocks go through the block cache of the disk, and
+/// whole blocks are transferred directly.
 #[allow(unused)]
 pub struct SeekableDisk {
-    dev: AxBlockDevice,
+    dev: Partition,
 
     block_id: u64,
     offset: usize,
     block_size_log2: u8,
-
-    read_buffer: Box<[u8]>,
-    write_buffer: Box<[u8]>,
-    /// Whether we have unsaved changes in the write buffer.
-    ///
-    /// It's guaranteed that when `offset == 0`, write_buffer_dirty is false.
-    write_buffer_dirty: bool,
 }
 
 #[allow(unused)]
 impl SeekableDisk {
     /// Create a new disk.
-    pub fn new(dev: AxBlockDevice) -> Self {
+    pub fn new(dev: Partition) -> Self {
         assert!(dev.block_size().is_power_of_two());
         let block_size_log2 = dev.block_size().trailing_zeros() as u8;
-        let read_buffer = vec![0u8; dev.block_size()].into_boxed_slice();
-        let write_buffer = vec![0u8; dev.block_size()].into_boxed_slice();
         Self {
             dev,
             block_id: 0,
             offset: 0,
             block_size_log2,
-            read_buffer,
-            write_buffer,
-            write_buffer_dirty: false,
         }
     }
 
@@ -69,28 +88,22 @@ impl SeekableDisk {
 
     /// Set the position of the cursor.
     pub fn set_position(&mut self, pos: u64) -> DevResult<()> {
-        self.flush()?;
         self.block_id = pos >> self.block_size_log2;
         self.offset = pos as usize & (self.block_size() - 1);
         Ok(())
     }
 
     /// Write all pending changes to the disk.
+    ///
+    /// This writes back the block cache of the whole disk.
     pub fn flush(&mut self) -> DevResult<()> {
-        if self.write_buffer_dirty {
-            self.dev.write_block(self.block_id, &self.write_buffer)?;
-            self.write_buffer_dirty = false;
-        }
-        Ok(())
+        self.dev.flush()
     }
 
-    fn read_partial(&mut self, mut buf: &mut &mut [u8]) -> DevResult<usize> {
-        self.flush()?;
-        self.dev.read_block(self.block_id, &mut self.read_buffer)?;
-
-        let data = &self.read_buffer[self.offset..];
-        let length = buf.len().min(data.len());
-        take_mut(buf, length).copy_from_slice(&data[..length]);
+    fn read_partial(&mut self, buf: &mut &mut [u8]) -> DevResult<usize> {
+        let length = buf.len().min(self.block_size() - self.offset);
+        self.dev
+            .read_cached(self.block_id, self.offset, take_mut(buf, length))?;
 
         self.offset += length;
         if self.offset == self.block_size() {
@@ -124,18 +137,12 @@ impl SeekableDisk {
     }
 
     fn write_partial(&mut self, buf: &mut &[u8]) -> DevResult<usize> {
-        if !self.write_buffer_dirty {
-            self.dev.read_block(self.block_id, &mut self.write_buffer)?;
-            self.write_buffer_dirty = true;
-        }
-
-        let data = &mut self.write_buffer[self.offset..];
-        let length = buf.len().min(data.len());
-        data[..length].copy_from_slice(take(buf, length));
+        let length = buf.len().min(self.block_size() - self.offset);
+        self.dev
+            .write_cached(self.block_id, self.offset, take(buf, length))?;
 
         self.offset += length;
         if self.offset == self.block_size() {
-            self.flush()?;
             self.block_id += 1;
             self.offset = 0;
         }
@@ -0,0 +1,292 @@
+use alloc::{format, string::String, sync::Arc};
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    task::Context,
+};
+
+use axdriver::AxBlockDevice;
+use axfs_ng_vfs::{DeviceId, NodeFlags, NodePermission, NodeType, VfsError, VfsResult};
+use axio::{IoEvents, Pollable};
+use kspin::SpinNoPreempt as Mutex;
+
+use super::{DeviceOps, register_device};
+use crate::{
+    disk::{SeekableDisk, into_vfs_err},
+    partition::{Partition, scan},
+};
+
+/// Major number of block devices exposed by devfs (`virtblk` on Linux).
+const BLOCK_MAJOR: u32 = 254;
+
+macro_rules! always_ready {
+    ($ty:ty) => {
+        impl Pollable for $ty {
+            fn poll(&self) -> IoEvents {
+                IoEvents::IN | IoEvents::OUT
+            }
+
+            fn register(&self, _context: &mut Context<'_>, _events: IoEvents) {}
+        }
+    };
+}
+
+/// `/dev/null`: reads return end of file, writes are discarded.
+pub struct Null;
+
+always_ready!(Null);
+
+impl DeviceOps for Null {
+    fn read_at(&self, _buf: &mut [u8], _offset: u64) -> VfsResult<usize> {
+        Ok(0)
+    }
+
+    fn write_at(&self, buf: &[u8], _offset: u64) -> VfsResult<usize> {
+        Ok(buf.len())
+    }
+}
+
+/// `/dev/zero`: reads return zeros, writes are discarded.
+pub struct Zero;
+
+always_ready!(Zero);
+
+impl DeviceOps for Zero {
+    fn read_at(&self, buf: &mut [u8], _offset: u64) -> VfsResult<usize> {
+        buf.fill(0);
+        Ok(buf.len())
+    }
+
+    fn write_at(&self, buf: &[u8], _offset: u64) -> VfsResult<usize> {
+        Ok(buf.len())
+    }
+}
+
+/// `/dev/random` and `/dev/urandom`.
+///
+/// Bytes come from a xorshift generator seeded with the boot time, which is
+/// fine for randomizing but not suitable for cryptography.
+pub struct Random {
+    state: AtomicU64,
+}
+
+impl Random {
+    pub fn new() -> Self {
+        Self {
+            state: AtomicU64::new(axhal::time::monotonic_time_nanos() | 1),
+        }
+    }
+
+    fn next(&self) -> u64 {
+        let mut x = self.state.load(Ordering::Relaxed);
+        loop {
+            // The state must never be zero, or the generator gets stuck.
+            let mut next = x.max(1);
+            next ^= next >> 12;
+            next ^= next << 25;
+            next ^= next >> 27;
+            match self
+                .state
+                .compare_exchange_weak(x, next, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => return next.wrapping_mul(0x2545_f491_4f6c_dd1d),
+                Err(current) => x = current,
+            }
+        }
+    }
+}
+
+impl Default for Random {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+always_ready!(Random);
+
+impl DeviceOps for Random {
+    fn read_at(&self, buf: &mut [u8], _offset: u64) -> VfsResult<usize> {
+        for chunk in buf.chunks_mut(8) {
+            chunk.copy_from_slice(&self.next().to_le_bytes()[..chunk.len()]);
+        }
+        Ok(buf.len())
+    }
+
+    fn write_at(&self, buf: &[u8], _offset: u64) -> VfsResult<usize> {
+        // Writing mixes the data into the state, like Linux does.
+        for chunk in buf.chunks(8) {
+            let mut bytes = [0; 8];
+            bytes[..chunk.len()].copy_from_slice(chunk);
+            self.state
+                .fetch_xor(u64::from_le_bytes(bytes) | 1, Ordering::Relaxed);
+        }
+        Ok(buf.len())
+    }
+}
+
+/// `/dev/console`, backed by the platform console.
+#[derive(Default)]
+pub struct Console {
+    /// Byte read to tell whether input is pending, returned by the next read.
+    pending: Mutex<Option<u8>>,
+}
+
+impl Pollable for Console {
+    fn poll(&self) -> IoEvents {
+        let mut pending = self.pending.lock();
+        if pending.is_none() {
+            let mut byte = [0];
+            if axhal::console::read_bytes(&mut byte) == 1 {
+                *pending = Some(byte[0]);
+            }
+        }
+        if pending.is_some() {
+            IoEvents::IN | IoEvents::OUT
+        } else {
+            IoEvents::OUT
+        }
+    }
+
+    fn register(&self, _context: &mut Context<'_>, _events: IoEvents) {}
+}
+
+impl DeviceOps for Console {
+    fn read_at(&self, buf: &mut [u8], _offset: u64) -> VfsResult<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut pending = self.pending.lock();
+        let mut read = 0;
+        if let Some(byte) = pending.take() {
+            buf[0] = byte;
+            read = 1;
+        }
+        match read + axhal::console::read_bytes(&mut buf[read..]) {
+            0 => Err(VfsError::WouldBlock),
+            read => Ok(read),
+        }
+    }
+
+    fn write_at(&self, buf: &[u8], _offset: u64) -> VfsResult<usize> {
+        axhal::console::write_bytes(buf);
+        Ok(buf.len())
+    }
+
+    fn flags(&self) -> NodeFlags {
+        NodeFlags::NON_CACHEABLE | NodeFlags::STREAM | NodeFlags::BLOCKING
+    }
+}
+
+/// A disk or partition exposed as a file of its whole capacity.
+pub struct BlockDevice {
+    disk: Mutex<SeekableDisk>,
+}
+
+impl BlockDevice {
+    pub fn new(dev: Partition) -> Self {
+        Self {
+            disk: Mutex::new(SeekableDisk::new(dev)),
+        }
+    }
+}
+
+always_ready!(BlockDevice);
+
+impl DeviceOps for BlockDevice {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> VfsResult<usize> {
+        let mut disk = self.disk.lock();
+        let len = buf.len().min(disk.size().saturating_sub(offset) as usize);
+        if len == 0 {
+            return Ok(0);
+        }
+        disk.set_position(offset).map_err(into_vfs_err)?;
+        disk.read(&mut buf[..len]).map_err(into_vfs_err)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> VfsResult<usize> {
+        let mut disk = self.disk.lock();
+        let len = buf.len().min(disk.size().saturating_sub(offset) as usize);
+        if len == 0 && !buf.is_empty() {
+            return Err(VfsError::StorageFull);
+        }
+        disk.set_position(offset).map_err(into_vfs_err)?;
+        let written = disk.write(&buf[..len]).map_err(into_vfs_err)?;
+        disk.flush().map_err(into_vfs_err)?;
+        Ok(written)
+    }
+
+    fn len(&self) -> u64 {
+        self.disk.lock().size()
+    }
+
+    fn flags(&self) -> NodeFlags {
+        NodeFlags::NON_CACHEABLE | NodeFlags::BLOCKING
+    }
+}
+
+/// Registers `null`, `zero`, `random`, `urandom` and `console`.
+pub fn register_builtin_devices() -> VfsResult<()> {
+    let mode = NodePermission::from_bits_truncate(0o666);
+    let char_dev = NodeType::CharacterDevice;
+    let random = Arc::new(Random::new());
+    register_device("null", char_dev, DeviceId::new(1, 3), mode, Arc::new(Null))?;
+    register_device("zero", char_dev, DeviceId::new(1, 5), mode, Arc::new(Zero))?;
+    register_device(
+        "random",
+        char_dev,
+        DeviceId::new(1, 8),
+        mode,
+        random.clone(),
+    )?;
+    register_device("urandom", char_dev, DeviceId::new(1, 9), mode, random)?;
+    register_device(
+        "console",
+        char_dev,
+        DeviceId::new(5, 1),
+        mode,
+        Arc::new(Console::default()),
+    )?;
+    Ok(())
+}
+
+/// Registers the `index`-th block device of the system as `vda`, `vdb`, ...,
+/// and its partitions as `vda1`, `vda2`, ...
+///
+/// Returns the name of the device node of the whole disk.
+pub fn register_block_device(index: usize, dev: AxBlockDevice) -> VfsResult<String> {
+    register_disk(index, scan(dev).map_err(into_vfs_err)?)
+}
+
+/// Registers the `index`-th disk of the system, given by its `partitions` as
+/// returned by [`scan`], like [`register_block_device`].
+///
+/// This exposes disks in use, e.g. by the root filesystem.
+pub fn register_disk(index: usize, partitions: Vec<Partition>) -> VfsResult<String> {
+    if index >= 26 {
+        return Err(VfsError::InvalidInput);
+    }
+    let name = format!("vd{}", (b'a' + index as u8) as char);
+    let minor = index as u32 * 16;
+    let mode = NodePermission::from_bits_truncate(0o660);
+    let register = |name: &str, minor: u32, dev: Partition| {
+        register_device(
+            name,
+            NodeType::BlockDevice,
+            DeviceId::new(BLOCK_MAJOR, minor),
+            mode,
+            Arc::new(BlockDevice::new(dev)),
+        )
+    };
+
+    let Some(first) = partitions.first() else {
+        return Err(VfsError::InvalidInput);
+    };
+    register(&name, minor, first.whole_disk())?;
+    for partition in partitions {
+        let part = partition.info().index;
+        // Like Linux, there are 15 minor numbers for the partitions of a disk.
+        if (1..16).contains(&part) {
+            register(&format!("{name}{part}"), minor + part as u32, partition)?;
+        }
+    }
+    Ok(name)
+}
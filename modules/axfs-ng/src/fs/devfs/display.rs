@@ -0,0 +1,69 @@
+use alloc::sync::Arc;
+use core::task::Context;
+
+use axdriver::prelude::DisplayDriverOps;
+use axfs_ng_vfs::{DeviceId, NodeFlags, NodePermission, NodeType, VfsResult};
+use axio::{IoEvents, Pollable};
+
+use super::{DeviceOps, register_device};
+use crate::disk::into_vfs_err;
+
+/// The framebuffer of the main display, as `/dev/fb0`.
+pub struct FrameBuffer;
+
+impl Pollable for FrameBuffer {
+    fn poll(&self) -> IoEvents {
+        IoEvents::IN | IoEvents::OUT
+    }
+
+    fn register(&self, _context: &mut Context<'_>, _events: IoEvents) {}
+}
+
+impl DeviceOps for FrameBuffer {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> VfsResult<usize> {
+        let display = axdisplay::main_display();
+        let info = display.info();
+        let start = (offset as usize).min(info.fb_size);
+        let len = buf.len().min(info.fb_size - start);
+        let fb =
+            unsafe { core::slice::from_raw_parts(info.fb_base_vaddr as *const u8, info.fb_size) };
+        buf[..len].copy_from_slice(&fb[start..start + len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> VfsResult<usize> {
+        let mut display = axdisplay::main_display();
+        let info = display.info();
+        let start = (offset as usize).min(info.fb_size);
+        let len = buf.len().min(info.fb_size - start);
+        let fb =
+            unsafe { core::slice::from_raw_parts_mut(info.fb_base_vaddr as *mut u8, info.fb_size) };
+        fb[start..start + len].copy_from_slice(&buf[..len]);
+        if display.need_flush() {
+            display.flush().map_err(into_vfs_err)?;
+        }
+        Ok(len)
+    }
+
+    fn len(&self) -> u64 {
+        axdisplay::main_display().info().fb_size as u64
+    }
+
+    fn flags(&self) -> NodeFlags {
+        NodeFlags::NON_CACHEABLE
+    }
+}
+
+/// Registers the main display as `fb0`, if there is one.
+pub fn register_display_devices() -> VfsResult<()> {
+    if !axdisplay::has_display() {
+        return Ok(());
+    }
+    register_device(
+        "fb0",
+        NodeType::CharacterDevice,
+        DeviceId::new(29, 0),
+        NodePermission::from_bits_truncate(0o660),
+        Arc::new(FrameBuffer),
+    )
+}
@@ -0,0 +1,284 @@
+use alloc::{borrow::ToOwned, sync::Arc};
+use core::{any::Any, cell::OnceCell, task::Context, time::Duration};
+
+use axfs_ng_vfs::{
+    DeviceId, DirEntry, DirEntrySink, DirNode, DirNodeOps, FileNode, FileNodeOps, Filesystem,
+    FilesystemOps, Metadata, MetadataUpdate, NodeFlags, NodeOps, NodePermission, NodeType,
+    Reference, StatFs, VfsError, VfsResult, WeakDirEntry, path::MAX_NAME_LEN,
+};
+use axio::{IoEvents, Pollable};
+
+use super::{Device, ROOT_INO, find_device, list_devices};
+use crate::fs::now;
+
+const DEVFS_MAGIC: u64 = 0x1373;
+
+pub struct DevFilesystem {
+    root_dir: OnceCell<DirEntry>,
+}
+
+impl DevFilesystem {
+    /// Creates a new devfs showing all registered devices.
+    pub fn new() -> Filesystem {
+        let fs = Arc::new(Self {
+            root_dir: OnceCell::new(),
+        });
+        let _ = fs.root_dir.set(DirEntry::new_dir(
+            |this| {
+                DirNode::new(Arc::new(DevDir {
+                    fs: fs.clone(),
+                    this,
+                }))
+            },
+            Reference::root(),
+        ));
+        Filesystem::new(fs)
+    }
+}
+
+unsafe impl Send for DevFilesystem {}
+
+unsafe impl Sync for DevFilesystem {}
+
+impl FilesystemOps for DevFilesystem {
+    fn name(&self) -> &str {
+        "devfs"
+    }
+
+    fn root_dir(&self) -> DirEntry {
+        self.root_dir.get().unwrap().clone()
+    }
+
+    fn stat(&self) -> VfsResult<StatFs> {
+        Ok(StatFs {
+            fs_type: DEVFS_MAGIC as _,
+            block_size: 0,
+            blocks: 0,
+            blocks_free: 0,
+            blocks_available: 0,
+
+            file_count: list_devices().len() as _,
+            free_file_count: 0,
+
+            name_length: MAX_NAME_LEN as _,
+            fragment_size: 0,
+            mount_flags: 0,
+        })
+    }
+}
+
+/// The root directory of devfs, listing the device registry.
+struct DevDir {
+    fs: Arc<DevFilesystem>,
+    this: WeakDirEntry,
+}
+
+impl NodeOps for DevDir {
+    fn inode(&self) -> u64 {
+        ROOT_INO
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        Ok(Metadata {
+            inode: ROOT_INO,
+            device: 0,
+            nlink: 2,
+            mode: NodePermission::from_bits_truncate(0o755),
+            node_type: NodeType::Directory,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            block_size: 0,
+            blocks: 0,
+            rdev: DeviceId::default(),
+            atime: Duration::default(),
+            mtime: Duration::default(),
+            ctime: Duration::default(),
+        })
+    }
+
+    fn update_metadata(&self, _update: MetadataUpdate) -> VfsResult<()> {
+        Err(VfsError::OperationNotPermitted)
+    }
+
+    fn filesystem(&self) -> &dyn FilesystemOps {
+        &*self.fs
+    }
+
+    fn sync(&self, _data_only: bool) -> VfsResult<()> {
+        Ok(())
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+
+    fn flags(&self) -> NodeFlags {
+        NodeFlags::empty()
+    }
+}
+
+impl DirNodeOps for DevDir {
+    fn read_dir(&self, offset: u64, sink: &mut dyn DirEntrySink) -> VfsResult<usize> {
+        let dots = [
+            (".".to_owned(), ROOT_INO, NodeType::Directory),
+            ("..".to_owned(), ROOT_INO, NodeType::Directory),
+        ];
+        let devices = list_devices()
+            .into_iter()
+            .map(|(name, dev)| (name, dev.ino, dev.node_type));
+        let mut count = 0;
+        for (i, (name, ino, node_type)) in dots
+            .into_iter()
+            .chain(devices)
+            .enumerate()
+            .skip(offset as usize)
+        {
+            if !sink.accept(&name, ino, node_type, i as u64 + 1) {
+                break;
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn lookup(&self, name: &str) -> VfsResult<DirEntry> {
+        let device = find_device(name).ok_or(VfsError::NotFound)?;
+        let node_type = device.node_type;
+        Ok(DirEntry::new_file(
+            FileNode::new(Arc::new(DevNode {
+                fs: self.fs.clone(),
+                device,
+            })),
+            node_type,
+            Reference::new(self.this.upgrade(), name.to_owned()),
+        ))
+    }
+
+    fn create(
+        &self,
+        _name: &str,
+        _node_type: NodeType,
+        _permission: NodePermission,
+    ) -> VfsResult<DirEntry> {
+        // Device nodes are only added through `register_device`.
+        Err(VfsError::OperationNotPermitted)
+    }
+
+    fn link(&self, _name: &str, _node: &DirEntry) -> VfsResult<DirEntry> {
+        Err(VfsError::OperationNotPermitted)
+    }
+
+    fn unlink(&self, _name: &str) -> VfsResult<()> {
+        Err(VfsError::OperationNotPermitted)
+    }
+
+    fn rename(&self, _src_name: &str, _dst_dir: &DirNode, _dst_name: &str) -> VfsResult<()> {
+        Err(VfsError::OperationNotPermitted)
+    }
+}
+
+/// A device node, forwarding file operations to the device.
+struct DevNode {
+    fs: Arc<DevFilesystem>,
+    device: Arc<Device>,
+}
+
+impl NodeOps for DevNode {
+    fn inode(&self) -> u64 {
+        self.device.ino
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        let meta = self.device.meta.lock();
+        Ok(Metadata {
+            inode: self.device.ino,
+            device: 0,
+            nlink: 1,
+            mode: meta.mode,
+            node_type: self.device.node_type,
+            uid: meta.uid,
+            gid: meta.gid,
+            size: self.device.ops.len(),
+            block_size: 0,
+            blocks: 0,
+            rdev: self.device.rdev,
+            atime: meta.atime,
+            mtime: meta.mtime,
+            ctime: meta.ctime,
+        })
+    }
+
+    fn update_metadata(&self, update: MetadataUpdate) -> VfsResult<()> {
+        let mut meta = self.device.meta.lock();
+        if let Some(mode) = update.mode {
+            meta.mode = mode;
+        }
+        if let Some((uid, gid)) = update.owner {
+            meta.uid = uid;
+            meta.gid = gid;
+        }
+        if let Some(atime) = update.atime {
+            meta.atime = atime;
+        }
+        if let Some(mtime) = update.mtime {
+            meta.mtime = mtime;
+        }
+        meta.ctime = now();
+        Ok(())
+    }
+
+    fn len(&self) -> VfsResult<u64> {
+        Ok(self.device.ops.len())
+    }
+
+    fn filesystem(&self) -> &dyn FilesystemOps {
+        &*self.fs
+    }
+
+    fn sync(&self, _data_only: bool) -> VfsResult<()> {
+        self.device.ops.sync()
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+
+    fn flags(&self) -> NodeFlags {
+        self.device.ops.flags()
+    }
+}
+
+impl FileNodeOps for DevNode {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> VfsResult<usize> {
+        self.device.ops.read_at(buf, offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> VfsResult<usize> {
+        self.device.ops.write_at(buf, offset)
+    }
+
+    fn append(&self, buf: &[u8]) -> VfsResult<(usize, u64)> {
+        let written = self.device.ops.write_at(buf, self.device.ops.len())?;
+        Ok((written, self.device.ops.len()))
+    }
+
+    fn set_len(&self, _len: u64) -> VfsResult<()> {
+        // Truncating a device, e.g. when opened with `O_TRUNC`, is a no-op.
+        Ok(())
+    }
+
+    fn set_symlink(&self, _target: &str) -> VfsResult<()> {
+        Err(VfsError::InvalidInput)
+    }
+}
+
+impl Pollable for DevNode {
+    fn poll(&self) -> IoEvents {
+        self.device.ops.poll()
+    }
+
+    fn register(&self, context: &mut Context<'_>, events: IoEvents) {
+        self.device.ops.register(context, events)
+    }
+}
@@ -0,0 +1,108 @@
+use alloc::{format, sync::Arc};
+use core::task::Context;
+
+use axdriver::prelude::{AxInputDevice, DevError, Event, InputDriverOps};
+use axfs_ng_vfs::{DeviceId, NodePermission, NodeType, VfsError, VfsResult};
+use axio::{IoEvents, Pollable};
+use kspin::SpinNoPreempt as Mutex;
+
+use super::{DeviceOps, register_device};
+use crate::disk::into_vfs_err;
+
+/// Size of `struct input_event` on 64-bit Linux.
+const EVENT_SIZE: usize = 24;
+
+struct InputInner {
+    dev: AxInputDevice,
+    /// An event fetched by `poll` but not read yet.
+    pending: Option<Event>,
+}
+
+impl InputInner {
+    fn next_event(&mut self) -> VfsResult<Option<Event>> {
+        if let Some(event) = self.pending.take() {
+            return Ok(Some(event));
+        }
+        match self.dev.read_event() {
+            Ok(event) => Ok(Some(event)),
+            Err(DevError::Again) => Ok(None),
+            Err(err) => Err(into_vfs_err(err)),
+        }
+    }
+}
+
+/// An input device, as `/dev/input/eventN` on Linux.
+///
+/// Reads return whole `struct input_event`s in the Linux layout.
+pub struct InputDevice {
+    inner: Mutex<InputInner>,
+}
+
+impl InputDevice {
+    pub fn new(dev: AxInputDevice) -> Self {
+        Self {
+            inner: Mutex::new(InputInner { dev, pending: None }),
+        }
+    }
+}
+
+impl Pollable for InputDevice {
+    fn poll(&self) -> IoEvents {
+        let mut inner = self.inner.lock();
+        if inner.pending.is_none() {
+            inner.pending = inner.next_event().ok().flatten();
+        }
+        if inner.pending.is_some() {
+            IoEvents::IN
+        } else {
+            IoEvents::empty()
+        }
+    }
+
+    fn register(&self, _context: &mut Context<'_>, _events: IoEvents) {}
+}
+
+impl DeviceOps for InputDevice {
+    fn read_at(&self, buf: &mut [u8], _offset: u64) -> VfsResult<usize> {
+        if buf.len() < EVENT_SIZE {
+            return Err(VfsError::InvalidInput);
+        }
+        let mut inner = self.inner.lock();
+        let mut read = 0;
+        for chunk in buf.chunks_exact_mut(EVENT_SIZE) {
+            let Some(event) = inner.next_event()? else {
+                break;
+            };
+            let time = axhal::time::monotonic_time();
+            chunk[0..8].copy_from_slice(&time.as_secs().to_le_bytes());
+            chunk[8..16].copy_from_slice(&(time.subsec_micros() as u64).to_le_bytes());
+            chunk[16..18].copy_from_slice(&event.event_type.to_le_bytes());
+            chunk[18..20].copy_from_slice(&event.code.to_le_bytes());
+            chunk[20..24].copy_from_slice(&event.value.to_le_bytes());
+            read += EVENT_SIZE;
+        }
+        if read == 0 {
+            return Err(VfsError::WouldBlock);
+        }
+        Ok(read)
+    }
+
+    fn write_at(&self, _buf: &[u8], _offset: u64) -> VfsResult<usize> {
+        Err(VfsError::Unsupported)
+    }
+}
+
+/// Registers all input devices taken from [`axinput`] as `event0`,
+/// `event1`, ...
+pub fn register_input_devices() -> VfsResult<()> {
+    for (i, dev) in axinput::take_inputs().into_iter().enumerate() {
+        register_device(
+            &format!("event{i}"),
+            NodeType::CharacterDevice,
+            DeviceId::new(13, 64 + i as u32),
+            NodePermission::from_bits_truncate(0o660),
+            Arc::new(InputDevice::new(dev)),
+        )?;
+    }
+    Ok(())
+}
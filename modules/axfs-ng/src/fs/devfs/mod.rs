@@ -0,0 +1,137 @@
+//! A pseudo filesystem exposing devices as files, usually mounted at `/dev`.
+//!
+//! Devices live in a kernel-wide registry rather than in a filesystem
+//! instance, so that any module can add its own nodes with
+//! [`register_device`], before or after devfs is mounted.
+
+mod builtin;
+#[cfg(feature = "display")]
+mod display;
+mod fs;
+#[cfg(feature = "input")]
+mod input;
+
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use axfs_ng_vfs::{DeviceId, NodeFlags, NodePermission, NodeType, VfsError, VfsResult};
+use axio::Pollable;
+pub use builtin::*;
+#[cfg(feature = "display")]
+pub use display::*;
+pub use fs::DevFilesystem;
+#[cfg(feature = "input")]
+pub use input::*;
+use kspin::SpinNoPreempt as Mutex;
+
+/// Operations of a device node.
+///
+/// Offsets are only meaningful to devices without [`NodeFlags::STREAM`].
+pub trait DeviceOps: Pollable + Send + Sync {
+    /// Reads data from the device at `offset`.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> VfsResult<usize>;
+
+    /// Writes data to the device at `offset`.
+    fn write_at(&self, buf: &[u8], offset: u64) -> VfsResult<usize>;
+
+    /// Returns the size of the device, or 0 if it has no size.
+    fn len(&self) -> u64 {
+        0
+    }
+
+    /// Flushes the data written to the device.
+    fn sync(&self) -> VfsResult<()> {
+        Ok(())
+    }
+
+    fn flags(&self) -> NodeFlags {
+        NodeFlags::NON_CACHEABLE | NodeFlags::STREAM
+    }
+}
+
+pub(crate) struct DeviceMeta {
+    pub mode: NodePermission,
+    pub uid: u32,
+    pub gid: u32,
+    pub atime: Duration,
+    pub mtime: Duration,
+    pub ctime: Duration,
+}
+
+/// A registered device.
+pub(crate) struct Device {
+    pub ino: u64,
+    pub node_type: NodeType,
+    pub rdev: DeviceId,
+    pub ops: Arc<dyn DeviceOps>,
+    pub meta: Mutex<DeviceMeta>,
+}
+
+/// Inode number of the devfs root directory.
+const ROOT_INO: u64 = 1;
+
+static NEXT_INO: AtomicU64 = AtomicU64::new(ROOT_INO + 1);
+
+static DEVICES: Mutex<BTreeMap<String, Arc<Device>>> = Mutex::new(BTreeMap::new());
+
+/// Adds a device node named `name` to devfs.
+///
+/// `node_type` should be either [`NodeType::CharacterDevice`] or
+/// [`NodeType::BlockDevice`].
+pub fn register_device(
+    name: &str,
+    node_type: NodeType,
+    rdev: DeviceId,
+    mode: NodePermission,
+    ops: Arc<dyn DeviceOps>,
+) -> VfsResult<()> {
+    let mut devices = DEVICES.lock();
+    if devices.contains_key(name) {
+        return Err(VfsError::AlreadyExists);
+    }
+    let time = super::now();
+    devices.insert(
+        name.into(),
+        Arc::new(Device {
+            ino: NEXT_INO.fetch_add(1, Ordering::Relaxed),
+            node_type,
+            rdev,
+            ops,
+            meta: Mutex::new(DeviceMeta {
+                mode,
+                uid: 0,
+                gid: 0,
+                atime: time,
+                mtime: time,
+                ctime: time,
+            }),
+        }),
+    );
+    Ok(())
+}
+
+/// Removes the device node named `name` from devfs.
+///
+/// Files that are already open keep working on the device.
+pub fn unregister_device(name: &str) -> VfsResult<()> {
+    DEVICES
+        .lock()
+        .remove(name)
+        .map(|_| ())
+        .ok_or(VfsError::NotFound)
+}
+
+pub(crate) fn find_device(name: &str) -> Option<Arc<Device>> {
+    DEVICES.lock().get(name).cloned()
+}
+
+pub(crate) fn list_devices() -> Vec<(String, Arc<Device>)> {
+    DEVICES
+        .lock()
+        .iter()
+        .map(|(name, dev)| (name.clone(), dev.clone()))
+        .collect()
+}
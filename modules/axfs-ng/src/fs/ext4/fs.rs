@@ -1,33 +1,84 @@
-use alloc::sync::Arc;
-use core::cell::OnceCell;
+use alloc::{collections::btree_map::BTreeMap, sync::Arc};
+use core::{
+    cell::OnceCell,
+    ops::{Deref, DerefMut},
+};
 
-use axdriver::AxBlockDevice;
+use axdriver::prelude::BlockDriverOps;
+use axerrno::LinuxError;
 use axfs_ng_vfs::{
-    DirEntry, DirNode, Filesystem, FilesystemOps, Reference, StatFs, VfsResult, path::MAX_NAME_LEN,
+    DirEntry, DirNode, Filesystem, FilesystemOps, Reference, StatFs, VfsError, VfsResult,
+    path::MAX_NAME_LEN,
 };
 use kspin::{SpinNoPreempt as Mutex, SpinNoPreemptGuard as MutexGuard};
+use log::warn;
 use lwext4_rust::{FsConfig, ffi::EXT4_ROOT_INO};
 
 use super::{
     Ext4Disk, Inode,
+    journal::Journal,
+    ondisk::{RO_COMPAT_METADATA_CSUM, Superblock},
     util::{LwExt4Filesystem, into_vfs_err},
 };
+use crate::{disk, partition::Partition};
 
 const EXT4_CONFIG: FsConfig = FsConfig { bcache_size: 256 };
 
 pub struct Ext4Filesystem {
     inner: Mutex<LwExt4Filesystem>,
     root_dir: OnceCell<DirEntry>,
+    journal: Option<Arc<Mutex<Journal>>>,
+    dev: Mutex<Partition>,
+    read_only: bool,
 }
 
-impl Ext4Filesystem {
-    pub fn new(dev: AxBlockDevice) -> VfsResult<Filesystem> {
-        let ext4 =
-            lwext4_rust::Ext4Filesystem::new(Ext4Disk(dev), EXT4_CONFIG).map_err(into_vfs_err)?;
+/// Options to open an ext4 filesystem with.
+#[derive(Debug, Clone, Default)]
+pub struct Ext4Options {
+    read_only: bool,
+}
+
+impl Ext4Options {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the option to leave the device untouched, besides replaying the
+    /// journal.
+    ///
+    /// Changes made through the filesystem then fail with `EROFS`. This is
+    /// the only way to open filesystems with metadata checksums, as
+    /// maintaining them on write is not supported.
+    pub fn read_only(&mut self, read_only: bool) -> &mut Self {
+        self.read_only = read_only;
+        self
+    }
 
-        let fs = Arc::new(Self {
+    /// Opens the filesystem on `dev`, replaying its journal if it has one.
+    pub fn open(&self, mut dev: Partition) -> VfsResult<Filesystem> {
+        let sb = Superblock::read(&mut dev)?;
+        if sb.feature_ro_compat & RO_COMPAT_METADATA_CSUM != 0 && !self.read_only {
+            // Writing would leave stale checksums, which Linux takes for
+            // corruption.
+            warn!("ext4: metadata checksums are not supported, open it read-only");
+            return Err(VfsError::Unsupported);
+        }
+        let journal = Journal::open(&mut dev)?
+            .filter(|_| !self.read_only)
+            .map(|journal| Arc::new(Mutex::new(journal)));
+        let disk = Ext4Disk {
+            dev: dev.clone(),
+            journal: journal.clone(),
+            shadow: self.read_only.then(BTreeMap::new),
+        };
+        let ext4 = lwext4_rust::Ext4Filesystem::new(disk, EXT4_CONFIG).map_err(into_vfs_err)?;
+
+        let fs = Arc::new(Ext4Filesystem {
             inner: Mutex::new(ext4),
             root_dir: OnceCell::new(),
+            journal,
+            dev: Mutex::new(dev),
+            read_only: self.read_only,
         });
         let _ = fs.root_dir.set(DirEntry::new_dir(
             |this| DirNode::new(Inode::new(fs.clone(), EXT4_ROOT_INO, Some(this))),
@@ -35,9 +86,79 @@ impl Ext4Filesystem {
         ));
         Ok(Filesystem::new(fs))
     }
+}
+
+impl Ext4Filesystem {
+    /// Opens the filesystem on `dev` with the default options.
+    pub fn new(dev: Partition) -> VfsResult<Filesystem> {
+        Ext4Options::new().open(dev)
+    }
+
+    /// Returns the number of bytes of a file one operation may write.
+    pub(crate) fn max_write(&self) -> usize {
+        match &self.journal {
+            Some(journal) => journal.lock().max_write(),
+            None => usize::MAX,
+        }
+    }
+
+    /// Locks the filesystem for one operation.
+    pub(crate) fn lock(&self) -> Ext4Guard<'_> {
+        Ext4Guard {
+            fs: self,
+            inner: self.inner.lock(),
+        }
+    }
+
+    /// Locks the filesystem for one operation changing it, which fails with
+    /// `EROFS` if it is read-only.
+    pub(crate) fn lock_writable(&self) -> VfsResult<Ext4Guard<'_>> {
+        if self.read_only {
+            return Err(VfsError::Other(LinuxError::EROFS));
+        }
+        Ok(self.lock())
+    }
+}
+
+/// Exclusive access to the filesystem for one operation, at the end of which
+/// the journal is committed if it fills up.
+pub(crate) struct Ext4Guard<'a> {
+    fs: &'a Ext4Filesystem,
+    inner: MutexGuard<'a, LwExt4Filesystem>,
+}
+
+impl Deref for Ext4Guard<'_> {
+    type Target = LwExt4Filesystem;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl DerefMut for Ext4Guard<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
 
-    pub(crate) fn lock(&self) -> MutexGuard<LwExt4Filesystem> {
-        self.inner.lock()
+impl Drop for Ext4Guard<'_> {
+    fn drop(&mut self) {
+        let Some(journal) = &self.fs.journal else {
+            return;
+        };
+        if !journal.lock().should_commit() {
+            return;
+        }
+        // Between operations, the blocks lwext4 caches are consistent with
+        // those it wrote, and commit together.
+        let result = self
+            .inner
+            .flush()
+            .map_err(into_vfs_err)
+            .and_then(|_| journal.lock().commit());
+        if let Err(err) = result {
+            warn!("ext4: failed to commit journal: {err:?}");
+        }
     }
 }
 
@@ -74,6 +195,15 @@ impl FilesystemOps for Ext4Filesystem {
     }
 
     fn flush(&self) -> VfsResult<()> {
-        self.inner.lock().flush().map_err(into_vfs_err)
+        // Keep the filesystem from writing in the meantime.
+        let mut fs = self.inner.lock();
+        fs.flush().map_err(into_vfs_err)?;
+        if self.read_only {
+            return Ok(());
+        }
+        match &self.journal {
+            Some(journal) => journal.lock().commit(),
+            None => self.dev.lock().flush().map_err(disk::into_vfs_err),
+        }
     }
 }
@@ -1,4 +1,4 @@
-use alloc::{borrow::ToOwned, string::String, sync::Arc};
+use alloc::{borrow::ToOwned, string::String, sync::Arc, vec::Vec};
 use core::{any::Any, task::Context};
 
 use axfs_ng_vfs::{
@@ -7,12 +7,13 @@ use axfs_ng_vfs::{
     VfsResult, WeakDirEntry,
 };
 use axio::{IoEvents, Pollable};
-use lwext4_rust::{FileAttr, InodeType};
+use lwext4_rust::{Ext4Result, FileAttr, InodeType};
 
 use super::{
     Ext4Filesystem,
     util::{LwExt4Filesystem, into_vfs_err, into_vfs_type},
 };
+use crate::highlevel::{XattrFlags, XattrNode, check_set_flags, no_data};
 
 pub struct Inode {
     fs: Arc<Ext4Filesystem>,
@@ -50,6 +51,30 @@ impl Inode {
         Ok(self.create_entry(&entry, name))
     }
 
+    /// Writes `buf` in chunks, each in an operation of its own so that the
+    /// journal can commit in between, with `write` given the bytes written
+    /// so far.
+    fn write_chunks(
+        &self,
+        buf: &[u8],
+        mut write: impl FnMut(&mut LwExt4Filesystem, &[u8], usize) -> Ext4Result<usize>,
+    ) -> VfsResult<usize> {
+        let mut written = 0;
+        for chunk in buf.chunks(self.fs.max_write()) {
+            match write(&mut self.fs.lock_writable()?, chunk, written) {
+                Ok(n) => {
+                    written += n;
+                    if n < chunk.len() {
+                        break;
+                    }
+                }
+                Err(_) if written > 0 => break,
+                Err(err) => return Err(into_vfs_err(err)),
+            }
+        }
+        Ok(written)
+    }
+
     fn update_ctime_locked(&self, fs: &mut LwExt4Filesystem, ino: u32) -> VfsResult<()> {
         fs.with_inode_ref(ino, |ino| {
             ino.update_ctime();
@@ -89,7 +114,7 @@ impl NodeOps for Inode {
     }
 
     fn update_metadata(&self, update: MetadataUpdate) -> VfsResult<()> {
-        let mut fs = self.fs.lock();
+        let mut fs = self.fs.lock_writable()?;
         fs.with_inode_ref(self.ino, |inode| {
             if let Some(mode) = update.mode {
                 inode.set_mode((inode.mode() & !0xfff) | (mode.bits() as u32));
@@ -143,28 +168,35 @@ impl FileNodeOps for Inode {
     }
 
     fn write_at(&self, buf: &[u8], offset: u64) -> VfsResult<usize> {
-        self.fs
-            .lock()
-            .write_at(self.ino, buf, offset)
-            .map_err(into_vfs_err)
+        self.write_chunks(buf, |fs, chunk, written| {
+            fs.write_at(self.ino, chunk, offset + written as u64)
+        })
     }
 
     fn append(&self, buf: &[u8]) -> VfsResult<(usize, u64)> {
-        let mut fs = self.fs.lock();
-        let length = fs
-            .with_inode_ref(self.ino, |inode| Ok(inode.size()))
-            .map_err(into_vfs_err)?;
-        let written = fs.write_at(self.ino, buf, length).map_err(into_vfs_err)?;
-        Ok((written, length + written as u64))
+        let mut end = 0;
+        let written = self.write_chunks(buf, |fs, chunk, _| {
+            let length = fs.with_inode_ref(self.ino, |inode| Ok(inode.size()))?;
+            let written = fs.write_at(self.ino, chunk, length)?;
+            end = length + written as u64;
+            Ok(written)
+        })?;
+        if written == 0 {
+            end = self.len()?;
+        }
+        Ok((written, end))
     }
 
     fn set_len(&self, len: u64) -> VfsResult<()> {
-        self.fs.lock().set_len(self.ino, len).map_err(into_vfs_err)
+        self.fs
+            .lock_writable()?
+            .set_len(self.ino, len)
+            .map_err(into_vfs_err)
     }
 
     fn set_symlink(&self, target: &str) -> VfsResult<()> {
         self.fs
-            .lock()
+            .lock_writable()?
             .set_symlink(self.ino, target.as_bytes())
             .map_err(into_vfs_err)
     }
@@ -221,7 +253,7 @@ impl DirNodeOps for Inode {
                 return Err(VfsError::InvalidData);
             }
         };
-        let mut fs = self.fs.lock();
+        let mut fs = self.fs.lock_writable()?;
         if fs.lookup(self.ino, name).is_ok() {
             return Err(VfsError::AlreadyExists);
         }
@@ -249,7 +281,7 @@ impl DirNodeOps for Inode {
     }
 
     fn link(&self, name: &str, node: &DirEntry) -> VfsResult<DirEntry> {
-        let mut fs = self.fs.lock();
+        let mut fs = self.fs.lock_writable()?;
         fs.link(self.ino, name, node.inode() as _)
             .map_err(into_vfs_err)?;
         self.update_ctime_locked(&mut fs, node.inode() as _)?;
@@ -257,13 +289,54 @@ impl DirNodeOps for Inode {
     }
 
     fn unlink(&self, name: &str) -> VfsResult<()> {
-        self.fs.lock().unlink(self.ino, name).map_err(into_vfs_err)
+        self.fs
+            .lock_writable()?
+            .unlink(self.ino, name)
+            .map_err(into_vfs_err)
     }
 
     fn rename(&self, src_name: &str, dst_dir: &DirNode, dst_name: &str) -> VfsResult<()> {
         let dst_dir: Arc<Self> = dst_dir.downcast().map_err(|_| VfsError::InvalidInput)?;
-        let mut fs = self.fs.lock();
+        let mut fs = self.fs.lock_writable()?;
         fs.rename(self.ino, src_name, dst_dir.ino, dst_name)
             .map_err(into_vfs_err)
     }
 }
+
+impl XattrNode for Inode {
+    fn get_xattr(&self, name: &str) -> VfsResult<Vec<u8>> {
+        self.fs
+            .lock()
+            .get_xattr(self.ino, name)
+            .map_err(into_vfs_err)?
+            .ok_or_else(no_data)
+    }
+
+    fn set_xattr(&self, name: &str, value: &[u8], flags: XattrFlags) -> VfsResult<()> {
+        let mut fs = self.fs.lock_writable()?;
+        let exists = fs
+            .get_xattr(self.ino, name)
+            .map_err(into_vfs_err)?
+            .is_some();
+        check_set_flags(exists, flags)?;
+        fs.set_xattr(self.ino, name, value).map_err(into_vfs_err)?;
+        self.update_ctime_locked(&mut fs, self.ino)
+    }
+
+    fn list_xattr(&self) -> VfsResult<Vec<String>> {
+        self.fs.lock().list_xattr(self.ino).map_err(into_vfs_err)
+    }
+
+    fn remove_xattr(&self, name: &str) -> VfsResult<()> {
+        let mut fs = self.fs.lock_writable()?;
+        if fs
+            .get_xattr(self.ino, name)
+            .map_err(into_vfs_err)?
+            .is_none()
+        {
+            return Err(no_data());
+        }
+        fs.remove_xattr(self.ino, name).map_err(into_vfs_err)?;
+        self.update_ctime_locked(&mut fs, self.ino)
+    }
+}
@@ -0,0 +1,530 @@
+//! The jbd2 journal of ext4, which lwext4 does not maintain.
+//!
+//! Pending transactions are replayed when the filesystem is mounted. Blocks
+//! written afterwards are held in memory until the filesystem is flushed,
+//! and are then committed to the journal as one transaction before being
+//! written in place, so that a crash leaves either all of them or none.
+//! Commits only happen between operations of the filesystem, which flushes
+//! its own cache first, so that no operation is split across transactions.
+//! Writes to files are split into operations small enough for that, see
+//! [`Journal::max_write`].
+//!
+//! Transactions are written without checksums, which jbd2 accepts on any
+//! filesystem; the checksums of transactions being replayed are not checked.
+
+use alloc::{boxed::Box, collections::btree_map::BTreeMap, vec, vec::Vec};
+use core::mem;
+
+use axdriver::prelude::BlockDriverOps;
+use axfs_ng_vfs::{VfsError, VfsResult};
+use log::{info, warn};
+
+use super::ondisk::{
+    COMPAT_HAS_JOURNAL, Extent, INCOMPAT_RECOVER, SUPERBLOCK_OFFSET, SUPERBLOCK_SIZE, Superblock,
+    read_bytes, update_incompat, write_bytes,
+};
+use crate::{disk::into_vfs_err, partition::Partition};
+
+pub const JBD2_MAGIC: u32 = 0xc03b_3998;
+
+pub const BLOCK_DESCRIPTOR: u32 = 1;
+pub const BLOCK_COMMIT: u32 = 2;
+pub const BLOCK_SUPERBLOCK_V1: u32 = 3;
+pub const BLOCK_SUPERBLOCK_V2: u32 = 4;
+pub const BLOCK_REVOKE: u32 = 5;
+
+pub const FEATURE_INCOMPAT_REVOKE: u32 = 0x1;
+pub const FEATURE_INCOMPAT_64BIT: u32 = 0x2;
+pub const FEATURE_INCOMPAT_ASYNC_COMMIT: u32 = 0x4;
+pub const FEATURE_INCOMPAT_CSUM_V2: u32 = 0x8;
+pub const FEATURE_INCOMPAT_CSUM_V3: u32 = 0x10;
+const FEATURE_INCOMPAT_CSUM: u32 = FEATURE_INCOMPAT_CSUM_V2 | FEATURE_INCOMPAT_CSUM_V3;
+const FEATURE_INCOMPAT_SUPPORTED: u32 = FEATURE_INCOMPAT_REVOKE
+    | FEATURE_INCOMPAT_64BIT
+    | FEATURE_INCOMPAT_ASYNC_COMMIT
+    | FEATURE_INCOMPAT_CSUM;
+
+const FLAG_ESCAPE: u32 = 0x1;
+const FLAG_SAME_UUID: u32 = 0x2;
+const FLAG_LAST_TAG: u32 = 0x8;
+
+/// Length of the header of every journal block.
+pub const HEADER_LEN: usize = 12;
+/// Offset of the UUID in the journal superblock.
+pub const UUID_OFFSET: usize = 48;
+
+pub fn be32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+pub fn put_be32(buf: &mut [u8], offset: usize, value: u32) {
+    buf[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+}
+
+fn be16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+fn put_be16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset..offset + 2].copy_from_slice(&value.to_be_bytes());
+}
+
+/// Writes the header of a journal block.
+pub fn put_header(buf: &mut [u8], block_type: u32, sequence: u32) {
+    put_be32(buf, 0, JBD2_MAGIC);
+    put_be32(buf, 4, block_type);
+    put_be32(buf, 8, sequence);
+}
+
+/// Returns whether transaction `a` is `b` or later, sequence numbers
+/// wrapping around.
+fn tid_geq(a: u32, b: u32) -> bool {
+    a.wrapping_sub(b) as i32 >= 0
+}
+
+/// Returns the size of the tags of descriptor blocks written with the
+/// features `incompat`.
+fn tag_bytes(incompat: u32) -> usize {
+    if incompat & FEATURE_INCOMPAT_CSUM_V3 != 0 {
+        return 16;
+    }
+    let mut size = 8;
+    if incompat & FEATURE_INCOMPAT_CSUM_V2 != 0 {
+        size += 2;
+    }
+    if incompat & FEATURE_INCOMPAT_64BIT != 0 {
+        size += 4;
+    }
+    size
+}
+
+/// A block logged by a transaction.
+struct Tag {
+    target: u64,
+    /// Where the block is in the log.
+    log: u32,
+    escaped: bool,
+}
+
+pub(crate) struct Journal {
+    dev: Partition,
+    block_size: usize,
+    /// Where the log is on the device.
+    extents: Vec<Extent>,
+    /// First block of the log after the journal superblock.
+    first: u32,
+    maxlen: u32,
+    /// Sequence number of the next transaction.
+    sequence: u32,
+    incompat: u32,
+    /// Blocks written since the last commit, by block number.
+    pending: BTreeMap<u64, Box<[u8]>>,
+}
+
+impl Journal {
+    /// Opens the journal of the ext4 filesystem on `dev`, replaying the
+    /// transactions it holds.
+    ///
+    /// Returns `None` if the filesystem has no journal.
+    pub fn open(dev: &mut Partition) -> VfsResult<Option<Self>> {
+        let sb = Superblock::read(dev)?;
+        if sb.feature_compat & COMPAT_HAS_JOURNAL == 0 {
+            return Ok(None);
+        }
+        if sb.journal_inum == 0 {
+            warn!("ext4: external journals are not supported");
+            return Err(VfsError::Unsupported);
+        }
+        let inode = sb.read_inode(dev, sb.journal_inum)?;
+        let mut journal = Self {
+            dev: dev.clone(),
+            block_size: sb.block_size,
+            extents: sb.file_extents(dev, &inode)?,
+            first: 0,
+            maxlen: 0,
+            sequence: 0,
+            incompat: 0,
+            pending: BTreeMap::new(),
+        };
+
+        let mut jsb = vec![0; sb.block_size];
+        journal.read_log(0, &mut jsb)?;
+        let version = be32(&jsb, 4);
+        if be32(&jsb, 0) != JBD2_MAGIC
+            || !(BLOCK_SUPERBLOCK_V1..=BLOCK_SUPERBLOCK_V2).contains(&version)
+            || be32(&jsb, 12) as usize != sb.block_size
+        {
+            warn!("ext4: invalid journal superblock");
+            return Err(VfsError::InvalidData);
+        }
+        journal.maxlen = be32(&jsb, 16);
+        journal.first = be32(&jsb, 20);
+        journal.sequence = be32(&jsb, 24);
+        if version == BLOCK_SUPERBLOCK_V2 {
+            journal.incompat = be32(&jsb, 40);
+        }
+        let log_len = (journal.maxlen as u64).saturating_sub(1);
+        if journal.first == 0
+            || journal.first >= journal.maxlen
+            || Extent::map(&journal.extents, log_len).is_none()
+        {
+            warn!("ext4: journal is smaller than its superblock says");
+            return Err(VfsError::InvalidData);
+        }
+        if journal.incompat & !FEATURE_INCOMPAT_SUPPORTED != 0 {
+            warn!(
+                "ext4: unsupported journal features {:#x}",
+                journal.incompat & !FEATURE_INCOMPAT_SUPPORTED
+            );
+            return Err(VfsError::Unsupported);
+        }
+        if sb.blocks_count > u32::MAX as u64 && journal.incompat & FEATURE_INCOMPAT_64BIT == 0 {
+            return Err(VfsError::InvalidData);
+        }
+
+        let start = be32(&jsb, 28);
+        // Transactions are written without checksums from now on, which the
+        // journal superblock says once the first one is committed.
+        let incompat = journal.incompat;
+        journal.incompat &= !(FEATURE_INCOMPAT_CSUM | FEATURE_INCOMPAT_ASYNC_COMMIT);
+        if start != 0 {
+            journal.sequence = journal.replay(start, incompat)?;
+            journal.write_superblock(0)?;
+            journal.set_recover(false)?;
+            journal.flush()?;
+        }
+        Ok(Some(journal))
+    }
+
+    fn log_offset(&self, block: u32) -> VfsResult<u64> {
+        let block = Extent::map(&self.extents, block as u64).ok_or(VfsError::InvalidData)?;
+        Ok(block * self.block_size as u64)
+    }
+
+    fn read_log(&mut self, block: u32, buf: &mut [u8]) -> VfsResult<()> {
+        let offset = self.log_offset(block)?;
+        read_bytes(&mut self.dev, offset, buf)
+    }
+
+    fn write_log(&mut self, block: u32, buf: &[u8]) -> VfsResult<()> {
+        let offset = self.log_offset(block)?;
+        write_bytes(&mut self.dev, offset, buf)
+    }
+
+    fn next(&self, block: u32) -> u32 {
+        if block + 1 >= self.maxlen {
+            self.first
+        } else {
+            block + 1
+        }
+    }
+
+    fn flush(&mut self) -> VfsResult<()> {
+        self.dev.flush().map_err(into_vfs_err)
+    }
+
+    /// Updates the start of the log and the sequence in the journal
+    /// superblock.
+    fn write_superblock(&mut self, start: u32) -> VfsResult<()> {
+        let mut jsb = vec![0; self.block_size];
+        self.read_log(0, &mut jsb)?;
+        put_be32(&mut jsb, 24, self.sequence);
+        put_be32(&mut jsb, 28, start);
+        if be32(&jsb, 4) == BLOCK_SUPERBLOCK_V2 {
+            // Commit block checksums, a compatible feature.
+            put_be32(&mut jsb, 36, 0);
+            put_be32(&mut jsb, 40, self.incompat);
+        }
+        self.write_log(0, &jsb)
+    }
+
+    /// Sets or clears the flag telling that the journal must be replayed
+    /// before the filesystem is used.
+    fn set_recover(&mut self, recover: bool) -> VfsResult<()> {
+        let mut sb = [0; SUPERBLOCK_SIZE];
+        read_bytes(&mut self.dev, SUPERBLOCK_OFFSET, &mut sb)?;
+        if recover {
+            update_incompat(&mut sb, INCOMPAT_RECOVER, 0);
+        } else {
+            update_incompat(&mut sb, 0, INCOMPAT_RECOVER);
+        }
+        write_bytes(&mut self.dev, SUPERBLOCK_OFFSET, &sb)
+    }
+
+    /// Replays the transactions of the log starting at `start`, written with
+    /// the features `incompat`, returning the sequence number of the next
+    /// transaction.
+    fn replay(&mut self, start: u32, incompat: u32) -> VfsResult<u32> {
+        let mut jsb = vec![0; self.block_size];
+        self.read_log(0, &mut jsb)?;
+        let mut sequence = be32(&jsb, 24);
+
+        let tag_bytes = tag_bytes(incompat);
+        let is_64bit = incompat & FEATURE_INCOMPAT_64BIT != 0;
+        let tags_end = if incompat & FEATURE_INCOMPAT_CSUM != 0 {
+            self.block_size - 4
+        } else {
+            self.block_size
+        };
+
+        let mut transactions = Vec::new();
+        // The last transaction revoking each block.
+        let mut revoked = BTreeMap::new();
+        let mut tags = Vec::new();
+        let mut revokes = Vec::new();
+        let mut block = vec![0; self.block_size];
+        let mut pos = start;
+        for _ in 0..self.maxlen {
+            self.read_log(pos, &mut block)?;
+            if be32(&block, 0) != JBD2_MAGIC || be32(&block, 8) != sequence {
+                break;
+            }
+            match be32(&block, 4) {
+                BLOCK_DESCRIPTOR => {
+                    let mut offset = HEADER_LEN;
+                    while offset + tag_bytes <= tags_end {
+                        let tag = &block[offset..offset + tag_bytes];
+                        let mut target = be32(tag, 0) as u64;
+                        if is_64bit {
+                            target |= (be32(tag, 8) as u64) << 32;
+                        }
+                        let flags = if incompat & FEATURE_INCOMPAT_CSUM_V3 != 0 {
+                            be32(tag, 4)
+                        } else {
+                            be16(tag, 6) as u32
+                        };
+                        pos = self.next(pos);
+                        tags.push(Tag {
+                            target,
+                            log: pos,
+                            escaped: flags & FLAG_ESCAPE != 0,
+                        });
+                        offset += tag_bytes;
+                        if flags & FLAG_SAME_UUID == 0 {
+                            offset += 16;
+                        }
+                        if flags & FLAG_LAST_TAG != 0 {
+                            break;
+                        }
+                    }
+                }
+                BLOCK_COMMIT => {
+                    transactions.push((sequence, mem::take(&mut tags)));
+                    for target in revokes.drain(..) {
+                        revoked.insert(target, sequence);
+                    }
+                    sequence = sequence.wrapping_add(1);
+                }
+                BLOCK_REVOKE => {
+                    let end = (be32(&block, 12) as usize).clamp(HEADER_LEN + 4, tags_end);
+                    let record = if is_64bit { 8 } else { 4 };
+                    for entry in block[HEADER_LEN + 4..end].chunks_exact(record) {
+                        revokes.push(if is_64bit {
+                            (be32(entry, 0) as u64) << 32 | be32(entry, 4) as u64
+                        } else {
+                            be32(entry, 0) as u64
+                        });
+                    }
+                }
+                _ => break,
+            }
+            pos = self.next(pos);
+        }
+
+        let mut data = vec![0; self.block_size];
+        for (tx, tags) in &transactions {
+            for tag in tags {
+                if revoked
+                    .get(&tag.target)
+                    .is_some_and(|&revoker| tid_geq(revoker, *tx))
+                {
+                    continue;
+                }
+                self.read_log(tag.log, &mut data)?;
+                if tag.escaped {
+                    put_be32(&mut data, 0, JBD2_MAGIC);
+                }
+                let offset = tag.target * self.block_size as u64;
+                write_bytes(&mut self.dev, offset, &data)?;
+            }
+        }
+        self.flush()?;
+        info!("ext4: replayed {} journal transactions", transactions.len());
+        Ok(sequence)
+    }
+
+    /// Returns the number of blocks that may be pending, so that a
+    /// transaction of them fits in the log with its descriptor and commit
+    /// blocks.
+    fn max_pending(&self) -> usize {
+        let log_len = (self.maxlen - self.first) as usize;
+        let per_descriptor = (self.block_size - HEADER_LEN - 16) / tag_bytes(self.incompat);
+        log_len.saturating_sub(2) * per_descriptor / (per_descriptor + 1)
+    }
+
+    /// Returns the number of bytes of a file one operation may write, so that
+    /// its blocks fit in the room [`should_commit`](Self::should_commit)
+    /// leaves, along with the metadata they change.
+    pub fn max_write(&self) -> usize {
+        (self.max_pending() / 4).max(1) * self.block_size
+    }
+
+    /// Returns whether the pending blocks should be committed at the end of
+    /// the current operation, leaving room for the next ones.
+    pub fn should_commit(&self) -> bool {
+        self.pending.len() >= self.max_pending() / 2
+    }
+
+    /// Reads `buf.len()` bytes at byte `offset` of the device, as last
+    /// written.
+    pub fn read(&mut self, offset: u64, buf: &mut [u8]) -> VfsResult<()> {
+        let block_size = self.block_size as u64;
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let start = (pos % block_size) as usize;
+            let len = (self.block_size - start).min(buf.len() - done);
+            let dst = &mut buf[done..done + len];
+            match self.pending.get(&(pos / block_size)) {
+                Some(data) => dst.copy_from_slice(&data[start..start + len]),
+                None => read_bytes(&mut self.dev, pos, dst)?,
+            }
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Writes `buf` at byte `offset` of the device, within the next
+    /// transaction.
+    ///
+    /// Fails with [`VfsError::StorageFull`] if the transaction would not fit
+    /// in the log.
+    pub fn write(&mut self, offset: u64, buf: &[u8]) -> VfsResult<()> {
+        let block_size = self.block_size as u64;
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let block = pos / block_size;
+            let start = (pos % block_size) as usize;
+            let len = (self.block_size - start).min(buf.len() - done);
+            if !self.pending.contains_key(&block) {
+                if self.pending.len() >= self.max_pending() {
+                    warn!("ext4: transaction does not fit in the journal");
+                    return Err(VfsError::StorageFull);
+                }
+                let mut data = vec![0; self.block_size].into_boxed_slice();
+                if len < self.block_size {
+                    read_bytes(&mut self.dev, block * block_size, &mut data)?;
+                }
+                self.pending.insert(block, data);
+            }
+            let data = self.pending.get_mut(&block).unwrap();
+            data[start..start + len].copy_from_slice(&buf[done..done + len]);
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Commits the pending blocks as one transaction and writes them in
+    /// place.
+    pub fn commit(&mut self) -> VfsResult<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let pending = mem::take(&mut self.pending);
+        let sequence = self.sequence;
+        let tag_bytes = tag_bytes(self.incompat);
+        let is_64bit = self.incompat & FEATURE_INCOMPAT_64BIT != 0;
+
+        let mut jsb = vec![0; self.block_size];
+        self.read_log(0, &mut jsb)?;
+        let mut uuid = [0; 16];
+        uuid.copy_from_slice(&jsb[UUID_OFFSET..UUID_OFFSET + 16]);
+
+        // The log is live from here, and must be replayed after a crash.
+        self.set_recover(true)?;
+        self.write_superblock(self.first)?;
+        self.flush()?;
+
+        let blocks = pending.iter().collect::<Vec<_>>();
+        let per_descriptor = (self.block_size - HEADER_LEN - 16) / tag_bytes;
+        let mut pos = self.first;
+        let mut escaped = vec![0; self.block_size];
+        for chunk in blocks.chunks(per_descriptor) {
+            let mut descriptor = vec![0; self.block_size];
+            put_header(&mut descriptor, BLOCK_DESCRIPTOR, sequence);
+            let descriptor_pos = pos;
+            pos = self.next(pos);
+            let mut offset = HEADER_LEN;
+            for (i, (target, data)) in chunk.iter().enumerate() {
+                let mut flags = 0;
+                if i > 0 {
+                    flags |= FLAG_SAME_UUID;
+                }
+                if i + 1 == chunk.len() {
+                    flags |= FLAG_LAST_TAG;
+                }
+                if be32(data, 0) == JBD2_MAGIC {
+                    flags |= FLAG_ESCAPE;
+                    escaped.copy_from_slice(data);
+                    put_be32(&mut escaped, 0, 0);
+                    self.write_log(pos, &escaped)?;
+                } else {
+                    self.write_log(pos, data)?;
+                }
+                pos = self.next(pos);
+
+                let tag = &mut descriptor[offset..offset + tag_bytes];
+                put_be32(tag, 0, **target as u32);
+                put_be16(tag, 6, flags as u16);
+                if is_64bit {
+                    put_be32(tag, 8, (**target >> 32) as u32);
+                }
+                offset += tag_bytes;
+                if i == 0 {
+                    descriptor[offset..offset + 16].copy_from_slice(&uuid);
+                    offset += 16;
+                }
+            }
+            self.write_log(descriptor_pos, &descriptor)?;
+        }
+        self.flush()?;
+
+        let mut commit = vec![0; self.block_size];
+        put_header(&mut commit, BLOCK_COMMIT, sequence);
+        self.write_log(pos, &commit)?;
+        self.flush()?;
+
+        // Checkpoint, keeping the superblock flagged until the log is empty.
+        let block_size = self.block_size as u64;
+        for (&target, data) in &pending {
+            let offset = target * block_size;
+            if (offset..offset + block_size).contains(&SUPERBLOCK_OFFSET) {
+                let mut data = data.clone();
+                let start = (SUPERBLOCK_OFFSET - offset) as usize;
+                update_incompat(
+                    &mut data[start..start + SUPERBLOCK_SIZE],
+                    INCOMPAT_RECOVER,
+                    0,
+                );
+                write_bytes(&mut self.dev, offset, &data)?;
+            } else {
+                write_bytes(&mut self.dev, offset, data)?;
+            }
+        }
+        self.flush()?;
+
+        self.sequence = sequence.wrapping_add(1);
+        self.write_superblock(0)?;
+        self.set_recover(false)?;
+        self.flush()
+    }
+}
+
+impl Drop for Journal {
+    fn drop(&mut self) {
+        if let Err(err) = self.commit() {
+            warn!("ext4: failed to commit journal: {err:?}");
+        }
+    }
+}
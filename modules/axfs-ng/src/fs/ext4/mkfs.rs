@@ -0,0 +1,491 @@
+//! Creation of ext4 filesystems.
+//!
+//! The layout is that of `mke2fs -t ext4 -O ^metadata_csum,^flex_bg,^64bit
+//! -O ^resize_inode,^dir_index`: block groups with sparse superblock
+//! backups, extents, and optionally a journal stored in one extent right
+//! after the first inode table.
+
+use alloc::{vec, vec::Vec};
+
+use axdriver::prelude::BlockDriverOps;
+use axfs_ng_vfs::{VfsError, VfsResult};
+
+use super::{
+    journal::{BLOCK_SUPERBLOCK_V2, UUID_OFFSET, put_be32, put_header},
+    ondisk::*,
+};
+use crate::{
+    disk::into_vfs_err,
+    fs::devfs::{DeviceOps, Random},
+    partition::Partition,
+};
+
+const INODE_SIZE: usize = 256;
+/// Size of the fields of the inodes past the first 128 bytes.
+const EXTRA_ISIZE: u16 = 32;
+const DESC_SIZE: usize = 32;
+/// Largest length of an initialized extent.
+const MAX_EXTENT_LEN: u64 = 32768;
+/// Smallest journal that jbd2 accepts.
+const MIN_JOURNAL_BLOCKS: u64 = 1024;
+
+const S_IFREG: u16 = 0o100000;
+const S_IFDIR: u16 = 0o040000;
+const FT_DIR: u8 = 2;
+
+/// Options to create an ext4 filesystem with.
+#[derive(Debug, Clone)]
+pub struct Ext4MkfsOptions {
+    block_size: Option<usize>,
+    bytes_per_inode: usize,
+    journal: bool,
+    journal_blocks: Option<u64>,
+    label: [u8; 16],
+    uuid: Option<[u8; 16]>,
+}
+
+impl Default for Ext4MkfsOptions {
+    fn default() -> Self {
+        Self {
+            block_size: None,
+            bytes_per_inode: 16384,
+            journal: true,
+            journal_blocks: None,
+            label: [0; 16],
+            uuid: None,
+        }
+    }
+}
+
+impl Ext4MkfsOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the block size, one of 1024, 2048 and 4096.
+    ///
+    /// By default, it is 1024 for devices smaller than 512 MiB and 4096
+    /// otherwise.
+    pub fn block_size(&mut self, block_size: usize) -> &mut Self {
+        self.block_size = Some(block_size);
+        self
+    }
+
+    /// Sets the number of bytes of the device per inode, 16384 by default.
+    pub fn bytes_per_inode(&mut self, bytes_per_inode: usize) -> &mut Self {
+        self.bytes_per_inode = bytes_per_inode;
+        self
+    }
+
+    /// Sets whether to create a journal, which is the default.
+    pub fn journal(&mut self, journal: bool) -> &mut Self {
+        self.journal = journal;
+        self
+    }
+
+    /// Sets the size of the journal in blocks, at least 1024 and at most what
+    /// the first block group has left.
+    ///
+    /// By default, it is a 64th of the filesystem, between 1024 and 8192
+    /// blocks, capped to what the first group has left; filesystems where
+    /// that is too little go without a journal.
+    pub fn journal_blocks(&mut self, blocks: u64) -> &mut Self {
+        self.journal_blocks = Some(blocks);
+        self
+    }
+
+    /// Sets the volume label, at most 16 bytes.
+    pub fn label(&mut self, label: &str) -> &mut Self {
+        let len = label.len().min(16);
+        self.label = [0; 16];
+        self.label[..len].copy_from_slice(&label.as_bytes()[..len]);
+        self
+    }
+
+    /// Sets the UUID, random by default.
+    pub fn uuid(&mut self, uuid: [u8; 16]) -> &mut Self {
+        self.uuid = Some(uuid);
+        self
+    }
+
+    /// Formats `dev`, erasing what it holds.
+    ///
+    /// Use [`Partition::whole`] to format a whole block device.
+    pub fn format(&self, dev: &mut Partition) -> VfsResult<()> {
+        let layout = Layout::new(self, dev)?;
+        let uuid = self.uuid.unwrap_or_else(|| {
+            let mut uuid = [0; 16];
+            let _ = Random::new().read_at(&mut uuid, 0);
+            // A random UUID, version 4.
+            uuid[6] = (uuid[6] & 0x0f) | 0x40;
+            uuid[8] = (uuid[8] & 0x3f) | 0x80;
+            uuid
+        });
+        layout.write(dev, self, &uuid)?;
+        dev.flush().map_err(into_vfs_err)
+    }
+}
+
+/// Returns whether group `group` holds a backup of the superblock, which
+/// with sparse superblocks are groups 0, 1 and powers of 3, 5 and 7.
+fn has_super(group: u64) -> bool {
+    fn is_power_of(mut n: u64, base: u64) -> bool {
+        while n % base == 0 {
+            n /= base;
+        }
+        n == 1
+    }
+    group <= 1 || is_power_of(group, 3) || is_power_of(group, 5) || is_power_of(group, 7)
+}
+
+/// Where everything goes on the device.
+struct Layout {
+    block_size: usize,
+    blocks_count: u64,
+    first_data_block: u64,
+    blocks_per_group: u64,
+    groups: u64,
+    inodes_per_group: u64,
+    inode_table_blocks: u64,
+    gdt_blocks: u64,
+    journal_blocks: u64,
+}
+
+impl Layout {
+    fn new(options: &Ext4MkfsOptions, dev: &Partition) -> VfsResult<Self> {
+        let size = dev.num_blocks() * dev.block_size() as u64;
+        let block_size = options
+            .block_size
+            .unwrap_or(if size < 512 << 20 { 1024 } else { 4096 });
+        if ![1024, 2048, 4096].contains(&block_size) || block_size < dev.block_size() {
+            return Err(VfsError::InvalidInput);
+        }
+        let bs = block_size as u64;
+        let first_data_block = if block_size == 1024 { 1 } else { 0 };
+        let blocks_per_group = 8 * bs;
+        let mut blocks_count = (size / bs).min(u32::MAX as u64);
+        if blocks_count < first_data_block + 64 {
+            return Err(VfsError::InvalidInput);
+        }
+
+        let inodes_per_block = bs / INODE_SIZE as u64;
+        let mut layout = loop {
+            let groups = (blocks_count - first_data_block).div_ceil(blocks_per_group);
+            let inodes = (size / options.bytes_per_inode.max(1024) as u64).max(16);
+            let inodes_per_group = inodes
+                .div_ceil(groups)
+                .next_multiple_of(inodes_per_block.max(8))
+                .min(blocks_per_group);
+            let layout = Self {
+                block_size,
+                blocks_count,
+                first_data_block,
+                blocks_per_group,
+                groups,
+                inodes_per_group,
+                inode_table_blocks: inodes_per_group / inodes_per_block,
+                gdt_blocks: (groups * DESC_SIZE as u64).div_ceil(bs),
+                journal_blocks: 0,
+            };
+            // Drop the last group if it cannot hold its own metadata.
+            let last = layout.group_len(groups - 1);
+            if last >= layout.overhead(groups - 1) + 16 {
+                break layout;
+            }
+            if groups == 1 {
+                return Err(VfsError::InvalidInput);
+            }
+            blocks_count -= last;
+        };
+
+        if options.journal {
+            // Room left in the first group, besides the root directory and
+            // lost+found.
+            let room = (layout.group_len(0) - layout.overhead(0) - 2).min(MAX_EXTENT_LEN);
+            layout.journal_blocks = match options.journal_blocks {
+                Some(blocks) if (MIN_JOURNAL_BLOCKS..=room).contains(&blocks) => blocks,
+                Some(_) => return Err(VfsError::InvalidInput),
+                None => {
+                    let blocks = (layout.blocks_count / 64)
+                        .clamp(MIN_JOURNAL_BLOCKS, 8192)
+                        .min(room);
+                    // Filesystems too small for a journal go without.
+                    if blocks >= MIN_JOURNAL_BLOCKS {
+                        blocks
+                    } else {
+                        0
+                    }
+                }
+            };
+        }
+        Ok(layout)
+    }
+
+    fn group_start(&self, group: u64) -> u64 {
+        self.first_data_block + group * self.blocks_per_group
+    }
+
+    fn group_len(&self, group: u64) -> u64 {
+        (self.blocks_count - self.group_start(group)).min(self.blocks_per_group)
+    }
+
+    /// Returns the number of blocks of metadata at the start of `group`.
+    fn overhead(&self, group: u64) -> u64 {
+        let backup = if has_super(group) {
+            1 + self.gdt_blocks
+        } else {
+            0
+        };
+        backup + 2 + self.inode_table_blocks
+    }
+
+    fn block_bitmap(&self, group: u64) -> u64 {
+        self.group_start(group) + self.overhead(group) - self.inode_table_blocks - 2
+    }
+
+    fn inode_bitmap(&self, group: u64) -> u64 {
+        self.block_bitmap(group) + 1
+    }
+
+    fn inode_table(&self, group: u64) -> u64 {
+        self.block_bitmap(group) + 2
+    }
+
+    /// Block of the root directory, followed by that of lost+found and by
+    /// the journal.
+    fn root_block(&self) -> u64 {
+        self.inode_table(0) + self.inode_table_blocks
+    }
+
+    fn offset(&self, block: u64) -> u64 {
+        block * self.block_size as u64
+    }
+
+    fn write(
+        &self,
+        dev: &mut Partition,
+        options: &Ext4MkfsOptions,
+        uuid: &[u8; 16],
+    ) -> VfsResult<()> {
+        let bs = self.block_size;
+        let inodes_count = self.groups * self.inodes_per_group;
+        // Inodes 1 to 10 are reserved, 11 is lost+found.
+        let used_inodes = FIRST_INO as u64;
+        let data_blocks = 2 + self.journal_blocks;
+
+        // Group descriptors and bitmaps.
+        let mut gdt = vec![0; (self.gdt_blocks as usize) * bs];
+        let mut free_blocks = 0;
+        for group in 0..self.groups {
+            let len = self.group_len(group);
+            let mut used = self.overhead(group);
+            if group == 0 {
+                used += data_blocks;
+            }
+            let mut bitmap = vec![0; bs];
+            set_bits(&mut bitmap, 0..used as usize);
+            set_bits(&mut bitmap, len as usize..bs * 8);
+            write_bytes(dev, self.offset(self.block_bitmap(group)), &bitmap)?;
+
+            let mut bitmap = vec![0; bs];
+            if group == 0 {
+                set_bits(&mut bitmap, 0..used_inodes as usize);
+            }
+            set_bits(&mut bitmap, self.inodes_per_group as usize..bs * 8);
+            write_bytes(dev, self.offset(self.inode_bitmap(group)), &bitmap)?;
+
+            zero(
+                dev,
+                self.offset(self.inode_table(group)),
+                self.inode_table_blocks as usize * bs,
+            )?;
+
+            let free_inodes = self.inodes_per_group - if group == 0 { used_inodes } else { 0 };
+            let desc = &mut gdt[group as usize * DESC_SIZE..][..DESC_SIZE];
+            put32(desc, 0, self.block_bitmap(group) as u32);
+            put32(desc, 4, self.inode_bitmap(group) as u32);
+            put32(desc, 8, self.inode_table(group) as u32);
+            put16(desc, 12, (len - used) as u16);
+            put16(desc, 14, free_inodes as u16);
+            // The root directory and lost+found.
+            put16(desc, 16, if group == 0 { 2 } else { 0 });
+            free_blocks += len - used;
+        }
+
+        // Inodes and their data.
+        let now = crate::fs::now().as_secs() as u32;
+        let root = self.root_block();
+        let lost_found = root + 1;
+        let mut block = vec![0; bs];
+        write_dir(
+            &mut block,
+            &[(ROOT_INO, "."), (ROOT_INO, ".."), (FIRST_INO, "lost+found")],
+        );
+        write_bytes(dev, self.offset(root), &block)?;
+        let inode = new_inode(S_IFDIR | 0o755, 3, bs as u64, root, 1, now);
+        self.write_inode(dev, ROOT_INO, &inode)?;
+
+        block.fill(0);
+        write_dir(&mut block, &[(FIRST_INO, "."), (ROOT_INO, "..")]);
+        write_bytes(dev, self.offset(lost_found), &block)?;
+        let inode = new_inode(S_IFDIR | 0o700, 2, bs as u64, lost_found, 1, now);
+        self.write_inode(dev, FIRST_INO, &inode)?;
+
+        let mut journal_inode = None;
+        if self.journal_blocks > 0 {
+            let start = lost_found + 1;
+            let size = self.journal_blocks * bs as u64;
+            let inode = new_inode(S_IFREG | 0o600, 1, size, start, self.journal_blocks, now);
+            self.write_inode(dev, JOURNAL_INO, &inode)?;
+
+            block.fill(0);
+            put_header(&mut block, BLOCK_SUPERBLOCK_V2, 0);
+            put_be32(&mut block, 12, bs as u32);
+            put_be32(&mut block, 16, self.journal_blocks as u32);
+            // First block of the log, and sequence of its first transaction.
+            put_be32(&mut block, 20, 1);
+            put_be32(&mut block, 24, 1);
+            block[UUID_OFFSET..UUID_OFFSET + 16].copy_from_slice(uuid);
+            // One user, the filesystem itself.
+            put_be32(&mut block, 64, 1);
+            block[0x100..0x110].copy_from_slice(uuid);
+            write_bytes(dev, self.offset(start), &block)?;
+            journal_inode = Some(inode);
+        }
+
+        // Superblocks.
+        let mut sb = vec![0; SUPERBLOCK_SIZE];
+        put32(&mut sb, 0, inodes_count as u32);
+        put32(&mut sb, 4, self.blocks_count as u32);
+        put32(&mut sb, 12, free_blocks as u32);
+        put32(&mut sb, 16, (inodes_count - used_inodes) as u32);
+        put32(&mut sb, 20, self.first_data_block as u32);
+        let log_block_size = (bs / 1024).trailing_zeros();
+        put32(&mut sb, 24, log_block_size);
+        put32(&mut sb, 28, log_block_size);
+        put32(&mut sb, 32, self.blocks_per_group as u32);
+        put32(&mut sb, 36, self.blocks_per_group as u32);
+        put32(&mut sb, 40, self.inodes_per_group as u32);
+        put32(&mut sb, 48, now);
+        put16(&mut sb, 54, u16::MAX);
+        put16(&mut sb, 56, MAGIC);
+        // Cleanly unmounted, continue on errors.
+        put16(&mut sb, 58, 1);
+        put16(&mut sb, 60, 1);
+        put32(&mut sb, 64, now);
+        // Dynamic revision.
+        put32(&mut sb, 76, 1);
+        put32(&mut sb, 84, FIRST_INO);
+        put16(&mut sb, 88, INODE_SIZE as u16);
+        let compat = if self.journal_blocks > 0 {
+            COMPAT_HAS_JOURNAL
+        } else {
+            0
+        };
+        put32(&mut sb, 92, compat);
+        put32(
+            &mut sb,
+            INCOMPAT_OFFSET,
+            INCOMPAT_FILETYPE | INCOMPAT_EXTENTS,
+        );
+        put32(
+            &mut sb,
+            100,
+            RO_COMPAT_SPARSE_SUPER | RO_COMPAT_LARGE_FILE | RO_COMPAT_EXTRA_ISIZE,
+        );
+        sb[104..120].copy_from_slice(uuid);
+        sb[120..136].copy_from_slice(&options.label);
+        if let Some(inode) = &journal_inode {
+            put32(&mut sb, 224, JOURNAL_INO);
+            // A backup of the block map and size of the journal inode.
+            sb[253] = 1;
+            sb[268..328].copy_from_slice(&inode[40..100]);
+            put32(&mut sb, 268 + 16 * 4, le32(inode, 4));
+        }
+        put32(&mut sb, 264, now);
+        put16(&mut sb, 348, EXTRA_ISIZE);
+        put16(&mut sb, 350, EXTRA_ISIZE);
+
+        // Erase the boot sector, and with it any previous filesystem.
+        zero(dev, 0, SUPERBLOCK_OFFSET as usize)?;
+        for group in (0..self.groups).filter(|&group| has_super(group)) {
+            put16(&mut sb, 90, group as u16);
+            let start = self.group_start(group);
+            if group == 0 {
+                write_bytes(dev, SUPERBLOCK_OFFSET, &sb)?;
+            } else {
+                write_bytes(dev, self.offset(start), &sb)?;
+            }
+            write_bytes(dev, self.offset(start + 1), &gdt)?;
+        }
+        Ok(())
+    }
+
+    fn write_inode(&self, dev: &mut Partition, ino: u32, inode: &[u8]) -> VfsResult<()> {
+        let index = (ino - 1) as u64;
+        let offset = self.offset(self.inode_table(0)) + index * INODE_SIZE as u64;
+        write_bytes(dev, offset, inode)
+    }
+}
+
+/// Writes `len` zeroes at byte `offset` of the device.
+fn zero(dev: &mut Partition, mut offset: u64, mut len: usize) -> VfsResult<()> {
+    let zeroes = vec![0; 64 * 1024];
+    while len > 0 {
+        let chunk = len.min(zeroes.len());
+        write_bytes(dev, offset, &zeroes[..chunk])?;
+        offset += chunk as u64;
+        len -= chunk;
+    }
+    Ok(())
+}
+
+fn set_bits(bitmap: &mut [u8], bits: core::ops::Range<usize>) {
+    for bit in bits {
+        bitmap[bit / 8] |= 1 << (bit % 8);
+    }
+}
+
+/// Returns an inode whose data is the `len` blocks at `start`.
+fn new_inode(mode: u16, links: u16, size: u64, start: u64, len: u64, now: u32) -> Vec<u8> {
+    let mut inode = vec![0; INODE_SIZE];
+    put16(&mut inode, 0, mode);
+    put32(&mut inode, 4, size as u32);
+    put32(&mut inode, 8, now);
+    put32(&mut inode, 12, now);
+    put32(&mut inode, 16, now);
+    put16(&mut inode, 26, links);
+    put32(&mut inode, 28, (size / 512) as u32);
+    put32(&mut inode, 32, INODE_FLAG_EXTENTS);
+
+    let extents = &mut inode[40..100];
+    put16(extents, 0, EXTENT_MAGIC);
+    put16(extents, 2, 1);
+    put16(extents, 4, 4);
+    put32(extents, 12, 0);
+    put16(extents, 16, len as u16);
+    put16(extents, 18, (start >> 32) as u16);
+    put32(extents, 20, start as u32);
+
+    put32(&mut inode, 108, (size >> 32) as u32);
+    put16(&mut inode, 128, EXTRA_ISIZE);
+    inode
+}
+
+/// Fills a directory block with `entries`, all directories.
+fn write_dir(block: &mut [u8], entries: &[(u32, &str)]) {
+    let mut offset = 0;
+    for (i, (ino, name)) in entries.iter().enumerate() {
+        let rec_len = if i + 1 == entries.len() {
+            block.len() - offset
+        } else {
+            (8 + name.len()).next_multiple_of(4)
+        };
+        put32(block, offset, *ino);
+        put16(block, offset + 4, rec_len as u16);
+        block[offset + 6] = name.len() as u8;
+        block[offset + 7] = FT_DIR;
+        block[offset + 8..offset + 8 + name.len()].copy_from_slice(name.as_bytes());
+        offset += rec_len;
+    }
+}
@@ -1,20 +1,62 @@
 mod fs;
 mod inode;
+mod journal;
+pub mod mkfs;
+mod ondisk;
 mod util;
 
+use alloc::{collections::btree_map::BTreeMap, sync::Arc};
+
 #[allow(unused_imports)]
-use axdriver::{AxBlockDevice, prelude::BlockDriverOps};
+use axdriver::prelude::BlockDriverOps;
+use axfs_ng_vfs::VfsError;
 pub use fs::*;
 pub use inode::*;
-use lwext4_rust::{BlockDevice, EXT4_DEV_BSIZE, Ext4Error, Ext4Result, ffi::EIO};
+use journal::Journal;
+use kspin::SpinNoPreempt as Mutex;
+use lwext4_rust::{
+    BlockDevice, EXT4_DEV_BSIZE, Ext4Error, Ext4Result,
+    ffi::{EIO, ENOSPC},
+};
+pub use mkfs::Ext4MkfsOptions;
+
+use crate::partition::Partition;
 
-pub(crate) struct Ext4Disk(AxBlockDevice);
+pub(crate) struct Ext4Disk {
+    dev: Partition,
+    /// Where writes go if the filesystem has a journal.
+    journal: Option<Arc<Mutex<Journal>>>,
+    /// Blocks written to a read-only filesystem, kept in memory so that the
+    /// device is left untouched. Nodes refuse changes, but lwext4 writes some
+    /// blocks even if nothing changes, like the mount count.
+    shadow: Option<BTreeMap<u64, [u8; EXT4_DEV_BSIZE]>>,
+}
 
 impl BlockDevice for Ext4Disk {
     fn read_blocks(&mut self, block_id: u64, buf: &mut [u8]) -> Ext4Result<usize> {
+        if let Some(shadow) = &mut self.shadow {
+            for (i, block) in buf.chunks_mut(EXT4_DEV_BSIZE).enumerate() {
+                let id = block_id + i as u64;
+                match shadow.get(&id) {
+                    Some(data) => block.copy_from_slice(data),
+                    None => self
+                        .dev
+                        .read_block(id, block)
+                        .map_err(|_| Ext4Error::new(EIO as _, None))?,
+                }
+            }
+            return Ok(buf.len());
+        }
+        if let Some(journal) = &self.journal {
+            journal
+                .lock()
+                .read(block_id * EXT4_DEV_BSIZE as u64, buf)
+                .map_err(|_| Ext4Error::new(EIO as _, None))?;
+            return Ok(buf.len());
+        }
         let mut block_buf = [0u8; EXT4_DEV_BSIZE];
         for (i, block) in buf.chunks_mut(EXT4_DEV_BSIZE).enumerate() {
-            self.0
+            self.dev
                 .read_block(block_id + i as u64, &mut block_buf)
                 .map_err(|_| Ext4Error::new(EIO as _, None))?;
             block.copy_from_slice(&block_buf);
@@ -23,10 +65,26 @@ impl BlockDevice for Ext4Disk {
     }
 
     fn write_blocks(&mut self, block_id: u64, buf: &[u8]) -> Ext4Result<usize> {
+        if let Some(shadow) = &mut self.shadow {
+            for (i, block) in buf.chunks(EXT4_DEV_BSIZE).enumerate() {
+                shadow.insert(block_id + i as u64, block.try_into().unwrap());
+            }
+            return Ok(buf.len());
+        }
+        if let Some(journal) = &self.journal {
+            journal
+                .lock()
+                .write(block_id * EXT4_DEV_BSIZE as u64, buf)
+                .map_err(|err| match err {
+                    VfsError::StorageFull => Ext4Error::new(ENOSPC as _, None),
+                    _ => Ext4Error::new(EIO as _, None),
+                })?;
+            return Ok(buf.len());
+        }
         let mut block_buf = [0u8; EXT4_DEV_BSIZE];
         for (i, block) in buf.chunks(EXT4_DEV_BSIZE).enumerate() {
             block_buf.copy_from_slice(block);
-            self.0
+            self.dev
                 .write_block(block_id + i as u64, &block_buf)
                 .map_err(|_| Ext4Error::new(EIO as _, None))?;
         }
@@ -34,6 +92,6 @@ impl BlockDevice for Ext4Disk {
     }
 
     fn num_blocks(&self) -> Ext4Result<u64> {
-        Ok(self.0.num_blocks())
+        Ok(self.dev.num_blocks())
     }
 }
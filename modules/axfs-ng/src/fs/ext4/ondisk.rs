@@ -0,0 +1,313 @@
+//! On-disk structures of ext4 handled outside of lwext4: those written by
+//! [`mkfs`](super::mkfs), and those read to find the journal.
+
+use alloc::{vec, vec::Vec};
+
+use axdriver::prelude::BlockDriverOps;
+use axfs_ng_vfs::{VfsError, VfsResult};
+
+use crate::{disk::into_vfs_err, partition::Partition};
+
+pub const SUPERBLOCK_OFFSET: u64 = 1024;
+pub const SUPERBLOCK_SIZE: usize = 1024;
+pub const MAGIC: u16 = 0xef53;
+
+pub const COMPAT_HAS_JOURNAL: u32 = 0x4;
+pub const INCOMPAT_FILETYPE: u32 = 0x2;
+pub const INCOMPAT_RECOVER: u32 = 0x4;
+pub const INCOMPAT_EXTENTS: u32 = 0x40;
+pub const INCOMPAT_64BIT: u32 = 0x80;
+pub const RO_COMPAT_SPARSE_SUPER: u32 = 0x1;
+pub const RO_COMPAT_LARGE_FILE: u32 = 0x2;
+pub const RO_COMPAT_EXTRA_ISIZE: u32 = 0x40;
+pub const RO_COMPAT_METADATA_CSUM: u32 = 0x400;
+
+/// Offset of the incompatible features in the superblock.
+pub const INCOMPAT_OFFSET: usize = 96;
+
+pub const ROOT_INO: u32 = 2;
+pub const JOURNAL_INO: u32 = 8;
+/// The first inode not reserved, which is `lost+found`.
+pub const FIRST_INO: u32 = 11;
+
+pub const INODE_FLAG_EXTENTS: u32 = 0x80000;
+pub const EXTENT_MAGIC: u16 = 0xf30a;
+
+pub fn le16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+pub fn le32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+pub fn put16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+pub fn put32(buf: &mut [u8], offset: usize, value: u32) {
+    buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Computes the CRC32C of `data`, as ext4 does: without the final inversion.
+pub fn crc32c(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+/// Sets or clears incompatible features of the superblock `sb`, updating its
+/// checksum if it has one.
+pub fn update_incompat(sb: &mut [u8], set: u32, clear: u32) {
+    let incompat = le32(sb, INCOMPAT_OFFSET);
+    put32(sb, INCOMPAT_OFFSET, (incompat | set) & !clear);
+    if le32(sb, 100) & RO_COMPAT_METADATA_CSUM != 0 {
+        let checksum = crc32c(!0, &sb[..0x3fc]);
+        put32(sb, 0x3fc, checksum);
+    }
+}
+
+/// Reads `buf.len()` bytes at byte `offset` of the device.
+pub fn read_bytes(dev: &mut Partition, offset: u64, buf: &mut [u8]) -> VfsResult<()> {
+    let block_size = dev.block_size() as u64;
+    let mut block = vec![0; block_size as usize];
+    let mut done = 0;
+    while done < buf.len() {
+        let pos = offset + done as u64;
+        let start = (pos % block_size) as usize;
+        let len = (block_size as usize - start).min(buf.len() - done);
+        dev.read_block(pos / block_size, &mut block)
+            .map_err(into_vfs_err)?;
+        buf[done..done + len].copy_from_slice(&block[start..start + len]);
+        done += len;
+    }
+    Ok(())
+}
+
+/// Writes `buf` at byte `offset` of the device.
+pub fn write_bytes(dev: &mut Partition, offset: u64, buf: &[u8]) -> VfsResult<()> {
+    let block_size = dev.block_size() as u64;
+    let mut block = vec![0; block_size as usize];
+    let mut done = 0;
+    while done < buf.len() {
+        let pos = offset + done as u64;
+        let start = (pos % block_size) as usize;
+        let len = (block_size as usize - start).min(buf.len() - done);
+        if len < block.len() {
+            dev.read_block(pos / block_size, &mut block)
+                .map_err(into_vfs_err)?;
+        }
+        block[start..start + len].copy_from_slice(&buf[done..done + len]);
+        dev.write_block(pos / block_size, &block)
+            .map_err(into_vfs_err)?;
+        done += len;
+    }
+    Ok(())
+}
+
+/// Fields of the superblock needed to find inodes.
+#[derive(Debug, Clone)]
+pub struct Superblock {
+    pub block_size: usize,
+    pub blocks_count: u64,
+    pub first_data_block: u32,
+    pub inodes_per_group: u32,
+    pub inode_size: usize,
+    pub desc_size: usize,
+    pub feature_compat: u32,
+    pub feature_incompat: u32,
+    pub feature_ro_compat: u32,
+    pub journal_inum: u32,
+}
+
+impl Superblock {
+    pub fn read(dev: &mut Partition) -> VfsResult<Self> {
+        let mut sb = [0; SUPERBLOCK_SIZE];
+        read_bytes(dev, SUPERBLOCK_OFFSET, &mut sb)?;
+        if le16(&sb, 56) != MAGIC || le32(&sb, 24) > 6 || le32(&sb, 40) == 0 {
+            return Err(VfsError::InvalidData);
+        }
+        let feature_incompat = le32(&sb, INCOMPAT_OFFSET);
+        let is_64bit = feature_incompat & INCOMPAT_64BIT != 0;
+        let mut blocks_count = le32(&sb, 4) as u64;
+        if is_64bit {
+            blocks_count |= (le32(&sb, 336) as u64) << 32;
+        }
+        Ok(Self {
+            block_size: 1024 << le32(&sb, 24),
+            blocks_count,
+            first_data_block: le32(&sb, 20),
+            inodes_per_group: le32(&sb, 40),
+            inode_size: match le32(&sb, 76) {
+                0 => 128,
+                _ => le16(&sb, 88) as usize,
+            },
+            desc_size: if is_64bit {
+                le16(&sb, 254) as usize
+            } else {
+                32
+            },
+            feature_compat: le32(&sb, 92),
+            feature_incompat,
+            feature_ro_compat: le32(&sb, 100),
+            journal_inum: le32(&sb, 224),
+        })
+    }
+
+    fn block_offset(&self, block: u64) -> u64 {
+        block * self.block_size as u64
+    }
+
+    /// Reads the on-disk inode `ino`.
+    pub fn read_inode(&self, dev: &mut Partition, ino: u32) -> VfsResult<Vec<u8>> {
+        let group = ((ino - 1) / self.inodes_per_group) as u64;
+        let index = ((ino - 1) % self.inodes_per_group) as u64;
+        let mut desc = vec![0; self.desc_size];
+        let desc_table = self.block_offset(self.first_data_block as u64 + 1);
+        read_bytes(dev, desc_table + group * self.desc_size as u64, &mut desc)?;
+        let mut inode_table = le32(&desc, 8) as u64;
+        if self.desc_size >= 64 {
+            inode_table |= (le32(&desc, 0x28) as u64) << 32;
+        }
+        let mut inode = vec![0; self.inode_size];
+        let offset = self.block_offset(inode_table) + index * self.inode_size as u64;
+        read_bytes(dev, offset, &mut inode)?;
+        Ok(inode)
+    }
+
+    /// Returns the runs of blocks the data of `inode` is stored in, as
+    /// `(logical, physical, len)` in blocks.
+    pub fn file_extents(&self, dev: &mut Partition, inode: &[u8]) -> VfsResult<Vec<Extent>> {
+        let size = le32(inode, 4) as u64 | (le32(inode, 108) as u64) << 32;
+        let blocks = size.div_ceil(self.block_size as u64);
+        let mut extents = Vec::new();
+        if le32(inode, 32) & INODE_FLAG_EXTENTS != 0 {
+            self.walk_extent_node(dev, &inode[40..100], &mut extents)?;
+        } else {
+            let mut mapper = BlockMapper {
+                sb: self,
+                dev,
+                extents: &mut extents,
+                next: 0,
+                end: blocks,
+            };
+            for i in 0..15 {
+                let block = le32(inode, 40 + i * 4);
+                let level = i.saturating_sub(11);
+                mapper.map(block, level)?;
+            }
+        }
+        extents.retain(|it| it.logical < blocks);
+        Ok(extents)
+    }
+
+    fn walk_extent_node(
+        &self,
+        dev: &mut Partition,
+        node: &[u8],
+        extents: &mut Vec<Extent>,
+    ) -> VfsResult<()> {
+        if le16(node, 0) != EXTENT_MAGIC {
+            return Err(VfsError::InvalidData);
+        }
+        let entries = le16(node, 2) as usize;
+        let depth = le16(node, 6);
+        if 12 + entries * 12 > node.len() {
+            return Err(VfsError::InvalidData);
+        }
+        for entry in node[12..12 + entries * 12].chunks(12) {
+            if depth == 0 {
+                let len = le16(entry, 4) as u64;
+                extents.push(Extent {
+                    logical: le32(entry, 0) as u64,
+                    physical: (le16(entry, 6) as u64) << 32 | le32(entry, 8) as u64,
+                    // Longer extents are preallocated, but are mapped all the
+                    // same.
+                    len: if len > 32768 { len - 32768 } else { len },
+                });
+            } else {
+                let child = (le16(entry, 8) as u64) << 32 | le32(entry, 4) as u64;
+                let mut block = vec![0; self.block_size];
+                read_bytes(dev, self.block_offset(child), &mut block)?;
+                self.walk_extent_node(dev, &block, extents)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A run of contiguous blocks of a file.
+#[derive(Debug, Clone, Copy)]
+pub struct Extent {
+    pub logical: u64,
+    pub physical: u64,
+    pub len: u64,
+}
+
+impl Extent {
+    /// Returns the physical block of the logical block `block` of a file
+    /// stored in `extents`.
+    pub fn map(extents: &[Extent], block: u64) -> Option<u64> {
+        extents
+            .iter()
+            .find(|it| (it.logical..it.logical + it.len).contains(&block))
+            .map(|it| it.physical + block - it.logical)
+    }
+}
+
+/// Maps the blocks of a file without extents, as in ext2 and ext3.
+struct BlockMapper<'a> {
+    sb: &'a Superblock,
+    dev: &'a mut Partition,
+    extents: &'a mut Vec<Extent>,
+    /// Next logical block to map.
+    next: u64,
+    end: u64,
+}
+
+impl BlockMapper<'_> {
+    /// Maps the blocks `block` points to through `level` indirect blocks.
+    fn map(&mut self, block: u32, level: usize) -> VfsResult<()> {
+        let per_block = (self.sb.block_size / 4) as u64;
+        let span = per_block.pow(level as u32);
+        if self.next >= self.end {
+            return Ok(());
+        }
+        if block == 0 {
+            // A hole.
+            self.next += span;
+            return Ok(());
+        }
+        if level == 0 {
+            match self.extents.last_mut() {
+                Some(last)
+                    if last.logical + last.len == self.next
+                        && last.physical + last.len == block as u64 =>
+                {
+                    last.len += 1
+                }
+                _ => self.extents.push(Extent {
+                    logical: self.next,
+                    physical: block as u64,
+                    len: 1,
+                }),
+            }
+            self.next += 1;
+            return Ok(());
+        }
+        let mut data = vec![0; self.sb.block_size];
+        read_bytes(self.dev, self.sb.block_offset(block as u64), &mut data)?;
+        for i in 0..per_block as usize {
+            self.map(le32(&data, i * 4), level - 1)?;
+        }
+        Ok(())
+    }
+}
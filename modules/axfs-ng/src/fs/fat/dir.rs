@@ -9,7 +9,8 @@ use axfs_ng_vfs::{
 use super::{
     FsRef, ff,
     file::FatFileNode,
-    fs::FatFilesystem,
+    fs::{FatFilesystem, FatFilesystemInner},
+    unix::{self, AttrRef, AttrTable, SIDECAR_NAME, UnixAttrs},
     util::{file_metadata, into_vfs_err},
 };
 
@@ -18,6 +19,8 @@ pub struct FatDirNode {
     pub(crate) inner: FsRef<ff::Dir<'static>>,
     inode: u64,
     this: WeakDirEntry,
+    /// Attributes of the entries, loaded from the sidecar when first needed.
+    attrs: FsRef<Option<AttrTable>>,
 }
 
 impl FatDirNode {
@@ -28,15 +31,32 @@ impl FatDirNode {
             inner: FsRef::new(unsafe { mem::transmute::<ff::Dir, ff::Dir>(dir) }),
             inode,
             this,
+            attrs: FsRef::new(None),
         }))
     }
 
-    fn create_entry(&self, entry: ff::DirEntry, name: impl Into<String>, inode: u64) -> DirEntry {
-        let reference = Reference::new(self.this.upgrade(), name.into());
-        if entry.is_file() {
+    fn create_entry(
+        &self,
+        fs: &FatFilesystemInner,
+        entry: ff::DirEntry,
+        name: impl Into<String>,
+        inode: u64,
+    ) -> VfsResult<DirEntry> {
+        let name = name.into();
+        let reference = Reference::new(self.this.upgrade(), name.clone());
+        Ok(if entry.is_file() {
+            let mut file = entry.to_file();
+            let symlink = self.fs.emulates_unix()
+                && self.attr(fs, &name)?.is_some_and(|it| it.symlink)
+                && unix::is_symlink(&mut file)?;
+            let node_type = if symlink {
+                NodeType::Symlink
+            } else {
+                NodeType::RegularFile
+            };
             DirEntry::new_file(
-                FatFileNode::new(self.fs.clone(), entry.to_file(), inode),
-                NodeType::RegularFile,
+                FatFileNode::new(self.fs.clone(), file, inode, symlink, self.attr_ref(name)),
+                node_type,
                 reference,
             )
         } else {
@@ -44,7 +64,80 @@ impl FatDirNode {
                 |this| FatDirNode::new(self.fs.clone(), entry.to_dir(), inode, this),
                 reference,
             )
+        })
+    }
+
+    /// Returns where the attributes of the file `name` are, if emulated.
+    fn attr_ref(&self, name: String) -> Option<AttrRef> {
+        if !self.fs.emulates_unix() {
+            return None;
+        }
+        let this = self.this.upgrade()?;
+        let dir = this.as_dir().ok()?.downcast().ok()?;
+        Some(AttrRef { dir, name })
+    }
+
+    fn attr_table<'a>(&self, fs: &'a FatFilesystemInner) -> VfsResult<&'a mut AttrTable> {
+        let table = self.attrs.borrow_mut(fs);
+        if table.is_none() {
+            *table = Some(AttrTable::load(self.inner.borrow(fs))?);
+        }
+        Ok(table.as_mut().unwrap())
+    }
+
+    /// Returns the attributes of the entry `name` if set, `.` being the
+    /// directory itself.
+    pub(crate) fn attr(&self, fs: &FatFilesystemInner, name: &str) -> VfsResult<Option<UnixAttrs>> {
+        Ok(self.attr_table(fs)?.get(name))
+    }
+
+    /// Updates the attributes of the entry `name`, of type `node_type`, and
+    /// stores them.
+    pub(crate) fn update_attr(
+        &self,
+        fs: &FatFilesystemInner,
+        name: &str,
+        node_type: NodeType,
+        f: impl FnOnce(&mut UnixAttrs),
+    ) -> VfsResult<()> {
+        let table = self.attr_table(fs)?;
+        let mut attrs = table
+            .get(name)
+            .unwrap_or_else(|| UnixAttrs::default_for(node_type));
+        f(&mut attrs);
+        table.set(name, node_type, attrs);
+        table.store(self.inner.borrow(fs))
+    }
+
+    /// Sets the attributes of the new entry `name`, of type `node_type`.
+    fn add_attr(
+        &self,
+        fs: &FatFilesystemInner,
+        name: &str,
+        node_type: NodeType,
+        attrs: UnixAttrs,
+    ) -> VfsResult<()> {
+        self.attr_table(fs)?
+            .add(self.inner.borrow(fs), name, node_type, attrs)
+    }
+
+    fn remove_attr(&self, fs: &FatFilesystemInner, name: &str) -> VfsResult<Option<UnixAttrs>> {
+        let table = self.attr_table(fs)?;
+        let attrs = table.remove(name);
+        if attrs.is_some() {
+            table.store(self.inner.borrow(fs))?;
         }
+        Ok(attrs)
+    }
+
+    /// Returns the node of the file `name` if cached.
+    fn cached_file(&self, name: &str) -> Option<Arc<FatFileNode>> {
+        let this = self.this.upgrade()?;
+        let entry = this
+            .as_dir()
+            .ok()?
+            .lookup_cache(&name.to_ascii_lowercase())?;
+        entry.as_file().ok()?.downcast().ok()
     }
 }
 
@@ -60,32 +153,53 @@ impl NodeOps for FatDirNode {
     fn metadata(&self) -> VfsResult<Metadata> {
         let fs = self.fs.lock();
         let dir = self.inner.borrow(&fs);
-        if let Some(file) = dir.as_file() {
-            return Ok(file_metadata(&fs, file, NodeType::Directory));
+        let mut metadata = if let Some(file) = dir.as_file() {
+            file_metadata(&fs, file, NodeType::Directory)
+        } else {
+            // root directory
+            let block_size = fs.inner.bytes_per_sector() as u64;
+            Metadata {
+                inode: self.inode(),
+                device: 0,
+                nlink: 1,
+                mode: NodePermission::default(),
+                node_type: NodeType::Directory,
+                uid: 0,
+                gid: 0,
+                size: block_size,
+                block_size,
+                blocks: 1,
+                rdev: DeviceId::default(),
+                atime: Duration::default(),
+                mtime: Duration::default(),
+                ctime: Duration::default(),
+            }
+        };
+        if self.fs.emulates_unix() {
+            let unix = self
+                .attr(&fs, ".")?
+                .unwrap_or_else(|| UnixAttrs::default_for(NodeType::Directory));
+            metadata.mode = unix.mode;
+            metadata.uid = unix.uid;
+            metadata.gid = unix.gid;
         }
-
-        // root directory
-        let block_size = fs.inner.bytes_per_sector() as u64;
-        Ok(Metadata {
-            inode: self.inode(),
-            device: 0,
-            nlink: 1,
-            mode: NodePermission::default(),
-            node_type: NodeType::Directory,
-            uid: 0,
-            gid: 0,
-            size: block_size,
-            block_size,
-            blocks: 1,
-            rdev: DeviceId::default(),
-            atime: Duration::default(),
-            mtime: Duration::default(),
-            ctime: Duration::default(),
-        })
+        Ok(metadata)
     }
 
-    fn update_metadata(&self, _update: MetadataUpdate) -> VfsResult<()> {
-        // TODO: update metadata on directory
+    fn update_metadata(&self, update: MetadataUpdate) -> VfsResult<()> {
+        // TODO: update times on directory
+        if self.fs.emulates_unix() {
+            let fs = self.fs.lock();
+            self.update_attr(&fs, ".", NodeType::Directory, |unix| {
+                if let Some(mode) = update.mode {
+                    unix.mode = mode;
+                }
+                if let Some((uid, gid)) = update.owner {
+                    unix.uid = uid;
+                    unix.gid = gid;
+                }
+            })?;
+        }
         Ok(())
     }
 
@@ -114,23 +228,22 @@ impl DirNodeOps for FatDirNode {
         let dir_node = this_entry.as_dir()?;
 
         let mut count = 0;
-        for entry in dir.iter().skip(offset as usize) {
+        for (index, entry) in dir.iter().enumerate().skip(offset as usize) {
             let entry = entry.map_err(into_vfs_err)?;
             let name = entry.file_name().to_ascii_lowercase();
-            let node_type = if entry.is_file() {
-                NodeType::RegularFile
-            } else {
-                NodeType::Directory
-            };
-            let inode = if let Some(entry) = dir_node.lookup_cache(&name) {
-                entry.inode()
+            if self.fs.emulates_unix() && unix::is_sidecar(&name) {
+                continue;
+            }
+            let (inode, node_type) = if let Some(entry) = dir_node.lookup_cache(&name) {
+                (entry.inode(), entry.node_type())
             } else {
-                let entry = self.create_entry(entry, name.clone(), fs.alloc_inode());
-                let inode = entry.inode();
+                let inode = fs.alloc_inode();
+                let entry = self.create_entry(&fs, entry, name.clone(), inode)?;
+                let result = (entry.inode(), entry.node_type());
                 dir_node.insert_cache(name.clone(), entry);
-                inode
+                result
             };
-            if !sink.accept(&name, inode, node_type, offset + count + 1) {
+            if !sink.accept(&name, inode, node_type, index as u64 + 1) {
                 break;
             }
             count += 1;
@@ -139,43 +252,71 @@ impl DirNodeOps for FatDirNode {
     }
 
     fn lookup(&self, name: &str) -> VfsResult<DirEntry> {
+        if self.fs.emulates_unix() && unix::is_sidecar(name) {
+            return Err(VfsError::NotFound);
+        }
         let mut fs = self.fs.lock();
         let dir = self.inner.borrow(&fs);
-        dir.iter()
+        let entry = dir
+            .iter()
             .find_map(|entry| entry.ok().filter(|it| it.eq_name(name)))
-            .map(|entry| self.create_entry(entry, name.to_ascii_lowercase(), fs.alloc_inode()))
-            .ok_or(VfsError::NotFound)
+            .ok_or(VfsError::NotFound)?;
+        let inode = fs.alloc_inode();
+        self.create_entry(&fs, entry, name.to_ascii_lowercase(), inode)
     }
 
     fn create(
         &self,
         name: &str,
         node_type: NodeType,
-        _permission: NodePermission,
+        permission: NodePermission,
     ) -> VfsResult<DirEntry> {
+        let emulate_unix = self.fs.emulates_unix();
+        if emulate_unix && unix::is_sidecar(name) {
+            return Err(VfsError::InvalidInput);
+        }
         let mut fs = self.fs.lock();
         let dir = self.inner.borrow(&fs);
         let reference = Reference::new(self.this.upgrade(), name.to_ascii_lowercase());
         match node_type {
-            NodeType::RegularFile => dir
-                .create_file(name)
-                .map(|file| {
-                    DirEntry::new_file(
-                        FatFileNode::new(self.fs.clone(), file, fs.alloc_inode()),
-                        NodeType::RegularFile,
-                        reference,
-                    )
-                })
-                .map_err(into_vfs_err),
-            NodeType::Directory => dir
-                .create_dir(name)
-                .map(|dir| {
-                    DirEntry::new_dir(
-                        |this| FatDirNode::new(self.fs.clone(), dir, fs.alloc_inode(), this),
-                        reference,
-                    )
-                })
-                .map_err(into_vfs_err),
+            NodeType::RegularFile | NodeType::Symlink
+                if node_type == NodeType::RegularFile || emulate_unix =>
+            {
+                let mut file = dir.create_file(name).map_err(into_vfs_err)?;
+                let symlink = node_type == NodeType::Symlink;
+                if symlink {
+                    unix::write_symlink(&mut file, "")?;
+                    self.add_attr(&fs, name, node_type, UnixAttrs::default_for(node_type))?;
+                } else if emulate_unix {
+                    let attrs = UnixAttrs {
+                        mode: permission,
+                        ..UnixAttrs::default_for(node_type)
+                    };
+                    self.add_attr(&fs, name, node_type, attrs)?;
+                }
+                let attrs = self.attr_ref(name.to_ascii_lowercase());
+                Ok(DirEntry::new_file(
+                    FatFileNode::new(self.fs.clone(), file, fs.alloc_inode(), symlink, attrs),
+                    node_type,
+                    reference,
+                ))
+            }
+            NodeType::Directory => {
+                let dir = dir.create_dir(name).map_err(into_vfs_err)?;
+                let entry = DirEntry::new_dir(
+                    |this| FatDirNode::new(self.fs.clone(), dir, fs.alloc_inode(), this),
+                    reference,
+                );
+                if emulate_unix {
+                    let node: Arc<Self> = entry.as_dir()?.downcast().map_err(|_| VfsError::Io)?;
+                    let attrs = UnixAttrs {
+                        mode: permission,
+                        ..UnixAttrs::default_for(node_type)
+                    };
+                    node.add_attr(&fs, ".", node_type, attrs)?;
+                }
+                Ok(entry)
+            }
             _ => Err(VfsError::InvalidInput),
         }
     }
@@ -189,7 +330,29 @@ impl DirNodeOps for FatDirNode {
     fn unlink(&self, name: &str) -> VfsResult<()> {
         let fs = self.fs.lock();
         let dir = self.inner.borrow(&fs);
-        dir.remove(name).map_err(into_vfs_err)
+        if self.fs.emulates_unix() {
+            if let Ok(child) = dir.open_dir(name) {
+                // The sidecar alone does not keep a directory from being
+                // removed.
+                let only_sidecar = child.iter().all(|entry| {
+                    entry.is_ok_and(|it| {
+                        let name = it.file_name();
+                        name == "." || name == ".." || unix::is_sidecar(&name)
+                    })
+                });
+                if only_sidecar {
+                    match child.remove(SIDECAR_NAME) {
+                        Ok(()) | Err(fatfs::Error::NotFound) => {}
+                        Err(err) => return Err(into_vfs_err(err)),
+                    }
+                }
+            }
+        }
+        dir.remove(name).map_err(into_vfs_err)?;
+        if self.fs.emulates_unix() {
+            self.remove_attr(&fs, name)?;
+        }
+        Ok(())
     }
 
     fn rename(&self, src_name: &str, dst_dir: &DirNode, dst_name: &str) -> VfsResult<()> {
@@ -209,7 +372,24 @@ impl DirNodeOps for FatDirNode {
         }
 
         dir.rename(src_name, dst_dir.inner.borrow(&fs), dst_name)
-            .map_err(into_vfs_err)
+            .map_err(into_vfs_err)?;
+
+        if self.fs.emulates_unix() {
+            // The attributes of files follow them to their new entry.
+            let attrs = self.remove_attr(&fs, src_name)?;
+            let dst_table = dst_dir.attr_table(&fs)?;
+            let replaced = match attrs {
+                Some(attrs) => dst_table.insert(dst_name, attrs),
+                None => dst_table.remove(dst_name),
+            };
+            if attrs.is_some() || replaced.is_some() {
+                dst_table.store(dst_dir.inner.borrow(&fs))?;
+            }
+            if let Some(node) = self.cached_file(src_name) {
+                node.moved(&fs, dst_dir.clone(), &dst_name.to_ascii_lowercase());
+            }
+        }
+        Ok(())
     }
 }
 
@@ -11,6 +11,7 @@ use fatfs::{Read, Seek, SeekFrom, Write};
 use super::{
     FsRef, ff,
     fs::FatFilesystem,
+    unix::{self, AttrRef, SYMLINK_MAGIC, UnixAttrs},
     util::{file_metadata, into_vfs_err, update_file_metadata},
 };
 use crate::fs::fat::fs::FatFilesystemInner;
@@ -19,17 +20,56 @@ pub struct FatFileNode {
     fs: Arc<FatFilesystem>,
     inner: FsRef<ff::File<'static>>,
     inode: u64,
+    /// Whether the file emulates a symlink.
+    symlink: bool,
+    /// Where the attributes of the file are, if emulated.
+    attrs: Option<FsRef<AttrRef>>,
 }
 
 impl FatFileNode {
-    pub fn new(fs: Arc<FatFilesystem>, file: ff::File, inode: u64) -> FileNode {
+    pub fn new(
+        fs: Arc<FatFilesystem>,
+        file: ff::File,
+        inode: u64,
+        symlink: bool,
+        attrs: Option<AttrRef>,
+    ) -> FileNode {
         FileNode::new(Arc::new(Self {
             fs,
             // SAFETY: FsRef guarantees correct lifetime
             inner: FsRef::new(unsafe { mem::transmute::<ff::File, ff::File>(file) }),
             inode,
+            symlink,
+            attrs: attrs.map(FsRef::new),
         }))
     }
+
+    fn node_type(&self) -> NodeType {
+        if self.symlink {
+            NodeType::Symlink
+        } else {
+            NodeType::RegularFile
+        }
+    }
+
+    /// Returns the offset of the data in the file, past the header of a
+    /// symlink.
+    fn data_offset(&self) -> u64 {
+        if self.symlink {
+            SYMLINK_MAGIC.len() as u64
+        } else {
+            0
+        }
+    }
+
+    /// Records that the file was moved to the entry `name` of `dir`.
+    pub(crate) fn moved(&self, fs: &FatFilesystemInner, dir: Arc<super::FatDirNode>, name: &str) {
+        if let Some(attrs) = &self.attrs {
+            let attrs = attrs.borrow_mut(fs);
+            attrs.dir = dir;
+            attrs.name = name.into();
+        }
+    }
 }
 
 fn grow_file(fs: &FatFilesystemInner, file: &mut ff::File<'static>, len: u64) -> VfsResult<()> {
@@ -59,13 +99,42 @@ impl NodeOps for FatFileNode {
     fn metadata(&self) -> VfsResult<Metadata> {
         let fs = self.fs.lock();
         let file = self.inner.borrow(&fs);
-        Ok(file_metadata(&fs, file, NodeType::RegularFile))
+        let mut metadata = file_metadata(&fs, file, self.node_type());
+        metadata.size -= self.data_offset();
+        if let Some(attrs) = &self.attrs {
+            let attrs = attrs.borrow(&fs);
+            let unix = attrs
+                .dir
+                .attr(&fs, &attrs.name)?
+                .unwrap_or_else(|| UnixAttrs::default_for(self.node_type()));
+            metadata.mode = unix.mode;
+            metadata.uid = unix.uid;
+            metadata.gid = unix.gid;
+        }
+        Ok(metadata)
     }
 
     fn update_metadata(&self, update: MetadataUpdate) -> VfsResult<()> {
-        // FatFS has no ownership & permission
-
         let fs = self.fs.lock();
+        if let Some(attrs) = self
+            .attrs
+            .as_ref()
+            .filter(|_| update.mode.is_some() || update.owner.is_some())
+        {
+            let attrs = attrs.borrow(&fs);
+            attrs
+                .dir
+                .update_attr(&fs, &attrs.name, self.node_type(), |unix| {
+                    if let Some(mode) = update.mode {
+                        unix.mode = mode;
+                    }
+                    if let Some((uid, gid)) = update.owner {
+                        unix.uid = uid;
+                        unix.gid = gid;
+                    }
+                })?;
+        }
+        // FatFS has no ownership & permission of its own.
         let file = self.inner.borrow_mut(&fs);
         update_file_metadata(file, update);
         Ok(())
@@ -78,7 +147,7 @@ impl NodeOps for FatFileNode {
     fn len(&self) -> VfsResult<u64> {
         let fs = self.fs.lock();
         let file = self.inner.borrow(&fs);
-        Ok(file.size().unwrap_or(0) as u64)
+        Ok(file.size().unwrap_or(0) as u64 - self.data_offset())
     }
 
     fn sync(&self, _data_only: bool) -> VfsResult<()> {
@@ -100,7 +169,8 @@ impl FileNodeOps for FatFileNode {
     fn read_at(&self, mut buf: &mut [u8], offset: u64) -> VfsResult<usize> {
         let fs = self.fs.lock();
         let file = self.inner.borrow_mut(&fs);
-        file.seek(SeekFrom::Start(offset)).map_err(into_vfs_err)?;
+        file.seek(SeekFrom::Start(offset + self.data_offset()))
+            .map_err(into_vfs_err)?;
 
         let mut read = 0;
         loop {
@@ -151,8 +221,12 @@ impl FileNodeOps for FatFileNode {
         }
     }
 
-    fn set_symlink(&self, _target: &str) -> VfsResult<()> {
-        Err(VfsError::PermissionDenied)
+    fn set_symlink(&self, target: &str) -> VfsResult<()> {
+        if !self.symlink {
+            return Err(VfsError::PermissionDenied);
+        }
+        let fs = self.fs.lock();
+        unix::write_symlink(self.inner.borrow_mut(&fs), target)
     }
 }
 
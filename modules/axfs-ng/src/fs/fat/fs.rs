@@ -1,7 +1,7 @@
 use alloc::sync::Arc;
 use core::marker::PhantomPinned;
 
-use axdriver::AxBlockDevice;
+use axdriver::prelude::BlockDriverOps;
 use axfs_ng_vfs::{
     DirEntry, Filesystem, FilesystemOps, Reference, StatFs, VfsResult, path::MAX_NAME_LEN,
 };
@@ -9,7 +9,10 @@ use kspin::{SpinNoPreempt as Mutex, SpinNoPreemptGuard as MutexGuard};
 use slab::Slab;
 
 use super::{dir::FatDirNode, ff, util::into_vfs_err};
-use crate::disk::SeekableDisk;
+use crate::{
+    disk::{self, SeekableDisk},
+    partition::Partition,
+};
 
 pub struct FatFilesystemInner {
     pub inner: ff::FileSystem,
@@ -27,23 +30,39 @@ impl FatFilesystemInner {
     }
 }
 
-pub struct FatFilesystem {
-    inner: Mutex<FatFilesystemInner>,
-    root_dir: Mutex<Option<DirEntry>>,
+/// Options to open a FAT filesystem with.
+#[derive(Debug, Clone, Default)]
+pub struct FatOptions {
+    emulate_unix: bool,
 }
 
-impl FatFilesystem {
-    pub fn new(dev: AxBlockDevice) -> Filesystem {
+impl FatOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the option to emulate symlinks, and the modes and owners of
+    /// files, as described in [`unix`](super::unix).
+    pub fn emulate_unix(&mut self, emulate_unix: bool) -> &mut Self {
+        self.emulate_unix = emulate_unix;
+        self
+    }
+
+    /// Opens the filesystem on `dev`.
+    pub fn open(&self, dev: Partition) -> VfsResult<Filesystem> {
+        let handle = dev.clone();
         let mut inner = FatFilesystemInner {
             inner: ff::FileSystem::new(SeekableDisk::new(dev), fatfs::FsOptions::new())
-                .expect("failed to initialize FAT filesystem"),
+                .map_err(into_vfs_err)?,
             inode_allocator: Slab::new(),
             _pinned: PhantomPinned,
         };
         let root_inode = inner.alloc_inode();
-        let result = Arc::new(Self {
+        let result = Arc::new(FatFilesystem {
             inner: Mutex::new(inner),
             root_dir: Mutex::default(),
+            dev: Mutex::new(handle),
+            emulate_unix: self.emulate_unix,
         });
 
         let root_dir = DirEntry::new_dir(
@@ -58,14 +77,31 @@ impl FatFilesystem {
             Reference::root(),
         );
         *result.root_dir.lock() = Some(root_dir);
-        Filesystem::new(result)
+        Ok(Filesystem::new(result))
     }
 }
 
+pub struct FatFilesystem {
+    inner: Mutex<FatFilesystemInner>,
+    root_dir: Mutex<Option<DirEntry>>,
+    /// Handle to the device, to write its block cache back on flush.
+    dev: Mutex<Partition>,
+    emulate_unix: bool,
+}
+
 impl FatFilesystem {
+    /// Opens the filesystem on `dev` with the default options.
+    pub fn new(dev: Partition) -> VfsResult<Filesystem> {
+        FatOptions::new().open(dev)
+    }
+
     pub(crate) fn lock(&self) -> MutexGuard<FatFilesystemInner> {
         self.inner.lock()
     }
+
+    pub(crate) fn emulates_unix(&self) -> bool {
+        self.emulate_unix
+    }
 }
 
 impl FilesystemOps for FatFilesystem {
@@ -95,4 +131,10 @@ impl FilesystemOps for FatFilesystem {
             mount_flags: 0,
         })
     }
+
+    fn flush(&self) -> VfsResult<()> {
+        // Keep the filesystem from writing in the meantime.
+        let _fs = self.inner.lock();
+        self.dev.lock().flush().map_err(disk::into_vfs_err)
+    }
 }
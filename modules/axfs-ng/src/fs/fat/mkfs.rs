@@ -0,0 +1,93 @@
+//! Creation of FAT filesystems.
+
+use alloc::vec;
+
+use axdriver::prelude::BlockDriverOps;
+use axfs_ng_vfs::{VfsError, VfsResult};
+pub use fatfs::FatType;
+use fatfs::FormatVolumeOptions;
+
+use super::util::into_vfs_err;
+use crate::{
+    disk::{self, SeekableDisk},
+    partition::Partition,
+};
+
+/// Number of bytes at the start of the device erased before formatting.
+const ERASE_LEN: usize = 4096;
+
+/// Options to create a FAT filesystem with.
+#[derive(Debug, Clone, Default)]
+pub struct FatMkfsOptions {
+    fat_type: Option<FatType>,
+    bytes_per_cluster: Option<u32>,
+    volume_id: Option<u32>,
+    volume_label: Option<[u8; 11]>,
+}
+
+impl FatMkfsOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the type of FAT, which by default depends on the size of the
+    /// device.
+    pub fn fat_type(&mut self, fat_type: FatType) -> &mut Self {
+        self.fat_type = Some(fat_type);
+        self
+    }
+
+    /// Sets the size of clusters, a power of two from the sector size to
+    /// 32 KiB.
+    pub fn bytes_per_cluster(&mut self, bytes_per_cluster: u32) -> &mut Self {
+        self.bytes_per_cluster = Some(bytes_per_cluster);
+        self
+    }
+
+    pub fn volume_id(&mut self, volume_id: u32) -> &mut Self {
+        self.volume_id = Some(volume_id);
+        self
+    }
+
+    /// Sets the volume label, at most 11 bytes.
+    pub fn volume_label(&mut self, label: &str) -> &mut Self {
+        let mut volume_label = [b' '; 11];
+        let len = label.len().min(11);
+        volume_label[..len].copy_from_slice(&label.as_bytes()[..len]);
+        self.volume_label = Some(volume_label);
+        self
+    }
+
+    /// Formats `dev`, erasing what it holds.
+    ///
+    /// Use [`Partition::whole`] to format a whole block device.
+    pub fn format(&self, dev: &mut Partition) -> VfsResult<()> {
+        let bytes_per_sector = dev.block_size();
+        if !(512..=4096).contains(&bytes_per_sector) {
+            return Err(VfsError::InvalidInput);
+        }
+        let mut options = FormatVolumeOptions::new().bytes_per_sector(bytes_per_sector as u16);
+        if let Some(fat_type) = self.fat_type {
+            options = options.fat_type(fat_type);
+        }
+        if let Some(bytes_per_cluster) = self.bytes_per_cluster {
+            options = options.bytes_per_cluster(bytes_per_cluster);
+        }
+        if let Some(volume_id) = self.volume_id {
+            options = options.volume_id(volume_id);
+        }
+        if let Some(volume_label) = self.volume_label {
+            options = options.volume_label(volume_label);
+        }
+        // Erase the superblocks of other filesystems, which the reserved
+        // sectors may not cover.
+        let zeroes = vec![0; bytes_per_sector];
+        for block in 0..ERASE_LEN.div_ceil(bytes_per_sector) as u64 {
+            dev.write_block(block, &zeroes)
+                .map_err(disk::into_vfs_err)?;
+        }
+        let mut storage = SeekableDisk::new(dev.clone());
+        fatfs::format_volume(&mut storage, options).map_err(into_vfs_err)?;
+        storage.flush().map_err(disk::into_vfs_err)
+    }
+}
@@ -2,6 +2,8 @@ mod dir;
 mod ff;
 mod file;
 mod fs;
+pub mod mkfs;
+pub mod unix;
 mod util;
 
 use core::cell::UnsafeCell;
@@ -9,8 +11,9 @@ use core::cell::UnsafeCell;
 pub use dir::*;
 use fatfs::SeekFrom;
 pub use file::*;
-pub use fs::FatFilesystem;
 use fs::FatFilesystemInner;
+pub use fs::{FatFilesystem, FatOptions};
+pub use mkfs::{FatMkfsOptions, FatType};
 
 use crate::disk::SeekableDisk;
 
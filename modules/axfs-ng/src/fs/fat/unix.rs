@@ -0,0 +1,233 @@
+//! Emulation of Unix symlinks and attributes, which FAT lacks.
+//!
+//! The modes and owners of the entries of a directory are stored in a hidden
+//! file of the directory, [`SIDECAR_NAME`], as one line per entry, the
+//! directory itself being `.`:
+//!
+//! ```text
+//! 755 1000 1000 .
+//! 600 1000 100 notes.txt
+//! 120777 0 0 link
+//! ```
+//!
+//! Entries without a line have the default attributes of FAT entries, so
+//! that volumes written elsewhere read as before. Lines are appended as
+//! entries are created, later lines overriding earlier ones.
+//!
+//! Symlinks are stored as regular files holding [`SYMLINK_MAGIC`] followed by
+//! their target, whose line has the type bits of a symlink in its mode. Files
+//! merely starting with the magic are not taken for symlinks.
+
+use alloc::{collections::btree_map::BTreeMap, format, string::String, sync::Arc, vec, vec::Vec};
+
+use axfs_ng_vfs::{NodePermission, NodeType, VfsError, VfsResult};
+use fatfs::{Read, Seek, SeekFrom, Write};
+
+use super::{
+    FatDirNode, ff,
+    util::{CaseInsensitiveString, into_vfs_err},
+};
+
+/// Header of the files emulating symlinks.
+pub const SYMLINK_MAGIC: &[u8] = b"!<axfs-symlink>\n";
+
+/// Name of the file holding the attributes of the entries of a directory,
+/// which is hidden from the directory.
+pub const SIDECAR_NAME: &str = ".axfs-unix";
+
+/// Maximum length of the target of a symlink.
+const SYMLINK_MAX: usize = 4096;
+
+/// Type bits of the mode of a symlink, as in `st_mode`.
+const S_IFLNK: u32 = 0o120000;
+/// Mask of the type bits of a mode.
+const S_IFMT: u32 = 0o170000;
+
+/// Returns whether `name` is that of the sidecar, which FAT compares without
+/// case.
+pub(crate) fn is_sidecar(name: &str) -> bool {
+    name.eq_ignore_ascii_case(SIDECAR_NAME)
+}
+
+/// Unix attributes of an entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct UnixAttrs {
+    pub mode: NodePermission,
+    pub uid: u32,
+    pub gid: u32,
+    /// Whether the entry is a symlink, which its line always tells.
+    pub symlink: bool,
+}
+
+impl UnixAttrs {
+    /// Returns the attributes of entries without a line in the sidecar.
+    pub fn default_for(node_type: NodeType) -> Self {
+        let symlink = node_type == NodeType::Symlink;
+        let mode = if symlink {
+            NodePermission::from_bits_truncate(0o777)
+        } else {
+            NodePermission::default()
+        };
+        Self {
+            mode,
+            uid: 0,
+            gid: 0,
+            symlink,
+        }
+    }
+
+    /// Returns the line of the sidecar holding these attributes of `name`.
+    fn line(&self, name: &str) -> String {
+        let mut mode = self.mode.bits() as u32;
+        if self.symlink {
+            mode |= S_IFLNK;
+        }
+        format!("{:o} {} {} {}\n", mode, self.uid, self.gid, name)
+    }
+}
+
+/// The attributes of the entries of a directory, as stored in its sidecar.
+#[derive(Default)]
+pub(crate) struct AttrTable(BTreeMap<CaseInsensitiveString, UnixAttrs>);
+
+impl AttrTable {
+    /// Reads the sidecar of `dir`, skipping malformed lines.
+    pub fn load(dir: &ff::Dir) -> VfsResult<Self> {
+        let mut file = match dir.open_file(SIDECAR_NAME) {
+            Ok(file) => file,
+            Err(fatfs::Error::NotFound) => return Ok(Self::default()),
+            Err(err) => return Err(into_vfs_err(err)),
+        };
+        let mut data = Vec::new();
+        let mut buf = vec![0; 512];
+        loop {
+            let read = file.read(&mut buf).map_err(into_vfs_err)?;
+            if read == 0 {
+                break;
+            }
+            data.extend_from_slice(&buf[..read]);
+        }
+
+        let mut table = Self::default();
+        for line in String::from_utf8_lossy(&data).lines() {
+            let mut fields = line.splitn(4, ' ');
+            let mut field = || fields.next();
+            let (Some(mode), Some(uid), Some(gid), Some(name)) =
+                (field(), field(), field(), field())
+            else {
+                continue;
+            };
+            let (Ok(mode), Ok(uid), Ok(gid)) =
+                (u32::from_str_radix(mode, 8), uid.parse(), gid.parse())
+            else {
+                continue;
+            };
+            let attrs = UnixAttrs {
+                mode: NodePermission::from_bits_truncate(mode as u16),
+                uid,
+                gid,
+                symlink: mode & S_IFMT == S_IFLNK,
+            };
+            table.0.insert(CaseInsensitiveString(name.into()), attrs);
+        }
+        Ok(table)
+    }
+
+    /// Writes the table to the sidecar of `dir`, which is removed if empty.
+    pub fn store(&self, dir: &ff::Dir) -> VfsResult<()> {
+        if self.0.is_empty() {
+            return match dir.remove(SIDECAR_NAME) {
+                Ok(()) | Err(fatfs::Error::NotFound) => Ok(()),
+                Err(err) => Err(into_vfs_err(err)),
+            };
+        }
+        let mut data = String::new();
+        for (name, attrs) in &self.0 {
+            data += &attrs.line(&name.0);
+        }
+        let mut file = dir.create_file(SIDECAR_NAME).map_err(into_vfs_err)?;
+        file.truncate().map_err(into_vfs_err)?;
+        file.write_all(data.as_bytes()).map_err(into_vfs_err)?;
+        file.flush().map_err(into_vfs_err)
+    }
+
+    pub fn get(&self, name: &str) -> Option<UnixAttrs> {
+        self.0.get(&CaseInsensitiveString(name.into())).copied()
+    }
+
+    /// Sets the attributes of `name`, dropping its line if they are the
+    /// default ones of a file or directory.
+    ///
+    /// Returns whether the line is kept.
+    pub fn set(&mut self, name: &str, node_type: NodeType, attrs: UnixAttrs) -> bool {
+        let name = CaseInsensitiveString(name.into());
+        if attrs == UnixAttrs::default_for(node_type) && !attrs.symlink {
+            self.0.remove(&name);
+            false
+        } else {
+            self.0.insert(name, attrs);
+            true
+        }
+    }
+
+    /// Sets the attributes of the new entry `name`, appending its line to
+    /// the sidecar of `dir` instead of rewriting it.
+    pub fn add(
+        &mut self,
+        dir: &ff::Dir,
+        name: &str,
+        node_type: NodeType,
+        attrs: UnixAttrs,
+    ) -> VfsResult<()> {
+        let stale = self.get(name).is_some();
+        if !self.set(name, node_type, attrs) {
+            // Drop the line left by a previous entry of that name.
+            return if stale { self.store(dir) } else { Ok(()) };
+        }
+        let mut file = dir.create_file(SIDECAR_NAME).map_err(into_vfs_err)?;
+        file.seek(SeekFrom::End(0)).map_err(into_vfs_err)?;
+        file.write_all(attrs.line(name).as_bytes())
+            .map_err(into_vfs_err)?;
+        file.flush().map_err(into_vfs_err)
+    }
+
+    pub fn insert(&mut self, name: &str, attrs: UnixAttrs) -> Option<UnixAttrs> {
+        self.0.insert(CaseInsensitiveString(name.into()), attrs)
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<UnixAttrs> {
+        self.0.remove(&CaseInsensitiveString(name.into()))
+    }
+}
+
+/// Where the attributes of a file are stored: the line of `name` in the
+/// sidecar of `dir`.
+pub(crate) struct AttrRef {
+    pub dir: Arc<FatDirNode>,
+    pub name: String,
+}
+
+/// Returns whether `file`, whose line in the sidecar marks it as a symlink,
+/// holds one.
+pub(crate) fn is_symlink(file: &mut ff::File) -> VfsResult<bool> {
+    let size = file.size().unwrap_or(0) as usize;
+    if !(SYMLINK_MAGIC.len()..=SYMLINK_MAGIC.len() + SYMLINK_MAX).contains(&size) {
+        return Ok(false);
+    }
+    let mut header = [0; SYMLINK_MAGIC.len()];
+    file.seek(SeekFrom::Start(0)).map_err(into_vfs_err)?;
+    file.read_exact(&mut header).map_err(into_vfs_err)?;
+    Ok(header == SYMLINK_MAGIC)
+}
+
+/// Makes `file` emulate a symlink to `target`.
+pub(crate) fn write_symlink(file: &mut ff::File, target: &str) -> VfsResult<()> {
+    if target.len() > SYMLINK_MAX {
+        return Err(VfsError::NameTooLong);
+    }
+    file.seek(SeekFrom::Start(0)).map_err(into_vfs_err)?;
+    file.truncate().map_err(into_vfs_err)?;
+    file.write_all(SYMLINK_MAGIC).map_err(into_vfs_err)?;
+    file.write_all(target.as_bytes()).map_err(into_vfs_err)?;
+    file.flush().map_err(into_vfs_err)
+}
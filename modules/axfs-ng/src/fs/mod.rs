@@ -4,18 +4,37 @@ pub mod fat;
 #[cfg(feature = "ext4")]
 pub mod ext4;
 
-use axdriver::AxBlockDevice;
-use axfs_ng_vfs::{Filesystem, VfsResult};
-use cfg_if::cfg_if;
+pub mod devfs;
+pub mod overlayfs;
+mod probe;
+pub mod procfs;
+pub mod tmpfs;
 
-pub fn new_default(dev: AxBlockDevice) -> VfsResult<Filesystem> {
-    cfg_if! {
-        if #[cfg(feature = "ext4")] {
-            ext4::Ext4Filesystem::new(dev)
-        } else if #[cfg(feature = "fat")] {
-            Ok(fat::FatFilesystem::new(dev))
-        } else {
-            panic!("No filesystem feature enabled");
+use core::time::Duration;
+
+use axdriver::prelude::BaseDriverOps;
+use axfs_ng_vfs::{Filesystem, VfsError, VfsResult};
+use log::error;
+pub use probe::{FsType, mkfs, new_filesystem, probe};
+
+use crate::partition::Partition;
+
+/// Returns the current time for timestamps of in-memory nodes.
+pub(crate) fn now() -> Duration {
+    if cfg!(feature = "times") {
+        axhal::time::wall_time()
+    } else {
+        Duration::default()
+    }
+}
+
+/// Creates a filesystem on the device, of the format found by [`probe`].
+pub fn new_default(mut dev: Partition) -> VfsResult<Filesystem> {
+    match probe(&mut dev)? {
+        Some(fs_type) => new_filesystem(fs_type, dev),
+        None => {
+            error!("No known filesystem found on {}", dev.device_name());
+            Err(VfsError::InvalidData)
         }
     }
 }
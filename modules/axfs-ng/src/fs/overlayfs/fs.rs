@@ -0,0 +1,77 @@
+use alloc::sync::Arc;
+use core::cell::OnceCell;
+
+use axfs_ng_vfs::{
+    DirEntry, DirNode, Filesystem, FilesystemOps, Location, Mountpoint, Reference, StatFs,
+    VfsResult,
+};
+
+use axsync::Mutex;
+
+use super::inode::OverlayNode;
+
+const OVERLAYFS_SUPER_MAGIC: u64 = 0x794c7630;
+
+pub struct OverlayFilesystem {
+    root_dir: OnceCell<DirEntry>,
+    upper: Location,
+    /// Serializes copy-ups, so that a node is copied up only once even
+    /// through several handles.
+    pub(crate) copy_up_lock: Mutex<()>,
+}
+
+impl OverlayFilesystem {
+    /// Creates a new overlay of the writable `upper` filesystem over `lower`.
+    ///
+    /// Both layers should not be modified other than through the overlay
+    /// while it is in use.
+    pub fn new(upper: &Filesystem, lower: &Filesystem) -> Filesystem {
+        let upper = Mountpoint::new_root(upper).root_location();
+        let lower = Mountpoint::new_root(lower).root_location();
+        let fs = Arc::new(Self {
+            root_dir: OnceCell::new(),
+            upper: upper.clone(),
+            copy_up_lock: Mutex::new(()),
+        });
+        let _ = fs.root_dir.set(DirEntry::new_dir(
+            |this| {
+                DirNode::new(OverlayNode::new(
+                    fs.clone(),
+                    Some(upper),
+                    Some(lower),
+                    None,
+                    Some(this),
+                ))
+            },
+            Reference::root(),
+        ));
+        Filesystem::new(fs)
+    }
+}
+
+unsafe impl Send for OverlayFilesystem {}
+
+unsafe impl Sync for OverlayFilesystem {}
+
+impl FilesystemOps for OverlayFilesystem {
+    fn name(&self) -> &str {
+        "overlay"
+    }
+
+    fn root_dir(&self) -> DirEntry {
+        self.root_dir.get().unwrap().clone()
+    }
+
+    fn stat(&self) -> VfsResult<StatFs> {
+        // Free space is that of the upper layer, where all writes go.
+        let stat = self.upper.filesystem().stat()?;
+        Ok(StatFs {
+            fs_type: OVERLAYFS_SUPER_MAGIC as _,
+            ..stat
+        })
+    }
+
+    fn flush(&self) -> VfsResult<()> {
+        self.upper.filesystem().flush()
+    }
+}
@@ -0,0 +1,579 @@
+use alloc::{
+    borrow::ToOwned,
+    collections::BTreeMap,
+    format,
+    string::String,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+use core::{any::Any, task::Context};
+
+use axfs_ng_vfs::{
+    DirEntry, DirEntrySink, DirNode, DirNodeOps, FileNode, FileNodeOps, FilesystemOps, Location,
+    Metadata, MetadataUpdate, NodeFlags, NodeOps, NodePermission, NodeType, Reference, VfsError,
+    VfsResult, WeakDirEntry,
+};
+use axio::{IoEvents, Pollable};
+use axsync::Mutex;
+
+use super::{OPAQUE_MARKER, OverlayFilesystem, WHITEOUT_PREFIX};
+use crate::highlevel::{XattrExt, XattrFlags, XattrNode};
+
+/// Size of the buffer used to copy file contents up.
+const COPY_CHUNK: usize = 0x4000;
+
+/// Tags inode numbers of upper-only nodes, so that they never collide with
+/// those of the lower layer.
+const UPPER_INO_BIT: u64 = 1 << 63;
+
+fn whiteout_name(name: &str) -> String {
+    format!("{WHITEOUT_PREFIX}{name}")
+}
+
+fn is_opaque(upper: &Location) -> bool {
+    upper.lookup_no_follow(OPAQUE_MARKER).is_ok()
+}
+
+/// Lists the entries of `dir` other than `.` and `..`.
+fn list(dir: &Location) -> VfsResult<BTreeMap<String, (u64, NodeType)>> {
+    let mut entries = BTreeMap::new();
+    let mut offset = 0;
+    loop {
+        let mut done = true;
+        dir.read_dir(offset, &mut |name: &str,
+                                   ino: u64,
+                                   node_type: NodeType,
+                                   next: u64| {
+            if name != "." && name != ".." {
+                entries.insert(name.to_owned(), (ino, node_type));
+            }
+            offset = next;
+            done = false;
+            true
+        })?;
+        if done {
+            return Ok(entries);
+        }
+    }
+}
+
+/// A node of the overlay, made of the nodes at the same path in each layer.
+pub struct OverlayNode {
+    fs: Arc<OverlayFilesystem>,
+    /// The node in the upper layer, set when the node is copied up.
+    upper: Mutex<Option<Location>>,
+    lower: Option<Location>,
+    /// The parent directory and name of the node, to copy it up.
+    ///
+    /// Only needed while the node is lower-only, so it is fine for them to be
+    /// stale once the node has been copied up and renamed.
+    origin: Option<(Arc<OverlayNode>, String)>,
+    this: Option<WeakDirEntry>,
+    me: Weak<OverlayNode>,
+}
+
+impl OverlayNode {
+    pub(crate) fn new(
+        fs: Arc<OverlayFilesystem>,
+        upper: Option<Location>,
+        lower: Option<Location>,
+        origin: Option<(Arc<OverlayNode>, String)>,
+        this: Option<WeakDirEntry>,
+    ) -> Arc<Self> {
+        Arc::new_cyclic(|me| Self {
+            fs,
+            upper: Mutex::new(upper),
+            lower,
+            origin,
+            this,
+            me: me.clone(),
+        })
+    }
+
+    /// Returns the layer the node is currently served from.
+    fn active(&self) -> Location {
+        match &*self.upper.lock() {
+            Some(upper) => upper.clone(),
+            None => self.lower.clone().unwrap(),
+        }
+    }
+
+    fn upper_location(&self) -> Option<Location> {
+        self.upper.lock().clone()
+    }
+
+    /// Returns the node in the upper layer, copying it up first if needed.
+    fn copy_up(&self) -> VfsResult<Location> {
+        let mut upper = self.upper.lock();
+        if let Some(upper) = &*upper {
+            return Ok(upper.clone());
+        }
+        // The root always has an upper layer, so there is always an origin.
+        let (parent, name) = self.origin.as_ref().unwrap();
+        let parent = parent.copy_up()?;
+
+        // Other nodes of the same path, such as those of other handles, may
+        // have copied it up already.
+        let _guard = self.fs.copy_up_lock.lock();
+        let copy = match parent.lookup_no_follow(name) {
+            Ok(copy) => copy,
+            Err(VfsError::NotFound) => self.copy_to(&parent, name)?,
+            Err(err) => return Err(err),
+        };
+        *upper = Some(copy.clone());
+        Ok(copy)
+    }
+
+    /// Copies the lower node to `name` in `parent`, removing the copy if
+    /// that fails halfway.
+    fn copy_to(&self, parent: &Location, name: &str) -> VfsResult<Location> {
+        let lower = self.lower.as_ref().unwrap();
+        let meta = lower.metadata()?;
+        let copy = parent.create(name, meta.node_type, meta.mode)?;
+        if let Err(err) = Self::copy_contents(lower, &copy, &meta) {
+            let _ = parent.unlink(name, meta.node_type == NodeType::Directory);
+            return Err(err);
+        }
+        Ok(copy)
+    }
+
+    /// Copies the contents and attributes of `lower`, with metadata `meta`,
+    /// to `copy`.
+    fn copy_contents(lower: &Location, copy: &Location, meta: &Metadata) -> VfsResult<()> {
+        match meta.node_type {
+            NodeType::Symlink => {
+                copy.entry().as_file()?.set_symlink(&lower.read_link()?)?;
+            }
+            NodeType::RegularFile => {
+                let src = lower.entry().as_file()?;
+                let dst = copy.entry().as_file()?;
+                let mut buf = vec![0; COPY_CHUNK];
+                let mut offset = 0;
+                loop {
+                    let read = src.read_at(&mut buf, offset)?;
+                    if read == 0 {
+                        break;
+                    }
+                    let mut written = 0;
+                    while written < read {
+                        written += dst.write_at(&buf[written..read], offset + written as u64)?;
+                    }
+                    offset += read as u64;
+                }
+            }
+            _ => {}
+        }
+        let mut update = MetadataUpdate::default();
+        update.owner = Some((meta.uid, meta.gid));
+        update.atime = Some(meta.atime);
+        update.mtime = Some(meta.mtime);
+        copy.update_metadata(update)?;
+        // Extended attributes are lost if the upper layer lacks them.
+        if let Ok(names) = lower.list_xattr() {
+            for name in names {
+                match copy.set_xattr(&name, &lower.get_xattr(&name)?, XattrFlags::empty()) {
+                    Ok(()) | Err(VfsError::Unsupported) => {}
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns whether `name` is hidden by a whiteout in the upper layer.
+    fn is_whiteout(upper: &Location, name: &str) -> bool {
+        upper.lookup_no_follow(&whiteout_name(name)).is_ok()
+    }
+
+    /// Returns whether the lower layer shows an entry named `name` here.
+    fn lower_has(&self, name: &str) -> bool {
+        if let Some(upper) = self.upper_location()
+            && is_opaque(&upper)
+        {
+            return false;
+        }
+        self.lower
+            .as_ref()
+            .is_some_and(|lower| lower.lookup_no_follow(name).is_ok())
+    }
+
+    /// Looks up the layers of the child `name`.
+    fn lookup_layers(&self, name: &str) -> VfsResult<(Option<Location>, Option<Location>)> {
+        if name.starts_with(WHITEOUT_PREFIX) {
+            return Err(VfsError::NotFound);
+        }
+        let upper_dir = self.upper_location();
+        let upper = match &upper_dir {
+            Some(dir) => match dir.lookup_no_follow(name) {
+                Ok(upper) => Some(upper),
+                Err(VfsError::NotFound) if Self::is_whiteout(dir, name) => {
+                    return Err(VfsError::NotFound);
+                }
+                Err(VfsError::NotFound) => None,
+                Err(err) => return Err(err),
+            },
+            None => None,
+        };
+
+        let lower = match &upper {
+            // Only directories are merged.
+            Some(upper) if !upper.is_dir() || is_opaque(upper) => None,
+            _ => match &self.lower {
+                Some(dir) => match dir.lookup_no_follow(name) {
+                    Ok(lower) if upper.is_none() || lower.is_dir() => Some(lower),
+                    Ok(_) | Err(VfsError::NotFound) => None,
+                    Err(err) => return Err(err),
+                },
+                None => None,
+            },
+        };
+
+        if upper.is_none() && lower.is_none() {
+            return Err(VfsError::NotFound);
+        }
+        Ok((upper, lower))
+    }
+
+    fn new_entry(
+        &self,
+        name: &str,
+        upper: Option<Location>,
+        lower: Option<Location>,
+    ) -> VfsResult<DirEntry> {
+        let node_type = upper.as_ref().or(lower.as_ref()).unwrap().node_type();
+        let origin = Some((self.me.upgrade().unwrap(), name.to_owned()));
+        let reference = Reference::new(
+            self.this.as_ref().and_then(WeakDirEntry::upgrade),
+            name.to_owned(),
+        );
+        Ok(if node_type == NodeType::Directory {
+            DirEntry::new_dir(
+                |this| DirNode::new(Self::new(self.fs.clone(), upper, lower, origin, Some(this))),
+                reference,
+            )
+        } else {
+            DirEntry::new_file(
+                FileNode::new(Self::new(self.fs.clone(), upper, lower, origin, None)),
+                node_type,
+                reference,
+            )
+        })
+    }
+
+    /// Lists the merged entries of this directory.
+    fn merged_entries(&self) -> VfsResult<BTreeMap<String, (u64, NodeType)>> {
+        let upper = self.upper_location();
+        let mut entries = BTreeMap::new();
+        if let Some(lower) = &self.lower
+            && !upper.as_ref().is_some_and(is_opaque)
+        {
+            entries = list(lower)?;
+        }
+        if let Some(upper) = &upper {
+            for (name, (ino, node_type)) in list(upper)? {
+                if let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX) {
+                    entries.remove(hidden);
+                    continue;
+                }
+                match entries.get(&name) {
+                    // Merged directories keep the inode number of the lower
+                    // one, like in `inode`.
+                    Some(&(_, NodeType::Directory)) if node_type == NodeType::Directory => {}
+                    _ => {
+                        entries.insert(name, (ino | UPPER_INO_BIT, node_type));
+                    }
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Removes whiteouts and the opaque marker from an upper directory that
+    /// is logically empty, so that it can be removed.
+    fn clear_whiteouts(dir: &Location) -> VfsResult<()> {
+        for name in list(dir)?.into_keys() {
+            if name.starts_with(WHITEOUT_PREFIX) {
+                dir.unlink(&name, false)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Leaves a whiteout for `name` in the upper layer if the lower layer has
+    /// an entry with that name.
+    fn whiteout(&self, upper: &Location, name: &str) -> VfsResult<()> {
+        if self.lower_has(name) && !Self::is_whiteout(upper, name) {
+            upper.create(
+                &whiteout_name(name),
+                NodeType::RegularFile,
+                NodePermission::from_bits_truncate(0o000),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Makes `name` available in the upper layer for a new entry, returning
+    /// whether it hides a lower entry.
+    fn prepare_create(&self, upper: &Location, name: &str) -> VfsResult<bool> {
+        if Self::is_whiteout(upper, name) {
+            upper.unlink(&whiteout_name(name), false)?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    fn mark_opaque(dir: &Location) -> VfsResult<()> {
+        dir.create(
+            OPAQUE_MARKER,
+            NodeType::RegularFile,
+            NodePermission::from_bits_truncate(0o000),
+        )?;
+        Ok(())
+    }
+}
+
+impl NodeOps for OverlayNode {
+    fn inode(&self) -> u64 {
+        match &self.lower {
+            Some(lower) => lower.inode(),
+            None => self.active().inode() | UPPER_INO_BIT,
+        }
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        let mut meta = self.active().metadata()?;
+        meta.inode = self.inode();
+        Ok(meta)
+    }
+
+    fn update_metadata(&self, update: MetadataUpdate) -> VfsResult<()> {
+        self.copy_up()?.update_metadata(update)
+    }
+
+    fn len(&self) -> VfsResult<u64> {
+        Ok(self.active().metadata()?.size)
+    }
+
+    fn filesystem(&self) -> &dyn FilesystemOps {
+        &*self.fs
+    }
+
+    fn sync(&self, data_only: bool) -> VfsResult<()> {
+        match self.upper_location() {
+            Some(upper) if !upper.is_dir() => upper.entry().as_file()?.sync(data_only),
+            _ => Ok(()),
+        }
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+
+    fn flags(&self) -> NodeFlags {
+        self.active().flags()
+    }
+}
+
+impl FileNodeOps for OverlayNode {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> VfsResult<usize> {
+        self.active().entry().as_file()?.read_at(buf, offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> VfsResult<usize> {
+        self.copy_up()?.entry().as_file()?.write_at(buf, offset)
+    }
+
+    fn append(&self, buf: &[u8]) -> VfsResult<(usize, u64)> {
+        self.copy_up()?.entry().as_file()?.append(buf)
+    }
+
+    fn set_len(&self, len: u64) -> VfsResult<()> {
+        self.copy_up()?.entry().as_file()?.set_len(len)
+    }
+
+    fn set_symlink(&self, target: &str) -> VfsResult<()> {
+        self.copy_up()?.entry().as_file()?.set_symlink(target)
+    }
+}
+
+impl Pollable for OverlayNode {
+    fn poll(&self) -> IoEvents {
+        self.active().poll()
+    }
+
+    fn register(&self, context: &mut Context<'_>, events: IoEvents) {
+        self.active().register(context, events)
+    }
+}
+
+impl DirNodeOps for OverlayNode {
+    fn read_dir(&self, offset: u64, sink: &mut dyn DirEntrySink) -> VfsResult<usize> {
+        let parent = match &self.origin {
+            Some((parent, _)) => parent.inode(),
+            None => self.inode(),
+        };
+        let dots = [
+            (".".to_owned(), (self.inode(), NodeType::Directory)),
+            ("..".to_owned(), (parent, NodeType::Directory)),
+        ];
+        let entries = self.merged_entries()?;
+
+        let mut count = 0;
+        for (i, (name, (ino, node_type))) in dots
+            .into_iter()
+            .chain(entries)
+            .enumerate()
+            .skip(offset as usize)
+        {
+            if !sink.accept(&name, ino, node_type, i as u64 + 1) {
+                break;
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn lookup(&self, name: &str) -> VfsResult<DirEntry> {
+        let (upper, lower) = self.lookup_layers(name)?;
+        self.new_entry(name, upper, lower)
+    }
+
+    fn create(
+        &self,
+        name: &str,
+        node_type: NodeType,
+        permission: NodePermission,
+    ) -> VfsResult<DirEntry> {
+        if name.starts_with(WHITEOUT_PREFIX) {
+            return Err(VfsError::InvalidInput);
+        }
+        match self.lookup_layers(name) {
+            Ok(_) => return Err(VfsError::AlreadyExists),
+            Err(VfsError::NotFound) => {}
+            Err(err) => return Err(err),
+        }
+        let upper = self.copy_up()?;
+        let hides_lower = self.prepare_create(&upper, name)?;
+        let created = upper.create(name, node_type, permission)?;
+        if hides_lower && node_type == NodeType::Directory {
+            Self::mark_opaque(&created)?;
+        }
+        self.new_entry(name, Some(created), None)
+    }
+
+    fn link(&self, name: &str, node: &DirEntry) -> VfsResult<DirEntry> {
+        if name.starts_with(WHITEOUT_PREFIX) {
+            return Err(VfsError::InvalidInput);
+        }
+        let src: Arc<Self> = node
+            .as_file()?
+            .downcast()
+            .map_err(|_| VfsError::InvalidInput)?;
+        let src = src.copy_up()?;
+        let upper = self.copy_up()?;
+        self.prepare_create(&upper, name)?;
+        let linked = upper.link(name, &src)?;
+        self.new_entry(name, Some(linked), None)
+    }
+
+    fn unlink(&self, name: &str) -> VfsResult<()> {
+        let (child_upper, child_lower) = self.lookup_layers(name)?;
+        let is_dir = child_upper
+            .as_ref()
+            .or(child_lower.as_ref())
+            .unwrap()
+            .is_dir();
+        if is_dir {
+            let child = Self::new(
+                self.fs.clone(),
+                child_upper.clone(),
+                child_lower,
+                None,
+                None,
+            );
+            if !child.merged_entries()?.is_empty() {
+                return Err(VfsError::DirectoryNotEmpty);
+            }
+        }
+
+        let upper = self.copy_up()?;
+        if let Some(child) = child_upper {
+            if is_dir {
+                Self::clear_whiteouts(&child)?;
+            }
+            upper.unlink(name, is_dir)?;
+        }
+        self.whiteout(&upper, name)
+    }
+
+    fn rename(&self, src_name: &str, dst_dir: &DirNode, dst_name: &str) -> VfsResult<()> {
+        if dst_name.starts_with(WHITEOUT_PREFIX) {
+            return Err(VfsError::InvalidInput);
+        }
+        let dst_dir: Arc<Self> = dst_dir.downcast().map_err(|_| VfsError::InvalidInput)?;
+        let (src_upper, src_lower) = self.lookup_layers(src_name)?;
+        let is_dir = src_upper.as_ref().or(src_lower.as_ref()).unwrap().is_dir();
+        // Moving a directory with lower entries would need all of them to be
+        // copied up, so leave that to user space like Linux does.
+        if is_dir && src_lower.is_some() {
+            return Err(VfsError::CrossesDevices);
+        }
+
+        match dst_dir.lookup_layers(dst_name) {
+            Ok((dst_upper, dst_lower)) => {
+                let dst = dst_upper.as_ref().or(dst_lower.as_ref()).unwrap();
+                if dst.is_dir() {
+                    let dst = Self::new(self.fs.clone(), dst_upper.clone(), dst_lower, None, None);
+                    if !dst.merged_entries()?.is_empty() {
+                        return Err(VfsError::DirectoryNotEmpty);
+                    }
+                    if let Some(dst_upper) = &dst_upper {
+                        Self::clear_whiteouts(dst_upper)?;
+                    }
+                }
+            }
+            Err(VfsError::NotFound) => {}
+            Err(err) => return Err(err),
+        }
+
+        let src = Self::new(
+            self.fs.clone(),
+            src_upper,
+            src_lower,
+            Some((self.me.upgrade().unwrap(), src_name.to_owned())),
+            None,
+        );
+        src.copy_up()?;
+        let src_dir = self.copy_up()?;
+        let dst_upper = dst_dir.copy_up()?;
+        let hides_lower =
+            dst_dir.prepare_create(&dst_upper, dst_name)? || dst_dir.lower_has(dst_name);
+        src_dir.rename(src_name, &dst_upper, dst_name)?;
+        if is_dir && hides_lower {
+            let moved = dst_upper.lookup_no_follow(dst_name)?;
+            if !is_opaque(&moved) {
+                Self::mark_opaque(&moved)?;
+            }
+        }
+        self.whiteout(&src_dir, src_name)
+    }
+}
+
+impl XattrNode for OverlayNode {
+    fn get_xattr(&self, name: &str) -> VfsResult<Vec<u8>> {
+        self.active().get_xattr(name)
+    }
+
+    fn set_xattr(&self, name: &str, value: &[u8], flags: XattrFlags) -> VfsResult<()> {
+        self.copy_up()?.set_xattr(name, value, flags)
+    }
+
+    fn list_xattr(&self) -> VfsResult<Vec<String>> {
+        self.active().list_xattr()
+    }
+
+    fn remove_xattr(&self, name: &str) -> VfsResult<()> {
+        self.copy_up()?.remove_xattr(name)
+    }
+}
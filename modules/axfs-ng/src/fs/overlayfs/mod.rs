@@ -0,0 +1,23 @@
+//! A union filesystem stacking a writable upper layer over a lower one.
+//!
+//! Lookups see the upper layer first, and fall through to the lower layer for
+//! names the upper one lacks. Directories present in both layers are merged.
+//! The lower layer is never modified: a file or directory is copied up to the
+//! upper layer, along with its parents, before its first modification.
+//!
+//! Deleting an entry that exists in the lower layer leaves a whiteout, an
+//! empty file named `.wh.<name>` in the upper layer, which hides it. A
+//! directory recreated over a whiteout is marked opaque by a `.wh..wh..opq`
+//! file, so that none of the lower entries show through it. Names starting
+//! with `.wh.` are reserved and never listed.
+
+mod fs;
+mod inode;
+
+pub use fs::OverlayFilesystem;
+pub(crate) use inode::OverlayNode;
+
+/// Prefix of whiteout names.
+const WHITEOUT_PREFIX: &str = ".wh.";
+/// Name of the file marking a directory as opaque.
+const OPAQUE_MARKER: &str = ".wh..wh..opq";
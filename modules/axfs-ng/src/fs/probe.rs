@@ -0,0 +1,116 @@
+use alloc::vec;
+use core::fmt;
+
+use axdriver::prelude::*;
+use axfs_ng_vfs::{Filesystem, VfsError, VfsResult};
+use log::warn;
+
+use crate::{disk::into_vfs_err, partition::Partition};
+
+/// Number of bytes at the start of a device that [`probe`] looks at.
+const PROBE_LEN: usize = 2048;
+
+/// A filesystem format recognized by [`probe`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsType {
+    Ext4,
+    Fat,
+}
+
+impl fmt::Display for FsType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Ext4 => "ext4",
+            Self::Fat => "vfat",
+        })
+    }
+}
+
+fn le16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+/// Checks the magic number of the superblock, which lives at byte 1024.
+///
+/// ext2 and ext3 share it, and are readable as ext4.
+fn is_ext4(data: &[u8]) -> bool {
+    le16(data, 1024 + 56) == 0xef53
+}
+
+/// Checks the boot sector signature and the sanity of the BIOS parameter
+/// block, since many other formats share the signature.
+fn is_fat(data: &[u8]) -> bool {
+    let jump = data[0] == 0xe9 || (data[0] == 0xeb && data[2] == 0x90);
+    let bytes_per_sector = le16(data, 11);
+    let sectors_per_cluster = data[13];
+    let reserved_sectors = le16(data, 14);
+    let num_fats = data[16];
+    jump && data[510..512] == [0x55, 0xaa]
+        && bytes_per_sector.is_power_of_two()
+        && (512..=4096).contains(&bytes_per_sector)
+        && sectors_per_cluster.is_power_of_two()
+        && reserved_sectors > 0
+        && num_fats > 0
+}
+
+/// Probes, in order, for each known format.
+const PROBES: &[(FsType, fn(&[u8]) -> bool)] = &[(FsType::Ext4, is_ext4), (FsType::Fat, is_fat)];
+
+/// Detects the filesystem on a device from its superblock.
+///
+/// Returns `None` if the format is unknown. Formats whose support is not
+/// compiled in are detected all the same.
+pub fn probe(dev: &mut Partition) -> VfsResult<Option<FsType>> {
+    let block_size = dev.block_size();
+    let blocks = PROBE_LEN.div_ceil(block_size) as u64;
+    if dev.num_blocks() < blocks {
+        return Ok(None);
+    }
+    let mut data = vec![0; blocks as usize * block_size];
+    for (i, block) in data.chunks_mut(block_size).enumerate() {
+        dev.read_block(i as u64, block).map_err(into_vfs_err)?;
+    }
+    Ok(PROBES
+        .iter()
+        .find(|(_, probe)| probe(&data))
+        .map(|(fs_type, _)| *fs_type))
+}
+
+/// Creates a filesystem of the given type on the device.
+///
+/// Fails with [`VfsError::Unsupported`] if the support of the format is not
+/// compiled in.
+pub fn new_filesystem(fs_type: FsType, dev: Partition) -> VfsResult<Filesystem> {
+    #[allow(unreachable_patterns)]
+    match fs_type {
+        #[cfg(feature = "ext4")]
+        FsType::Ext4 => super::ext4::Ext4Filesystem::new(dev),
+        #[cfg(feature = "fat")]
+        FsType::Fat => super::fat::FatFilesystem::new(dev),
+        _ => {
+            drop(dev);
+            warn!("{fs_type} support is not enabled");
+            Err(VfsError::Unsupported)
+        }
+    }
+}
+
+/// Formats the device with a filesystem of the given type, with the default
+/// options of its `mkfs`.
+///
+/// Fails with [`VfsError::Unsupported`] if the support of the format is not
+/// compiled in.
+pub fn mkfs(fs_type: FsType, dev: &mut Partition) -> VfsResult<()> {
+    #[allow(unreachable_patterns)]
+    match fs_type {
+        #[cfg(feature = "ext4")]
+        FsType::Ext4 => super::ext4::Ext4MkfsOptions::new().format(dev),
+        #[cfg(feature = "fat")]
+        FsType::Fat => super::fat::FatMkfsOptions::new().format(dev),
+        _ => {
+            let _ = dev;
+            warn!("{fs_type} support is not enabled");
+            Err(VfsError::Unsupported)
+        }
+    }
+}
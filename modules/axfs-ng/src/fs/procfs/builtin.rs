@@ -0,0 +1,69 @@
+use alloc::{format, string::String};
+use core::fmt::Write;
+
+use axalloc::{UsageKind, global_allocator};
+use axfs_ng_vfs::VfsResult;
+
+use super::register_entry;
+use crate::highlevel::{MountFlags, mounts};
+
+const PAGE_SIZE: usize = 4096;
+
+fn meminfo() -> String {
+    let alloc = global_allocator();
+    let stats = alloc.usage_stats();
+    let total = (alloc.used_pages() + alloc.available_pages()) * PAGE_SIZE;
+    let free = alloc.available_pages() * PAGE_SIZE + alloc.available_bytes();
+
+    let mut out = String::new();
+    let mut line = |name: &str, bytes: usize| {
+        let _ = writeln!(out, "{:<16}{:>10} kB", format!("{name}:"), bytes / 1024);
+    };
+    line("MemTotal", total);
+    line("MemFree", free);
+    line("Cached", stats.get(UsageKind::PageCache));
+    line("PageTables", stats.get(UsageKind::PageTable));
+    line("KernelHeap", stats.get(UsageKind::RustHeap));
+    line("UserMem", stats.get(UsageKind::UserMem));
+    line("Dma", stats.get(UsageKind::Dma));
+    out
+}
+
+fn mounts_info() -> String {
+    let mut out = String::new();
+    for mount in mounts() {
+        let mut options = String::from(if mount.flags.contains(MountFlags::READ_ONLY) {
+            "ro"
+        } else {
+            "rw"
+        });
+        if mount.flags.contains(MountFlags::NO_SUID) {
+            options.push_str(",nosuid");
+        }
+        if mount.flags.contains(MountFlags::NO_EXEC) {
+            options.push_str(",noexec");
+        }
+        if mount.flags.contains(MountFlags::NO_ATIME) {
+            options.push_str(",noatime");
+        }
+        let _ = writeln!(
+            out,
+            "{} {} {} {} 0 0",
+            mount.source, mount.path, mount.fs_type, options
+        );
+    }
+    out
+}
+
+fn uptime() -> String {
+    let uptime = axhal::time::monotonic_time();
+    format!("{}.{:02}\n", uptime.as_secs(), uptime.subsec_millis() / 10)
+}
+
+/// Registers `meminfo`, `mounts` and `uptime`.
+pub fn register_builtin_entries() -> VfsResult<()> {
+    register_entry("meminfo", meminfo)?;
+    register_entry("mounts", mounts_info)?;
+    register_entry("uptime", uptime)?;
+    Ok(())
+}
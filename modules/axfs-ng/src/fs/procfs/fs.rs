@@ -0,0 +1,296 @@
+use alloc::{borrow::ToOwned, format, string::String, sync::Arc, vec::Vec};
+use core::{any::Any, cell::OnceCell, task::Context, time::Duration};
+
+use axfs_ng_vfs::{
+    DeviceId, DirEntry, DirEntrySink, DirNode, DirNodeOps, FileNode, FileNodeOps, Filesystem,
+    FilesystemOps, Metadata, MetadataUpdate, NodeFlags, NodeOps, NodePermission, NodeType,
+    Reference, StatFs, VfsError, VfsResult, WeakDirEntry, path::MAX_NAME_LEN,
+};
+use axio::{IoEvents, Pollable};
+
+use super::{Generator, list, lookup};
+
+const PROC_SUPER_MAGIC: u64 = 0x9fa0;
+
+const ROOT_INO: u64 = 1;
+
+/// Derives a stable inode number from the path of a node.
+fn path_ino(path: &str) -> u64 {
+    if path.is_empty() {
+        return ROOT_INO;
+    }
+    // FNV-1a
+    let hash = path.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, b| {
+        (hash ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+    });
+    hash.max(ROOT_INO + 1)
+}
+
+fn join(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_owned()
+    } else {
+        format!("{dir}/{name}")
+    }
+}
+
+fn metadata(path: &str, node_type: NodeType, mode: u16) -> Metadata {
+    Metadata {
+        inode: path_ino(path),
+        device: 0,
+        nlink: 1,
+        mode: NodePermission::from_bits_truncate(mode),
+        node_type,
+        uid: 0,
+        gid: 0,
+        size: 0,
+        block_size: 0,
+        blocks: 0,
+        rdev: DeviceId::default(),
+        atime: Duration::default(),
+        mtime: Duration::default(),
+        ctime: Duration::default(),
+    }
+}
+
+pub struct ProcFilesystem {
+    root_dir: OnceCell<DirEntry>,
+}
+
+impl ProcFilesystem {
+    /// Creates a new procfs showing all registered entries.
+    pub fn new() -> Filesystem {
+        let fs = Arc::new(Self {
+            root_dir: OnceCell::new(),
+        });
+        let _ = fs.root_dir.set(DirEntry::new_dir(
+            |this| ProcDir::new(fs.clone(), String::new(), this),
+            Reference::root(),
+        ));
+        Filesystem::new(fs)
+    }
+}
+
+unsafe impl Send for ProcFilesystem {}
+
+unsafe impl Sync for ProcFilesystem {}
+
+impl FilesystemOps for ProcFilesystem {
+    fn name(&self) -> &str {
+        "proc"
+    }
+
+    fn root_dir(&self) -> DirEntry {
+        self.root_dir.get().unwrap().clone()
+    }
+
+    fn stat(&self) -> VfsResult<StatFs> {
+        Ok(StatFs {
+            fs_type: PROC_SUPER_MAGIC as _,
+            block_size: 0,
+            blocks: 0,
+            blocks_free: 0,
+            blocks_available: 0,
+
+            file_count: 0,
+            free_file_count: 0,
+
+            name_length: MAX_NAME_LEN as _,
+            fragment_size: 0,
+            mount_flags: 0,
+        })
+    }
+}
+
+struct ProcDir {
+    fs: Arc<ProcFilesystem>,
+    path: String,
+    this: WeakDirEntry,
+}
+
+impl ProcDir {
+    fn new(fs: Arc<ProcFilesystem>, path: String, this: WeakDirEntry) -> DirNode {
+        DirNode::new(Arc::new(Self { fs, path, this }))
+    }
+}
+
+impl NodeOps for ProcDir {
+    fn inode(&self) -> u64 {
+        path_ino(&self.path)
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        Ok(metadata(&self.path, NodeType::Directory, 0o555))
+    }
+
+    fn update_metadata(&self, _update: MetadataUpdate) -> VfsResult<()> {
+        Err(VfsError::OperationNotPermitted)
+    }
+
+    fn filesystem(&self) -> &dyn FilesystemOps {
+        &*self.fs
+    }
+
+    fn sync(&self, _data_only: bool) -> VfsResult<()> {
+        Ok(())
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+
+    fn flags(&self) -> NodeFlags {
+        NodeFlags::empty()
+    }
+}
+
+impl DirNodeOps for ProcDir {
+    fn read_dir(&self, offset: u64, sink: &mut dyn DirEntrySink) -> VfsResult<usize> {
+        let parent = match self.path.rsplit_once('/') {
+            Some((parent, _)) => path_ino(parent),
+            None => ROOT_INO,
+        };
+        let dots = [
+            (".".to_owned(), self.inode(), NodeType::Directory),
+            ("..".to_owned(), parent, NodeType::Directory),
+        ];
+        let children = list(&self.path)
+            .into_iter()
+            .map(|(name, node_type)| {
+                let ino = path_ino(&join(&self.path, &name));
+                (name, ino, node_type)
+            })
+            .collect::<Vec<_>>();
+
+        let mut count = 0;
+        for (i, (name, ino, node_type)) in dots
+            .into_iter()
+            .chain(children)
+            .enumerate()
+            .skip(offset as usize)
+        {
+            if !sink.accept(&name, ino, node_type, i as u64 + 1) {
+                break;
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn lookup(&self, name: &str) -> VfsResult<DirEntry> {
+        let path = join(&self.path, name);
+        let reference = Reference::new(self.this.upgrade(), name.to_owned());
+        match lookup(&path).ok_or(VfsError::NotFound)? {
+            Some(generator) => Ok(DirEntry::new_file(
+                FileNode::new(Arc::new(ProcFile {
+                    fs: self.fs.clone(),
+                    path,
+                    generator,
+                })),
+                NodeType::RegularFile,
+                reference,
+            )),
+            None => Ok(DirEntry::new_dir(
+                |this| ProcDir::new(self.fs.clone(), path, this),
+                reference,
+            )),
+        }
+    }
+
+    fn create(
+        &self,
+        _name: &str,
+        _node_type: NodeType,
+        _permission: NodePermission,
+    ) -> VfsResult<DirEntry> {
+        Err(VfsError::OperationNotPermitted)
+    }
+
+    fn link(&self, _name: &str, _node: &DirEntry) -> VfsResult<DirEntry> {
+        Err(VfsError::OperationNotPermitted)
+    }
+
+    fn unlink(&self, _name: &str) -> VfsResult<()> {
+        Err(VfsError::OperationNotPermitted)
+    }
+
+    fn rename(&self, _src_name: &str, _dst_dir: &DirNode, _dst_name: &str) -> VfsResult<()> {
+        Err(VfsError::OperationNotPermitted)
+    }
+}
+
+struct ProcFile {
+    fs: Arc<ProcFilesystem>,
+    path: String,
+    generator: Generator,
+}
+
+impl NodeOps for ProcFile {
+    fn inode(&self) -> u64 {
+        path_ino(&self.path)
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        Ok(metadata(&self.path, NodeType::RegularFile, 0o444))
+    }
+
+    fn update_metadata(&self, _update: MetadataUpdate) -> VfsResult<()> {
+        Err(VfsError::OperationNotPermitted)
+    }
+
+    fn len(&self) -> VfsResult<u64> {
+        // Like Linux, the size is unknown until the file is read.
+        Ok(0)
+    }
+
+    fn filesystem(&self) -> &dyn FilesystemOps {
+        &*self.fs
+    }
+
+    fn sync(&self, _data_only: bool) -> VfsResult<()> {
+        Ok(())
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+
+    fn flags(&self) -> NodeFlags {
+        NodeFlags::NON_CACHEABLE
+    }
+}
+
+impl FileNodeOps for ProcFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> VfsResult<usize> {
+        let content = (self.generator)();
+        let content = content.as_bytes();
+        let start = (offset as usize).min(content.len());
+        let len = buf.len().min(content.len() - start);
+        buf[..len].copy_from_slice(&content[start..start + len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, _buf: &[u8], _offset: u64) -> VfsResult<usize> {
+        Err(VfsError::PermissionDenied)
+    }
+
+    fn append(&self, _buf: &[u8]) -> VfsResult<(usize, u64)> {
+        Err(VfsError::PermissionDenied)
+    }
+
+    fn set_len(&self, _len: u64) -> VfsResult<()> {
+        Err(VfsError::PermissionDenied)
+    }
+
+    fn set_symlink(&self, _target: &str) -> VfsResult<()> {
+        Err(VfsError::InvalidInput)
+    }
+}
+
+impl Pollable for ProcFile {
+    fn poll(&self) -> IoEvents {
+        IoEvents::IN
+    }
+
+    fn register(&self, _context: &mut Context<'_>, _events: IoEvents) {}
+}
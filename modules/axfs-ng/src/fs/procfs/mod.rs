@@ -0,0 +1,97 @@
+//! A pseudo filesystem for kernel introspection, usually mounted at `/proc`.
+//!
+//! Every file is backed by a generator that renders its content on each read.
+//! Generators live in a kernel-wide registry, so that each module can add its
+//! own entries with [`register_entry`], e.g. `net/dev` from the network
+//! module. Directories are implied by the paths of the entries.
+
+mod builtin;
+mod fs;
+
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+
+use axfs_ng_vfs::{NodeType, VfsError, VfsResult};
+pub use builtin::register_builtin_entries;
+pub use fs::ProcFilesystem;
+use kspin::SpinNoPreempt as Mutex;
+
+/// Renders the content of a procfs file.
+pub type Generator = Arc<dyn Fn() -> String + Send + Sync>;
+
+static ENTRIES: Mutex<BTreeMap<String, Generator>> = Mutex::new(BTreeMap::new());
+
+/// Adds a file at `path` (relative to the procfs root, e.g. `net/dev`) whose
+/// content is rendered by `generator` on each read.
+pub fn register_entry(
+    path: &str,
+    generator: impl Fn() -> String + Send + Sync + 'static,
+) -> VfsResult<()> {
+    if path.is_empty() || path.split('/').any(str::is_empty) {
+        return Err(VfsError::InvalidInput);
+    }
+    let mut entries = ENTRIES.lock();
+    for existing in entries.keys() {
+        if existing == path || is_child_of(existing, path) {
+            return Err(VfsError::AlreadyExists);
+        }
+        if is_child_of(path, existing) {
+            return Err(VfsError::NotADirectory);
+        }
+    }
+    entries.insert(path.into(), Arc::new(generator));
+    Ok(())
+}
+
+/// Removes the file at `path` added by [`register_entry`].
+pub fn unregister_entry(path: &str) -> VfsResult<()> {
+    ENTRIES
+        .lock()
+        .remove(path)
+        .map(|_| ())
+        .ok_or(VfsError::NotFound)
+}
+
+/// Returns whether `path` lies under the directory `dir`, where the root
+/// directory is the empty string.
+fn is_child_of(path: &str, dir: &str) -> bool {
+    dir.is_empty()
+        || path
+            .strip_prefix(dir)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// Looks up `path`, returning its generator if it is a file.
+pub(crate) fn lookup(path: &str) -> Option<Option<Generator>> {
+    let entries = ENTRIES.lock();
+    if let Some(generator) = entries.get(path) {
+        Some(Some(generator.clone()))
+    } else if entries.keys().any(|it| is_child_of(it, path)) {
+        Some(None)
+    } else {
+        None
+    }
+}
+
+/// Lists the direct children of the directory at `dir`.
+pub(crate) fn list(dir: &str) -> Vec<(String, NodeType)> {
+    let mut children: Vec<(String, NodeType)> = Vec::new();
+    for path in ENTRIES.lock().keys() {
+        if !is_child_of(path, dir) {
+            continue;
+        }
+        let rest = if dir.is_empty() {
+            path.as_str()
+        } else {
+            &path[dir.len() + 1..]
+        };
+        let (name, node_type) = match rest.split_once('/') {
+            Some((name, _)) => (name, NodeType::Directory),
+            None => (rest, NodeType::RegularFile),
+        };
+        // Entries are sorted, so children of the same directory are adjacent.
+        if children.last().is_none_or(|(last, _)| last != name) {
+            children.push((name.into(), node_type));
+        }
+    }
+    children
+}
@@ -0,0 +1,338 @@
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+use core::{
+    ops::Range,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use axfs_ng_vfs::{NodePermission, NodeType, VfsError, VfsResult};
+use kspin::SpinNoPreempt as Mutex;
+
+use crate::{
+    fs::now,
+    highlevel::{CachedFileShared, PageCache},
+};
+
+pub const PAGE_SIZE: usize = 4096;
+
+fn pages_of(size: u64) -> u64 {
+    size.div_ceil(PAGE_SIZE as u64)
+}
+
+/// Space and inode accounting shared by all nodes of a filesystem.
+pub struct Capacity {
+    limit: Option<u64>,
+    used: AtomicU64,
+    nodes: AtomicU64,
+}
+
+impl Capacity {
+    pub fn new(limit: Option<u64>) -> Self {
+        Self {
+            limit,
+            used: AtomicU64::new(0),
+            nodes: AtomicU64::new(0),
+        }
+    }
+
+    pub fn limit(&self) -> Option<u64> {
+        self.limit
+    }
+
+    pub fn used(&self) -> u64 {
+        self.used.load(Ordering::Acquire)
+    }
+
+    pub fn nodes(&self) -> u64 {
+        self.nodes.load(Ordering::Relaxed)
+    }
+
+    /// Charges `bytes` against the size limit, failing with
+    /// [`VfsError::StorageFull`] if the limit would be exceeded.
+    pub fn reserve(&self, bytes: u64) -> VfsResult<()> {
+        self.used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                let new_used = used.checked_add(bytes)?;
+                match self.limit {
+                    Some(limit) if new_used > limit => None,
+                    _ => Some(new_used),
+                }
+            })
+            .map(|_| ())
+            .map_err(|_| VfsError::StorageFull)
+    }
+
+    pub fn release(&self, bytes: u64) {
+        self.used.fetch_sub(bytes, Ordering::AcqRel);
+    }
+}
+
+/// Contents of a tmpfs file.
+///
+/// The page cache is the only storage of the file. It is owned by the inode
+/// rather than by a directory entry, so that all hard links share it and it
+/// is not lost when entries are dropped.
+pub struct FileData {
+    size: u64,
+    pages: Arc<CachedFileShared>,
+}
+
+impl FileData {
+    pub fn new() -> Self {
+        Self {
+            size: 0,
+            pages: Arc::new(CachedFileShared::new_unbounded()),
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn pages(&self) -> &Arc<CachedFileShared> {
+        &self.pages
+    }
+
+    pub fn read_at(&self, buf: &mut [u8], offset: u64) -> usize {
+        if offset >= self.size {
+            return 0;
+        }
+        let len = buf.len().min((self.size - offset) as usize);
+        let mut cache = self.pages.page_cache.lock();
+        let mut read = 0;
+        while read < len {
+            let pos = offset + read as u64;
+            let pn = (pos / PAGE_SIZE as u64) as u32;
+            let page_offset = (pos % PAGE_SIZE as u64) as usize;
+            let n = (PAGE_SIZE - page_offset).min(len - read);
+            let dst = &mut buf[read..read + n];
+            match cache.peek_mut(&pn) {
+                Some(page) => dst.copy_from_slice(&page.data()[page_offset..page_offset + n]),
+                None => dst.fill(0),
+            }
+            read += n;
+        }
+        read
+    }
+
+    pub fn write_at(&mut self, capacity: &Capacity, buf: &[u8], offset: u64) -> VfsResult<usize> {
+        let end = offset
+            .checked_add(buf.len() as u64)
+            .ok_or(VfsError::InvalidInput)?;
+        if end > self.size {
+            self.set_len(capacity, end)?;
+        }
+        let mut cache = self.pages.page_cache.lock();
+        let mut written = 0;
+        while written < buf.len() {
+            let pos = offset + written as u64;
+            let pn = (pos / PAGE_SIZE as u64) as u32;
+            let page_offset = (pos % PAGE_SIZE as u64) as usize;
+            let n = (PAGE_SIZE - page_offset).min(buf.len() - written);
+            if !cache.contains(&pn) {
+                let mut page = PageCache::new_in_memory()?;
+                page.data().fill(0);
+                cache.put(pn, page);
+            }
+            let page = cache.peek_mut(&pn).unwrap();
+            page.data()[page_offset..page_offset + n].copy_from_slice(&buf[written..written + n]);
+            written += n;
+        }
+        Ok(written)
+    }
+
+    pub fn set_len(&mut self, capacity: &Capacity, len: u64) -> VfsResult<()> {
+        let (old_pages, new_pages) = (pages_of(self.size), pages_of(len));
+        if new_pages > old_pages {
+            capacity.reserve((new_pages - old_pages) * PAGE_SIZE as u64)?;
+        } else {
+            capacity.release((old_pages - new_pages) * PAGE_SIZE as u64);
+        }
+
+        if len < self.size {
+            // Drop the pages beyond the new end and clear the tail of the last
+            // one, so that growing the file again reads zeros.
+            let mut cache = self.pages.page_cache.lock();
+            let keys = cache
+                .iter()
+                .map(|(pn, _)| *pn)
+                .filter(|pn| *pn as u64 >= new_pages)
+                .collect::<Vec<_>>();
+            for pn in keys {
+                cache.pop(&pn);
+            }
+            let tail = (len % PAGE_SIZE as u64) as usize;
+            if tail != 0 {
+                if let Some(page) = cache.peek_mut(&((len / PAGE_SIZE as u64) as u32)) {
+                    page.data()[tail..].fill(0);
+                }
+            }
+        }
+        self.size = len;
+        Ok(())
+    }
+
+    /// Brings in the missing pages of `range`, within the file.
+    pub fn allocate(&mut self, range: Range<u64>) -> VfsResult<()> {
+        let end = range.end.min(self.size);
+        if range.start >= end {
+            return Ok(());
+        }
+        let mut cache = self.pages.page_cache.lock();
+        for pn in (range.start / PAGE_SIZE as u64) as u32..pages_of(end) as u32 {
+            if !cache.contains(&pn) {
+                let mut page = PageCache::new_in_memory()?;
+                page.data().fill(0);
+                cache.put(pn, page);
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops the pages within `range`, and zeroes the parts of it in the
+    /// pages at its ends.
+    pub fn punch_hole(&mut self, range: Range<u64>) {
+        let full_pages = pages_of(range.start) as u32..(range.end / PAGE_SIZE as u64) as u32;
+        let mut cache = self.pages.page_cache.lock();
+        for pn in full_pages.clone() {
+            cache.pop(&pn);
+        }
+        for pn in [
+            range.start / PAGE_SIZE as u64,
+            (range.end - 1) / PAGE_SIZE as u64,
+        ] {
+            let pn = pn as u32;
+            if full_pages.contains(&pn) {
+                continue;
+            }
+            if let Some(page) = cache.peek_mut(&pn) {
+                let page_start = pn as u64 * PAGE_SIZE as u64;
+                let start = range.start.saturating_sub(page_start) as usize;
+                let end = (range.end - page_start).min(PAGE_SIZE as u64) as usize;
+                page.data()[start..end].fill(0);
+            }
+        }
+    }
+
+    /// Returns the start of the first page at or after `offset`, if any
+    /// before the end of the file.
+    pub fn next_data(&self, offset: u64) -> Option<u64> {
+        let start = (offset / PAGE_SIZE as u64) as u32;
+        let cache = self.pages.page_cache.lock();
+        cache
+            .iter()
+            .map(|(pn, _)| *pn)
+            .filter(|pn| *pn >= start)
+            .min()
+            .map(|pn| (pn as u64 * PAGE_SIZE as u64).max(offset))
+            .filter(|it| *it < self.size)
+    }
+
+    /// Returns the start of the first missing page at or after `offset`, or
+    /// the end of the file.
+    pub fn next_hole(&self, offset: u64) -> u64 {
+        let mut pn = (offset / PAGE_SIZE as u64) as u32;
+        let cache = self.pages.page_cache.lock();
+        while cache.contains(&pn) {
+            pn += 1;
+        }
+        (pn as u64 * PAGE_SIZE as u64).max(offset).min(self.size)
+    }
+}
+
+pub struct DirData {
+    pub entries: BTreeMap<String, Arc<NodeData>>,
+    /// Inode number of the parent directory, reported as `..`.
+    pub parent: u64,
+}
+
+pub enum NodeContent {
+    File(FileData),
+    Symlink(String),
+    Dir(DirData),
+}
+
+pub struct NodeMeta {
+    pub mode: NodePermission,
+    pub uid: u32,
+    pub gid: u32,
+    pub nlink: u64,
+    pub atime: Duration,
+    pub mtime: Duration,
+    pub ctime: Duration,
+}
+
+/// An inode of the in-memory filesystem, shared by all of its hard links.
+pub struct NodeData {
+    pub ino: u64,
+    pub node_type: NodeType,
+    pub meta: Mutex<NodeMeta>,
+    pub content: Mutex<NodeContent>,
+    /// Extended attributes, by name.
+    pub xattrs: Mutex<BTreeMap<String, Vec<u8>>>,
+    capacity: Arc<Capacity>,
+}
+
+impl NodeData {
+    pub fn new(
+        capacity: Arc<Capacity>,
+        ino: u64,
+        node_type: NodeType,
+        mode: NodePermission,
+        parent: u64,
+    ) -> Self {
+        capacity.nodes.fetch_add(1, Ordering::Relaxed);
+        let time = now();
+        let content = match node_type {
+            NodeType::Directory => NodeContent::Dir(DirData {
+                entries: BTreeMap::new(),
+                parent,
+            }),
+            NodeType::Symlink => NodeContent::Symlink(String::new()),
+            _ => NodeContent::File(FileData::new()),
+        };
+        Self {
+            ino,
+            node_type,
+            meta: Mutex::new(NodeMeta {
+                mode,
+                uid: 0,
+                gid: 0,
+                nlink: if node_type == NodeType::Directory {
+                    2
+                } else {
+                    1
+                },
+                atime: time,
+                mtime: time,
+                ctime: time,
+            }),
+            content: Mutex::new(content),
+            xattrs: Mutex::new(BTreeMap::new()),
+            capacity,
+        }
+    }
+
+    pub fn capacity(&self) -> &Capacity {
+        &self.capacity
+    }
+
+    pub fn touch(&self, modified: bool) {
+        let time = now();
+        let mut meta = self.meta.lock();
+        if modified {
+            meta.mtime = time;
+        }
+        meta.ctime = time;
+    }
+}
+
+impl Drop for NodeData {
+    fn drop(&mut self) {
+        if let NodeContent::File(data) = self.content.get_mut() {
+            self.capacity
+                .release(pages_of(data.size()) * PAGE_SIZE as u64);
+        }
+        self.capacity.nodes.fetch_sub(1, Ordering::Relaxed);
+    }
+}
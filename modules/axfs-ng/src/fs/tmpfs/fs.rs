@@ -0,0 +1,122 @@
+use alloc::sync::Arc;
+use core::{
+    cell::OnceCell,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use axfs_ng_vfs::{
+    DirEntry, DirNode, Filesystem, FilesystemOps, NodePermission, NodeType, Reference, StatFs,
+    VfsResult, path::MAX_NAME_LEN,
+};
+use kspin::{SpinNoPreempt as Mutex, SpinNoPreemptGuard as MutexGuard};
+
+use super::{
+    Inode,
+    data::{Capacity, NodeData, PAGE_SIZE},
+};
+
+const TMPFS_MAGIC: u64 = 0x01021994;
+
+const ROOT_INO: u64 = 1;
+
+pub struct TmpFilesystem {
+    capacity: Arc<Capacity>,
+    next_ino: AtomicU64,
+    /// Serializes renames so that two directories are never locked in
+    /// different orders.
+    rename_lock: Mutex<()>,
+    root_dir: OnceCell<DirEntry>,
+}
+
+impl TmpFilesystem {
+    /// Creates a new tmpfs without a size limit.
+    pub fn new() -> Filesystem {
+        Self::new_inner(None)
+    }
+
+    /// Creates a new tmpfs that holds at most `size_limit` bytes of file data.
+    pub fn with_size_limit(size_limit: u64) -> Filesystem {
+        Self::new_inner(Some(size_limit))
+    }
+
+    fn new_inner(size_limit: Option<u64>) -> Filesystem {
+        let capacity = Arc::new(Capacity::new(size_limit));
+        let root = Arc::new(NodeData::new(
+            capacity.clone(),
+            ROOT_INO,
+            NodeType::Directory,
+            NodePermission::from_bits_truncate(0o1777),
+            ROOT_INO,
+        ));
+        let fs = Arc::new(Self {
+            capacity,
+            next_ino: AtomicU64::new(ROOT_INO + 1),
+            rename_lock: Mutex::new(()),
+            root_dir: OnceCell::new(),
+        });
+        let _ = fs.root_dir.set(DirEntry::new_dir(
+            |this| DirNode::new(Inode::new(fs.clone(), root, Some(this))),
+            Reference::root(),
+        ));
+        Filesystem::new(fs)
+    }
+
+    pub(crate) fn new_node(
+        &self,
+        node_type: NodeType,
+        mode: NodePermission,
+        parent: u64,
+    ) -> Arc<NodeData> {
+        let ino = self.next_ino.fetch_add(1, Ordering::Relaxed);
+        Arc::new(NodeData::new(
+            self.capacity.clone(),
+            ino,
+            node_type,
+            mode,
+            parent,
+        ))
+    }
+
+    pub(crate) fn lock_rename(&self) -> MutexGuard<()> {
+        self.rename_lock.lock()
+    }
+}
+
+unsafe impl Send for TmpFilesystem {}
+
+unsafe impl Sync for TmpFilesystem {}
+
+impl FilesystemOps for TmpFilesystem {
+    fn name(&self) -> &str {
+        "tmpfs"
+    }
+
+    fn root_dir(&self) -> DirEntry {
+        self.root_dir.get().unwrap().clone()
+    }
+
+    fn stat(&self) -> VfsResult<StatFs> {
+        let used = self.capacity.used() / PAGE_SIZE as u64;
+        let blocks = match self.capacity.limit() {
+            Some(limit) => limit / PAGE_SIZE as u64,
+            // Without a limit, the filesystem can grow until we run out of
+            // memory.
+            None => used + axalloc::global_allocator().available_pages() as u64,
+        };
+        let blocks_free = blocks.saturating_sub(used);
+        Ok(StatFs {
+            fs_type: TMPFS_MAGIC as _,
+            block_size: PAGE_SIZE as _,
+            blocks,
+            blocks_free,
+            blocks_available: blocks_free,
+
+            file_count: self.capacity.nodes() as _,
+            free_file_count: u32::MAX as _,
+
+            name_length: MAX_NAME_LEN as _,
+            fragment_size: 0,
+            mount_flags: 0,
+        })
+    }
+}
@@ -0,0 +1,440 @@
+use alloc::{
+    borrow::ToOwned,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use core::{any::Any, ops::Range, task::Context};
+
+use axfs_ng_vfs::{
+    DeviceId, DirEntry, DirEntrySink, DirNode, DirNodeOps, FileNode, FileNodeOps, FilesystemOps,
+    Location, Metadata, MetadataUpdate, NodeFlags, NodeOps, NodePermission, NodeType, Reference,
+    VfsError, VfsResult, WeakDirEntry,
+};
+use axio::{IoEvents, Pollable};
+
+use super::{
+    TmpFilesystem,
+    data::{DirData, FileData, NodeContent, NodeData, PAGE_SIZE},
+};
+use crate::{
+    fs::now,
+    highlevel::{CachedFileShared, SparseNode, XattrFlags, XattrNode, check_set_flags, no_data},
+};
+
+/// Returns the page cache holding the contents of a tmpfs file.
+pub(crate) fn page_cache(location: &Location) -> Option<Arc<CachedFileShared>> {
+    let inode: Arc<Inode> = location.entry().as_file().ok()?.downcast().ok()?;
+    match &*inode.node.content.lock() {
+        NodeContent::File(data) => Some(data.pages().clone()),
+        _ => None,
+    }
+}
+
+pub struct Inode {
+    fs: Arc<TmpFilesystem>,
+    node: Arc<NodeData>,
+    this: Option<WeakDirEntry>,
+}
+
+impl Inode {
+    pub(crate) fn new(
+        fs: Arc<TmpFilesystem>,
+        node: Arc<NodeData>,
+        this: Option<WeakDirEntry>,
+    ) -> Arc<Self> {
+        Arc::new(Self { fs, node, this })
+    }
+
+    fn create_entry(&self, node: Arc<NodeData>, name: &str) -> DirEntry {
+        let reference = Reference::new(
+            self.this.as_ref().and_then(WeakDirEntry::upgrade),
+            name.to_owned(),
+        );
+        let node_type = node.node_type;
+        if node_type == NodeType::Directory {
+            DirEntry::new_dir(
+                |this| DirNode::new(Inode::new(self.fs.clone(), node, Some(this))),
+                reference,
+            )
+        } else {
+            DirEntry::new_file(
+                FileNode::new(Inode::new(self.fs.clone(), node, None)),
+                node_type,
+                reference,
+            )
+        }
+    }
+
+    fn with_file<R>(&self, f: impl FnOnce(&mut FileData) -> VfsResult<R>) -> VfsResult<R> {
+        match &mut *self.node.content.lock() {
+            NodeContent::File(data) => f(data),
+            NodeContent::Symlink(_) => Err(VfsError::InvalidInput),
+            NodeContent::Dir(_) => Err(VfsError::IsADirectory),
+        }
+    }
+
+    fn with_dir<R>(&self, f: impl FnOnce(&mut DirData) -> VfsResult<R>) -> VfsResult<R> {
+        match &mut *self.node.content.lock() {
+            NodeContent::Dir(dir) => f(dir),
+            _ => Err(VfsError::NotADirectory),
+        }
+    }
+}
+
+fn is_empty_dir(node: &NodeData) -> bool {
+    match &*node.content.lock() {
+        NodeContent::Dir(dir) => dir.entries.is_empty(),
+        _ => false,
+    }
+}
+
+/// Drops one link of `node`, which has just been removed from `dir`.
+fn unlink_node(dir: &NodeData, node: &NodeData) {
+    let mut meta = node.meta.lock();
+    if node.node_type == NodeType::Directory {
+        meta.nlink = 0;
+        drop(meta);
+        dir.meta.lock().nlink -= 1;
+    } else {
+        meta.nlink -= 1;
+        meta.ctime = now();
+    }
+}
+
+impl NodeOps for Inode {
+    fn inode(&self) -> u64 {
+        self.node.ino
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        let size = self.len()?;
+        let meta = self.node.meta.lock();
+        Ok(Metadata {
+            inode: self.node.ino,
+            device: 0,
+            nlink: meta.nlink,
+            mode: meta.mode,
+            node_type: self.node.node_type,
+            uid: meta.uid,
+            gid: meta.gid,
+            size,
+            block_size: PAGE_SIZE as _,
+            blocks: size.div_ceil(PAGE_SIZE as u64) * (PAGE_SIZE as u64 / 512),
+            rdev: DeviceId::default(),
+            atime: meta.atime,
+            mtime: meta.mtime,
+            ctime: meta.ctime,
+        })
+    }
+
+    fn update_metadata(&self, update: MetadataUpdate) -> VfsResult<()> {
+        let mut meta = self.node.meta.lock();
+        if let Some(mode) = update.mode {
+            meta.mode = mode;
+        }
+        if let Some((uid, gid)) = update.owner {
+            meta.uid = uid;
+            meta.gid = gid;
+        }
+        if let Some(atime) = update.atime {
+            meta.atime = atime;
+        }
+        if let Some(mtime) = update.mtime {
+            meta.mtime = mtime;
+        }
+        meta.ctime = now();
+        Ok(())
+    }
+
+    fn len(&self) -> VfsResult<u64> {
+        match &*self.node.content.lock() {
+            NodeContent::File(data) => Ok(data.size()),
+            NodeContent::Symlink(target) => Ok(target.len() as u64),
+            NodeContent::Dir(dir) => Ok((dir.entries.len() + 2) as u64),
+        }
+    }
+
+    fn filesystem(&self) -> &dyn FilesystemOps {
+        &*self.fs
+    }
+
+    fn sync(&self, _data_only: bool) -> VfsResult<()> {
+        Ok(())
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+
+    fn flags(&self) -> NodeFlags {
+        NodeFlags::empty()
+    }
+}
+
+impl FileNodeOps for Inode {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> VfsResult<usize> {
+        match &*self.node.content.lock() {
+            NodeContent::File(data) => Ok(data.read_at(buf, offset)),
+            NodeContent::Symlink(target) => {
+                let target = target.as_bytes();
+                let start = (offset as usize).min(target.len());
+                let len = buf.len().min(target.len() - start);
+                buf[..len].copy_from_slice(&target[start..start + len]);
+                Ok(len)
+            }
+            NodeContent::Dir(_) => Err(VfsError::IsADirectory),
+        }
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> VfsResult<usize> {
+        let written = self.with_file(|data| data.write_at(self.node.capacity(), buf, offset))?;
+        self.node.touch(true);
+        Ok(written)
+    }
+
+    fn append(&self, buf: &[u8]) -> VfsResult<(usize, u64)> {
+        let result = self.with_file(|data| {
+            let written = data.write_at(self.node.capacity(), buf, data.size())?;
+            Ok((written, data.size()))
+        })?;
+        self.node.touch(true);
+        Ok(result)
+    }
+
+    fn set_len(&self, len: u64) -> VfsResult<()> {
+        self.with_file(|data| data.set_len(self.node.capacity(), len))?;
+        self.node.touch(true);
+        Ok(())
+    }
+
+    fn set_symlink(&self, target: &str) -> VfsResult<()> {
+        match &mut *self.node.content.lock() {
+            NodeContent::Symlink(old) => {
+                *old = target.to_owned();
+                Ok(())
+            }
+            _ => Err(VfsError::InvalidInput),
+        }
+    }
+}
+
+impl Pollable for Inode {
+    fn poll(&self) -> IoEvents {
+        IoEvents::IN | IoEvents::OUT
+    }
+
+    fn register(&self, _context: &mut Context<'_>, _events: IoEvents) {}
+}
+
+impl DirNodeOps for Inode {
+    fn read_dir(&self, offset: u64, sink: &mut dyn DirEntrySink) -> VfsResult<usize> {
+        let (parent, children) = self.with_dir(|dir| {
+            Ok((
+                dir.parent,
+                dir.entries
+                    .iter()
+                    .map(|(name, node)| (name.clone(), node.ino, node.node_type))
+                    .collect::<Vec<_>>(),
+            ))
+        })?;
+
+        let dots = [
+            (".".to_owned(), self.node.ino, NodeType::Directory),
+            ("..".to_owned(), parent, NodeType::Directory),
+        ];
+        let mut count = 0;
+        for (i, (name, ino, node_type)) in dots
+            .into_iter()
+            .chain(children)
+            .enumerate()
+            .skip(offset as usize)
+        {
+            if !sink.accept(&name, ino, node_type, i as u64 + 1) {
+                break;
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn lookup(&self, name: &str) -> VfsResult<DirEntry> {
+        let node = self.with_dir(|dir| dir.entries.get(name).cloned().ok_or(VfsError::NotFound))?;
+        Ok(self.create_entry(node, name))
+    }
+
+    fn create(
+        &self,
+        name: &str,
+        node_type: NodeType,
+        permission: NodePermission,
+    ) -> VfsResult<DirEntry> {
+        if node_type == NodeType::Unknown {
+            return Err(VfsError::InvalidData);
+        }
+        let node = self.with_dir(|dir| {
+            if dir.entries.contains_key(name) {
+                return Err(VfsError::AlreadyExists);
+            }
+            let node = self.fs.new_node(node_type, permission, self.node.ino);
+            dir.entries.insert(name.to_owned(), node.clone());
+            Ok(node)
+        })?;
+        if node_type == NodeType::Directory {
+            self.node.meta.lock().nlink += 1;
+        }
+        self.node.touch(true);
+        Ok(self.create_entry(node, name))
+    }
+
+    fn link(&self, name: &str, node: &DirEntry) -> VfsResult<DirEntry> {
+        let src: Arc<Self> = node
+            .as_file()?
+            .downcast()
+            .map_err(|_| VfsError::InvalidInput)?;
+        if !Arc::ptr_eq(&self.fs, &src.fs) {
+            return Err(VfsError::InvalidInput);
+        }
+        self.with_dir(|dir| {
+            if dir.entries.contains_key(name) {
+                return Err(VfsError::AlreadyExists);
+            }
+            dir.entries.insert(name.to_owned(), src.node.clone());
+            Ok(())
+        })?;
+        {
+            let mut meta = src.node.meta.lock();
+            meta.nlink += 1;
+            meta.ctime = now();
+        }
+        self.node.touch(true);
+        Ok(self.create_entry(src.node.clone(), name))
+    }
+
+    fn unlink(&self, name: &str) -> VfsResult<()> {
+        let node = self.with_dir(|dir| {
+            let node = dir.entries.get(name).ok_or(VfsError::NotFound)?;
+            if node.node_type == NodeType::Directory && !is_empty_dir(node) {
+                return Err(VfsError::DirectoryNotEmpty);
+            }
+            Ok(dir.entries.remove(name).unwrap())
+        })?;
+        unlink_node(&self.node, &node);
+        self.node.touch(true);
+        Ok(())
+    }
+
+    fn rename(&self, src_name: &str, dst_dir: &DirNode, dst_name: &str) -> VfsResult<()> {
+        let dst_dir: Arc<Self> = dst_dir.downcast().map_err(|_| VfsError::InvalidInput)?;
+        if !Arc::ptr_eq(&self.fs, &dst_dir.fs) {
+            return Err(VfsError::InvalidInput);
+        }
+        let _guard = self.fs.lock_rename();
+
+        let src =
+            self.with_dir(|dir| dir.entries.get(src_name).cloned().ok_or(VfsError::NotFound))?;
+        let same_node = dst_dir.with_dir(|dir| {
+            Ok(dir
+                .entries
+                .get(dst_name)
+                .is_some_and(|dst| Arc::ptr_eq(dst, &src)))
+        })?;
+        if same_node {
+            // Both names refer to the same inode, nothing to do.
+            return Ok(());
+        }
+
+        let is_dir = src.node_type == NodeType::Directory;
+        let replaced = dst_dir.with_dir(|dir| {
+            let Some(dst) = dir.entries.get(dst_name) else {
+                return Ok(None);
+            };
+            match (is_dir, dst.node_type == NodeType::Directory) {
+                (true, false) => return Err(VfsError::NotADirectory),
+                (false, true) => return Err(VfsError::IsADirectory),
+                (true, true) if !is_empty_dir(dst) => return Err(VfsError::DirectoryNotEmpty),
+                _ => {}
+            }
+            Ok(dir.entries.remove(dst_name))
+        })?;
+        if let Some(replaced) = &replaced {
+            unlink_node(&dst_dir.node, replaced);
+        }
+
+        self.with_dir(|dir| {
+            dir.entries.remove(src_name);
+            Ok(())
+        })?;
+        dst_dir.with_dir(|dir| {
+            dir.entries.insert(dst_name.to_owned(), src.clone());
+            Ok(())
+        })?;
+        if is_dir {
+            if let NodeContent::Dir(dir) = &mut *src.content.lock() {
+                dir.parent = dst_dir.node.ino;
+            }
+            if !Arc::ptr_eq(&self.node, &dst_dir.node) {
+                self.node.meta.lock().nlink -= 1;
+                dst_dir.node.meta.lock().nlink += 1;
+            }
+        }
+
+        src.touch(false);
+        self.node.touch(true);
+        dst_dir.node.touch(true);
+        Ok(())
+    }
+}
+
+impl XattrNode for Inode {
+    fn get_xattr(&self, name: &str) -> VfsResult<Vec<u8>> {
+        self.node
+            .xattrs
+            .lock()
+            .get(name)
+            .cloned()
+            .ok_or_else(no_data)
+    }
+
+    fn set_xattr(&self, name: &str, value: &[u8], flags: XattrFlags) -> VfsResult<()> {
+        let mut xattrs = self.node.xattrs.lock();
+        check_set_flags(xattrs.contains_key(name), flags)?;
+        xattrs.insert(name.to_string(), value.to_vec());
+        drop(xattrs);
+        self.node.touch(false);
+        Ok(())
+    }
+
+    fn list_xattr(&self) -> VfsResult<Vec<String>> {
+        Ok(self.node.xattrs.lock().keys().cloned().collect())
+    }
+
+    fn remove_xattr(&self, name: &str) -> VfsResult<()> {
+        self.node.xattrs.lock().remove(name).ok_or_else(no_data)?;
+        self.node.touch(false);
+        Ok(())
+    }
+}
+
+impl SparseNode for Inode {
+    fn allocate(&self, range: Range<u64>) -> VfsResult<()> {
+        self.with_file(|data| data.allocate(range))
+    }
+
+    fn punch_hole(&self, range: Range<u64>) -> VfsResult<()> {
+        self.with_file(|data| {
+            data.punch_hole(range);
+            Ok(())
+        })?;
+        self.node.touch(true);
+        Ok(())
+    }
+
+    fn next_data(&self, offset: u64) -> VfsResult<Option<u64>> {
+        self.with_file(|data| Ok(data.next_data(offset)))
+    }
+
+    fn next_hole(&self, offset: u64) -> VfsResult<u64> {
+        self.with_file(|data| Ok(data.next_hole(offset)))
+    }
+}
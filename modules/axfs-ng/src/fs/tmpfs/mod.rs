@@ -0,0 +1,13 @@
+//! An in-memory filesystem.
+//!
+//! File contents live only in the page cache. Each inode owns its cache, and
+//! [`CachedFile`](crate::highlevel::CachedFile) picks it up when the file is
+//! opened instead of allocating a new one.
+
+mod data;
+mod fs;
+mod inode;
+
+pub use fs::TmpFilesystem;
+pub use inode::Inode;
+pub(crate) use inode::page_cache;
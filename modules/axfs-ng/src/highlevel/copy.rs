@@ -0,0 +1,118 @@
+//! In-kernel copies between files.
+
+use alloc::vec;
+
+use axfs_ng_vfs::{VfsError, VfsResult};
+
+use super::{File, FileFlags, mount::same_location};
+
+/// Size of the buffer data is copied or sent through.
+const COPY_CHUNK: usize = 0x10000;
+
+/// Copies up to `len` bytes from `src` to `dst`, like `copy_file_range`.
+///
+/// Each file is accessed at its offset if given, or else at its position,
+/// which is moved past the bytes copied. Data is read from the page cache of
+/// `src` and written to that of `dst` through a kernel buffer, without ever
+/// reaching user memory.
+///
+/// Returns the number of bytes copied, which is less than `len` only if the
+/// end of `src` is reached, or if an error happens after some bytes were
+/// copied.
+pub fn copy_file_range(
+    src: &File,
+    src_offset: Option<&mut u64>,
+    dst: &File,
+    dst_offset: Option<&mut u64>,
+    len: usize,
+) -> VfsResult<usize> {
+    let src_backend = src.access(FileFlags::READ)?;
+    let dst_backend = dst.access(FileFlags::WRITE)?;
+    if dst.flags().contains(FileFlags::APPEND) {
+        return Err(VfsError::BadFileDescriptor);
+    }
+    if src.location().is_dir() || dst.location().is_dir() {
+        return Err(VfsError::IsADirectory);
+    }
+    let same_file = same_location(src.location(), dst.location());
+
+    src.with_offset(src_offset, |src_start| {
+        dst.with_offset(dst_offset, |dst_start| {
+            let len_u64 = len as u64;
+            if same_file
+                && src_start < dst_start.saturating_add(len_u64)
+                && dst_start < src_start.saturating_add(len_u64)
+            {
+                return Err(VfsError::InvalidInput);
+            }
+
+            let mut buf = vec![0; len.min(COPY_CHUNK)];
+            let mut copied = 0;
+            while copied < len {
+                let chunk = (len - copied).min(buf.len());
+                let result = src_backend
+                    .read_at(&mut &mut buf[..chunk], src_start + copied as u64)
+                    .and_then(|read| {
+                        let mut data = &buf[..read];
+                        dst_backend.write_at(&mut data, dst_start + copied as u64)?;
+                        Ok((read, read - data.len()))
+                    });
+                match result {
+                    Ok((0, _)) => break,
+                    Ok((read, written)) => {
+                        copied += written;
+                        if written < read {
+                            break;
+                        }
+                    }
+                    Err(_) if copied > 0 => break,
+                    Err(err) => return Err(err),
+                }
+            }
+            Ok(copied)
+        })
+    })
+}
+
+/// Sends up to `count` bytes of `file` through `send`, like `sendfile`.
+///
+/// The file is read at `offset` if given, or else at its position, which is
+/// moved past the bytes sent. `send` consumes bytes from the front of the
+/// slice it is given and returns how many; returning 0 or an error stops the
+/// transfer, which is how a full non-blocking socket ends it early.
+///
+/// Returns the number of bytes sent, which is less than `count` only if the
+/// end of the file is reached, or if sending stops after some bytes were
+/// sent.
+pub fn send_file(
+    file: &File,
+    offset: Option<&mut u64>,
+    count: usize,
+    mut send: impl FnMut(&mut &[u8]) -> VfsResult<usize>,
+) -> VfsResult<usize> {
+    let backend = file.access(FileFlags::READ)?;
+    let mut buf = vec![0; count.min(COPY_CHUNK)];
+    file.with_offset(offset, |start| {
+        let mut sent = 0;
+        while sent < count {
+            let chunk = (count - sent).min(buf.len());
+            let read = match backend.read_at(&mut &mut buf[..chunk], start + sent as u64) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(_) if sent > 0 => break,
+                Err(err) => return Err(err),
+            };
+            let mut data = &buf[..read];
+            while !data.is_empty() {
+                let remaining = data.len();
+                match send(&mut data) {
+                    Ok(0) => return Ok(sent),
+                    Ok(_) => sent += remaining - data.len(),
+                    Err(_) if sent > 0 => return Ok(sent),
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+        Ok(sent)
+    })
+}
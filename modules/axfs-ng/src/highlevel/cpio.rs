@@ -0,0 +1,154 @@
+//! Unpacking of `newc` cpio archives, the format of Linux initramfs images.
+
+use alloc::{collections::BTreeMap, format, string::String};
+use core::{str, time::Duration};
+
+use axfs_ng_vfs::{MetadataUpdate, NodePermission, NodeType, VfsError, VfsResult};
+use log::debug;
+
+use super::FsContext;
+
+const HEADER_LEN: usize = 110;
+const TRAILER: &str = "TRAILER!!!";
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+/// A parsed `newc` header, see `man 5 cpio`.
+struct Header {
+    ino: u32,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    nlink: u32,
+    mtime: u32,
+    file_size: usize,
+    name_size: usize,
+}
+
+impl Header {
+    fn parse(data: &[u8]) -> VfsResult<Self> {
+        let header = data.get(..HEADER_LEN).ok_or(VfsError::InvalidData)?;
+        // `070702` is the same format with a checksum, which we don't verify.
+        if &header[..6] != b"070701" && &header[..6] != b"070702" {
+            return Err(VfsError::InvalidData);
+        }
+        let field = |index: usize| -> VfsResult<u32> {
+            let start = 6 + index * 8;
+            str::from_utf8(&header[start..start + 8])
+                .ok()
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .ok_or(VfsError::InvalidData)
+        };
+        Ok(Self {
+            ino: field(0)?,
+            mode: field(1)?,
+            uid: field(2)?,
+            gid: field(3)?,
+            nlink: field(4)?,
+            mtime: field(5)?,
+            file_size: field(6)? as usize,
+            name_size: field(11)? as usize,
+        })
+    }
+}
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+/// Unpacks a `newc` cpio archive into the root directory of `cx`.
+///
+/// Directories, regular files, hard links and symbolic links are restored
+/// along with their permissions, owners and modification times. Device nodes,
+/// FIFOs and sockets are skipped, since devices are provided by devfs instead.
+///
+/// Entries must come after their parent directories, as produced by
+/// `find . | cpio -o -H newc`. Existing directories are merged and existing
+/// files are overwritten.
+pub fn unpack_cpio(cx: &FsContext, data: &[u8]) -> VfsResult<()> {
+    // Maps inode numbers of hard-linked files to the first path unpacked.
+    let mut links: BTreeMap<u32, String> = BTreeMap::new();
+    let mut offset = 0;
+    loop {
+        let header = Header::parse(data.get(offset..).ok_or(VfsError::InvalidData)?)?;
+        let name_start = offset + HEADER_LEN;
+        let name = data
+            .get(name_start..name_start + header.name_size)
+            .and_then(|name| name.strip_suffix(b"\0"))
+            .and_then(|name| str::from_utf8(name).ok())
+            .ok_or(VfsError::InvalidData)?;
+        let data_start = align4(name_start + header.name_size);
+        let content = data
+            .get(data_start..data_start + header.file_size)
+            .ok_or(VfsError::InvalidData)?;
+        offset = align4(data_start + header.file_size);
+
+        if name == TRAILER {
+            return Ok(());
+        }
+        // The root directory itself is usually stored as `.`.
+        let path = name
+            .strip_prefix('.')
+            .filter(|rest| rest.is_empty() || rest.starts_with('/'))
+            .unwrap_or(name)
+            .trim_start_matches('/');
+        let path = format!("/{path}");
+        let mode = NodePermission::from_bits_truncate((header.mode & 0o7777) as _);
+
+        let location = match header.mode & S_IFMT {
+            S_IFDIR => match cx.resolve_no_follow(&path) {
+                Ok(dir) => {
+                    dir.check_is_dir()?;
+                    dir
+                }
+                Err(VfsError::NotFound) => cx.create_dir(&path, mode)?,
+                Err(err) => return Err(err),
+            },
+            S_IFREG => {
+                let location = match links.get(&header.ino) {
+                    // newc stores the data of hard links with the last one.
+                    Some(first) if header.nlink > 1 => {
+                        let _ = cx.remove_file(&path);
+                        cx.link(first, &path)?
+                    }
+                    _ => {
+                        let (dir, name) = cx.resolve_nonexistent(path.as_ref())?;
+                        let _ = dir.unlink(name, false);
+                        let location = dir.create(name, NodeType::RegularFile, mode)?;
+                        if header.nlink > 1 {
+                            links.insert(header.ino, path.clone());
+                        }
+                        location
+                    }
+                };
+                if !content.is_empty() {
+                    let file = location.entry().as_file()?;
+                    file.set_len(0)?;
+                    let mut written = 0;
+                    while written < content.len() {
+                        written += file.write_at(&content[written..], written as u64)?;
+                    }
+                }
+                location
+            }
+            S_IFLNK => {
+                let target = str::from_utf8(content).map_err(|_| VfsError::InvalidData)?;
+                let _ = cx.remove_file(&path);
+                cx.symlink(target, &path)?
+            }
+            _ => {
+                debug!("cpio: skipping special file {path}");
+                continue;
+            }
+        };
+
+        let mut update = MetadataUpdate::default();
+        update.mode = Some(mode);
+        update.owner = Some((header.uid, header.gid));
+        update.mtime = Some(Duration::from_secs(header.mtime as u64));
+        location.update_metadata(update)?;
+    }
+}
@@ -0,0 +1,128 @@
+//! Credentials of a filesystem context and Unix permission checks.
+
+use alloc::vec::Vec;
+
+use axfs_ng_vfs::{Metadata, NodeType, VfsError, VfsResult};
+
+/// Mode bit of a directory restricting the removal and renaming of its
+/// entries to their owner.
+const STICKY: u16 = 0o1000;
+/// Mode bit of a program running as its owner.
+const SET_UID: u16 = 0o4000;
+/// Mode bit of a program running as its group.
+const SET_GID: u16 = 0o2000;
+
+bitflags::bitflags! {
+    /// Kinds of access to a file, with the values of the permission bits.
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    pub struct Access: u16 {
+        const READ = 4;
+        const WRITE = 2;
+        /// Executing a file, or searching a directory.
+        const EXECUTE = 1;
+    }
+}
+
+/// User and groups that a [`FsContext`](super::FsContext) acts as.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub uid: u32,
+    pub gid: u32,
+    /// Supplementary groups.
+    pub groups: Vec<u32>,
+}
+
+impl Credentials {
+    pub fn new(uid: u32, gid: u32, groups: Vec<u32>) -> Self {
+        Self { uid, gid, groups }
+    }
+
+    /// Returns the credentials of the superuser, which pass all checks.
+    pub const fn root() -> Self {
+        Self {
+            uid: 0,
+            gid: 0,
+            groups: Vec::new(),
+        }
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.uid == 0
+    }
+
+    /// Returns whether `gid` is the group or one of the supplementary groups.
+    pub fn in_group(&self, gid: u32) -> bool {
+        self.gid == gid || self.groups.contains(&gid)
+    }
+
+    /// Checks that `access` to a file with `metadata` is granted, failing
+    /// with [`VfsError::PermissionDenied`] otherwise.
+    ///
+    /// The superuser may do anything, except execute a file no one may.
+    pub fn check_access(&self, metadata: &Metadata, access: Access) -> VfsResult<()> {
+        let mode = metadata.mode.bits();
+        let granted = if self.is_root() {
+            if access.contains(Access::EXECUTE)
+                && metadata.node_type != NodeType::Directory
+                && mode & 0o111 == 0
+            {
+                Access::empty()
+            } else {
+                Access::all()
+            }
+        } else if self.uid == metadata.uid {
+            Access::from_bits_truncate(mode >> 6)
+        } else if self.in_group(metadata.gid) {
+            Access::from_bits_truncate(mode >> 3)
+        } else {
+            Access::from_bits_truncate(mode)
+        };
+        if granted.contains(access) {
+            Ok(())
+        } else {
+            Err(VfsError::PermissionDenied)
+        }
+    }
+
+    /// Checks that an entry with `metadata` may be removed from, or renamed
+    /// within, a directory with `dir_metadata`, as restricted by its sticky
+    /// bit.
+    pub fn check_sticky(&self, dir_metadata: &Metadata, metadata: &Metadata) -> VfsResult<()> {
+        if dir_metadata.mode.bits() & STICKY != 0
+            && !self.is_root()
+            && self.uid != metadata.uid
+            && self.uid != dir_metadata.uid
+        {
+            return Err(VfsError::OperationNotPermitted);
+        }
+        Ok(())
+    }
+
+    /// Returns the credentials that a program with `metadata` runs with when
+    /// executed with these, as changed by its set-user-ID and set-group-ID
+    /// bits.
+    pub fn exec(&self, metadata: &Metadata) -> Self {
+        let mode = metadata.mode.bits();
+        let mut credentials = self.clone();
+        if mode & SET_UID != 0 {
+            credentials.uid = metadata.uid;
+        }
+        // Without group execute permission, the bit doesn't apply.
+        if mode & SET_GID != 0 && mode & 0o010 != 0 {
+            credentials.gid = metadata.gid;
+        }
+        credentials
+    }
+
+    /// Returns the owner to give to the files created with these
+    /// credentials, or `None` for the default one of the filesystem.
+    pub(crate) fn owner(&self) -> Option<(u32, u32)> {
+        (self.uid != 0 || self.gid != 0).then_some((self.uid, self.gid))
+    }
+}
+
+impl Default for Credentials {
+    fn default() -> Self {
+        Self::root()
+    }
+}
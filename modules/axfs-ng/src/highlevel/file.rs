@@ -1,11 +1,18 @@
 use alloc::{
     boxed::Box,
     sync::{Arc, Weak},
+    vec,
     vec::Vec,
 };
 #[cfg(feature = "times")]
-use core::sync::atomic::{AtomicU8, Ordering};
-use core::{num::NonZeroUsize, ops::Range, task::Context};
+use core::sync::atomic::AtomicU8;
+use core::{
+    num::NonZeroUsize,
+    ops::Range,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    task::Context,
+    time::Duration,
+};
 
 use allocator::AllocError;
 use axalloc::{UsageKind, global_allocator};
@@ -19,7 +26,13 @@ use log::warn;
 use lru::LruCache;
 use spin::{Mutex, RwLock};
 
-use super::FsContext;
+use super::{
+    Access, AllocateMode, FileLocks, FsContext, LockType, MountFlags, RecordLock, WatchMask,
+    mount::{check_writable, mount_flags},
+    reclaim, sparse,
+    watch::notify_entry,
+    writeback,
+};
 
 bitflags::bitflags! {
     #[derive(Debug, Clone, Copy)]
@@ -29,6 +42,7 @@ bitflags::bitflags! {
         const EXECUTE = 4;
         const APPEND = 8;
         const PATH = 16;
+        const DIRECT = 32;
     }
 }
 
@@ -68,6 +82,7 @@ pub struct OpenOptions {
     read: bool,
     write: bool,
     append: bool,
+    execute: bool,
     truncate: bool,
     create: bool,
     create_new: bool,
@@ -89,6 +104,7 @@ impl OpenOptions {
             read: false,
             write: false,
             append: false,
+            execute: false,
             truncate: false,
             create: false,
             create_new: false,
@@ -121,6 +137,13 @@ impl OpenOptions {
         self
     }
 
+    /// Sets the option for execute access, denied on filesystems mounted with
+    /// [`MountFlags::NO_EXEC`].
+    pub fn execute(&mut self, execute: bool) -> &mut Self {
+        self.execute = execute;
+        self
+    }
+
     /// Sets the option for truncating a previous file.
     pub fn truncate(&mut self, truncate: bool) -> &mut Self {
         self.truncate = truncate;
@@ -151,7 +174,11 @@ impl OpenOptions {
         self
     }
 
-    /// Sets the option to open the file with direct I/O.\
+    /// Sets the option to open the file with direct I/O.
+    ///
+    /// Regular files on disk are then read and written bypassing the page
+    /// cache, at offsets, lengths and buffer addresses aligned to
+    /// [`DIRECT_IO_ALIGN`].
     pub fn direct(&mut self, direct: bool) -> &mut Self {
         self.direct = direct;
         self
@@ -192,8 +219,20 @@ impl OpenOptions {
             }
             loc.check_is_dir()?;
         }
+        // Devices and other special files stay writable on read-only mounts.
+        let on_storage = matches!(
+            loc.node_type(),
+            NodeType::RegularFile | NodeType::Directory | NodeType::Symlink
+        );
+        if on_storage && (flags.contains(FileFlags::WRITE) || self.truncate) {
+            check_writable(&loc)?;
+        }
+        if self.execute && mount_flags(&loc).contains(MountFlags::NO_EXEC) {
+            return Err(VfsError::PermissionDenied);
+        }
         if self.truncate {
             loc.entry().as_file()?.set_len(0)?;
+            notify_entry(&loc, WatchMask::MODIFY);
         }
 
         Ok(if loc.is_dir() {
@@ -209,8 +248,17 @@ impl OpenOptions {
                 || self.path
                 || self.direct
                 || loc.flags().contains(NodeFlags::NON_CACHEABLE);
+            // The page cache of a tmpfs file is its storage, so direct I/O
+            // there is just uncached.
+            let direct_io = self.direct
+                && !self.path
+                && !non_cacheable_type
+                && !loc.flags().contains(NodeFlags::NON_CACHEABLE)
+                && loc.filesystem().name() != "tmpfs";
             let backend = if !direct || loc.flags().contains(NodeFlags::ALWAYS_CACHE) {
                 FileBackend::new_cached(loc)
+            } else if direct_io {
+                FileBackend::new_direct_io(loc)
             } else {
                 FileBackend::new_direct(loc)
             };
@@ -230,34 +278,85 @@ impl OpenOptions {
             return Err(VfsError::InvalidInput);
         }
 
-        let loc = match context.resolve_parent(path.as_ref()) {
+        let (loc, created) = match context.resolve_parent(path.as_ref()) {
             Ok((parent, name)) => {
-                let mut loc = parent.open_file(
-                    &name,
-                    &axfs_ng_vfs::OpenOptions {
-                        create: self.create,
-                        create_new: self.create_new,
-                        node_type: self.node_type,
-                        permission: NodePermission::from_bits_truncate(self.mode as _),
-                        user: self.user,
-                    },
-                )?;
+                context.check_access(&parent, Access::EXECUTE)?;
+                let (mut loc, created) = self.open_entry(context, &parent, &name)?;
+                if created {
+                    notify_entry(&loc, WatchMask::CREATE);
+                }
                 if !self.no_follow {
                     loc = context
                         .with_current_dir(parent)?
                         .try_resolve_symlink(loc, &mut 0)?;
                 }
-                loc
+                (loc, created)
             }
             Err(VfsError::InvalidInput) => {
                 // root directory
-                context.root_dir().clone()
+                (context.root_dir().clone(), false)
             }
             Err(err) => return Err(err),
         };
+        if !created {
+            context.check_access(&loc, self.required_access())?;
+        }
         self._open(loc)
     }
 
+    /// Opens `name` in `parent`, creating it if told to, and returns whether
+    /// it was created.
+    ///
+    /// Nodes are created exclusively, so that one created by someone else in
+    /// the meantime is opened as an existing one, with its access checked.
+    fn open_entry(
+        &self,
+        context: &FsContext,
+        parent: &Location,
+        name: &str,
+    ) -> VfsResult<(Location, bool)> {
+        let options = axfs_ng_vfs::OpenOptions {
+            create: true,
+            create_new: true,
+            node_type: self.node_type,
+            permission: NodePermission::from_bits_truncate((self.mode & !context.umask()) as _),
+            user: self.user.or_else(|| context.credentials().owner()),
+        };
+        loop {
+            match parent.lookup_no_follow(name) {
+                Ok(_) if self.create_new => return Err(VfsError::AlreadyExists),
+                Ok(loc) => return Ok((loc, false)),
+                Err(VfsError::NotFound) if self.create || self.create_new => {}
+                Err(err) => return Err(err),
+            }
+            check_writable(parent)?;
+            context.check_access(parent, Access::WRITE)?;
+            match parent.open_file(name, &options) {
+                Ok(loc) => return Ok((loc, true)),
+                Err(VfsError::AlreadyExists) if !self.create_new => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Returns the access to an existing file that opening it requires.
+    fn required_access(&self) -> Access {
+        let mut access = Access::empty();
+        if self.path {
+            return access;
+        }
+        if self.read {
+            access |= Access::READ;
+        }
+        if self.write || self.append || self.truncate {
+            access |= Access::WRITE;
+        }
+        if self.execute {
+            access |= Access::EXECUTE;
+        }
+        access
+    }
+
     pub(crate) fn to_flags(&self) -> VfsResult<FileFlags> {
         Ok(match (self.read, self.write, self.append) {
             (true, false, false) => FileFlags::READ,
@@ -270,6 +369,14 @@ impl OpenOptions {
             FileFlags::PATH
         } else {
             FileFlags::empty()
+        } | if self.direct {
+            FileFlags::DIRECT
+        } else {
+            FileFlags::empty()
+        } | if self.execute {
+            FileFlags::EXECUTE
+        } else {
+            FileFlags::empty()
         })
     }
 
@@ -300,16 +407,88 @@ impl Default for OpenOptions {
     }
 }
 
-const PAGE_SIZE: usize = 4096;
+pub(crate) const PAGE_SIZE: usize = 4096;
+/// Alignment of the offsets, lengths and buffers of direct I/O.
+pub const DIRECT_IO_ALIGN: usize = 512;
+
+/// Returns the numbers of the pages overlapping `range`.
+fn page_range(range: &Range<u64>) -> Range<u32> {
+    (range.start / PAGE_SIZE as u64) as u32..range.end.div_ceil(PAGE_SIZE as u64) as u32
+}
+
+/// Number of pages read ahead once a file is first read sequentially.
+const READAHEAD_INITIAL: u32 = 4;
+/// Maximum number of pages read ahead.
+const READAHEAD_MAX: u32 = 32;
+
+/// Expected access pattern of a file, like the advice of `posix_fadvise`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FileAdvice {
+    /// Read ahead once sequential access is detected.
+    #[default]
+    Normal,
+    /// Always read ahead as much as possible.
+    Sequential,
+    /// Never read ahead.
+    Random,
+}
+
+/// Readahead state of an open file.
+#[derive(Default)]
+struct Readahead {
+    advice: FileAdvice,
+    /// Page following the last read.
+    next: u32,
+    /// Number of pages read ahead of the last read.
+    window: u32,
+}
+
+impl Readahead {
+    /// Records a read of `pages`, and returns how many pages to read ahead
+    /// of it.
+    ///
+    /// The window doubles on each sequential read, up to `max`, and is reset
+    /// on any other read.
+    fn on_read(&mut self, pages: Range<u32>, max: u32) -> u32 {
+        // Reads not aligned to pages start again from the last page read.
+        let sequential = pages.start == self.next || pages.start + 1 == self.next;
+        self.window = match self.advice {
+            FileAdvice::Normal if sequential => (self.window * 2).max(READAHEAD_INITIAL),
+            FileAdvice::Normal | FileAdvice::Random => 0,
+            FileAdvice::Sequential => max,
+        }
+        .min(max);
+        self.next = pages.end;
+        self.window
+    }
+}
 
 #[derive(Debug)]
 pub struct PageCache {
     addr: VirtAddr,
-    dirty: bool,
+    pub(crate) dirty: bool,
+    /// When the page became dirty, in monotonic time.
+    dirtied_at: Duration,
+    /// Tick of the last access, to order pages for reclaim.
+    pub(crate) accessed: u64,
+    /// Whether the page is the storage of an in-memory file.
+    in_memory: bool,
 }
 
 impl PageCache {
-    fn new() -> VfsResult<Self> {
+    /// Allocates a page of a file backed by a disk, which counts toward the
+    /// budget of the page cache.
+    pub(crate) fn new() -> VfsResult<Self> {
+        reclaim::before_alloc();
+        Self::alloc(false)
+    }
+
+    /// Allocates a page of an in-memory file, which can't be reclaimed.
+    pub(crate) fn new_in_memory() -> VfsResult<Self> {
+        Self::alloc(true)
+    }
+
+    fn alloc(in_memory: bool) -> VfsResult<Self> {
         let addr = global_allocator()
             .alloc_pages(1, PAGE_SIZE, UsageKind::PageCache)
             .map_err(|err| {
@@ -319,9 +498,15 @@ impl PageCache {
                     _ => VfsError::InvalidInput,
                 }
             })?;
+        if in_memory {
+            reclaim::account_in_memory(true);
+        }
         Ok(Self {
             addr: addr.into(),
             dirty: false,
+            dirtied_at: Duration::ZERO,
+            accessed: reclaim::tick(),
+            in_memory,
         })
     }
 
@@ -329,8 +514,23 @@ impl PageCache {
         virt_to_phys(self.addr)
     }
 
+    fn touch(&mut self) {
+        self.accessed = reclaim::tick();
+    }
+
     pub fn mark_dirty(&mut self) {
-        self.dirty = true;
+        if !self.dirty {
+            self.dirty = true;
+            self.dirtied_at = axhal::time::monotonic_time();
+            writeback::account_dirty(true);
+        }
+    }
+
+    fn mark_clean(&mut self) {
+        if self.dirty {
+            self.dirty = false;
+            writeback::account_dirty(false);
+        }
     }
 
     pub fn data(&mut self) -> &mut [u8] {
@@ -342,21 +542,28 @@ impl Drop for PageCache {
     fn drop(&mut self) {
         if self.dirty {
             warn!("dirty page dropped without flushing");
+            self.mark_clean();
+        }
+        if self.in_memory {
+            reclaim::account_in_memory(false);
         }
         global_allocator().dealloc_pages(self.addr.as_usize(), 1, UsageKind::PageCache);
     }
 }
 
-struct EvictListener {
+pub(crate) struct EvictListener {
     listener: Box<dyn Fn(u32, &PageCache) + Send + Sync>,
     link: LinkedListAtomicLink,
 }
 
-intrusive_adapter!(EvictListenerAdapter = Box<EvictListener>: EvictListener { link: LinkedListAtomicLink });
+intrusive_adapter!(pub(crate) EvictListenerAdapter = Box<EvictListener>: EvictListener { link: LinkedListAtomicLink });
 
-struct CachedFileShared {
-    page_cache: Mutex<LruCache<u32, PageCache>>,
-    evict_listeners: Mutex<LinkedList<EvictListenerAdapter>>,
+pub(crate) struct CachedFileShared {
+    pub(crate) page_cache: Mutex<LruCache<u32, PageCache>>,
+    pub(crate) evict_listeners: Mutex<LinkedList<EvictListenerAdapter>>,
+    /// Whether the file is tracked for write-back, only changed with the page
+    /// cache locked.
+    tracked: AtomicBool,
 }
 
 impl CachedFileShared {
@@ -364,6 +571,7 @@ impl CachedFileShared {
         Self {
             page_cache: Mutex::new(LruCache::new(NonZeroUsize::new(64).unwrap())),
             evict_listeners: Mutex::new(LinkedList::default()),
+            tracked: AtomicBool::new(false),
         }
     }
 
@@ -371,17 +579,19 @@ impl CachedFileShared {
         Self {
             page_cache: Mutex::new(LruCache::unbounded()),
             evict_listeners: Mutex::new(LinkedList::default()),
+            tracked: AtomicBool::new(false),
         }
     }
 }
 
 pub struct CachedFile {
     inner: Location,
-    shared: Arc<CachedFileShared>,
+    pub(crate) shared: Arc<CachedFileShared>,
     in_memory: bool,
     /// Only one thread can append to the file at a time, while multiple writers
     /// are permitted.
     append_lock: RwLock<()>,
+    readahead: Mutex<Readahead>,
 }
 
 impl Clone for CachedFile {
@@ -391,6 +601,7 @@ impl Clone for CachedFile {
             shared: self.shared.clone(),
             in_memory: self.in_memory,
             append_lock: RwLock::new(()),
+            readahead: Mutex::default(),
         }
     }
 }
@@ -418,10 +629,14 @@ impl CachedFile {
             shared
         } else {
             let (shared, user_data) = if in_memory {
-                let shared = Arc::new(CachedFileShared::new_unbounded());
+                // The page cache of a tmpfs file is its only storage, which is
+                // owned by the inode so that it outlives this location.
+                let shared = crate::fs::tmpfs::page_cache(&location)
+                    .unwrap_or_else(|| Arc::new(CachedFileShared::new_unbounded()));
                 (shared.clone(), FileUserData::Strong(shared))
             } else {
                 let shared = Arc::new(CachedFileShared::new());
+                reclaim::register(&shared);
                 let user_data = FileUserData::Weak(Arc::downgrade(&shared));
                 (shared, user_data)
             };
@@ -435,6 +650,26 @@ impl CachedFile {
             shared,
             in_memory,
             append_lock: RwLock::new(()),
+            readahead: Mutex::default(),
+        }
+    }
+
+    /// Returns the cached file at `location` if it has a page cache, which
+    /// may hold some of its pages.
+    pub(crate) fn existing(location: &Location) -> Option<Self> {
+        let shared = location.user_data().get::<FileUserData>()?.get()?;
+        Some(Self::from_shared(location.clone(), shared))
+    }
+
+    /// Returns a handle to the cached file at `location` whose shared state
+    /// is `shared`.
+    pub(crate) fn from_shared(location: Location, shared: Arc<CachedFileShared>) -> Self {
+        Self {
+            in_memory: location.filesystem().name() == "tmpfs",
+            inner: location,
+            shared,
+            append_lock: RwLock::new(()),
+            readahead: Mutex::default(),
         }
     }
 
@@ -446,6 +681,13 @@ impl CachedFile {
         self.in_memory
     }
 
+    /// Declares how the file is going to be read through this handle.
+    pub fn advise(&self, advice: FileAdvice) {
+        let mut readahead = self.readahead.lock();
+        readahead.advice = advice;
+        readahead.window = 0;
+    }
+
     pub fn add_evict_listener<F>(&self, listener: F) -> usize
     where
         F: Fn(u32, &PageCache) + Send + Sync + 'static,
@@ -465,15 +707,71 @@ impl CachedFile {
         cursor.remove();
     }
 
+    fn write_page(file: &FileNode, pn: u32, page: &mut PageCache) -> VfsResult<()> {
+        let page_start = pn as u64 * PAGE_SIZE as u64;
+        let len = (file.len()? - page_start).min(PAGE_SIZE as u64) as usize;
+        file.write_at(&page.data()[..len], page_start)?;
+        page.mark_clean();
+        Ok(())
+    }
+
     fn evict_cache(&self, file: &FileNode, pn: u32, page: &mut PageCache) -> VfsResult<()> {
         for listener in self.shared.evict_listeners.lock().iter() {
             (listener.listener)(pn, &page);
         }
         if page.dirty {
-            let page_start = pn as u64 * PAGE_SIZE as u64;
-            let len = (file.len()? - page_start).min(PAGE_SIZE as u64) as usize;
-            file.write_at(&page.data()[..len], page_start)?;
-            page.dirty = false;
+            Self::write_page(file, pn, page)?;
+        }
+        Ok(())
+    }
+
+    /// Tracks the file for write-back, if one of its pages just became
+    /// dirty. `cache` is its locked page cache.
+    fn track_dirty(&self, cache: &LruCache<u32, PageCache>, pn: u32) {
+        if cache.peek(&pn).is_some_and(|page| page.dirty)
+            && !self.shared.tracked.swap(true, Ordering::AcqRel)
+        {
+            writeback::track(self);
+        }
+    }
+
+    /// Writes back the dirty pages, keeping them cached. If `before` is
+    /// given, only pages dirtied before it are written.
+    pub(crate) fn write_back(&self, before: Option<Duration>) -> VfsResult<()> {
+        self.write_back_if(|_, page| before.is_none_or(|it| page.dirtied_at <= it))
+    }
+
+    /// Writes back the dirty pages overlapping `range`, keeping them cached.
+    pub(crate) fn write_back_range(&self, range: &Range<u64>) -> VfsResult<()> {
+        let pages = page_range(range);
+        self.write_back_if(|pn, _| pages.contains(&pn))
+    }
+
+    fn write_back_if(&self, filter: impl Fn(u32, &PageCache) -> bool) -> VfsResult<()> {
+        if self.in_memory {
+            return Ok(());
+        }
+        let file = self.inner.entry().as_file()?;
+        let mut guard = self.shared.page_cache.lock();
+        let mut pages = guard
+            .iter()
+            .filter(|(pn, page)| page.dirty && filter(**pn, page))
+            .map(|(pn, _)| *pn)
+            .collect::<Vec<_>>();
+        pages.sort_unstable();
+        for pn in pages {
+            let page = guard.peek_mut(&pn).unwrap();
+            // Drop the mappings of the page, so that writing to it through
+            // them marks it dirty again.
+            for listener in self.shared.evict_listeners.lock().iter() {
+                (listener.listener)(pn, page);
+            }
+            Self::write_page(file, pn, page)?;
+        }
+
+        if guard.iter().all(|(_, page)| !page.dirty) {
+            self.shared.tracked.store(false, Ordering::Release);
+            writeback::untrack(&self.shared);
         }
         Ok(())
     }
@@ -487,7 +785,9 @@ impl CachedFile {
         // TODO: Matching the result of `get_mut` confuses compiler. See
         // https://users.rust-lang.org/t/return-do-not-release-mutable-borrow/55757.
         if cache.contains(&pn) {
-            return Ok((cache.get_mut(&pn).unwrap(), None));
+            let page = cache.get_mut(&pn).unwrap();
+            page.touch();
+            return Ok((page, None));
         }
         let mut evicted = None;
         if cache.len() == cache.cap().get() {
@@ -499,18 +799,62 @@ impl CachedFile {
         }
 
         // Page not in cache, read it
-        let mut page = PageCache::new()?;
-        if self.in_memory {
+        let page = if self.in_memory {
+            let mut page = PageCache::new_in_memory()?;
             page.data().fill(0);
+            page
         } else {
+            let mut page = PageCache::new()?;
             file.read_at(page.data(), pn as u64 * PAGE_SIZE as u64)?;
-        }
+            page
+        };
         cache.put(pn, page);
         Ok((cache.get_mut(&pn).unwrap(), evicted))
     }
 
+    /// Brings the pages of `pages` into the cache with a single read, up to
+    /// the first one already cached.
+    fn read_pages(
+        &self,
+        file: &FileNode,
+        cache: &mut LruCache<u32, PageCache>,
+        pages: Range<u32>,
+    ) -> VfsResult<()> {
+        let count = pages.clone().take_while(|pn| !cache.contains(pn)).count();
+        if count == 0 {
+            return Ok(());
+        }
+        let mut buf = vec![0; count * PAGE_SIZE];
+        let mut read = 0;
+        while read < buf.len() {
+            let offset = pages.start as u64 * PAGE_SIZE as u64 + read as u64;
+            match file.read_at(&mut buf[read..], offset)? {
+                0 => break,
+                n => read += n,
+            }
+        }
+
+        for (pn, data) in pages.zip(buf.chunks(PAGE_SIZE)) {
+            if cache.len() == cache.cap().get() {
+                if let Some((pn, mut page)) = cache.pop_lru() {
+                    self.evict_cache(file, pn, &mut page)?;
+                }
+            }
+            let mut page = PageCache::new()?;
+            page.data().copy_from_slice(data);
+            cache.put(pn, page);
+        }
+        Ok(())
+    }
+
     pub fn with_page<R>(&self, pn: u32, f: impl FnOnce(Option<&mut PageCache>) -> R) -> R {
-        f(self.shared.page_cache.lock().get_mut(&pn))
+        let mut guard = self.shared.page_cache.lock();
+        let result = f(guard.get_mut(&pn).map(|page| {
+            page.touch();
+            page
+        }));
+        self.track_dirty(&guard, pn);
+        result
     }
 
     pub fn with_page_or_insert<R>(
@@ -523,9 +867,15 @@ impl CachedFile {
         f(page, evicted)
     }
 
+    /// Runs `page_each` on the pages of `range` in order, bringing them into
+    /// the cache first.
+    ///
+    /// Missing pages are read in batches, which can extend `readahead` pages
+    /// past the end of the range.
     fn with_pages<T>(
         &self,
         range: Range<u64>,
+        readahead: u32,
         page_initial: impl FnOnce(&FileNode) -> VfsResult<T>,
         mut page_each: impl FnMut(T, &mut PageCache, Range<usize>) -> VfsResult<T>,
     ) -> VfsResult<T> {
@@ -534,10 +884,21 @@ impl CachedFile {
         let start_page = (range.start / PAGE_SIZE as u64) as u32;
         let end_page = range.end.div_ceil(PAGE_SIZE as u64) as u32;
         let mut page_offset = (range.start % PAGE_SIZE as u64) as usize;
+        let batch_end = if readahead > 0 {
+            let file_pages = file.len()?.div_ceil(PAGE_SIZE as u64) as u32;
+            (end_page + readahead).min(file_pages)
+        } else {
+            0
+        };
         for pn in start_page..end_page {
             let page_start = pn as u64 * PAGE_SIZE as u64;
 
             let mut guard = self.shared.page_cache.lock();
+            if pn < batch_end && !guard.contains(&pn) {
+                // Keep batches small enough not to evict their own pages.
+                let batch = (guard.cap().get() as u32 / 2).max(1);
+                self.read_pages(file, &mut guard, pn..batch_end.min(pn + batch))?;
+            }
             let page = self.page_or_insert(file, &mut guard, pn)?.0;
 
             initial = page_each(
@@ -545,6 +906,7 @@ impl CachedFile {
                 page,
                 page_offset..(range.end - page_start).min(PAGE_SIZE as u64) as usize,
             )?;
+            self.track_dirty(&guard, pn);
             page_offset = 0;
         }
 
@@ -557,8 +919,16 @@ impl CachedFile {
         if end <= offset {
             return Ok(0);
         }
+        let readahead = if self.in_memory {
+            0
+        } else {
+            let pages = (offset / PAGE_SIZE as u64) as u32..end.div_ceil(PAGE_SIZE as u64) as u32;
+            let max = (self.shared.page_cache.lock().cap().get() as u32 / 2).min(READAHEAD_MAX);
+            self.readahead.lock().on_read(pages, max)
+        };
         self.with_pages(
             offset..end,
+            readahead,
             |_| Ok(0),
             |read, page, range| {
                 let len = range.end - range.start;
@@ -572,6 +942,7 @@ impl CachedFile {
         let end = offset + buf.remaining() as u64;
         self.with_pages(
             offset..end,
+            0,
             |file| {
                 if end > file.len()? {
                     file.set_len(end)?;
@@ -582,19 +953,29 @@ impl CachedFile {
                 let len = range.end - range.start;
                 buf.read(&mut page.data()[range.start..range.end])?;
                 if !self.in_memory {
-                    page.dirty = true;
+                    page.mark_dirty();
                 }
                 Ok(written + len)
             },
         )
+        .inspect(|_| {
+            if writeback::over_dirty_limit() {
+                // Throttle the writer by making it write back its own pages.
+                if let Err(err) = self.write_back(None) {
+                    warn!("Failed to write back dirty pages: {err:?}");
+                }
+            }
+        })
     }
 
     pub fn write_at(&self, buf: &mut impl Buf, offset: u64) -> VfsResult<usize> {
+        check_writable(&self.inner)?;
         let _guard = self.append_lock.read();
         self.write_at_locked(buf, offset)
     }
 
     pub fn append(&self, buf: &mut impl Buf) -> VfsResult<(usize, u64)> {
+        check_writable(&self.inner)?;
         let _guard = self.append_lock.write();
         let file = self.inner.entry().as_file()?;
         let len = file.len()?;
@@ -603,6 +984,7 @@ impl CachedFile {
     }
 
     pub fn set_len(&self, len: u64) -> VfsResult<()> {
+        check_writable(&self.inner)?;
         let file = self.inner.entry().as_file()?;
         let old_len = file.len()?;
         file.set_len(len)?;
@@ -631,7 +1013,7 @@ impl CachedFile {
                 if let Some(mut page) = guard.pop(&pn) {
                     if !self.in_memory {
                         // Don't write back pages since they're discarded
-                        page.dirty = false;
+                        page.mark_clean();
                         self.evict_cache(file, pn, &mut page)?;
                     }
                 }
@@ -640,6 +1022,89 @@ impl CachedFile {
         Ok(())
     }
 
+    /// Drops the cached pages overlapping `range` without writing them back,
+    /// so that they are read again from the filesystem.
+    pub(crate) fn invalidate_range(&self, range: &Range<u64>) {
+        let pages = page_range(range);
+        let mut guard = self.shared.page_cache.lock();
+        let keys = guard
+            .iter()
+            .map(|(pn, _)| *pn)
+            .filter(|pn| pages.contains(pn))
+            .collect::<Vec<_>>();
+        for pn in keys {
+            if let Some(mut page) = guard.pop(&pn) {
+                page.mark_clean();
+                for listener in self.shared.evict_listeners.lock().iter() {
+                    (listener.listener)(pn, &page);
+                }
+            }
+        }
+    }
+
+    /// Drops the cached pages within `range` without writing them back, and
+    /// zeroes the parts of `range` in the pages at its ends. `cache` is the
+    /// locked page cache.
+    fn discard_range(&self, cache: &mut LruCache<u32, PageCache>, range: &Range<u64>) {
+        let full_pages =
+            range.start.div_ceil(PAGE_SIZE as u64) as u32..(range.end / PAGE_SIZE as u64) as u32;
+        let keys = cache
+            .iter()
+            .map(|(pn, _)| *pn)
+            .filter(|pn| full_pages.contains(pn))
+            .collect::<Vec<_>>();
+        for pn in keys {
+            if let Some(mut page) = cache.pop(&pn) {
+                page.mark_clean();
+                for listener in self.shared.evict_listeners.lock().iter() {
+                    (listener.listener)(pn, &page);
+                }
+            }
+        }
+
+        let edge_pages = [
+            (range.start / PAGE_SIZE as u64) as u32,
+            ((range.end - 1) / PAGE_SIZE as u64) as u32,
+        ];
+        for pn in edge_pages {
+            if full_pages.contains(&pn) {
+                continue;
+            }
+            if let Some(page) = cache.peek_mut(&pn) {
+                let page_start = pn as u64 * PAGE_SIZE as u64;
+                let start = range.start.saturating_sub(page_start) as usize;
+                let end = (range.end - page_start).min(PAGE_SIZE as u64) as usize;
+                page.data()[start..end].fill(0);
+            }
+        }
+    }
+
+    /// Preallocates, punches or zeroes `range` of the file, as told by
+    /// `mode`. Punched pages are dropped from the cache, so that they are
+    /// never written back.
+    pub fn allocate(&self, mode: AllocateMode, range: Range<u64>) -> VfsResult<()> {
+        mode.validate(&self.inner, &range)?;
+        check_writable(&self.inner)?;
+        // Keep writers from dirtying the range meanwhile.
+        let _guard = self.append_lock.write();
+        let file = self.inner.entry().as_file()?;
+        // Grow first, so that the new part is allocated as well.
+        if !mode.contains(AllocateMode::KEEP_SIZE) && range.end > file.len()? {
+            self.set_len(range.end)?;
+        }
+        if self.in_memory {
+            // The page cache is the storage of the file.
+            sparse::allocate(&self.inner, file, mode, range)?;
+        } else {
+            let mut guard = self.shared.page_cache.lock();
+            if mode.intersects(AllocateMode::PUNCH_HOLE | AllocateMode::ZERO_RANGE) {
+                self.discard_range(&mut guard, &range);
+            }
+            sparse::allocate(&self.inner, file, mode, range)?;
+        }
+        Ok(())
+    }
+
     pub fn sync(&self, data_only: bool) -> VfsResult<()> {
         if self.in_memory {
             return Ok(());
@@ -649,6 +1114,10 @@ impl CachedFile {
         while let Some((pn, mut page)) = guard.pop_lru() {
             self.evict_cache(file, pn, &mut page)?;
         }
+        if self.shared.tracked.swap(false, Ordering::AcqRel) {
+            writeback::untrack(&self.shared);
+        }
+        drop(guard);
         file.sync(data_only)?;
         Ok(())
     }
@@ -676,6 +1145,25 @@ impl Drop for CachedFile {
 pub enum FileBackend {
     Cached(CachedFile),
     Direct(Location),
+    /// Direct I/O to a regular file, kept coherent with the pages other
+    /// handles may have cached.
+    DirectIo(Location),
+}
+
+/// Checks that a direct I/O buffer and its length are aligned.
+fn check_direct_buf(buf: &[u8]) -> VfsResult<()> {
+    if buf.as_ptr() as usize % DIRECT_IO_ALIGN != 0 || buf.len() % DIRECT_IO_ALIGN != 0 {
+        return Err(VfsError::InvalidInput);
+    }
+    Ok(())
+}
+
+/// Checks that a direct I/O offset is aligned.
+fn check_direct_offset(offset: u64) -> VfsResult<()> {
+    if offset % DIRECT_IO_ALIGN as u64 != 0 {
+        return Err(VfsError::InvalidInput);
+    }
+    Ok(())
 }
 
 impl FileBackend {
@@ -683,10 +1171,24 @@ impl FileBackend {
         Self::Direct(location)
     }
 
+    pub(crate) fn new_direct_io(location: Location) -> Self {
+        Self::DirectIo(location)
+    }
+
     pub(crate) fn new_cached(location: Location) -> Self {
         Self::Cached(CachedFile::get_or_create(location))
     }
 
+    /// Fails if the file is stored on a read-only mount. Cached files check
+    /// it themselves.
+    fn check_writable(&self) -> VfsResult<()> {
+        match self {
+            Self::Cached(_) => Ok(()),
+            Self::Direct(loc) if loc.node_type() != NodeType::RegularFile => Ok(()),
+            Self::Direct(loc) | Self::DirectIo(loc) => check_writable(loc),
+        }
+    }
+
     pub fn read_at(&self, dst: &mut impl BufMut, mut offset: u64) -> VfsResult<usize> {
         match self {
             Self::Cached(cached) => cached.read_at(dst, offset),
@@ -695,11 +1197,26 @@ impl FileBackend {
                     offset += *read as u64;
                 })
             }),
+            Self::DirectIo(loc) => {
+                check_direct_offset(offset)?;
+                // Dirty pages are newer than the filesystem.
+                if let Some(cached) = CachedFile::existing(loc) {
+                    cached.write_back_range(&(offset..offset + dst.remaining_mut() as u64))?;
+                }
+                let file = loc.entry().as_file()?;
+                dst.fill(|buf| {
+                    check_direct_buf(buf)?;
+                    file.read_at(buf, offset).inspect(|read| {
+                        offset += *read as u64;
+                    })
+                })
+            }
         }
     }
 
     pub fn write_at(&self, src: &mut impl Buf, mut offset: u64) -> VfsResult<usize> {
-        match self {
+        self.check_writable()?;
+        let written = match self {
             Self::Cached(cached) => cached.write_at(src, offset),
             Self::Direct(loc) => src.consume(|buf| {
                 loc.entry()
@@ -709,11 +1226,36 @@ impl FileBackend {
                         offset += *written as u64;
                     })
             }),
+            Self::DirectIo(loc) => {
+                check_direct_offset(offset)?;
+                let start = offset;
+                let cached = CachedFile::existing(loc);
+                if let Some(cached) = &cached {
+                    cached.write_back_range(&(start..start + src.remaining() as u64))?;
+                }
+                let file = loc.entry().as_file()?;
+                let result = src.consume(|buf| {
+                    check_direct_buf(buf)?;
+                    file.write_at(buf, offset).inspect(|written| {
+                        offset += *written as u64;
+                    })
+                });
+                // The cached pages are now stale.
+                if let Some(cached) = &cached {
+                    cached.invalidate_range(&(start..offset));
+                }
+                result
+            }
+        }?;
+        if written > 0 {
+            notify_entry(self.location(), WatchMask::MODIFY);
         }
+        Ok(written)
     }
 
     pub fn append(&self, src: &mut impl Buf) -> VfsResult<(usize, u64)> {
-        match self {
+        self.check_writable()?;
+        let (written, new_size) = match self {
             Self::Cached(cached) => cached.append(src),
             Self::Direct(loc) => {
                 let mut buffer = Box::<[u8]>::new_uninit_slice(src.remaining());
@@ -722,13 +1264,45 @@ impl FileBackend {
                     .as_file()?
                     .append(unsafe { buffer.assume_init_ref() })
             }
+            Self::DirectIo(loc) => {
+                let cached = CachedFile::existing(loc);
+                if let Some(cached) = &cached {
+                    cached.write_back(None)?;
+                }
+                let file = loc.entry().as_file()?;
+                let start = file.len()?;
+                check_direct_offset(start)?;
+                let mut new_size = start;
+                let result = src.consume(|buf| {
+                    check_direct_buf(buf)?;
+                    let (written, size) = file.append(buf)?;
+                    new_size = size;
+                    Ok(written)
+                });
+                if let Some(cached) = &cached {
+                    cached.invalidate_range(&(start..new_size));
+                }
+                result.map(|written| (written, new_size))
+            }
+        }?;
+        if written > 0 {
+            notify_entry(self.location(), WatchMask::MODIFY);
         }
+        Ok((written, new_size))
     }
 
     pub fn location(&self) -> &Location {
         match self {
             Self::Cached(cached) => cached.location(),
-            Self::Direct(loc) => loc,
+            Self::Direct(loc) | Self::DirectIo(loc) => loc,
+        }
+    }
+
+    /// Declares how the file is going to be read. Only cached files read
+    /// ahead, so this does nothing on the others.
+    pub fn advise(&self, advice: FileAdvice) {
+        if let Self::Cached(cached) = self {
+            cached.advise(advice);
         }
     }
 
@@ -736,15 +1310,100 @@ impl FileBackend {
         match self {
             Self::Cached(cached) => cached.sync(data_only),
             Self::Direct(loc) => loc.entry().as_file()?.sync(data_only),
+            Self::DirectIo(loc) => {
+                if let Some(cached) = CachedFile::existing(loc) {
+                    cached.sync(data_only)?;
+                }
+                loc.entry().as_file()?.sync(data_only)
+            }
         }
     }
 
     pub fn set_len(&self, len: u64) -> VfsResult<()> {
+        self.check_writable()?;
         match self {
             Self::Cached(cached) => cached.set_len(len),
             Self::Direct(loc) => loc.entry().as_file()?.set_len(len),
+            Self::DirectIo(loc) => {
+                let cached = CachedFile::existing(loc);
+                if let Some(cached) = &cached {
+                    cached.write_back(None)?;
+                }
+                loc.entry().as_file()?.set_len(len)?;
+                if let Some(cached) = &cached {
+                    cached.invalidate_range(&(len..u64::MAX));
+                }
+                Ok(())
+            }
+        }?;
+        notify_entry(self.location(), WatchMask::MODIFY);
+        Ok(())
+    }
+
+    /// Preallocates, punches or zeroes `range` of the file, like `fallocate`.
+    pub fn allocate(&self, mode: AllocateMode, range: Range<u64>) -> VfsResult<()> {
+        self.check_writable()?;
+        match self {
+            Self::Cached(cached) => cached.allocate(mode, range),
+            Self::Direct(loc) | Self::DirectIo(loc) => {
+                mode.validate(loc, &range)?;
+                let cached = match self {
+                    Self::DirectIo(_) => CachedFile::existing(loc),
+                    _ => None,
+                };
+                if let Some(cached) = &cached {
+                    cached.write_back_range(&range)?;
+                }
+                let file = loc.entry().as_file()?;
+                if !mode.contains(AllocateMode::KEEP_SIZE) && range.end > file.len()? {
+                    file.set_len(range.end)?;
+                }
+                sparse::allocate(loc, file, mode, range.clone())?;
+                if let Some(cached) = &cached {
+                    cached.invalidate_range(&range);
+                }
+                Ok(())
+            }
+        }?;
+        notify_entry(self.location(), WatchMask::MODIFY);
+        Ok(())
+    }
+
+    /// Writes back the cached pages, which the filesystem doesn't know about
+    /// yet, before looking for holes.
+    fn before_seek_sparse(&self) -> VfsResult<()> {
+        match self {
+            Self::Cached(cached) => cached.write_back(None),
+            Self::Direct(_) => Ok(()),
+            Self::DirectIo(loc) => match CachedFile::existing(loc) {
+                Some(cached) => cached.write_back(None),
+                None => Ok(()),
+            },
         }
     }
+
+    /// Returns the start of the first data at or after `offset`, like
+    /// `SEEK_DATA`.
+    pub fn seek_data(&self, offset: u64) -> VfsResult<u64> {
+        self.before_seek_sparse()?;
+        sparse::seek_data(self.location(), offset)
+    }
+
+    /// Returns the start of the first hole at or after `offset`, like
+    /// `SEEK_HOLE`.
+    pub fn seek_hole(&self, offset: u64) -> VfsResult<u64> {
+        self.before_seek_sparse()?;
+        sparse::seek_hole(self.location(), offset)
+    }
+}
+
+static NEXT_FILE_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Advisory locks taken through a [`File`], released when it is dropped.
+struct HeldLocks {
+    locks: Arc<FileLocks>,
+    /// Owners of the record locks set through the file.
+    owners: Vec<u64>,
 }
 
 /// Provides `std::fs::File`-like interface.
@@ -752,6 +1411,9 @@ pub struct File {
     inner: FileBackend,
     flags: FileFlags,
     position: Option<Mutex<u64>>,
+    /// Owner of the `flock` lock of the file.
+    id: u64,
+    held_locks: Mutex<Option<HeldLocks>>,
     #[cfg(feature = "times")]
     access_flags: AtomicU8,
 }
@@ -771,6 +1433,8 @@ impl File {
             inner,
             flags,
             position,
+            id: NEXT_FILE_ID.fetch_add(1, Ordering::Relaxed),
+            held_locks: Mutex::new(None),
             #[cfg(feature = "times")]
             access_flags: AtomicU8::new(0),
         }
@@ -827,6 +1491,17 @@ impl File {
         self.access(FileFlags::WRITE)?.write_at(src, offset)
     }
 
+    /// Preallocates, punches or zeroes `range` of the file, like `fallocate`.
+    pub fn allocate(&self, mode: AllocateMode, range: Range<u64>) -> VfsResult<()> {
+        self.access(FileFlags::WRITE)?.allocate(mode, range)
+    }
+
+    /// Declares how the file is going to be read, to tune readahead.
+    pub fn advise(&self, advice: FileAdvice) -> VfsResult<()> {
+        self.access(FileFlags::empty())?.advise(advice);
+        Ok(())
+    }
+
     /// Attempts to sync OS-internal file content and metadata to disk.
     ///
     /// If `data_only` is `true`, only the file data is synced, not the
@@ -837,25 +1512,33 @@ impl File {
     }
 
     pub fn read(&self, dst: &mut impl BufMut) -> axio::Result<usize> {
-        #[cfg(feature = "times")]
-        {
-            self.access_flags.fetch_or(1, Ordering::AcqRel);
-        }
-        if let Some(pos) = self.position.as_ref() {
+        let result = if let Some(pos) = self.position.as_ref() {
             let mut pos = pos.lock();
             self.read_at(dst, *pos).inspect(|n| {
                 *pos += *n as u64;
             })
         } else {
             self.read_at(dst, 0)
+        };
+        #[cfg(feature = "times")]
+        if result.is_ok() {
+            self.access_flags.fetch_or(1, Ordering::AcqRel);
         }
+        result
     }
 
     pub fn write(&self, src: &mut impl Buf) -> axio::Result<usize> {
+        let result = self.write_inner(src);
+        // Times are only updated for writes that happened, not for those
+        // failing e.g. on read-only mounts.
         #[cfg(feature = "times")]
-        {
+        if result.is_ok() {
             self.access_flags.fetch_or(3, Ordering::AcqRel);
         }
+        result
+    }
+
+    fn write_inner(&self, src: &mut impl Buf) -> axio::Result<usize> {
         if let Some(pos) = self.position.as_ref() {
             let mut pos = pos.lock();
             if let Ok(f) = self.access(FileFlags::APPEND) {
@@ -877,6 +1560,141 @@ impl File {
         self.access(FileFlags::empty())?;
         Ok(())
     }
+
+    /// Updates metadata of the file, e.g. its permissions or times.
+    pub fn update_metadata(&self, update: axfs_ng_vfs::MetadataUpdate) -> VfsResult<()> {
+        self.access(FileFlags::empty())?;
+        check_writable(self.location())?;
+        self.location().update_metadata(update)?;
+        notify_entry(self.location(), WatchMask::ATTRIB);
+        Ok(())
+    }
+
+    fn file_locks(&self, record_owner: Option<u64>) -> Arc<FileLocks> {
+        let mut held = self.held_locks.lock();
+        let held = held.get_or_insert_with(|| HeldLocks {
+            locks: FileLocks::of(self.location()),
+            owners: Vec::new(),
+        });
+        if let Some(owner) = record_owner {
+            if !held.owners.contains(&owner) {
+                held.owners.push(owner);
+            }
+        }
+        held.locks.clone()
+    }
+
+    /// Takes a whole-file lock, like `flock`, or converts the one held.
+    ///
+    /// The lock is shared by all users of this `File`, and released when it
+    /// is dropped. If another `File` holds a conflicting lock, waits for it
+    /// to be released if `wait` is set, and fails with
+    /// [`VfsError::WouldBlock`] otherwise.
+    pub fn lock_file(&self, kind: LockType, wait: bool) -> VfsResult<()> {
+        self.access(FileFlags::empty())?;
+        self.file_locks(None).flock(self.id, kind, wait)
+    }
+
+    /// Releases the whole-file lock, if any.
+    pub fn unlock_file(&self) -> VfsResult<()> {
+        self.access(FileFlags::empty())?;
+        if let Some(held) = self.held_locks.lock().as_ref() {
+            held.locks.unflock(self.id);
+        }
+        Ok(())
+    }
+
+    /// Sets (`Some`) or releases (`None`) a POSIX record lock on `range` for
+    /// `owner`, like `F_SETLK`, or `F_SETLKW` if `wait` is set.
+    ///
+    /// Shared locks need the file to be readable, and exclusive ones to be
+    /// writable. All record locks of `owner` are released when this `File`
+    /// is dropped.
+    pub fn set_record_lock(
+        &self,
+        owner: u64,
+        kind: Option<LockType>,
+        range: Range<u64>,
+        wait: bool,
+    ) -> VfsResult<()> {
+        match kind {
+            Some(LockType::Shared) => self.access(FileFlags::READ)?,
+            Some(LockType::Exclusive) => self.access(FileFlags::WRITE)?,
+            None => self.access(FileFlags::empty())?,
+        };
+        let locks = self.file_locks(Some(owner));
+        match kind {
+            Some(kind) => locks.lock_range(owner, kind, range, wait),
+            None => {
+                locks.unlock_range(owner, range);
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns a record lock that would prevent `owner` from locking `range`,
+    /// like `F_GETLK`.
+    pub fn get_record_lock(
+        &self,
+        owner: u64,
+        kind: LockType,
+        range: Range<u64>,
+    ) -> VfsResult<Option<RecordLock>> {
+        self.access(FileFlags::empty())?;
+        Ok(self.file_locks(None).test_range(owner, kind, range))
+    }
+}
+
+impl File {
+    /// Runs `f` at `offset` if given, or else at the position of the file,
+    /// then moves either past the bytes `f` returns it processed.
+    ///
+    /// The position is not locked while `f` runs, so that `f` can use it on
+    /// another file, or even this one.
+    pub fn with_offset(
+        &self,
+        offset: Option<&mut u64>,
+        f: impl FnOnce(u64) -> VfsResult<usize>,
+    ) -> VfsResult<usize> {
+        match offset {
+            Some(offset) => {
+                let n = f(*offset)?;
+                *offset += n as u64;
+                Ok(n)
+            }
+            None => {
+                let start = self.position.as_ref().map_or(0, |pos| *pos.lock());
+                let n = f(start)?;
+                if let Some(pos) = self.position.as_ref() {
+                    *pos.lock() = start + n as u64;
+                }
+                Ok(n)
+            }
+        }
+    }
+
+    fn seek_to(&self, pos: VfsResult<u64>) -> VfsResult<u64> {
+        let pos = pos?;
+        if let Some(guard) = self.position.as_ref() {
+            *guard.lock() = pos;
+        }
+        Ok(pos)
+    }
+
+    /// Seeks to the first data at or after `offset`, like `SEEK_DATA`.
+    ///
+    /// Fails with `ENXIO` if there is none before the end of the file.
+    pub fn seek_data(&self, offset: u64) -> VfsResult<u64> {
+        self.seek_to(self.access(FileFlags::empty())?.seek_data(offset))
+    }
+
+    /// Seeks to the first hole at or after `offset`, like `SEEK_HOLE`. The
+    /// end of the file counts as a hole.
+    ///
+    /// Fails with `ENXIO` if `offset` is past the end of the file.
+    pub fn seek_hole(&self, offset: u64) -> VfsResult<u64> {
+        self.seek_to(self.access(FileFlags::empty())?.seek_hole(offset))
+    }
 }
 
 impl<'a> axio::Seek for &'a File {
@@ -913,21 +1731,39 @@ impl Pollable for File {
     }
 }
 
-#[cfg(feature = "times")]
 impl Drop for File {
     fn drop(&mut self) {
-        let flags = self.access_flags.load(Ordering::Acquire);
-        if flags != 0 {
-            let mut update = axfs_ng_vfs::MetadataUpdate::default();
-            if flags & 1 != 0 {
-                update.atime = Some(axhal::time::wall_time());
+        if let Some(held) = self.held_locks.get_mut().take() {
+            held.locks.unflock(self.id);
+            for owner in held.owners {
+                held.locks.unlock_all(owner);
             }
-            if flags & 2 != 0 {
-                update.mtime = Some(axhal::time::wall_time());
+        }
+
+        #[cfg(feature = "times")]
+        {
+            let mut flags = self.access_flags.load(Ordering::Acquire);
+            if flags & 1 != 0 && mount_flags(self.location()).contains(MountFlags::NO_ATIME) {
+                flags &= !1;
             }
-            if let Err(err) = self.inner.location().update_metadata(update) {
-                warn!("Failed to update file times on drop: {err:?}");
+            if flags != 0 && check_writable(self.location()).is_ok() {
+                let mut update = axfs_ng_vfs::MetadataUpdate::default();
+                if flags & 1 != 0 {
+                    update.atime = Some(axhal::time::wall_time());
+                }
+                if flags & 2 != 0 {
+                    update.mtime = Some(axhal::time::wall_time());
+                }
+                match self.inner.location().update_metadata(update) {
+                    // The filesystem itself may be read-only.
+                    Ok(()) | Err(VfsError::Other(axerrno::LinuxError::EROFS)) => {}
+                    Err(err) => warn!("Failed to update file times on drop: {err:?}"),
+                }
             }
         }
+
+        if self.flags.contains(FileFlags::WRITE) && !self.is_path() {
+            notify_entry(self.location(), WatchMask::CLOSE_WRITE);
+        }
     }
 }
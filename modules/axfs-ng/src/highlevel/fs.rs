@@ -6,12 +6,18 @@ use alloc::{
 };
 
 use axfs_ng_vfs::{
-    Location, Metadata, NodePermission, NodeType, VfsError, VfsResult,
+    Location, Metadata, MetadataUpdate, NodePermission, NodeType, VfsError, VfsResult,
     path::{Component, Components, Path, PathBuf},
 };
 use axsync::Mutex;
 use spin::Once;
 
+use super::{
+    Access, Credentials, MountFlags, WatchMask,
+    mount::{check_writable, mount_flags, same_location},
+    watch::{notify_entry, notify_rename},
+};
+
 pub const SYMLINKS_MAX: usize = 40;
 
 pub static ROOT_FS_CONTEXT: Once<FsContext> = Once::new();
@@ -34,10 +40,16 @@ pub struct ReadDirEntry {
 }
 
 /// Provides `std::fs`-like interface.
+///
+/// Operations are checked against the permissions of the files for the
+/// credentials of the context, which are those of the superuser unless set
+/// otherwise.
 #[derive(Debug, Clone)]
 pub struct FsContext {
     root_dir: Location,
     current_dir: Location,
+    credentials: Arc<Credentials>,
+    umask: u32,
 }
 
 impl FsContext {
@@ -45,7 +57,76 @@ impl FsContext {
         Self {
             root_dir: root_dir.clone(),
             current_dir: root_dir,
+            credentials: Arc::new(Credentials::root()),
+            umask: 0,
+        }
+    }
+
+    pub fn credentials(&self) -> &Credentials {
+        &self.credentials
+    }
+
+    pub fn set_credentials(&mut self, credentials: Credentials) {
+        self.credentials = Arc::new(credentials);
+    }
+
+    /// Returns the mode bits cleared from the files created in this context.
+    pub fn umask(&self) -> u32 {
+        self.umask
+    }
+
+    /// Sets the umask, returning the previous one.
+    pub fn set_umask(&mut self, umask: u32) -> u32 {
+        core::mem::replace(&mut self.umask, umask & 0o777)
+    }
+
+    /// Checks that the credentials of the context grant `access` to `loc`.
+    pub fn check_access(&self, loc: &Location, access: Access) -> VfsResult<()> {
+        if access.is_empty()
+            || (self.credentials.is_root() && (access != Access::EXECUTE || loc.is_dir()))
+        {
+            return Ok(());
+        }
+        self.credentials.check_access(&loc.metadata()?, access)
+    }
+
+    /// Returns the credentials that the program at `loc` runs with when
+    /// executed in this context.
+    ///
+    /// Its set-user-ID and set-group-ID bits are ignored on filesystems
+    /// mounted with [`MountFlags::NO_SUID`].
+    pub fn exec_credentials(&self, loc: &Location) -> VfsResult<Credentials> {
+        if mount_flags(loc).contains(MountFlags::NO_SUID) {
+            return Ok(Credentials::clone(&self.credentials));
         }
+        Ok(self.credentials.exec(&loc.metadata()?))
+    }
+
+    /// Checks that entries may be created in or removed from `dir`.
+    fn check_dir_writable(&self, dir: &Location) -> VfsResult<()> {
+        check_writable(dir)?;
+        self.check_access(dir, Access::WRITE | Access::EXECUTE)
+    }
+
+    /// Checks that `entry` may be removed from its parent `dir`.
+    fn check_removable(&self, dir: &Location, entry: &Location) -> VfsResult<()> {
+        self.check_dir_writable(dir)?;
+        if !self.credentials.is_root() {
+            self.credentials
+                .check_sticky(&dir.metadata()?, &entry.metadata()?)?;
+        }
+        Ok(())
+    }
+
+    /// Gives a file just created in this context to its user.
+    fn set_owner(&self, loc: &Location) -> VfsResult<()> {
+        if let Some(owner) = self.credentials.owner() {
+            loc.update_metadata(MetadataUpdate {
+                owner: Some(owner),
+                ..Default::default()
+            })?;
+        }
+        Ok(())
     }
 
     pub fn root_dir(&self) -> &Location {
@@ -58,6 +139,7 @@ impl FsContext {
 
     pub fn set_current_dir(&mut self, current_dir: Location) -> VfsResult<()> {
         current_dir.check_is_dir()?;
+        self.check_access(&current_dir, Access::EXECUTE)?;
         self.current_dir = current_dir;
         Ok(())
     }
@@ -67,9 +149,26 @@ impl FsContext {
         Ok(Self {
             root_dir: self.root_dir.clone(),
             current_dir,
+            credentials: self.credentials.clone(),
+            umask: self.umask,
         })
     }
 
+    /// Makes the directory at `path` the root directory of the context, which
+    /// `..` then never leaves, and its current directory.
+    ///
+    /// Only the superuser may do so.
+    pub fn chroot(&mut self, path: impl AsRef<Path>) -> VfsResult<()> {
+        if !self.credentials.is_root() {
+            return Err(VfsError::OperationNotPermitted);
+        }
+        let root_dir = self.resolve(path)?;
+        root_dir.check_is_dir()?;
+        self.root_dir = root_dir.clone();
+        self.current_dir = root_dir;
+        Ok(())
+    }
+
     /// Attempts to resolve a possible symlink, at the current location (this
     /// assumes that `loc` is a child of current directory).
     pub fn try_resolve_symlink(
@@ -91,12 +190,29 @@ impl FsContext {
         self.resolve_components(PathBuf::from(target).components(), follow_count)
     }
 
-    fn lookup(&self, dir: &Location, name: &str, follow_count: &mut usize) -> VfsResult<Location> {
+    pub(crate) fn lookup(
+        &self,
+        dir: &Location,
+        name: &str,
+        follow_count: &mut usize,
+    ) -> VfsResult<Location> {
+        self.check_access(dir, Access::EXECUTE)?;
         let loc = dir.lookup_no_follow(name)?;
         self.with_current_dir(dir.clone())?
             .try_resolve_symlink(loc, follow_count)
     }
 
+    /// Returns the directory `..` leads to from `dir`.
+    pub(crate) fn parent_dir(&self, dir: &Location) -> Location {
+        // `..` never leaves the root directory, but does leave the root of a
+        // mounted filesystem for its mountpoint.
+        if same_location(dir, &self.root_dir) {
+            dir.clone()
+        } else {
+            dir.parent().unwrap_or_else(|| self.root_dir.clone())
+        }
+    }
+
     fn resolve_components(
         &self,
         components: Components,
@@ -107,7 +223,7 @@ impl FsContext {
             match comp {
                 Component::CurDir => {}
                 Component::ParentDir => {
-                    dir = dir.parent().unwrap_or_else(|| self.root_dir.clone());
+                    dir = self.parent_dir(&dir);
                 }
                 Component::RootDir => {
                     dir = self.root_dir.clone();
@@ -149,7 +265,10 @@ impl FsContext {
     pub fn resolve_no_follow(&self, path: impl AsRef<Path>) -> VfsResult<Location> {
         let (dir, name) = self.resolve_inner(path.as_ref(), &mut 0)?;
         match name {
-            Some(name) => dir.lookup_no_follow(name),
+            Some(name) => {
+                self.check_access(&dir, Access::EXECUTE)?;
+                dir.lookup_no_follow(name)
+            }
             None => Ok(dir),
         }
     }
@@ -191,9 +310,34 @@ impl FsContext {
         self.resolve(path)?.metadata()
     }
 
+    /// Updates metadata of the file, e.g. its permissions or times.
+    pub fn update_metadata(&self, path: impl AsRef<Path>, update: MetadataUpdate) -> VfsResult<()> {
+        let loc = self.resolve(path)?;
+        check_writable(&loc)?;
+        if !self.credentials.is_root() {
+            // Only the owner may change the metadata, and give the file to
+            // one of its own groups.
+            let metadata = loc.metadata()?;
+            let owner_ok = update
+                .owner
+                .is_none_or(|(uid, gid)| uid == metadata.uid && self.credentials.in_group(gid));
+            if self.credentials.uid != metadata.uid || !owner_ok {
+                return Err(VfsError::OperationNotPermitted);
+            }
+        }
+        loc.update_metadata(update)?;
+        notify_entry(&loc, WatchMask::ATTRIB);
+        Ok(())
+    }
+
     /// Returns an iterator over the entries in a directory.
     pub fn read_dir(&self, path: impl AsRef<Path>) -> VfsResult<ReadDir> {
-        let dir = self.resolve(path)?;
+        self.read_dir_at(self.resolve(path)?)
+    }
+
+    /// Returns an iterator over the entries in the directory `dir`.
+    pub(crate) fn read_dir_at(&self, dir: Location) -> VfsResult<ReadDir> {
+        self.check_access(&dir, Access::READ)?;
         Ok(ReadDir {
             dir,
             buf: VecDeque::new(),
@@ -204,20 +348,25 @@ impl FsContext {
 
     /// Removes a file from the filesystem.
     pub fn remove_file(&self, path: impl AsRef<Path>) -> VfsResult<()> {
-        let entry = self.resolve_no_follow(path.as_ref())?;
-        entry
-            .parent()
-            .ok_or(VfsError::IsADirectory)?
-            .unlink(entry.name(), false)
+        self.remove_entry(&self.resolve_no_follow(path.as_ref())?, false)
     }
 
     /// Removes a directory from the filesystem.
     pub fn remove_dir(&self, path: impl AsRef<Path>) -> VfsResult<()> {
-        let entry = self.resolve_no_follow(path.as_ref())?;
-        entry
-            .parent()
-            .ok_or(VfsError::ResourceBusy)?
-            .unlink(entry.name(), true)
+        self.remove_entry(&self.resolve_no_follow(path.as_ref())?, true)
+    }
+
+    /// Removes `entry` from its parent directory, as a directory if `is_dir`.
+    pub(crate) fn remove_entry(&self, entry: &Location, is_dir: bool) -> VfsResult<()> {
+        let parent = entry.parent().ok_or(if is_dir {
+            VfsError::ResourceBusy
+        } else {
+            VfsError::IsADirectory
+        })?;
+        self.check_removable(&parent, entry)?;
+        parent.unlink(entry.name(), is_dir)?;
+        notify_entry(entry, WatchMask::DELETE);
+        Ok(())
     }
 
     /// Renames a file or directory to a new name, replacing the original file
@@ -225,13 +374,47 @@ impl FsContext {
     pub fn rename(&self, from: impl AsRef<Path>, to: impl AsRef<Path>) -> VfsResult<()> {
         let (src_dir, src_name) = self.resolve_parent(from.as_ref())?;
         let (dst_dir, dst_name) = self.resolve_parent(to.as_ref())?;
-        src_dir.rename(&src_name, &dst_dir, &dst_name)
+        self.check_removable(&src_dir, &src_dir.lookup_no_follow(&src_name)?)?;
+        match dst_dir.lookup_no_follow(&dst_name) {
+            Ok(dst) => self.check_removable(&dst_dir, &dst)?,
+            Err(_) => self.check_dir_writable(&dst_dir)?,
+        }
+        src_dir.rename(&src_name, &dst_dir, &dst_name)?;
+        notify_rename(&src_dir, &src_name, &dst_dir, &dst_name);
+        Ok(())
     }
 
     /// Creates a new, empty directory at the provided path.
     pub fn create_dir(&self, path: impl AsRef<Path>, mode: NodePermission) -> VfsResult<Location> {
         let (dir, name) = self.resolve_nonexistent(path.as_ref())?;
-        dir.create(name, NodeType::Directory, mode)
+        self.create_dir_at(&dir, name, mode)
+    }
+
+    /// Creates a new, empty directory named `name` in `dir`.
+    pub(crate) fn create_dir_at(
+        &self,
+        dir: &Location,
+        name: &str,
+        mode: NodePermission,
+    ) -> VfsResult<Location> {
+        let mode = NodePermission::from_bits_truncate(mode.bits() & !(self.umask as u16));
+        self.create_at(dir, name, NodeType::Directory, mode)
+    }
+
+    /// Creates a node named `name` in `dir`, owned by the user of the
+    /// context.
+    pub(crate) fn create_at(
+        &self,
+        dir: &Location,
+        name: &str,
+        node_type: NodeType,
+        mode: NodePermission,
+    ) -> VfsResult<Location> {
+        self.check_dir_writable(dir)?;
+        let loc = dir.create(name, node_type, mode)?;
+        self.set_owner(&loc)?;
+        notify_entry(&loc, WatchMask::CREATE);
+        Ok(loc)
     }
 
     /// Creates a new hard link on the filesystem.
@@ -242,7 +425,10 @@ impl FsContext {
     ) -> VfsResult<Location> {
         let old = self.resolve(old_path.as_ref())?;
         let (new_dir, new_name) = self.resolve_nonexistent(new_path.as_ref())?;
-        new_dir.link(new_name, &old)
+        self.check_dir_writable(&new_dir)?;
+        let new = new_dir.link(new_name, &old)?;
+        notify_entry(&new, WatchMask::CREATE);
+        Ok(new)
     }
 
     /// Creates a new symbolic link on the filesystem.
@@ -252,11 +438,24 @@ impl FsContext {
         link_path: impl AsRef<Path>,
     ) -> VfsResult<Location> {
         let (dir, name) = self.resolve_nonexistent(link_path.as_ref())?;
+        self.symlink_at(&dir, name, target.as_ref())
+    }
+
+    /// Creates a new symbolic link named `name` in `dir`.
+    pub(crate) fn symlink_at(
+        &self,
+        dir: &Location,
+        name: &str,
+        target: &str,
+    ) -> VfsResult<Location> {
         if dir.lookup_no_follow(name).is_ok() {
             return Err(VfsError::AlreadyExists);
         }
+        self.check_dir_writable(dir)?;
         let symlink = dir.create(name, NodeType::Symlink, NodePermission::default())?;
-        symlink.entry().as_file()?.set_symlink(target.as_ref())?;
+        symlink.entry().as_file()?.set_symlink(target)?;
+        self.set_owner(&symlink)?;
+        notify_entry(&symlink, WatchMask::CREATE);
         Ok(symlink)
     }
 
@@ -0,0 +1,289 @@
+//! Advisory file locks.
+//!
+//! Two independent kinds of locks are supported, like on Linux:
+//!
+//! - `flock` locks cover a whole file, and are owned by an open [`File`]: all
+//!   handles sharing it share the lock.
+//! - POSIX record locks cover a range of bytes, and are owned by an arbitrary
+//!   owner id, usually a process id. Waiting for one fails if that would
+//!   deadlock.
+//!
+//! Locks are advisory: they never prevent any I/O, only other locks.
+//!
+//! [`File`]: super::File
+
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::ops::Range;
+
+use axerrno::LinuxError;
+use axfs_ng_vfs::{Location, VfsError, VfsResult};
+use axio::PollSet;
+use spin::Mutex;
+
+/// Type of an advisory lock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockType {
+    /// Shared lock, e.g. `LOCK_SH` or `F_RDLCK`.
+    Shared,
+    /// Exclusive lock, e.g. `LOCK_EX` or `F_WRLCK`.
+    Exclusive,
+}
+
+impl LockType {
+    fn conflicts(self, other: Self) -> bool {
+        self == Self::Exclusive || other == Self::Exclusive
+    }
+}
+
+/// A POSIX record lock.
+#[derive(Debug, Clone)]
+pub struct RecordLock {
+    pub owner: u64,
+    pub kind: LockType,
+    /// Locked bytes. An end of `u64::MAX` extends the lock to the end of the
+    /// file, however it grows.
+    pub range: Range<u64>,
+}
+
+fn overlaps(a: &Range<u64>, b: &Range<u64>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+/// Owners of record locks waiting for another owner.
+static BLOCKED: Mutex<BTreeMap<u64, u64>> = Mutex::new(BTreeMap::new());
+
+/// Returns whether `owner` waiting for `blocker` would close a cycle of
+/// owners waiting for each other.
+fn would_deadlock(owner: u64, blocker: u64) -> bool {
+    let blocked = BLOCKED.lock();
+    let mut cur = blocker;
+    for _ in 0..=blocked.len() {
+        if cur == owner {
+            return true;
+        }
+        match blocked.get(&cur) {
+            Some(&next) => cur = next,
+            None => return false,
+        }
+    }
+    false
+}
+
+#[derive(Default)]
+struct LockState {
+    flocks: Vec<(u64, LockType)>,
+    records: Vec<RecordLock>,
+}
+
+impl LockState {
+    fn try_flock(&mut self, owner: u64, kind: LockType) -> bool {
+        if self
+            .flocks
+            .iter()
+            .any(|(it, other)| *it != owner && kind.conflicts(*other))
+        {
+            return false;
+        }
+        self.flocks.retain(|(it, _)| *it != owner);
+        self.flocks.push((owner, kind));
+        true
+    }
+
+    fn conflict(&self, owner: u64, kind: LockType, range: &Range<u64>) -> Option<&RecordLock> {
+        self.records.iter().find(|lock| {
+            lock.owner != owner && overlaps(&lock.range, range) && kind.conflicts(lock.kind)
+        })
+    }
+
+    fn unlock_range(&mut self, owner: u64, range: &Range<u64>) {
+        let mut kept = Vec::with_capacity(self.records.len() + 1);
+        for lock in self.records.drain(..) {
+            if lock.owner != owner || !overlaps(&lock.range, range) {
+                kept.push(lock);
+                continue;
+            }
+            // Keep the parts outside of the range.
+            if lock.range.start < range.start {
+                kept.push(RecordLock {
+                    range: lock.range.start..range.start,
+                    ..lock.clone()
+                });
+            }
+            if lock.range.end > range.end {
+                kept.push(RecordLock {
+                    range: range.end..lock.range.end,
+                    ..lock
+                });
+            }
+        }
+        self.records = kept;
+    }
+
+    /// Sets a record lock, or returns the owner of a conflicting one.
+    fn try_lock_range(&mut self, owner: u64, kind: LockType, range: Range<u64>) -> Result<(), u64> {
+        if let Some(lock) = self.conflict(owner, kind, &range) {
+            return Err(lock.owner);
+        }
+        self.unlock_range(owner, &range);
+        // Merge with the adjacent locks of the same type.
+        let mut range = range;
+        self.records.retain(|lock| {
+            let adjacent = lock.owner == owner
+                && lock.kind == kind
+                && lock.range.start <= range.end
+                && range.start <= lock.range.end;
+            if adjacent {
+                range = range.start.min(lock.range.start)..range.end.max(lock.range.end);
+            }
+            !adjacent
+        });
+        self.records.push(RecordLock { owner, kind, range });
+        Ok(())
+    }
+}
+
+/// Advisory locks of a file.
+pub struct FileLocks {
+    state: Mutex<LockState>,
+    waiters: PollSet,
+}
+
+impl FileLocks {
+    /// Returns the locks of the file at `loc`.
+    pub fn of(loc: &Location) -> Arc<Self> {
+        let mut guard = loc.user_data();
+        if let Some(locks) = guard.get::<Arc<Self>>() {
+            return locks.clone();
+        }
+        let locks = Arc::new(Self {
+            state: Mutex::default(),
+            waiters: PollSet::new(),
+        });
+        guard.insert(locks.clone());
+        locks
+    }
+
+    /// Runs `f` until it returns something, each time the locks change.
+    #[cfg(feature = "multitask")]
+    fn wait<R>(&self, mut f: impl FnMut(&mut LockState) -> Option<VfsResult<R>>) -> VfsResult<R> {
+        use core::task::Poll;
+
+        axtask::future::block_on_interruptible(core::future::poll_fn(|cx| {
+            // Register first so that no release is missed.
+            self.waiters.register(cx.waker());
+            match f(&mut self.state.lock()) {
+                Some(result) => Poll::Ready(result),
+                None => Poll::Pending,
+            }
+        }))
+    }
+
+    /// Without other tasks, nothing could release a conflicting lock.
+    #[cfg(not(feature = "multitask"))]
+    fn wait<R>(&self, mut f: impl FnMut(&mut LockState) -> Option<VfsResult<R>>) -> VfsResult<R> {
+        f(&mut self.state.lock()).unwrap_or(Err(VfsError::WouldBlock))
+    }
+
+    /// Takes a `flock` lock for `owner`, or converts the one it holds.
+    ///
+    /// If another owner holds a conflicting lock, fails with
+    /// [`VfsError::WouldBlock`] unless `wait` is set.
+    pub fn flock(&self, owner: u64, kind: LockType, wait: bool) -> VfsResult<()> {
+        if !wait {
+            return match self.state.lock().try_flock(owner, kind) {
+                true => Ok(()),
+                false => Err(VfsError::WouldBlock),
+            };
+        }
+        self.wait(|state| state.try_flock(owner, kind).then_some(Ok(())))
+    }
+
+    /// Releases the `flock` lock of `owner`, if any.
+    pub fn unflock(&self, owner: u64) {
+        self.state.lock().flocks.retain(|(it, _)| *it != owner);
+        self.waiters.wake();
+    }
+
+    /// Returns a record lock of another owner that prevents `owner` from
+    /// locking `range`, like `F_GETLK`.
+    pub fn test_range(&self, owner: u64, kind: LockType, range: Range<u64>) -> Option<RecordLock> {
+        self.state.lock().conflict(owner, kind, &range).cloned()
+    }
+
+    /// Locks `range` for `owner`, replacing the locks it holds there, like
+    /// `F_SETLK`, or `F_SETLKW` if `wait` is set.
+    ///
+    /// Waiting fails with `EDEADLK` if the owner of the conflicting lock is
+    /// itself waiting, possibly indirectly, for `owner`.
+    pub fn lock_range(
+        &self,
+        owner: u64,
+        kind: LockType,
+        range: Range<u64>,
+        wait: bool,
+    ) -> VfsResult<()> {
+        if range.is_empty() {
+            return Err(VfsError::InvalidInput);
+        }
+        if !wait {
+            return self
+                .state
+                .lock()
+                .try_lock_range(owner, kind, range)
+                .map_err(|_| VfsError::WouldBlock);
+        }
+        let result = self.wait(
+            |state| match state.try_lock_range(owner, kind, range.clone()) {
+                Ok(()) => Some(Ok(())),
+                Err(blocker) if would_deadlock(owner, blocker) => {
+                    Some(Err(VfsError::Other(LinuxError::EDEADLK)))
+                }
+                Err(blocker) => {
+                    BLOCKED.lock().insert(owner, blocker);
+                    None
+                }
+            },
+        );
+        BLOCKED.lock().remove(&owner);
+        result
+    }
+
+    /// Releases the record locks of `owner` in `range`, like `F_UNLCK`.
+    pub fn unlock_range(&self, owner: u64, range: Range<u64>) {
+        self.state.lock().unlock_range(owner, &range);
+        self.waiters.wake();
+    }
+
+    /// Releases all record locks of `owner`.
+    pub fn unlock_all(&self, owner: u64) {
+        self.unlock_range(owner, 0..u64::MAX);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deadlock() {
+        let locks = FileLocks {
+            state: Mutex::default(),
+            waiters: PollSet::new(),
+        };
+        locks
+            .lock_range(1, LockType::Exclusive, 0..10, false)
+            .unwrap();
+        locks
+            .lock_range(2, LockType::Exclusive, 10..20, false)
+            .unwrap();
+        assert!(!would_deadlock(1, 2));
+
+        // Owner 2 waiting for owner 1, as another task would.
+        BLOCKED.lock().insert(2, 1);
+        let result = locks.lock_range(1, LockType::Exclusive, 10..20, true);
+        BLOCKED.lock().remove(&2);
+        assert!(matches!(result, Err(VfsError::Other(LinuxError::EDEADLK))));
+        // Nothing changed.
+        assert!(locks.test_range(3, LockType::Shared, 10..11).unwrap().owner == 2);
+    }
+}
@@ -1,5 +1,29 @@
+mod copy;
+mod cpio;
+mod cred;
 mod file;
 mod fs;
+mod lock;
+mod mount;
+mod reclaim;
+mod sparse;
+mod walk;
+mod watch;
+mod writeback;
+mod xattr;
 
+pub use copy::*;
+pub use cpio::*;
+pub use cred::*;
 pub use file::*;
 pub use fs::*;
+pub use lock::*;
+pub use mount::*;
+pub use reclaim::*;
+pub use sparse::AllocateMode;
+pub(crate) use sparse::SparseNode;
+pub use walk::*;
+pub use watch::{WatchEvent, WatchMask, Watcher, notify_entry};
+pub use writeback::*;
+pub use xattr::{XATTR_NAME_MAX, XATTR_SIZE_MAX, XattrExt, XattrFlags};
+pub(crate) use xattr::{XattrNode, check_set_flags, no_data};
@@ -0,0 +1,214 @@
+use alloc::{
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+
+use axerrno::LinuxError;
+use axfs_ng_vfs::{
+    Filesystem, Location, Mountpoint, VfsError, VfsResult,
+    path::{Path, PathBuf},
+};
+use spin::Mutex;
+
+use super::{FsContext, writeback};
+
+bitflags::bitflags! {
+    /// Flags of a mounted filesystem.
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    pub struct MountFlags: u32 {
+        /// Disallow modifications to the filesystem.
+        const READ_ONLY = 1;
+        /// Disallow executing programs from the filesystem.
+        const NO_EXEC = 2;
+        /// Ignore the set-user-ID and set-group-ID bits of programs on the
+        /// filesystem, in [`FsContext::exec_credentials`].
+        const NO_SUID = 4;
+        /// Don't update the access times of files, with the `times` feature.
+        const NO_ATIME = 8;
+    }
+}
+
+struct MountEntry {
+    source: String,
+    flags: MountFlags,
+    /// Root location of the mounted filesystem.
+    root: Location,
+}
+
+/// Kernel-wide table of mounted filesystems, in mount order.
+static MOUNT_TABLE: Mutex<Vec<MountEntry>> = Mutex::new(Vec::new());
+
+/// Information about a mounted filesystem, as returned by [`mounts`].
+#[derive(Debug, Clone)]
+pub struct MountInfo {
+    /// The device or name the filesystem was mounted from.
+    pub source: String,
+    /// Absolute path of the mountpoint.
+    pub path: PathBuf,
+    /// Name of the filesystem type.
+    pub fs_type: String,
+    pub flags: MountFlags,
+}
+
+pub(crate) fn same_location(a: &Location, b: &Location) -> bool {
+    Arc::ptr_eq(a.mountpoint(), b.mountpoint()) && a.inode() == b.inode()
+}
+
+/// Returns whether `loc` lies inside the filesystem mounted at `mount`,
+/// possibly through other mounts.
+fn is_under(loc: &Location, mount: &Location) -> bool {
+    let mut cur = loc.parent();
+    while let Some(loc) = cur {
+        if Arc::ptr_eq(loc.mountpoint(), mount.mountpoint()) {
+            return true;
+        }
+        cur = loc.parent();
+    }
+    false
+}
+
+/// Creates the root mount from `fs` and records it in the mount table.
+///
+/// Returns the root location, which is usually passed to [`FsContext::new`].
+pub fn mount_root(source: &str, fs: &Filesystem, flags: MountFlags) -> Location {
+    let root = Mountpoint::new_root(fs).root_location();
+    MOUNT_TABLE.lock().push(MountEntry {
+        source: source.to_string(),
+        flags,
+        root: root.clone(),
+    });
+    root
+}
+
+/// Returns the flags of the mount that `loc` belongs to.
+pub fn mount_flags(loc: &Location) -> MountFlags {
+    MOUNT_TABLE
+        .lock()
+        .iter()
+        .find(|entry| Arc::ptr_eq(entry.root.mountpoint(), loc.mountpoint()))
+        .map_or(MountFlags::empty(), |entry| entry.flags)
+}
+
+/// Fails with `EROFS` if the mount that `loc` belongs to is read-only.
+pub(crate) fn check_writable(loc: &Location) -> VfsResult<()> {
+    if mount_flags(loc).contains(MountFlags::READ_ONLY) {
+        return Err(VfsError::Other(LinuxError::EROFS));
+    }
+    Ok(())
+}
+
+/// Returns the root locations of all mounted filesystems.
+pub(crate) fn mount_roots() -> Vec<Location> {
+    MOUNT_TABLE
+        .lock()
+        .iter()
+        .map(|entry| entry.root.clone())
+        .collect()
+}
+
+/// Lists all mounted filesystems, in mount order.
+pub fn mounts() -> Vec<MountInfo> {
+    MOUNT_TABLE
+        .lock()
+        .iter()
+        .map(|entry| MountInfo {
+            source: entry.source.clone(),
+            path: entry
+                .root
+                .absolute_path()
+                .unwrap_or_else(|_| PathBuf::from("/")),
+            fs_type: entry.root.filesystem().name().to_string(),
+            flags: entry.flags,
+        })
+        .collect()
+}
+
+impl FsContext {
+    /// Mounts `fs` on the directory at `path`.
+    ///
+    /// `source` only serves as a description of the filesystem, e.g. the name
+    /// of the block device, and is reported by [`mounts`].
+    pub fn mount(
+        &self,
+        source: &str,
+        path: impl AsRef<Path>,
+        fs: &Filesystem,
+        flags: MountFlags,
+    ) -> VfsResult<Location> {
+        let target = self.resolve(path)?;
+        target.check_is_dir()?;
+        let root = target.mount(fs)?.root_location();
+        MOUNT_TABLE.lock().push(MountEntry {
+            source: source.to_string(),
+            flags,
+            root: root.clone(),
+        });
+        Ok(root)
+    }
+
+    /// Changes the flags of the filesystem mounted on `path`.
+    ///
+    /// When it becomes read-only, its dirty data is written back and the
+    /// filesystem flushed, while further modifications already fail.
+    pub fn remount(&self, path: impl AsRef<Path>, flags: MountFlags) -> VfsResult<()> {
+        let target = self.resolve(path)?;
+        if !target.is_root_of_mount() {
+            return Err(VfsError::InvalidInput);
+        }
+
+        let old_flags = {
+            let mut table = MOUNT_TABLE.lock();
+            let entry = table
+                .iter_mut()
+                .find(|entry| same_location(&entry.root, &target))
+                .ok_or(VfsError::InvalidInput)?;
+            core::mem::replace(&mut entry.flags, flags)
+        };
+        if flags.contains(MountFlags::READ_ONLY) && !old_flags.contains(MountFlags::READ_ONLY) {
+            let result =
+                writeback::write_back_mount(&target).and_then(|_| target.filesystem().flush());
+            if let Err(err) = result {
+                // Leave the mount writable, so that its dirty data may still
+                // be written back.
+                if let Some(entry) = MOUNT_TABLE
+                    .lock()
+                    .iter_mut()
+                    .find(|entry| same_location(&entry.root, &target))
+                {
+                    entry.flags = old_flags;
+                }
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Unmounts the filesystem mounted on `path`, once the dirty pages of its
+    /// files are written back.
+    ///
+    /// Fails with [`VfsError::ResourceBusy`] if other filesystems are still
+    /// mounted inside it, or if it is the root mount.
+    pub fn umount(&self, path: impl AsRef<Path>) -> VfsResult<()> {
+        let target = self.resolve(path)?;
+        if !target.is_root_of_mount() {
+            return Err(VfsError::InvalidInput);
+        }
+        // Before locking the table, which writing back may look up.
+        writeback::write_back_mount(&target)?;
+
+        let mut table = MOUNT_TABLE.lock();
+        let index = table
+            .iter()
+            .position(|entry| same_location(&entry.root, &target))
+            .ok_or(VfsError::InvalidInput)?;
+        if target.parent().is_none() || table.iter().any(|entry| is_under(&entry.root, &target)) {
+            return Err(VfsError::ResourceBusy);
+        }
+
+        target.filesystem().flush()?;
+        target.unmount()?;
+        table.remove(index);
+        Ok(())
+    }
+}
@@ -0,0 +1,173 @@
+//! Kernel-wide reclaim of the page cache.
+//!
+//! Pages of all files backed by a disk are reclaimed by a clock, whose hand
+//! goes round the files when the page cache grows past its budget, or when
+//! the allocator runs out of memory. Each file visited gives up its least
+//! recently used clean pages that were not accessed since the hand last went
+//! round, and any of them on the next round, so that a shrink takes time
+//! linear in the number of cached pages. Dirty pages are left to the
+//! write-back, and pages of in-memory files, or of files mapped in memory,
+//! are never reclaimed here.
+
+use alloc::{
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use axalloc::{UsageKind, global_allocator};
+use spin::Mutex;
+
+use super::{CachedFileShared, PAGE_SIZE};
+
+static TICK: AtomicU64 = AtomicU64::new(0);
+
+/// Maximum number of pages of the page cache, `usize::MAX` if unlimited.
+static BUDGET: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// Number of pages of in-memory files, which don't count toward the budget.
+static IN_MEMORY_PAGES: AtomicUsize = AtomicUsize::new(0);
+
+/// Files whose pages can be reclaimed.
+static FILES: Mutex<Vec<Weak<CachedFileShared>>> = Mutex::new(Vec::new());
+
+/// Index in [`FILES`] of the next file the clock hand visits.
+static HAND: AtomicUsize = AtomicUsize::new(0);
+
+/// Tick at which the clock hand last went round: pages accessed before are
+/// reclaimed.
+static SWEEP: AtomicU64 = AtomicU64::new(0);
+
+/// Number of pages dropped from a file per scan of its cache, which holds as
+/// many at most.
+const BATCH: usize = 64;
+
+/// Returns a new tick, to order page accesses.
+pub(crate) fn tick() -> u64 {
+    TICK.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Returns the number of pages of the page cache, as accounted by the
+/// allocator in [`UsageKind::PageCache`].
+pub fn page_cache_pages() -> usize {
+    global_allocator().usage_stats().get(UsageKind::PageCache) / PAGE_SIZE
+}
+
+pub(crate) fn account_in_memory(added: bool) {
+    if added {
+        IN_MEMORY_PAGES.fetch_add(1, Ordering::Relaxed);
+    } else {
+        IN_MEMORY_PAGES.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Returns the number of pages of the page cache that may be reclaimed, i.e.
+/// those of files backed by a disk.
+pub fn reclaimable_pages() -> usize {
+    page_cache_pages().saturating_sub(IN_MEMORY_PAGES.load(Ordering::Relaxed))
+}
+
+/// Returns the maximum number of pages of the page cache, if limited.
+pub fn page_cache_budget() -> Option<usize> {
+    match BUDGET.load(Ordering::Relaxed) {
+        usize::MAX => None,
+        budget => Some(budget),
+    }
+}
+
+/// Limits the number of pages of the page cache, or lifts the limit.
+///
+/// Pages in excess are reclaimed as new ones are brought in. Pages of
+/// in-memory files are not counted, as they can't be reclaimed.
+pub fn set_page_cache_budget(budget: Option<usize>) {
+    BUDGET.store(budget.unwrap_or(usize::MAX), Ordering::Relaxed);
+}
+
+/// Makes the pages of a file reclaimable.
+pub(crate) fn register(shared: &Arc<CachedFileShared>) {
+    let mut files = FILES.lock();
+    files.retain(|it| it.strong_count() > 0);
+    files.push(Arc::downgrade(shared));
+}
+
+/// Reclaims a page if the page cache is at its budget, before a new page is
+/// allocated.
+pub(crate) fn before_alloc() {
+    let budget = BUDGET.load(Ordering::Relaxed);
+    let pages = reclaimable_pages();
+    if pages >= budget {
+        shrink_page_cache(pages - budget + 1);
+    }
+}
+
+/// Drops up to `wanted` clean pages of a file accessed before the tick
+/// `before`, least recently used first, and returns the number of pages
+/// dropped.
+fn evict(shared: &CachedFileShared, wanted: usize, before: u64) -> usize {
+    // Keep mappings from being created meanwhile.
+    let Some(listeners) = shared.evict_listeners.try_lock() else {
+        return 0;
+    };
+    let Some(mut cache) = shared.page_cache.try_lock() else {
+        return 0;
+    };
+    if !listeners.is_empty() {
+        return 0;
+    }
+    let mut freed = 0;
+    while freed < wanted {
+        // Pages are gathered first, as the cache can't change while iterated.
+        let limit = BATCH.min(wanted - freed);
+        let mut batch = [0; BATCH];
+        let mut len = 0;
+        for (pn, page) in cache.iter().rev() {
+            // Pages are in the order of their accesses.
+            if len == limit || page.accessed >= before {
+                break;
+            }
+            if !page.dirty {
+                batch[len] = *pn;
+                len += 1;
+            }
+        }
+        for pn in &batch[..len] {
+            cache.pop(pn);
+        }
+        freed += len;
+        if len < limit {
+            break;
+        }
+    }
+    freed
+}
+
+/// Drops up to `wanted` clean pages of the page cache, and returns the number
+/// of pages dropped.
+///
+/// This never blocks nor allocates, so that it can serve as the
+/// [shrinker](axalloc::GlobalAllocator::set_shrinker) of the allocator: files
+/// in use are skipped.
+pub fn shrink_page_cache(wanted: usize) -> usize {
+    let Some(files) = FILES.try_lock() else {
+        return 0;
+    };
+    let mut freed = 0;
+    let mut hand = HAND.load(Ordering::Relaxed);
+    // Visiting every file twice goes round once fully after the hand wraps,
+    // when all pages not accessed meanwhile can be reclaimed.
+    for _ in 0..2 * files.len() {
+        if freed >= wanted {
+            break;
+        }
+        if hand >= files.len() {
+            hand = 0;
+            SWEEP.store(tick(), Ordering::Relaxed);
+        }
+        if let Some(shared) = files[hand].upgrade() {
+            freed += evict(&shared, wanted - freed, SWEEP.load(Ordering::Relaxed));
+        }
+        hand += 1;
+    }
+    HAND.store(hand, Ordering::Relaxed);
+    freed
+}
@@ -0,0 +1,145 @@
+//! Sparse files: preallocation, hole punching and seeking to data or holes.
+//!
+//! Filesystems tracking holes implement [`SparseNode`]. On the others, the
+//! whole file is data: holes can't be punched, zeroing a range writes zeros,
+//! and preallocation only happens through growing the file. ext4 is one of
+//! them for now, as its extents are neither walked nor trimmed here.
+
+use alloc::{sync::Arc, vec};
+use core::ops::Range;
+
+use axerrno::LinuxError;
+use axfs_ng_vfs::{FileNode, Location, VfsError, VfsResult};
+
+use super::xattr::downcast;
+
+/// Size of the buffer of zeros written to zero a range.
+const ZERO_CHUNK: usize = 0x4000;
+
+bitflags::bitflags! {
+    /// Modes of [`File::allocate`](super::File::allocate), with the same
+    /// values as the flags of `fallocate`.
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    pub struct AllocateMode: u32 {
+        /// Don't grow the file past its size.
+        const KEEP_SIZE = 0x1;
+        /// Deallocate the range, which then reads as zeros. Requires
+        /// `KEEP_SIZE`, and a filesystem tracking holes.
+        const PUNCH_HOLE = 0x2;
+        /// Zero the range, allocating it.
+        const ZERO_RANGE = 0x10;
+    }
+}
+
+impl AllocateMode {
+    pub(crate) fn validate(self, loc: &Location, range: &Range<u64>) -> VfsResult<()> {
+        if range.is_empty() {
+            return Err(VfsError::InvalidInput);
+        }
+        if self.contains(Self::PUNCH_HOLE) {
+            if self.contains(Self::ZERO_RANGE) {
+                return Err(VfsError::InvalidInput);
+            }
+            // Writing zeros instead would leave the blocks allocated.
+            if !self.contains(Self::KEEP_SIZE) || sparse_node(loc).is_none() {
+                return Err(VfsError::Unsupported);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Holes of a file, implemented by the filesystems tracking them.
+pub(crate) trait SparseNode: Send + Sync {
+    /// Allocates the blocks of `range` that are not yet, without changing
+    /// the size of the file.
+    fn allocate(&self, range: Range<u64>) -> VfsResult<()>;
+
+    /// Deallocates the blocks of `range`, which then reads as zeros. Partial
+    /// blocks at its ends are zeroed.
+    fn punch_hole(&self, range: Range<u64>) -> VfsResult<()>;
+
+    /// Returns the start of the first data at or after `offset`, if any
+    /// before the end of the file.
+    fn next_data(&self, offset: u64) -> VfsResult<Option<u64>>;
+
+    /// Returns the start of the first hole at or after `offset`, the end of
+    /// the file counting as one.
+    fn next_hole(&self, offset: u64) -> VfsResult<u64>;
+}
+
+fn sparse_node(loc: &Location) -> Option<Arc<dyn SparseNode>> {
+    if let Some(inode) = downcast::<crate::fs::tmpfs::Inode>(loc) {
+        return Some(inode);
+    }
+    None
+}
+
+/// Zeroes `range` of the file at `loc`, below its end, deallocating it if the
+/// filesystem tracks holes.
+fn zero_range(loc: &Location, file: &FileNode, range: Range<u64>) -> VfsResult<()> {
+    let range = range.start..range.end.min(file.len()?);
+    if range.is_empty() {
+        return Ok(());
+    }
+    if let Some(node) = sparse_node(loc) {
+        return node.punch_hole(range);
+    }
+    let zeros = vec![0; ZERO_CHUNK];
+    let mut offset = range.start;
+    while offset < range.end {
+        let len = ((range.end - offset) as usize).min(ZERO_CHUNK);
+        offset += file.write_at(&zeros[..len], offset)? as u64;
+    }
+    Ok(())
+}
+
+/// Applies `mode` to `range` of the file at `loc`, bypassing the page cache,
+/// but doesn't grow the file.
+pub(crate) fn allocate(
+    loc: &Location,
+    file: &FileNode,
+    mode: AllocateMode,
+    range: Range<u64>,
+) -> VfsResult<()> {
+    if mode.intersects(AllocateMode::PUNCH_HOLE | AllocateMode::ZERO_RANGE) {
+        zero_range(loc, file, range.clone())?;
+    }
+    if !mode.contains(AllocateMode::PUNCH_HOLE) {
+        // Elsewhere, blocks are allocated as the file grows.
+        if let Some(node) = sparse_node(loc) {
+            node.allocate(range)?;
+        }
+    }
+    Ok(())
+}
+
+/// Error of seeking past the end of a file, `ENXIO`.
+fn past_end() -> VfsError {
+    VfsError::Other(LinuxError::ENXIO)
+}
+
+/// Returns the start of the first data at or after `offset`, like
+/// `SEEK_DATA`.
+pub(crate) fn seek_data(loc: &Location, offset: u64) -> VfsResult<u64> {
+    if offset >= loc.len()? {
+        return Err(past_end());
+    }
+    match sparse_node(loc) {
+        Some(node) => node.next_data(offset)?.ok_or_else(past_end),
+        None => Ok(offset),
+    }
+}
+
+/// Returns the start of the first hole at or after `offset`, like
+/// `SEEK_HOLE`.
+pub(crate) fn seek_hole(loc: &Location, offset: u64) -> VfsResult<u64> {
+    let len = loc.len()?;
+    if offset >= len {
+        return Err(past_end());
+    }
+    match sparse_node(loc) {
+        Some(node) => Ok(node.next_hole(offset)?.min(len)),
+        None => Ok(len),
+    }
+}
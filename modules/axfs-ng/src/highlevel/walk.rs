@@ -0,0 +1,547 @@
+//! Recursive operations over directory trees.
+
+use alloc::{
+    borrow::ToOwned,
+    collections::vec_deque::VecDeque,
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+
+use axfs_ng_vfs::{
+    Location, MetadataUpdate, NodePermission, NodeType, VfsError, VfsResult,
+    path::{Component, Path},
+};
+
+use super::{Access, FsContext, OpenOptions, copy_file_range, mount::same_location};
+
+/// Order in which a [`WalkDir`] yields the entries of a tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalkOrder {
+    /// Each directory is followed by its whole subtree.
+    DepthFirst,
+    /// All entries at a depth come before those deeper.
+    BreadthFirst,
+}
+
+/// Options to walk a directory tree with.
+#[derive(Debug, Clone)]
+pub struct WalkOptions {
+    order: WalkOrder,
+    follow_symlinks: bool,
+    max_depth: usize,
+}
+
+impl WalkOptions {
+    /// Creates options to walk a whole tree depth-first, without following
+    /// symlinks.
+    pub fn new() -> Self {
+        Self {
+            order: WalkOrder::DepthFirst,
+            follow_symlinks: false,
+            max_depth: usize::MAX,
+        }
+    }
+
+    pub fn order(&mut self, order: WalkOrder) -> &mut Self {
+        self.order = order;
+        self
+    }
+
+    /// Sets the option to descend into the directories symlinks point to,
+    /// which are then yielded in place of the symlinks.
+    pub fn follow_symlinks(&mut self, follow_symlinks: bool) -> &mut Self {
+        self.follow_symlinks = follow_symlinks;
+        self
+    }
+
+    /// Sets the depth below which entries are not yielded, the starting
+    /// directory being at depth 0.
+    pub fn max_depth(&mut self, max_depth: usize) -> &mut Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Walks the tree at `path`.
+    pub fn walk(&self, cx: &FsContext, path: &str) -> VfsResult<WalkDir> {
+        let root = if self.follow_symlinks {
+            cx.resolve(path)?
+        } else {
+            cx.resolve_no_follow(path)?
+        };
+        Ok(self.walk_at(cx, path.to_owned(), root))
+    }
+
+    fn walk_at(&self, cx: &FsContext, path: String, root: Location) -> WalkDir {
+        WalkDir {
+            cx: cx.clone(),
+            options: self.clone(),
+            pending: VecDeque::from([Ok(WalkEntry {
+                path,
+                location: root,
+                depth: 0,
+                ancestors: Vec::new(),
+            })]),
+        }
+    }
+}
+
+impl Default for WalkOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// An entry yielded by a [`WalkDir`].
+pub struct WalkEntry {
+    /// Path of the entry, starting with the one the walk started from.
+    pub path: String,
+    pub location: Location,
+    pub depth: usize,
+    /// Directories above the entry, when following symlinks.
+    ancestors: Vec<Location>,
+}
+
+/// Iterator over the entries of a directory tree, including its root.
+///
+/// Errors reading a directory are yielded after the directory itself, and
+/// the walk goes on with the other ones. A symlink leading to a directory
+/// above it yields [`VfsError::FilesystemLoop`].
+pub struct WalkDir {
+    cx: FsContext,
+    options: WalkOptions,
+    pending: VecDeque<VfsResult<WalkEntry>>,
+}
+
+impl WalkDir {
+    fn children(&self, entry: &WalkEntry) -> VfsResult<Vec<VfsResult<WalkEntry>>> {
+        let dir = &entry.location;
+        let mut ancestors = entry.ancestors.clone();
+        if self.options.follow_symlinks {
+            ancestors.push(dir.clone());
+        }
+        let names = self.cx.read_dir_at(dir.clone())?;
+        let mut children = Vec::new();
+        for name in names {
+            let name = name?.name;
+            if name == "." || name == ".." {
+                continue;
+            }
+            let child = if self.options.follow_symlinks {
+                self.follow(dir, &name, &ancestors)
+            } else {
+                self.cx
+                    .check_access(dir, Access::EXECUTE)
+                    .and_then(|_| dir.lookup_no_follow(&name))
+            };
+            children.push(child.map(|location| WalkEntry {
+                path: join(&entry.path, &name),
+                location,
+                depth: entry.depth + 1,
+                ancestors: ancestors.clone(),
+            }));
+        }
+        Ok(children)
+    }
+
+    /// Looks up `name` in `dir`, following it if a symlink.
+    fn follow(&self, dir: &Location, name: &str, ancestors: &[Location]) -> VfsResult<Location> {
+        self.cx.check_access(dir, Access::EXECUTE)?;
+        let loc = dir.lookup_no_follow(name)?;
+        let loc = match self
+            .cx
+            .with_current_dir(dir.clone())?
+            .try_resolve_symlink(loc.clone(), &mut 0)
+        {
+            Ok(target) => target,
+            // Dangling symlinks are yielded as such.
+            Err(VfsError::NotFound) => loc,
+            Err(err) => return Err(err),
+        };
+        if loc.is_dir() && ancestors.iter().any(|it| same_location(it, &loc)) {
+            return Err(VfsError::FilesystemLoop);
+        }
+        Ok(loc)
+    }
+}
+
+impl Iterator for WalkDir {
+    type Item = VfsResult<WalkEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = match self.pending.pop_front()? {
+            Ok(entry) => entry,
+            Err(err) => return Some(Err(err)),
+        };
+        if entry.location.is_dir() && entry.depth < self.options.max_depth {
+            match self.children(&entry) {
+                Ok(children) => match self.options.order {
+                    WalkOrder::DepthFirst => {
+                        for child in children.into_iter().rev() {
+                            self.pending.push_front(child);
+                        }
+                    }
+                    WalkOrder::BreadthFirst => self.pending.extend(children),
+                },
+                Err(err) => self.pending.push_front(Err(err)),
+            }
+        }
+        Some(Ok(entry))
+    }
+}
+
+fn join(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_owned()
+    } else if dir.ends_with('/') {
+        format!("{dir}{name}")
+    } else {
+        format!("{dir}/{name}")
+    }
+}
+
+/// Returns whether `name` matches the shell wildcard `pattern`.
+///
+/// `*` matches any string, `?` any character, `[...]` any character of a
+/// set of characters and ranges, or of its complement if it starts with `!`
+/// or `^`. A `\` makes the next character match itself only.
+pub fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let name = name.chars().collect::<Vec<_>>();
+    let (mut p, mut n) = (0, 0);
+    // Where to resume after the last `*` if the rest does not match: the
+    // pattern past it, and the name past one more character.
+    let mut star = None;
+    while n < name.len() {
+        let next = match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, n));
+                p += 1;
+                continue;
+            }
+            Some('?') => Some(p + 1),
+            Some('[') => match match_class(&pattern[p + 1..], name[n]) {
+                Some((matched, len)) => matched.then_some(p + 1 + len),
+                // Unterminated, so a plain `[`.
+                None => (name[n] == '[').then_some(p + 1),
+            },
+            Some('\\') if p + 1 < pattern.len() => (pattern[p + 1] == name[n]).then_some(p + 2),
+            Some(&c) => (c == name[n]).then_some(p + 1),
+            None => None,
+        };
+        match (next, star) {
+            (Some(next), _) => {
+                p = next;
+                n += 1;
+            }
+            (None, Some((star_p, star_n))) => {
+                p = star_p;
+                n = star_n + 1;
+                star = Some((star_p, n));
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Matches `c` against the set at the start of `class`, just past its `[`.
+///
+/// Returns whether it matches and the length of the set up to its `]`, or
+/// `None` if there is no `]`.
+fn match_class(class: &[char], c: char) -> Option<(bool, usize)> {
+    let negated = matches!(class.first(), Some('!' | '^'));
+    let mut i = negated as usize;
+    let start = i;
+    let mut matched = false;
+    loop {
+        let lo = *class.get(i)?;
+        // A `]` right at the start is part of the set.
+        if lo == ']' && i > start {
+            return Some((matched != negated, i + 1));
+        }
+        match (class.get(i + 1), class.get(i + 2)) {
+            (Some('-'), Some(&hi)) if hi != ']' => {
+                matched |= (lo..=hi).contains(&c);
+                i += 3;
+            }
+            _ => {
+                matched |= lo == c;
+                i += 1;
+            }
+        }
+    }
+}
+
+fn has_wildcards(component: &str) -> bool {
+    component.contains(['*', '?', '['])
+}
+
+fn unescape(component: &str) -> String {
+    let mut result = String::new();
+    let mut chars = component.chars();
+    while let Some(c) = chars.next() {
+        result.push(if c == '\\' {
+            chars.next().unwrap_or(c)
+        } else {
+            c
+        });
+    }
+    result
+}
+
+impl FsContext {
+    /// Creates a directory and all of its missing parents, like `mkdir -p`.
+    ///
+    /// Returns the directory, which may have existed already.
+    pub fn create_dir_all(
+        &self,
+        path: impl AsRef<Path>,
+        mode: NodePermission,
+    ) -> VfsResult<Location> {
+        let mut dir = self.current_dir().clone();
+        let mut follow_count = 0;
+        for comp in path.as_ref().components() {
+            dir = match comp {
+                Component::CurDir => dir,
+                Component::ParentDir => self.parent_dir(&dir),
+                Component::RootDir => self.root_dir().clone(),
+                Component::Normal(name) => match self.lookup(&dir, name, &mut follow_count) {
+                    Ok(loc) => loc,
+                    Err(VfsError::NotFound) => match self.create_dir_at(&dir, name, mode) {
+                        // Created by someone else in the meantime.
+                        Err(VfsError::AlreadyExists) => {
+                            self.lookup(&dir, name, &mut follow_count)?
+                        }
+                        result => result?,
+                    },
+                    Err(err) => return Err(err),
+                },
+            };
+            dir.check_is_dir()?;
+        }
+        Ok(dir)
+    }
+
+    /// Removes a directory along with all of its contents, like `rm -r`.
+    ///
+    /// Symlinks are removed, not followed.
+    pub fn remove_dir_all(&self, path: impl AsRef<Path>) -> VfsResult<()> {
+        let root = self.resolve_no_follow(path)?;
+        root.check_is_dir()?;
+        // Directories being emptied, the deepest last.
+        let mut stack = vec![root];
+        while let Some(dir) = stack.last().cloned() {
+            let entries = self
+                .read_dir_at(dir.clone())?
+                .collect::<VfsResult<Vec<_>>>()?;
+            let mut subdirs = Vec::new();
+            for entry in entries {
+                if entry.name == "." || entry.name == ".." {
+                    continue;
+                }
+                self.check_access(&dir, Access::EXECUTE)?;
+                let loc = dir.lookup_no_follow(&entry.name)?;
+                if loc.is_dir() {
+                    subdirs.push(loc);
+                } else {
+                    self.remove_entry(&loc, false)?;
+                }
+            }
+            if subdirs.is_empty() {
+                self.remove_entry(&dir, true)?;
+                stack.pop();
+            } else {
+                stack.extend(subdirs);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns an iterator over the tree at `path`, walked with the default
+    /// [`WalkOptions`].
+    pub fn walk_dir(&self, path: &str) -> VfsResult<WalkDir> {
+        WalkOptions::new().walk(self, path)
+    }
+
+    /// Copies the file or tree at `from` to `to`, which must not exist, like
+    /// `cp -a`.
+    ///
+    /// Symlinks are copied as symlinks. Permissions and times are preserved,
+    /// and so are owners if the context is that of the superuser. Hard links
+    /// are copied as separate files.
+    pub fn copy_tree(&self, from: impl AsRef<Path>, to: impl AsRef<Path>) -> VfsResult<()> {
+        let src = self.resolve_no_follow(from)?;
+        let (dst_dir, dst_name) = self.resolve_nonexistent(to.as_ref())?;
+        if dst_dir.lookup_no_follow(dst_name).is_ok() {
+            return Err(VfsError::AlreadyExists);
+        }
+        // A tree copied into itself would never end.
+        let mut cur = Some(dst_dir.clone());
+        while let Some(loc) = cur {
+            if same_location(&loc, &src) {
+                return Err(VfsError::InvalidInput);
+            }
+            cur = loc.parent();
+        }
+
+        // Directories are made writable until their entries are copied, so
+        // their metadata is copied last.
+        let mut dirs = Vec::new();
+        let mut pending = vec![(src, dst_dir, dst_name.to_owned())];
+        while let Some((src, dst_dir, name)) = pending.pop() {
+            let dst = self.copy_node(&src, &dst_dir, &name)?;
+            if src.is_dir() {
+                for entry in self.read_dir_at(src.clone())? {
+                    let name = entry?.name;
+                    if name == "." || name == ".." {
+                        continue;
+                    }
+                    self.check_access(&src, Access::EXECUTE)?;
+                    pending.push((src.lookup_no_follow(&name)?, dst.clone(), name));
+                }
+                dirs.push((src, dst));
+            } else {
+                self.copy_metadata(&src, &dst)?;
+            }
+        }
+        for (src, dst) in dirs.iter().rev() {
+            self.copy_metadata(src, dst)?;
+        }
+        Ok(())
+    }
+
+    /// Creates a copy of `src` named `name` in `dir`, without the entries of
+    /// directories.
+    fn copy_node(&self, src: &Location, dir: &Location, name: &str) -> VfsResult<Location> {
+        let mode = NodePermission::from_bits_truncate(0o700);
+        match src.node_type() {
+            NodeType::Directory => self.create_at(dir, name, NodeType::Directory, mode),
+            NodeType::Symlink => self.symlink_at(dir, name, &src.read_link()?),
+            NodeType::RegularFile => {
+                self.check_access(src, Access::READ)?;
+                let dst = self.create_at(dir, name, NodeType::RegularFile, mode)?;
+                let src = OpenOptions::new().read(true).open_loc(src.clone())?;
+                let dst_file = OpenOptions::new().write(true).open_loc(dst.clone())?;
+                let (src, dst_file) = (src.into_file()?, dst_file.into_file()?);
+                while copy_file_range(&src, None, &dst_file, None, usize::MAX)? > 0 {}
+                Ok(dst)
+            }
+            node_type => self.create_at(dir, name, node_type, mode),
+        }
+    }
+
+    fn copy_metadata(&self, src: &Location, dst: &Location) -> VfsResult<()> {
+        let metadata = src.metadata()?;
+        let mut update = MetadataUpdate::default();
+        if metadata.node_type != NodeType::Symlink {
+            let mut mode = metadata.mode.bits();
+            if !self.credentials().is_root() {
+                // As the owner changes, so would the user these run as.
+                mode &= !0o6000;
+            }
+            update.mode = Some(NodePermission::from_bits_truncate(mode));
+        }
+        if self.credentials().is_root() {
+            update.owner = Some((metadata.uid, metadata.gid));
+        }
+        update.atime = Some(metadata.atime);
+        update.mtime = Some(metadata.mtime);
+        dst.update_metadata(update)
+    }
+
+    /// Returns the paths matching `pattern`, sorted.
+    ///
+    /// Each component of the pattern is matched by [`glob_matches`], except
+    /// `**` which matches any number of directories. Wildcards only match
+    /// names starting with `.` if the component does too. Directories which
+    /// cannot be read are skipped, as by shells.
+    pub fn glob(&self, pattern: &str) -> VfsResult<Vec<String>> {
+        let (start, rest) = match pattern.strip_prefix('/') {
+            Some(rest) => ("/", rest),
+            None => ("", pattern),
+        };
+        let start_dir = if start.is_empty() {
+            self.current_dir().clone()
+        } else {
+            self.root_dir().clone()
+        };
+        let mut matches = vec![(start.to_owned(), start_dir)];
+        let components = rest
+            .split('/')
+            .filter(|it| !it.is_empty())
+            .collect::<Vec<_>>();
+        for (i, &comp) in components.iter().enumerate() {
+            let last = i + 1 == components.len();
+            let mut next = Vec::new();
+            for (path, dir) in matches {
+                if !dir.is_dir() {
+                    continue;
+                }
+                match comp {
+                    "." => next.push((join(&path, comp), dir)),
+                    ".." => {
+                        let parent = self.parent_dir(&dir);
+                        next.push((join(&path, comp), parent));
+                    }
+                    "**" => {
+                        let mut walk = WalkOptions::new().walk_at(self, path.clone(), dir);
+                        // The first entry is the directory itself.
+                        if let Some(Ok(entry)) = walk.next() {
+                            next.push((path.clone(), entry.location));
+                        }
+                        for entry in walk.flatten() {
+                            let hidden = entry.path[path.len()..]
+                                .split('/')
+                                .any(|it| it.starts_with('.'));
+                            if !hidden && (last || entry.location.is_dir()) {
+                                next.push((entry.path, entry.location));
+                            }
+                        }
+                    }
+                    _ if !has_wildcards(comp) => {
+                        let name = unescape(comp);
+                        if let Ok(loc) = self.lookup(&dir, &name, &mut 0) {
+                            next.push((join(&path, &name), loc));
+                        }
+                    }
+                    _ => {
+                        let Ok(entries) = self.read_dir_at(dir.clone()) else {
+                            continue;
+                        };
+                        for entry in entries.flatten() {
+                            let name = entry.name;
+                            if name == "." || name == ".." {
+                                continue;
+                            }
+                            if name.starts_with('.') && !comp.starts_with('.') {
+                                continue;
+                            }
+                            if !glob_matches(comp, &name) {
+                                continue;
+                            }
+                            if let Ok(loc) = self.lookup(&dir, &name, &mut 0) {
+                                next.push((join(&path, &name), loc));
+                            }
+                        }
+                    }
+                }
+            }
+            matches = next;
+        }
+        let mut paths = matches
+            .into_iter()
+            .map(|(path, _)| {
+                if path.is_empty() {
+                    ".".to_string()
+                } else {
+                    path
+                }
+            })
+            .collect::<Vec<_>>();
+        paths.sort();
+        paths.dedup();
+        Ok(paths)
+    }
+}
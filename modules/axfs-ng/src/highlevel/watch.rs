@@ -0,0 +1,314 @@
+//! File change notification, like inotify.
+//!
+//! A [`Watcher`] registers interest in locations, and queues the events
+//! happening to them, or to the entries of watched directories. Events are
+//! raised by the highlevel operations of [`FsContext`](super::FsContext) and
+//! [`File`](super::File), so they work the same on all filesystems, but
+//! changes made below them, e.g. by the filesystem itself, go unnoticed.
+
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    string::{String, ToString},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
+    task::Context,
+};
+
+use axfs_ng_vfs::{Location, VfsError, VfsResult};
+use axio::{IoEvents, PollSet, Pollable};
+use spin::Mutex;
+
+/// Maximum number of events queued by a watcher before it overflows.
+const MAX_QUEUED_EVENTS: usize = 16384;
+
+bitflags::bitflags! {
+    /// Kinds of events, with the same values as inotify.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct WatchMask: u32 {
+        /// The file was written to or truncated.
+        const MODIFY = 0x2;
+        /// The metadata of the file changed.
+        const ATTRIB = 0x4;
+        /// The file was closed after being opened for writing.
+        const CLOSE_WRITE = 0x8;
+        /// An entry was moved out of the directory.
+        const MOVED_FROM = 0x40;
+        /// An entry was moved into the directory.
+        const MOVED_TO = 0x80;
+        /// An entry was created in the directory.
+        const CREATE = 0x100;
+        /// An entry was removed from the directory.
+        const DELETE = 0x200;
+
+        /// Events were dropped because the queue was full.
+        const Q_OVERFLOW = 0x4000;
+        /// The watch was removed.
+        const IGNORED = 0x8000;
+        /// The subject of the event is a directory.
+        const IS_DIR = 0x4000_0000;
+
+        const MOVE = Self::MOVED_FROM.bits() | Self::MOVED_TO.bits();
+        const ALL_EVENTS = Self::MODIFY.bits()
+            | Self::ATTRIB.bits()
+            | Self::CLOSE_WRITE.bits()
+            | Self::MOVE.bits()
+            | Self::CREATE.bits()
+            | Self::DELETE.bits();
+    }
+}
+
+/// An event queued by a [`Watcher`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatchEvent {
+    /// Watch descriptor returned by [`Watcher::add_watch`].
+    pub wd: u32,
+    pub mask: WatchMask,
+    /// Identifies the pair of `MOVED_FROM` and `MOVED_TO` events of a rename,
+    /// 0 for other events.
+    pub cookie: u32,
+    /// Name of the entry, for events on entries of a watched directory.
+    pub name: Option<String>,
+}
+
+/// Number of watches, to skip notifying when there are none.
+static WATCHES: AtomicUsize = AtomicUsize::new(0);
+
+static NEXT_COOKIE: AtomicU32 = AtomicU32::new(1);
+
+struct Queue {
+    events: Mutex<VecDeque<WatchEvent>>,
+    poll: PollSet,
+}
+
+impl Queue {
+    fn push(&self, event: WatchEvent) {
+        let mut events = self.events.lock();
+        if events.back() == Some(&event) {
+            // Coalesce identical events, like inotify.
+            return;
+        }
+        if events.len() >= MAX_QUEUED_EVENTS {
+            if events
+                .back()
+                .is_some_and(|it| it.mask != WatchMask::Q_OVERFLOW)
+            {
+                events.push_back(WatchEvent {
+                    wd: u32::MAX,
+                    mask: WatchMask::Q_OVERFLOW,
+                    cookie: 0,
+                    name: None,
+                });
+            }
+        } else {
+            events.push_back(event);
+        }
+        drop(events);
+        self.poll.wake();
+    }
+}
+
+struct WatchEntry {
+    queue: Weak<Queue>,
+    wd: u32,
+    mask: WatchMask,
+}
+
+/// Watches of a location, stored in its user data.
+#[derive(Default)]
+struct Watches(Mutex<Vec<WatchEntry>>);
+
+impl Watches {
+    fn get(loc: &Location) -> Option<Arc<Self>> {
+        loc.user_data().get::<Arc<Self>>().cloned()
+    }
+
+    fn get_or_create(loc: &Location) -> Arc<Self> {
+        let mut guard = loc.user_data();
+        if let Some(watches) = guard.get::<Arc<Self>>() {
+            return watches.clone();
+        }
+        let watches = Arc::new(Self::default());
+        guard.insert(watches.clone());
+        watches
+    }
+}
+
+/// A queue of events happening to watched locations.
+pub struct Watcher {
+    queue: Arc<Queue>,
+    /// Watched locations, kept alive until their watch is removed.
+    watches: Mutex<BTreeMap<u32, Location>>,
+    next_wd: AtomicU32,
+}
+
+impl Watcher {
+    pub fn new() -> Self {
+        Self {
+            queue: Arc::new(Queue {
+                events: Mutex::new(VecDeque::new()),
+                poll: PollSet::new(),
+            }),
+            watches: Mutex::new(BTreeMap::new()),
+            next_wd: AtomicU32::new(1),
+        }
+    }
+
+    /// Watches `loc` for the events in `mask`, and returns the watch
+    /// descriptor identifying its events.
+    ///
+    /// If `loc` is already watched, its mask is replaced and the same watch
+    /// descriptor is returned.
+    pub fn add_watch(&self, loc: &Location, mask: WatchMask) -> VfsResult<u32> {
+        let mask = mask & WatchMask::ALL_EVENTS;
+        if mask.is_empty() {
+            return Err(VfsError::InvalidInput);
+        }
+        let watches = Watches::get_or_create(loc);
+        let mut entries = watches.0.lock();
+        let queue = Arc::downgrade(&self.queue);
+        if let Some(entry) = entries.iter_mut().find(|it| it.queue.ptr_eq(&queue)) {
+            entry.mask = mask;
+            return Ok(entry.wd);
+        }
+        let wd = self.next_wd.fetch_add(1, Ordering::Relaxed);
+        entries.push(WatchEntry { queue, wd, mask });
+        self.watches.lock().insert(wd, loc.clone());
+        WATCHES.fetch_add(1, Ordering::Relaxed);
+        Ok(wd)
+    }
+
+    fn detach(&self, wd: u32, loc: &Location) {
+        // Watch descriptors are only unique within a watcher.
+        let queue = Arc::downgrade(&self.queue);
+        if let Some(watches) = Watches::get(loc) {
+            watches
+                .0
+                .lock()
+                .retain(|it| it.wd != wd || !it.queue.ptr_eq(&queue));
+        }
+        WATCHES.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Removes a watch, queuing an [`IGNORED`](WatchMask::IGNORED) event.
+    pub fn remove_watch(&self, wd: u32) -> VfsResult<()> {
+        let loc = self
+            .watches
+            .lock()
+            .remove(&wd)
+            .ok_or(VfsError::InvalidInput)?;
+        self.detach(wd, &loc);
+        self.queue.push(WatchEvent {
+            wd,
+            mask: WatchMask::IGNORED,
+            cookie: 0,
+            name: None,
+        });
+        Ok(())
+    }
+
+    /// Takes the oldest queued event, if any.
+    pub fn read_event(&self) -> Option<WatchEvent> {
+        self.queue.events.lock().pop_front()
+    }
+}
+
+impl Default for Watcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        for (wd, loc) in core::mem::take(self.watches.get_mut()) {
+            self.detach(wd, &loc);
+        }
+    }
+}
+
+impl Pollable for Watcher {
+    fn poll(&self) -> IoEvents {
+        let mut events = IoEvents::empty();
+        events.set(IoEvents::IN, !self.queue.events.lock().is_empty());
+        events
+    }
+
+    fn register(&self, context: &mut Context<'_>, events: IoEvents) {
+        if events.contains(IoEvents::IN) {
+            self.queue.poll.register(context.waker());
+        }
+    }
+}
+
+/// Queues an event for the watchers of `loc`.
+fn notify(loc: &Location, mask: WatchMask, name: Option<&str>, cookie: u32) {
+    let Some(watches) = Watches::get(loc) else {
+        return;
+    };
+    for entry in watches.0.lock().iter() {
+        if !entry.mask.intersects(mask) {
+            continue;
+        }
+        if let Some(queue) = entry.queue.upgrade() {
+            queue.push(WatchEvent {
+                wd: entry.wd,
+                mask,
+                cookie,
+                name: name.map(ToString::to_string),
+            });
+        }
+    }
+}
+
+/// Queues an event happening to `entry`, for its watchers and those of its
+/// parent directory.
+pub fn notify_entry(entry: &Location, mask: WatchMask) {
+    if !watching() {
+        return;
+    }
+    let mask = if entry.is_dir() {
+        mask | WatchMask::IS_DIR
+    } else {
+        mask
+    };
+    notify(entry, mask, None, 0);
+    if let Some(parent) = entry.parent() {
+        notify(&parent, mask, Some(entry.name()), 0);
+    }
+}
+
+/// Queues the events of a rename, once it is done.
+pub(crate) fn notify_rename(
+    src_dir: &Location,
+    src_name: &str,
+    dst_dir: &Location,
+    dst_name: &str,
+) {
+    if !watching() {
+        return;
+    }
+    let is_dir = dst_dir
+        .lookup_no_follow(dst_name)
+        .is_ok_and(|it| it.is_dir());
+    let flags = if is_dir {
+        WatchMask::IS_DIR
+    } else {
+        WatchMask::empty()
+    };
+    let cookie = NEXT_COOKIE.fetch_add(1, Ordering::Relaxed);
+    notify(
+        src_dir,
+        WatchMask::MOVED_FROM | flags,
+        Some(src_name),
+        cookie,
+    );
+    notify(dst_dir, WatchMask::MOVED_TO | flags, Some(dst_name), cookie);
+}
+
+/// Returns whether any location is watched.
+fn watching() -> bool {
+    WATCHES.load(Ordering::Relaxed) != 0
+}
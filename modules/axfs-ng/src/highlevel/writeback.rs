@@ -0,0 +1,151 @@
+//! Write-back of the dirty pages of the page cache.
+//!
+//! Files with dirty pages are tracked here until they are clean again. A
+//! kernel task is expected to call [`writeback_expired`] every
+//! [`interval`](WritebackConfig::interval), and [`sync_all`] before the system
+//! is powered off.
+
+use alloc::{
+    collections::BTreeMap,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use axalloc::global_allocator;
+use axfs_ng_vfs::{Location, VfsResult};
+use log::warn;
+use spin::Mutex;
+
+use super::{CachedFile, CachedFileShared, mount::mount_roots};
+
+/// Tunables of the write-back, like the `vm.dirty_*` sysctls of Linux.
+#[derive(Debug, Clone, Copy)]
+pub struct WritebackConfig {
+    /// How often the write-back task should run.
+    pub interval: Duration,
+    /// Age after which dirty pages are written back.
+    pub dirty_expire: Duration,
+    /// Percentage of the memory that dirty pages may take before writers
+    /// write back their own pages.
+    pub dirty_ratio: usize,
+}
+
+static CONFIG: Mutex<WritebackConfig> = Mutex::new(WritebackConfig {
+    interval: Duration::from_secs(5),
+    dirty_expire: Duration::from_secs(30),
+    dirty_ratio: 20,
+});
+
+static DIRTY_PAGES: AtomicUsize = AtomicUsize::new(0);
+
+/// Files with dirty pages, by address of their shared state.
+///
+/// They are held weakly, so that the last handle to a file still writes it
+/// back when closed.
+static DIRTY_FILES: Mutex<BTreeMap<usize, (Weak<CachedFileShared>, Location)>> =
+    Mutex::new(BTreeMap::new());
+
+/// Returns the current write-back tunables.
+pub fn writeback_config() -> WritebackConfig {
+    *CONFIG.lock()
+}
+
+/// Sets the write-back tunables.
+pub fn set_writeback_config(config: WritebackConfig) {
+    *CONFIG.lock() = config;
+}
+
+/// Returns the number of dirty pages in the page cache.
+pub fn dirty_pages() -> usize {
+    DIRTY_PAGES.load(Ordering::Relaxed)
+}
+
+pub(crate) fn account_dirty(dirty: bool) {
+    if dirty {
+        DIRTY_PAGES.fetch_add(1, Ordering::Relaxed);
+    } else {
+        DIRTY_PAGES.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Returns whether dirty pages take more memory than allowed.
+pub(crate) fn over_dirty_limit() -> bool {
+    let allocator = global_allocator();
+    let total = allocator.used_pages() + allocator.available_pages();
+    dirty_pages() > total * CONFIG.lock().dirty_ratio / 100
+}
+
+fn key(shared: &Arc<CachedFileShared>) -> usize {
+    Arc::as_ptr(shared) as usize
+}
+
+/// Tracks a file whose page just became dirty.
+///
+/// Must be called with its page cache locked, as well as [`untrack`].
+pub(crate) fn track(file: &CachedFile) {
+    let entry = (Arc::downgrade(&file.shared), file.location().clone());
+    DIRTY_FILES.lock().insert(key(&file.shared), entry);
+}
+
+/// Stops tracking a file with no dirty page left.
+pub(crate) fn untrack(shared: &Arc<CachedFileShared>) {
+    DIRTY_FILES.lock().remove(&key(shared));
+}
+
+/// Returns handles to the tracked files that are still open and match
+/// `filter`, forgetting the closed ones.
+///
+/// The handles must be dropped with [`DIRTY_FILES`] unlocked, since dropping
+/// the last one writes the file back.
+fn dirty_files(filter: impl Fn(&Location) -> bool) -> Vec<CachedFile> {
+    let mut files = DIRTY_FILES.lock();
+    files.retain(|_, (shared, _)| shared.strong_count() > 0);
+    files
+        .values()
+        .filter(|(_, location)| filter(location))
+        .filter_map(|(shared, location)| {
+            Some(CachedFile::from_shared(location.clone(), shared.upgrade()?))
+        })
+        .collect()
+}
+
+fn writeback(before: Option<Duration>) {
+    for file in dirty_files(|_| true) {
+        if let Err(err) = file.write_back(before) {
+            warn!("Failed to write back dirty pages: {err:?}");
+        }
+    }
+}
+
+/// Writes back the dirty pages of the files in the filesystem mounted at
+/// `root`.
+pub(crate) fn write_back_mount(root: &Location) -> VfsResult<()> {
+    let files = dirty_files(|location| Arc::ptr_eq(location.mountpoint(), root.mountpoint()));
+    for file in files {
+        file.write_back(None)?;
+    }
+    Ok(())
+}
+
+/// Writes back the pages which have been dirty for longer than
+/// [`dirty_expire`](WritebackConfig::dirty_expire).
+pub fn writeback_expired() {
+    let expire = CONFIG.lock().dirty_expire;
+    if let Some(before) = axhal::time::monotonic_time().checked_sub(expire) {
+        writeback(Some(before));
+    }
+}
+
+/// Writes back all dirty pages, then flushes all mounted filesystems.
+pub fn sync_all() {
+    writeback(None);
+    for root in mount_roots() {
+        if let Err(err) = root.filesystem().flush() {
+            warn!("Failed to flush {}: {err:?}", root.filesystem().name());
+        }
+    }
+}
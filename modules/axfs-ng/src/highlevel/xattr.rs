@@ -0,0 +1,212 @@
+//! Extended attributes.
+//!
+//! The VFS has no notion of extended attributes, so they are reached by
+//! downcasting the node of a location to one of the filesystems supporting
+//! them. Other filesystems fail with [`VfsError::Unsupported`].
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+use core::any::Any;
+
+use axerrno::LinuxError;
+use axfs_ng_vfs::{Location, VfsError, VfsResult, path::Path};
+
+use super::{Access, FsContext, WatchMask, mount::check_writable, notify_entry};
+
+/// Maximum length of the name of an extended attribute.
+pub const XATTR_NAME_MAX: usize = 255;
+/// Maximum size of the value of an extended attribute.
+pub const XATTR_SIZE_MAX: usize = 65536;
+
+/// Namespaces an attribute name must start with.
+const NAMESPACES: &[&str] = &["user.", "trusted.", "security.", "system."];
+
+bitflags::bitflags! {
+    /// Flags of [`XattrExt::set_xattr`], like those of `setxattr`.
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct XattrFlags: u32 {
+        /// Fail if the attribute exists.
+        const CREATE = 1;
+        /// Fail if the attribute does not exist.
+        const REPLACE = 2;
+    }
+}
+
+/// Error of a missing attribute, `ENODATA`.
+pub(crate) fn no_data() -> VfsError {
+    VfsError::Other(LinuxError::ENODATA)
+}
+
+/// Checks the flags of a `set_xattr` against whether the attribute exists.
+pub(crate) fn check_set_flags(exists: bool, flags: XattrFlags) -> VfsResult<()> {
+    if exists && flags.contains(XattrFlags::CREATE) {
+        Err(VfsError::AlreadyExists)
+    } else if !exists && flags.contains(XattrFlags::REPLACE) {
+        Err(no_data())
+    } else {
+        Ok(())
+    }
+}
+
+/// Extended attributes of a node, implemented by the filesystems supporting
+/// them. Names and values are checked beforehand.
+pub(crate) trait XattrNode: Send + Sync {
+    fn get_xattr(&self, name: &str) -> VfsResult<Vec<u8>>;
+
+    fn set_xattr(&self, name: &str, value: &[u8], flags: XattrFlags) -> VfsResult<()>;
+
+    fn list_xattr(&self) -> VfsResult<Vec<String>>;
+
+    fn remove_xattr(&self, name: &str) -> VfsResult<()>;
+}
+
+/// Returns the node of `loc` if it is a `T`.
+pub(crate) fn downcast<T: Any + Send + Sync>(loc: &Location) -> Option<Arc<T>> {
+    let entry = loc.entry();
+    match entry.as_dir() {
+        Ok(dir) => dir.downcast().ok(),
+        Err(_) => entry.as_file().ok()?.downcast().ok(),
+    }
+}
+
+fn xattr_node(loc: &Location) -> VfsResult<Arc<dyn XattrNode>> {
+    #[cfg(feature = "ext4")]
+    if let Some(inode) = downcast::<crate::fs::ext4::Inode>(loc) {
+        return Ok(inode);
+    }
+    if let Some(inode) = downcast::<crate::fs::tmpfs::Inode>(loc) {
+        return Ok(inode);
+    }
+    if let Some(node) = downcast::<crate::fs::overlayfs::OverlayNode>(loc) {
+        return Ok(node);
+    }
+    Err(VfsError::Unsupported)
+}
+
+fn check_name(name: &str) -> VfsResult<()> {
+    if name.is_empty() || name.len() > XATTR_NAME_MAX {
+        return Err(VfsError::Other(LinuxError::ERANGE));
+    }
+    if !NAMESPACES.iter().any(|it| name.starts_with(it)) {
+        return Err(VfsError::Unsupported);
+    }
+    Ok(())
+}
+
+/// Extended attribute operations on a [`Location`].
+pub trait XattrExt {
+    /// Returns the value of an attribute.
+    fn get_xattr(&self, name: &str) -> VfsResult<Vec<u8>>;
+
+    /// Sets the value of an attribute, creating it if needed unless `flags`
+    /// say otherwise.
+    fn set_xattr(&self, name: &str, value: &[u8], flags: XattrFlags) -> VfsResult<()>;
+
+    /// Returns the names of all attributes.
+    fn list_xattr(&self) -> VfsResult<Vec<String>>;
+
+    /// Removes an attribute.
+    fn remove_xattr(&self, name: &str) -> VfsResult<()>;
+}
+
+impl XattrExt for Location {
+    fn get_xattr(&self, name: &str) -> VfsResult<Vec<u8>> {
+        check_name(name)?;
+        xattr_node(self)?.get_xattr(name)
+    }
+
+    fn set_xattr(&self, name: &str, value: &[u8], flags: XattrFlags) -> VfsResult<()> {
+        check_name(name)?;
+        if value.len() > XATTR_SIZE_MAX {
+            return Err(VfsError::Other(LinuxError::E2BIG));
+        }
+        if flags.contains(XattrFlags::CREATE | XattrFlags::REPLACE) {
+            return Err(VfsError::InvalidInput);
+        }
+        check_writable(self)?;
+        xattr_node(self)?.set_xattr(name, value, flags)?;
+        notify_entry(self, WatchMask::ATTRIB);
+        Ok(())
+    }
+
+    fn list_xattr(&self) -> VfsResult<Vec<String>> {
+        xattr_node(self)?.list_xattr()
+    }
+
+    fn remove_xattr(&self, name: &str) -> VfsResult<()> {
+        check_name(name)?;
+        check_writable(self)?;
+        xattr_node(self)?.remove_xattr(name)?;
+        notify_entry(self, WatchMask::ATTRIB);
+        Ok(())
+    }
+}
+
+impl FsContext {
+    /// Returns the value of an attribute of the file.
+    pub fn get_xattr(&self, path: impl AsRef<Path>, name: &str) -> VfsResult<Vec<u8>> {
+        let loc = self.resolve(path)?;
+        self.check_access(&loc, Access::READ)?;
+        loc.get_xattr(name)
+    }
+
+    /// Like [`get_xattr`](Self::get_xattr), without following a final
+    /// symlink.
+    pub fn get_xattr_no_follow(&self, path: impl AsRef<Path>, name: &str) -> VfsResult<Vec<u8>> {
+        let loc = self.resolve_no_follow(path)?;
+        self.check_access(&loc, Access::READ)?;
+        loc.get_xattr(name)
+    }
+
+    /// Sets the value of an attribute of the file.
+    pub fn set_xattr(
+        &self,
+        path: impl AsRef<Path>,
+        name: &str,
+        value: &[u8],
+        flags: XattrFlags,
+    ) -> VfsResult<()> {
+        let loc = self.resolve(path)?;
+        self.check_access(&loc, Access::WRITE)?;
+        loc.set_xattr(name, value, flags)
+    }
+
+    /// Like [`set_xattr`](Self::set_xattr), without following a final
+    /// symlink.
+    pub fn set_xattr_no_follow(
+        &self,
+        path: impl AsRef<Path>,
+        name: &str,
+        value: &[u8],
+        flags: XattrFlags,
+    ) -> VfsResult<()> {
+        let loc = self.resolve_no_follow(path)?;
+        self.check_access(&loc, Access::WRITE)?;
+        loc.set_xattr(name, value, flags)
+    }
+
+    /// Returns the names of the attributes of the file.
+    pub fn list_xattr(&self, path: impl AsRef<Path>) -> VfsResult<Vec<String>> {
+        self.resolve(path)?.list_xattr()
+    }
+
+    /// Like [`list_xattr`](Self::list_xattr), without following a final
+    /// symlink.
+    pub fn list_xattr_no_follow(&self, path: impl AsRef<Path>) -> VfsResult<Vec<String>> {
+        self.resolve_no_follow(path)?.list_xattr()
+    }
+
+    /// Removes an attribute of the file.
+    pub fn remove_xattr(&self, path: impl AsRef<Path>, name: &str) -> VfsResult<()> {
+        let loc = self.resolve(path)?;
+        self.check_access(&loc, Access::WRITE)?;
+        loc.remove_xattr(name)
+    }
+
+    /// Like [`remove_xattr`](Self::remove_xattr), without following a final
+    /// symlink.
+    pub fn remove_xattr_no_follow(&self, path: impl AsRef<Path>, name: &str) -> VfsResult<()> {
+        let loc = self.resolve_no_follow(path)?;
+        self.check_access(&loc, Access::WRITE)?;
+        loc.remove_xattr(name)
+    }
+}
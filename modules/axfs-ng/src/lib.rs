@@ -4,9 +4,12 @@
 
 extern crate alloc;
 
+mod block_cache;
 mod disk;
 pub mod fs;
 mod highlevel;
+pub mod loop_device;
+pub mod partition;
 
 pub use highlevel::*;
 
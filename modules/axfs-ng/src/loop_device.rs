@@ -0,0 +1,210 @@
+//! Loop devices, which make block devices out of files.
+//!
+//! A filesystem image stored as a file on another filesystem is mounted by
+//! wrapping the file in a [`LoopDevice`], and that in a
+//! [`Partition`](crate::partition::Partition::from_loop):
+//!
+//! ```ignore
+//! let file = OpenOptions::new().read(true).write(true).open(cx, "/images/fat.img")?.into_file()?;
+//! let dev = LoopOptions::new().block_size(4096).open(file)?;
+//! let fs = fs::new_default(Partition::from_loop(dev))?;
+//! cx.mount("/images/fat.img", "/mnt", &fs, MountFlags::empty())?;
+//! ```
+
+use alloc::{format, string::String};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use axdriver::prelude::*;
+use axfs_ng_vfs::{VfsError, VfsResult};
+
+use crate::{File, FileFlags, disk::into_dev_err};
+
+/// Number given to the next loop device, as in `loop0`.
+static NEXT_LOOP_INDEX: AtomicUsize = AtomicUsize::new(0);
+
+/// Options to create a [`LoopDevice`] with, like those of `losetup`.
+#[derive(Debug, Clone)]
+pub struct LoopOptions {
+    block_size: usize,
+    offset: u64,
+    size_limit: Option<u64>,
+    read_only: bool,
+}
+
+impl LoopOptions {
+    /// Creates options for a writable device of 512-byte blocks covering the
+    /// whole file.
+    pub fn new() -> Self {
+        Self {
+            block_size: 512,
+            offset: 0,
+            size_limit: None,
+            read_only: false,
+        }
+    }
+
+    /// Sets the block size, a power of two from 512 to 4096.
+    pub fn block_size(&mut self, block_size: usize) -> &mut Self {
+        self.block_size = block_size;
+        self
+    }
+
+    /// Sets the offset of the device in the file.
+    pub fn offset(&mut self, offset: u64) -> &mut Self {
+        self.offset = offset;
+        self
+    }
+
+    /// Sets the maximum size of the device in bytes, which otherwise spans
+    /// until the end of the file.
+    pub fn size_limit(&mut self, size_limit: Option<u64>) -> &mut Self {
+        self.size_limit = size_limit;
+        self
+    }
+
+    /// Sets the option to reject writes to the device. It is read-only
+    /// anyway if the file is not open for writing.
+    pub fn read_only(&mut self, read_only: bool) -> &mut Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Creates a loop device backed by `file`.
+    ///
+    /// The size of the device is fixed from that of the file at this point,
+    /// rounded down to whole blocks.
+    pub fn open(&self, file: File) -> VfsResult<LoopDevice> {
+        if !self.block_size.is_power_of_two() || !(512..=4096).contains(&self.block_size) {
+            return Err(VfsError::InvalidInput);
+        }
+        file.access(FileFlags::READ)?;
+        if file.location().is_dir() {
+            return Err(VfsError::IsADirectory);
+        }
+        let len = file.location().len()?;
+        if self.offset > len {
+            return Err(VfsError::InvalidInput);
+        }
+        let mut size = len - self.offset;
+        if let Some(limit) = self.size_limit {
+            size = size.min(limit);
+        }
+
+        let read_only = self.read_only || !file.flags().contains(FileFlags::WRITE);
+        Ok(LoopDevice {
+            file,
+            name: format!("loop{}", NEXT_LOOP_INDEX.fetch_add(1, Ordering::Relaxed)),
+            block_size: self.block_size,
+            offset: self.offset,
+            num_blocks: size / self.block_size as u64,
+            read_only,
+        })
+    }
+}
+
+impl Default for LoopOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A block device whose blocks are stored in a file.
+///
+/// Accesses go through the page cache of the file, unless it was opened for
+/// direct I/O.
+pub struct LoopDevice {
+    file: File,
+    name: String,
+    block_size: usize,
+    offset: u64,
+    num_blocks: u64,
+    read_only: bool,
+}
+
+impl LoopDevice {
+    /// Returns the file the device is backed by.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Checks that the blocks accessed by a buffer of `len` bytes starting at
+    /// `block_id` lie within the device, and returns their offset in the
+    /// file.
+    fn file_offset(&self, block_id: u64, len: usize) -> DevResult<u64> {
+        if len % self.block_size != 0 {
+            return Err(DevError::InvalidParam);
+        }
+        let blocks = (len / self.block_size) as u64;
+        if block_id
+            .checked_add(blocks)
+            .is_none_or(|end| end > self.num_blocks)
+        {
+            return Err(DevError::InvalidParam);
+        }
+        Ok(self.offset + block_id * self.block_size as u64)
+    }
+}
+
+impl BaseDriverOps for LoopDevice {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Block
+    }
+
+    fn device_name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl BlockDriverOps for LoopDevice {
+    fn num_blocks(&self) -> u64 {
+        self.num_blocks
+    }
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn read_block(&mut self, block_id: u64, mut buf: &mut [u8]) -> DevResult {
+        let mut offset = self.file_offset(block_id, buf.len())?;
+        while !buf.is_empty() {
+            let read = self
+                .file
+                .read_at(&mut &mut *buf, offset)
+                .map_err(into_dev_err)?;
+            if read == 0 {
+                // The file shrank since, its missing part reads as zeros.
+                buf.fill(0);
+                break;
+            }
+            buf = &mut core::mem::take(&mut buf)[read..];
+            offset += read as u64;
+        }
+        Ok(())
+    }
+
+    fn write_block(&mut self, block_id: u64, mut buf: &[u8]) -> DevResult {
+        if self.read_only {
+            return Err(DevError::Unsupported);
+        }
+        let mut offset = self.file_offset(block_id, buf.len())?;
+        while !buf.is_empty() {
+            let written = self.file.write_at(&mut buf, offset).map_err(into_dev_err)?;
+            if written == 0 {
+                return Err(DevError::Io);
+            }
+            offset += written as u64;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> DevResult {
+        if self.read_only {
+            return Ok(());
+        }
+        self.file.sync(true).map_err(into_dev_err)
+    }
+}
@@ -0,0 +1,487 @@
+//! Discovery of MBR and GPT partitions on block devices.
+//!
+//! Filesystems are created on a [`Partition`], which is either a partition
+//! found by [`scan`] or a [whole disk](Partition::whole). All partitions of a
+//! disk share it, along with its [block cache](crate::block_cache), and
+//! translate block numbers to the right place of it.
+
+use alloc::{
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+use core::{fmt, str::FromStr};
+
+use axdriver::{AxBlockDevice, prelude::*};
+use axfs_ng_vfs::{VfsError, VfsResult};
+use kspin::SpinNoPreempt as Mutex;
+
+pub use crate::block_cache::CacheStats;
+use crate::{
+    block_cache::{BlockDevice, CachedDisk},
+    loop_device::LoopDevice,
+};
+
+/// Size of the sectors MBR addresses, whatever the block size of the disk.
+const MBR_SECTOR_SIZE: u64 = 512;
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+/// Partition type of the protective MBR of a GPT disk.
+const MBR_TYPE_GPT: u8 = 0xee;
+const MBR_TYPES_EXTENDED: [u8; 3] = [0x05, 0x0f, 0x85];
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+
+/// Where a partition is and how it is identified.
+#[derive(Debug, Clone)]
+pub struct PartitionInfo {
+    /// 1-based number of the partition, or 0 for a whole disk.
+    ///
+    /// Logical partitions of an MBR extended partition are numbered from 5,
+    /// like on Linux.
+    pub index: usize,
+    /// First block of the partition on the disk.
+    pub start: u64,
+    /// Number of blocks of the partition.
+    pub num_blocks: u64,
+    /// Unique partition GUID for GPT, or `SSSSSSSS-PP` for MBR, where `S` is
+    /// the disk signature and `P` the partition number, like Linux `PARTUUID`.
+    pub uuid: Option<String>,
+    /// Partition name, only available on GPT.
+    pub label: Option<String>,
+}
+
+/// A partition of a disk, or a whole disk.
+#[derive(Clone)]
+pub struct Partition {
+    disk: Arc<Mutex<CachedDisk>>,
+    info: PartitionInfo,
+    /// Name of the driver of the disk.
+    name: String,
+    block_size: usize,
+}
+
+impl Partition {
+    /// Wraps a disk as a whole, without looking for a partition table.
+    pub fn whole(dev: AxBlockDevice) -> Self {
+        Self::whole_device(BlockDevice::Native(dev))
+    }
+
+    /// Wraps a loop device as a whole disk, so that the filesystem image it
+    /// is backed by can be mounted.
+    pub fn from_loop(dev: LoopDevice) -> Self {
+        Self::whole_device(BlockDevice::Loop(dev))
+    }
+
+    fn whole_device(dev: BlockDevice) -> Self {
+        let info = PartitionInfo {
+            index: 0,
+            start: 0,
+            num_blocks: dev.num_blocks(),
+            uuid: None,
+            label: None,
+        };
+        let name = dev.device_name().to_string();
+        let block_size = dev.block_size();
+        Self {
+            disk: Arc::new(Mutex::new(CachedDisk::new(dev))),
+            info,
+            name,
+            block_size,
+        }
+    }
+
+    /// Returns the whole disk the partition is on.
+    pub fn whole_disk(&self) -> Self {
+        Self {
+            disk: self.disk.clone(),
+            info: PartitionInfo {
+                index: 0,
+                start: 0,
+                num_blocks: self.disk.lock().num_blocks(),
+                uuid: None,
+                label: None,
+            },
+            name: self.name.clone(),
+            block_size: self.block_size,
+        }
+    }
+
+    /// Looks for a partition table on the disk the partition is on, like
+    /// [`scan`], and returns the partitions of the disk.
+    pub fn partitions(&self) -> DevResult<Vec<Partition>> {
+        let mut disk = self.whole_disk();
+        let block_size = self.block_size as u64;
+        let disk_blocks = disk.num_blocks();
+        let mut infos = read_table(&mut disk)?
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|p| p.offset % block_size == 0 && p.size % block_size == 0)
+            .map(|p| PartitionInfo {
+                index: p.index,
+                start: p.offset / block_size,
+                num_blocks: p.size / block_size,
+                uuid: p.uuid,
+                label: p.label,
+            })
+            .filter(|info| info.num_blocks > 0 && info.start + info.num_blocks <= disk_blocks)
+            .collect::<Vec<_>>();
+        if infos.is_empty() {
+            return Ok(vec![disk]);
+        }
+        infos.sort_by_key(|info| info.index);
+        Ok(infos
+            .into_iter()
+            .map(|info| Partition {
+                info,
+                ..disk.clone()
+            })
+            .collect())
+    }
+
+    /// Returns where the partition is and how it is identified.
+    pub fn info(&self) -> &PartitionInfo {
+        &self.info
+    }
+
+    /// Returns the counters of the block cache of the disk.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.disk.lock().stats()
+    }
+
+    /// Reads `buf.len()` bytes at `offset` within block `block_id`, through
+    /// the block cache.
+    pub(crate) fn read_cached(
+        &mut self,
+        block_id: u64,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> DevResult {
+        assert!(offset + buf.len() <= self.block_size);
+        let block_id = self.translate(block_id, 1)?;
+        self.disk.lock().read_cached(block_id, offset, buf)
+    }
+
+    /// Writes `buf` at `offset` within block `block_id`, through the block
+    /// cache.
+    pub(crate) fn write_cached(&mut self, block_id: u64, offset: usize, buf: &[u8]) -> DevResult {
+        assert!(offset + buf.len() <= self.block_size);
+        let block_id = self.translate(block_id, 1)?;
+        self.disk.lock().write_cached(block_id, offset, buf)
+    }
+
+    /// Checks that the blocks accessed by a buffer of `len` bytes starting at
+    /// `block_id` lie within the partition, and translates `block_id`.
+    fn translate(&self, block_id: u64, len: usize) -> DevResult<u64> {
+        let blocks = len.div_ceil(self.block_size) as u64;
+        if block_id
+            .checked_add(blocks)
+            .is_none_or(|end| end > self.info.num_blocks)
+        {
+            return Err(DevError::InvalidParam);
+        }
+        Ok(self.info.start + block_id)
+    }
+}
+
+impl BaseDriverOps for Partition {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Block
+    }
+
+    fn device_name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl BlockDriverOps for Partition {
+    fn num_blocks(&self) -> u64 {
+        self.info.num_blocks
+    }
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        let block_id = self.translate(block_id, buf.len())?;
+        self.disk.lock().read_block(block_id, buf)
+    }
+
+    fn write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        let block_id = self.translate(block_id, buf.len())?;
+        self.disk.lock().write_block(block_id, buf)
+    }
+
+    fn flush(&mut self) -> DevResult {
+        self.disk.lock().flush()
+    }
+}
+
+/// Reads `len` bytes at byte offset `offset` of the disk.
+fn read_bytes(disk: &mut Partition, offset: u64, len: usize) -> DevResult<Vec<u8>> {
+    let block_size = disk.block_size() as u64;
+    let first = offset / block_size;
+    let last = (offset + len as u64).div_ceil(block_size);
+    let mut buf = vec![0; ((last - first) * block_size) as usize];
+    for (i, block) in buf.chunks_mut(block_size as usize).enumerate() {
+        disk.read_block(first + i as u64, block)?;
+    }
+    let start = (offset - first * block_size) as usize;
+    buf.drain(..start);
+    buf.truncate(len);
+    Ok(buf)
+}
+
+fn le32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn le64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// Formats a GUID stored in the mixed-endian layout of GPT.
+fn format_guid(guid: &[u8]) -> String {
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+        le32(guid, 0),
+        u16::from_le_bytes([guid[4], guid[5]]),
+        u16::from_le_bytes([guid[6], guid[7]]),
+        guid[8],
+        guid[9],
+        guid[10],
+        guid[11],
+        guid[12],
+        guid[13],
+        guid[14],
+        guid[15],
+    )
+}
+
+/// A partition found in a table, in bytes.
+struct RawPartition {
+    index: usize,
+    offset: u64,
+    size: u64,
+    uuid: Option<String>,
+    label: Option<String>,
+}
+
+fn parse_gpt(disk: &mut Partition) -> DevResult<Vec<RawPartition>> {
+    let block_size = disk.block_size() as u64;
+    let header = read_bytes(disk, block_size, 92)?;
+    if &header[..8] != GPT_SIGNATURE {
+        return Err(DevError::Unsupported);
+    }
+    let entries_lba = le64(&header, 72);
+    let num_entries = le32(&header, 80) as usize;
+    let entry_size = le32(&header, 84) as usize;
+    // The size of entries is a power of two from 128 bytes, which is also
+    // bounded so that the table stays small.
+    if !(128..=4096).contains(&entry_size) || !entry_size.is_power_of_two() || num_entries > 1024 {
+        return Err(DevError::InvalidParam);
+    }
+    let table_offset = entries_lba
+        .checked_mul(block_size)
+        .ok_or(DevError::InvalidParam)?;
+    let table_len = num_entries
+        .checked_mul(entry_size)
+        .ok_or(DevError::InvalidParam)?;
+    let entries = read_bytes(disk, table_offset, table_len)?;
+
+    let mut partitions = Vec::new();
+    for (i, entry) in entries.chunks(entry_size).enumerate() {
+        // Unused entries have a zero type GUID.
+        if entry[..16].iter().all(|&b| b == 0) {
+            continue;
+        }
+        let first = le64(entry, 32);
+        let last = le64(entry, 40);
+        if last < first {
+            continue;
+        }
+        let end = last
+            .checked_add(1)
+            .and_then(|it| it.checked_mul(block_size));
+        let (Some(offset), Some(end)) = (first.checked_mul(block_size), end) else {
+            continue;
+        };
+        let name = char::decode_utf16(
+            entry[56..128]
+                .chunks(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                .take_while(|&c| c != 0),
+        )
+        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect::<String>();
+        partitions.push(RawPartition {
+            index: i + 1,
+            offset,
+            size: end - offset,
+            uuid: Some(format_guid(&entry[16..32])),
+            label: (!name.is_empty()).then_some(name),
+        });
+    }
+    Ok(partitions)
+}
+
+fn parse_mbr(disk: &mut Partition, mbr: &[u8]) -> DevResult<Vec<RawPartition>> {
+    let signature = le32(mbr, 440);
+    let uuid = |index: usize| Some(format!("{signature:08x}-{index:02x}"));
+    let entry = |table: &[u8], i: usize| {
+        let entry = &table[446 + i * 16..446 + (i + 1) * 16];
+        (
+            entry[4],
+            le32(entry, 8) as u64 * MBR_SECTOR_SIZE,
+            le32(entry, 12) as u64 * MBR_SECTOR_SIZE,
+        )
+    };
+
+    let mut partitions = Vec::new();
+    for i in 0..4 {
+        let (kind, offset, size) = entry(mbr, i);
+        if kind == 0 || size == 0 {
+            continue;
+        }
+        if !MBR_TYPES_EXTENDED.contains(&kind) {
+            partitions.push(RawPartition {
+                index: i + 1,
+                offset,
+                size,
+                uuid: uuid(i + 1),
+                label: None,
+            });
+            continue;
+        }
+
+        // Walk the chain of extended boot records. The first entry of each
+        // is relative to the record itself, and the second one, the link to
+        // the next record, is relative to the extended partition. The chain
+        // ends on coming back to a record, or after 128 of them.
+        let mut ebr_offset = offset;
+        let mut visited = Vec::new();
+        while visited.len() < 128 && !visited.contains(&ebr_offset) {
+            visited.push(ebr_offset);
+            let ebr = read_bytes(disk, ebr_offset, MBR_SECTOR_SIZE as usize)?;
+            if ebr[510..512] != MBR_SIGNATURE {
+                break;
+            }
+            let (kind, start, size) = entry(&ebr, 0);
+            if kind != 0 && size != 0 {
+                let index = 5 + partitions.iter().filter(|p| p.index >= 5).count();
+                partitions.push(RawPartition {
+                    index,
+                    offset: ebr_offset + start,
+                    size,
+                    uuid: uuid(index),
+                    label: None,
+                });
+            }
+            let (kind, next, _) = entry(&ebr, 1);
+            if kind == 0 || next == 0 {
+                break;
+            }
+            ebr_offset = offset + next;
+        }
+    }
+    Ok(partitions)
+}
+
+/// Reads the partition table of the disk, if there is one.
+fn read_table(disk: &mut Partition) -> DevResult<Option<Vec<RawPartition>>> {
+    let mbr = read_bytes(disk, 0, MBR_SECTOR_SIZE as usize)?;
+    if mbr[510..512] != MBR_SIGNATURE {
+        return Ok(None);
+    }
+    // Boot sectors of FAT filesystems carry the same signature, but their
+    // partition entries are garbage, which usually shows in the boot flags.
+    if (0..4).any(|i| mbr[446 + i * 16] & 0x7f != 0) {
+        return Ok(None);
+    }
+    if (0..4).any(|i| mbr[446 + i * 16 + 4] == MBR_TYPE_GPT) {
+        parse_gpt(disk).map(Some)
+    } else {
+        parse_mbr(disk, &mbr).map(Some)
+    }
+}
+
+/// Looks for a GPT or MBR partition table on the disk, and returns its
+/// partitions, sorted by index.
+///
+/// If the disk is not partitioned, e.g. when it holds a filesystem directly,
+/// the list only holds the [whole disk](Partition::whole). CRCs of GPT headers
+/// are not verified.
+pub fn scan(dev: AxBlockDevice) -> DevResult<Vec<Partition>> {
+    Partition::whole(dev).partitions()
+}
+
+/// Selects a partition, in the syntax of the Linux `root=` parameter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PartitionSelector {
+    /// `<index>`: the partition with this number.
+    Index(usize),
+    /// `PARTUUID=<uuid>`: the partition with this UUID, case-insensitive.
+    Uuid(String),
+    /// `PARTLABEL=<label>`: the GPT partition with this name.
+    Label(String),
+}
+
+impl PartitionSelector {
+    /// Returns whether `info` is the selected partition.
+    pub fn matches(&self, info: &PartitionInfo) -> bool {
+        match self {
+            Self::Index(index) => info.index == *index,
+            Self::Uuid(uuid) => info
+                .uuid
+                .as_ref()
+                .is_some_and(|it| it.eq_ignore_ascii_case(uuid)),
+            Self::Label(label) => info.label.as_ref() == Some(label),
+        }
+    }
+}
+
+impl FromStr for PartitionSelector {
+    type Err = VfsError;
+
+    fn from_str(s: &str) -> VfsResult<Self> {
+        if let Some(uuid) = s.strip_prefix("PARTUUID=") {
+            Ok(Self::Uuid(uuid.to_string()))
+        } else if let Some(label) = s.strip_prefix("PARTLABEL=") {
+            Ok(Self::Label(label.to_string()))
+        } else {
+            s.parse()
+                .map(Self::Index)
+                .map_err(|_| VfsError::InvalidInput)
+        }
+    }
+}
+
+impl fmt::Display for PartitionSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Index(index) => write!(f, "{index}"),
+            Self::Uuid(uuid) => write!(f, "PARTUUID={uuid}"),
+            Self::Label(label) => write!(f, "PARTLABEL={label}"),
+        }
+    }
+}
+
+/// Picks the partition of a disk to use as a filesystem, among its
+/// `partitions` as returned by [`scan`].
+///
+/// Without a `selector`, this is the first partition, or the whole disk if it
+/// is not partitioned.
+pub fn select(
+    partitions: &[Partition],
+    selector: Option<&PartitionSelector>,
+) -> VfsResult<Partition> {
+    match selector {
+        Some(selector) => partitions
+            .iter()
+            .find(|p| selector.matches(p.info()))
+            .cloned()
+            .ok_or(VfsError::NotFound),
+        None => partitions.first().cloned().ok_or(VfsError::NotFound),
+    }
+}
@@ -0,0 +1,170 @@
+//! Host-side harness of the filesystem tests.
+//!
+//! Disks are files of a tmpfs accessed through loop devices, and are
+//! formatted by the in-kernel `mkfs`, so that no root privilege or host mount
+//! is needed.
+
+#![allow(dead_code)]
+
+use std::{
+    alloc::{Layout, alloc_zeroed},
+    sync::Once,
+};
+
+use axdriver::prelude::BlockDriverOps;
+#[cfg(feature = "fat")]
+pub use axfs_ng::fs::fat::FatType;
+use axfs_ng::{
+    File, FsContext, OpenOptions,
+    fs::{self, tmpfs::TmpFilesystem},
+    loop_device::LoopOptions,
+    partition::Partition,
+};
+use axfs_ng_vfs::{Filesystem, Mountpoint, VfsResult};
+
+/// Memory the page cache is allocated from.
+const PAGE_MEMORY: usize = 512 * 1024 * 1024;
+
+/// Initializes the allocator of the page cache, once for all tests.
+pub fn init() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        let layout = Layout::from_size_align(PAGE_MEMORY, 4096).unwrap();
+        // The memory is only committed by the host once touched.
+        let start = unsafe { alloc_zeroed(layout) };
+        assert!(!start.is_null());
+        axalloc::global_init(start as usize, PAGE_MEMORY);
+    });
+}
+
+/// Returns a context whose root is a new mount of `fs`.
+pub fn context(fs: &Filesystem) -> FsContext {
+    FsContext::new(Mountpoint::new_root(fs).root_location())
+}
+
+/// Returns a new disk of `size` bytes, stored as a file of a new tmpfs.
+pub fn disk(size: u64) -> Partition {
+    let host = context(&TmpFilesystem::new());
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(&host, "image")
+        .and_then(|it| it.into_file())
+        .unwrap();
+    file.backend().unwrap().set_len(size).unwrap();
+    Partition::from_loop(LoopOptions::new().open(file).unwrap())
+}
+
+/// Reads `buf.len()` bytes at byte `offset` of `dev`, past any filesystem.
+pub fn read_raw(dev: &mut Partition, offset: u64, buf: &mut [u8]) {
+    let block_size = dev.block_size() as u64;
+    let mut block = vec![0; block_size as usize];
+    for (i, byte) in buf.iter_mut().enumerate() {
+        let pos = offset + i as u64;
+        if i == 0 || pos % block_size == 0 {
+            dev.read_block(pos / block_size, &mut block).unwrap();
+        }
+        *byte = block[(pos % block_size) as usize];
+    }
+}
+
+/// Writes `data` at byte `offset` of `dev`, past any filesystem.
+pub fn write_raw(dev: &mut Partition, offset: u64, data: &[u8]) {
+    let block_size = dev.block_size() as u64;
+    let mut block = vec![0; block_size as usize];
+    let mut done = 0;
+    while done < data.len() {
+        let pos = offset + done as u64;
+        let start = (pos % block_size) as usize;
+        let len = (block.len() - start).min(data.len() - done);
+        dev.read_block(pos / block_size, &mut block).unwrap();
+        block[start..start + len].copy_from_slice(&data[done..done + len]);
+        dev.write_block(pos / block_size, &block).unwrap();
+        done += len;
+    }
+    dev.flush().unwrap();
+}
+
+/// Creates a tmpfs.
+pub fn tmpfs() -> Filesystem {
+    TmpFilesystem::new()
+}
+
+/// Returns a disk formatted with FAT of the given type, of a size within the
+/// range of cluster counts the type allows with 512-byte clusters.
+#[cfg(feature = "fat")]
+pub fn fat_disk(fat_type: FatType) -> Partition {
+    let size = match fat_type {
+        FatType::Fat12 => 1024 * 1024,
+        FatType::Fat16 => 16 * 1024 * 1024,
+        FatType::Fat32 => 48 * 1024 * 1024,
+    };
+    let mut dev = disk(size);
+    fs::fat::FatMkfsOptions::new()
+        .fat_type(fat_type)
+        .bytes_per_cluster(512)
+        .volume_label("AXFS-TEST")
+        .format(&mut dev)
+        .unwrap();
+    dev
+}
+
+/// Creates a FAT filesystem of the given type on a new disk.
+#[cfg(feature = "fat")]
+pub fn fat(fat_type: FatType) -> Filesystem {
+    fs::fat::FatFilesystem::new(fat_disk(fat_type)).unwrap()
+}
+
+/// Creates a FAT filesystem of the given type on a new disk, emulating
+/// symlinks and Unix attributes.
+#[cfg(feature = "fat")]
+pub fn fat_unix(fat_type: FatType) -> Filesystem {
+    fs::fat::FatOptions::new()
+        .emulate_unix(true)
+        .open(fat_disk(fat_type))
+        .unwrap()
+}
+
+/// Returns a disk of `size` bytes formatted with ext4.
+#[cfg(feature = "ext4")]
+pub fn ext4_disk(size: u64) -> Partition {
+    let mut dev = disk(size);
+    fs::ext4::Ext4MkfsOptions::new().format(&mut dev).unwrap();
+    dev
+}
+
+/// Creates an ext4 filesystem on a new disk of `size` bytes.
+#[cfg(feature = "ext4")]
+pub fn ext4(size: u64) -> Filesystem {
+    fs::ext4::Ext4Filesystem::new(ext4_disk(size)).unwrap()
+}
+
+/// Writes `data` to a new file at `path`, replacing any previous one.
+pub fn write_file(cx: &FsContext, path: &str, data: &[u8]) -> VfsResult<()> {
+    let file = File::create(cx, path)?;
+    let mut data = data;
+    let mut offset = 0;
+    while !data.is_empty() {
+        offset += file.write_at(&mut data, offset)? as u64;
+    }
+    Ok(())
+}
+
+/// Reads the whole file at `path`.
+pub fn read_file(cx: &FsContext, path: &str) -> VfsResult<Vec<u8>> {
+    read_all(&File::open(cx, path)?)
+}
+
+/// Reads `file` from its start to its end.
+pub fn read_all(file: &File) -> VfsResult<Vec<u8>> {
+    let mut data = Vec::new();
+    let mut buf = vec![0; 8192];
+    loop {
+        let read = file.read_at(&mut &mut buf[..], data.len() as u64)?;
+        if read == 0 {
+            return Ok(data);
+        }
+        data.extend_from_slice(&buf[..read]);
+    }
+}
@@ -1,175 +1,1959 @@
-// FIXME: The test is broken after we changed the ramdisk implementation!
+//! Conformance suite run over every filesystem backend.
 
-#![allow(unused)]
+mod common;
 
-use std::collections::HashSet;
+use std::collections::BTreeSet;
 
-use axdriver_block::ramdisk::RamDisk;
-use axfs_ng::{File, FsContext, fs};
-use axfs_ng_vfs::{
-    Filesystem, Location, Mountpoint, NodePermission, NodeType, VfsError, VfsResult, path::Path,
+use axerrno::LinuxError;
+use axfs_ng::{AllocateMode, DIRECT_IO_ALIGN, FsContext, OpenOptions};
+use axfs_ng_vfs::{Filesystem, NodePermission, NodeType, VfsError, VfsResult};
+use common::{read_all, read_file, write_file};
+
+/// What a backend supports beyond the common operations.
+#[derive(Clone, Copy)]
+struct Features {
+    hard_links: bool,
+    symlinks: bool,
+    /// Holes can be punched, and are found by `seek_hole`.
+    holes: bool,
+    xattrs: bool,
+}
+
+const ALL: Features = Features {
+    hard_links: true,
+    symlinks: true,
+    holes: true,
+    xattrs: true,
 };
-use axio::Read;
 
-type RawMutex = spin::Mutex<()>;
+fn dir_mode() -> NodePermission {
+    NodePermission::from_bits_truncate(0o755)
+}
 
-fn list_files(cx: &FsContext<RawMutex>, path: impl AsRef<Path>) -> VfsResult<HashSet<String>> {
+fn list_dir(cx: &FsContext, path: &str) -> VfsResult<BTreeSet<String>> {
     cx.read_dir(path)?
-        .map(|it| it.map(|entry| entry.name.to_owned()))
+        .map(|entry| entry.map(|it| it.name))
+        .filter(|name| !matches!(name.as_deref(), Ok(".") | Ok("..")))
         .collect()
 }
 
-fn test_fs_read(fs: &Filesystem<RawMutex>) -> VfsResult<()> {
-    let mount = Mountpoint::new_root(fs);
-    let cx: FsContext<spin::mutex::Mutex<()>> = FsContext::new(mount.root_location());
+/// Returns recognizable content of `len` bytes.
+fn pattern(len: usize, seed: u8) -> Vec<u8> {
+    (0..len)
+        .map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed))
+        .collect()
+}
 
-    let names = list_files(&cx, "/").unwrap();
-    assert!(
-        ["short.txt", "long.txt", "a", "very-long-dir-name"]
-            .into_iter()
-            .all(|it| names.contains(it))
+fn test_create(cx: &FsContext) -> VfsResult<()> {
+    cx.create_dir("/dir", dir_mode())?;
+    cx.create_dir("/dir/sub", dir_mode())?;
+    write_file(cx, "/dir/sub/file.txt", b"Rust is cool!\n")?;
+    assert_eq!(read_file(cx, "/dir/sub/file.txt")?, b"Rust is cool!\n");
+    assert_eq!(cx.metadata("/dir/sub/file.txt")?.size, 14);
+    assert_eq!(cx.metadata("/dir/sub")?.node_type, NodeType::Directory);
+    assert_eq!(
+        cx.resolve("/dir/./sub/../sub/file.txt")?
+            .absolute_path()?
+            .to_string(),
+        "/dir/sub/file.txt"
     );
-    assert_eq!(cx.metadata("short.txt")?.size, 14);
-    assert_eq!(cx.metadata("long.txt")?.size, 14000);
 
-    let entries = cx.read_dir("/")?.collect::<VfsResult<Vec<_>>>()?;
-    for entry in entries {
-        assert!(cx.root_dir().lookup_no_follow(&entry.name)?.inode() == entry.ino);
+    assert!(matches!(
+        cx.create_dir("/dir/sub", dir_mode()),
+        Err(VfsError::AlreadyExists)
+    ));
+    let result = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(cx, "/dir/sub/file.txt");
+    assert!(matches!(result, Err(VfsError::AlreadyExists)));
+    assert!(matches!(
+        cx.metadata("/dir/missing"),
+        Err(VfsError::NotFound)
+    ));
+    Ok(())
+}
+
+fn test_unlink(cx: &FsContext) -> VfsResult<()> {
+    cx.create_dir("/dir", dir_mode())?;
+    write_file(cx, "/dir/file", b"data")?;
+    assert!(matches!(
+        cx.remove_dir("/dir"),
+        Err(VfsError::DirectoryNotEmpty)
+    ));
+    cx.remove_file("/dir/file")?;
+    assert!(cx.resolve("/dir/file").is_err());
+    cx.remove_dir("/dir")?;
+    assert!(list_dir(cx, "/")?.is_empty());
+    Ok(())
+}
+
+fn test_rename(cx: &FsContext) -> VfsResult<()> {
+    write_file(cx, "/a", b"hello world")?;
+    cx.rename("/a", "/b")?;
+    assert!(cx.resolve("/a").is_err());
+    assert_eq!(read_file(cx, "/b")?, b"hello world");
+
+    // Over an existing file.
+    write_file(cx, "/c", b"hello world2")?;
+    cx.rename("/b", "/c")?;
+    assert_eq!(read_file(cx, "/c")?, b"hello world");
+    assert_eq!(list_dir(cx, "/")?, BTreeSet::from(["c".to_owned()]));
+
+    // Across directories, along with the content.
+    cx.create_dir("/d1", dir_mode())?;
+    cx.create_dir("/d2", dir_mode())?;
+    write_file(cx, "/d1/file", b"moved")?;
+    cx.rename("/d1", "/d2/d1")?;
+    assert_eq!(read_file(cx, "/d2/d1/file")?, b"moved");
+
+    cx.create_dir("/full", dir_mode())?;
+    write_file(cx, "/full/file", b"")?;
+    assert!(cx.rename("/d2", "/full").is_err());
+    Ok(())
+}
+
+fn test_links(cx: &FsContext, features: Features) -> VfsResult<()> {
+    write_file(cx, "/target", b"hello world")?;
+
+    let link = cx.link("/target", "/hard");
+    if features.hard_links {
+        link?;
+        assert_eq!(read_file(cx, "/hard")?, b"hello world");
+        assert_eq!(cx.metadata("/target")?.nlink, 2);
+        cx.remove_file("/target")?;
+        assert_eq!(read_file(cx, "/hard")?, b"hello world");
+        cx.rename("/hard", "/target")?;
+    } else {
+        assert!(link.is_err());
     }
 
-    assert_eq!(
-        list_files(&cx, "/a/long/path")?,
-        ["test.txt", ".", ".."]
-            .into_iter()
-            .map(str::to_owned)
-            .collect()
-    );
-    assert_eq!(
-        cx.read_to_string("/a/long/path/test.txt")?,
-        "Rust is cool!\n"
-    );
+    let symlink = cx.symlink("/target", "/soft");
+    if features.symlinks {
+        symlink?;
+        assert_eq!(read_file(cx, "/soft")?, b"hello world");
+        assert_eq!(cx.resolve_no_follow("/soft")?.read_link()?, "/target");
+        assert_eq!(
+            cx.resolve_no_follow("/soft")?.node_type(),
+            NodeType::Symlink
+        );
+        cx.symlink("soft", "/loop1")?;
+        cx.symlink("loop2", "/loop2")?;
+        assert!(matches!(
+            read_file(cx, "/loop2"),
+            Err(VfsError::FilesystemLoop)
+        ));
+        assert_eq!(read_file(cx, "/loop1")?, b"hello world");
+        cx.remove_file("/soft")?;
+        assert_eq!(read_file(cx, "/target")?, b"hello world");
+    } else {
+        assert!(symlink.is_err());
+    }
+    Ok(())
+}
 
-    assert_eq!(
-        cx.resolve("/a/long/path/test.txt")?
-            .absolute_path()?
-            .to_string(),
-        "/a/long/path/test.txt"
-    );
+fn test_truncate(cx: &FsContext) -> VfsResult<()> {
+    write_file(cx, "/file", &pattern(10000, 1))?;
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(cx, "/file")?
+        .into_file()?;
+    let backend = file.backend()?;
 
-    assert!(
-        cx.resolve("/very-long-dir-name/very-long-file-name.txt")?
-            .is_file()
-    );
-    let mut file = File::open(&cx, "/very-long-dir-name/very-long-file-name.txt")?;
-    let mut buf = vec![];
-    file.read_to_end(&mut buf)?;
+    backend.set_len(5000)?;
+    assert_eq!(read_all(&file)?, &pattern(10000, 1)[..5000]);
+    // Growing reads back as zeros, even where data used to be.
+    backend.set_len(12000)?;
+    let data = read_all(&file)?;
+    assert_eq!(data.len(), 12000);
+    assert_eq!(&data[..5000], &pattern(10000, 1)[..5000]);
+    assert!(data[5000..].iter().all(|&b| b == 0));
     drop(file);
-    assert_eq!(core::str::from_utf8(&buf).unwrap(), "Rust is cool!\n");
 
+    OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .open(cx, "/file")?;
+    assert_eq!(cx.metadata("/file")?.size, 0);
     Ok(())
 }
 
-fn test_fs_write(fs: &Filesystem<RawMutex>) -> VfsResult<()> {
-    let mount = Mountpoint::new_root(fs);
-    let cx = FsContext::new(mount.root_location());
+fn test_large_file(cx: &FsContext) -> VfsResult<()> {
+    const LEN: usize = 3 * 1024 * 1024 + 123;
+    let data = pattern(LEN, 7);
+    write_file(cx, "/large", &data)?;
+    assert_eq!(cx.metadata("/large")?.size, LEN as u64);
+    assert!(read_file(cx, "/large")? == data);
+
+    // Unaligned overwrite across pages.
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(cx, "/large")?
+        .into_file()?;
+    let patch = pattern(10000, 42);
+    file.write_at(&mut &patch[..], 1_000_001)?;
+    file.sync(false)?;
+    drop(file);
+    let mut expected = data;
+    expected[1_000_001..1_010_001].copy_from_slice(&patch);
+    assert!(read_file(cx, "/large")? == expected);
+    Ok(())
+}
 
-    let mode = NodePermission::from_bits(0o766).unwrap();
-    cx.create_dir("temp", mode)?;
-    cx.create_dir("temp2", mode)?;
-    assert!(cx.resolve("temp").is_ok() && cx.resolve("temp2").is_ok());
-    // cx.rename("temp", "temp2")?;
-    // assert!(cx.resolve("temp").is_err() && cx.resolve("temp2").is_ok());
+fn test_sparse(cx: &FsContext, features: Features) -> VfsResult<()> {
+    const PAGE: u64 = 4096;
+    let data = pattern(5 * PAGE as usize, 9);
+    let len = data.len() as u64;
+    write_file(cx, "/sparse", &data)?;
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(cx, "/sparse")?
+        .into_file()?;
+    // Dirty the cached pages, which must not bring back what is punched.
+    file.write_at(&mut &data[..], 0)?;
 
-    cx.create_dir("temp", mode)?;
-    cx.resolve("temp")?
-        .create("test.txt", NodeType::RegularFile, NodePermission::default())?;
+    // The pages at the ends of the hole are zeroed in part only.
+    let hole = PAGE + 100..4 * PAGE - 100;
+    let punched = file.allocate(
+        AllocateMode::KEEP_SIZE | AllocateMode::PUNCH_HOLE,
+        hole.clone(),
+    );
+    if features.holes {
+        punched?;
+    } else {
+        // Without holes, the range can only be zeroed, which is not the
+        // same as deallocating it.
+        assert!(matches!(punched, Err(VfsError::Unsupported)));
+        assert!(read_all(&file)? == data);
+        file.allocate(
+            AllocateMode::KEEP_SIZE | AllocateMode::ZERO_RANGE,
+            hole.clone(),
+        )?;
+    }
+    let mut expected = data;
+    expected[hole.start as usize..hole.end as usize].fill(0);
+    assert_eq!(cx.metadata("/sparse")?.size, len);
+    assert!(read_all(&file)? == expected);
+    file.sync(false)?;
+    let mut buf = vec![0; expected.len()];
+    let node = file.location().entry().as_file()?.clone();
+    node.read_at(&mut buf, 0)?;
+    assert!(buf == expected);
+
+    if features.holes {
+        // Only the page within the hole is gone.
+        assert_eq!(file.seek_data(0)?, 0);
+        assert_eq!(file.seek_hole(0)?, 2 * PAGE);
+        assert_eq!(file.seek_hole(2 * PAGE + 1)?, 2 * PAGE + 1);
+        assert_eq!(file.seek_data(2 * PAGE + 1)?, 3 * PAGE);
+        assert_eq!(file.seek_hole(3 * PAGE)?, len);
+        // Preallocated pages are data.
+        file.allocate(AllocateMode::KEEP_SIZE, 2 * PAGE..3 * PAGE)?;
+        assert_eq!(file.seek_data(2 * PAGE + 1)?, 2 * PAGE + 1);
+    } else {
+        assert_eq!(file.seek_data(2 * PAGE + 1)?, 2 * PAGE + 1);
+    }
+    assert_eq!(file.seek_hole(0)?, len);
+    assert!(read_all(&file)? == expected);
+    let past_end = |result| matches!(result, Err(VfsError::Other(LinuxError::ENXIO)));
+    assert!(past_end(file.seek_data(len)));
+    assert!(past_end(file.seek_hole(len)));
+    Ok(())
+}
+
+fn test_xattr(cx: &FsContext, features: Features) -> VfsResult<()> {
+    use axfs_ng::{XATTR_NAME_MAX, XATTR_SIZE_MAX, XattrFlags};
+
+    write_file(cx, "/file", b"data")?;
+    if !features.xattrs {
+        assert!(matches!(
+            cx.set_xattr("/file", "user.a", b"1", XattrFlags::empty()),
+            Err(VfsError::Unsupported)
+        ));
+        assert!(matches!(cx.list_xattr("/file"), Err(VfsError::Unsupported)));
+        return Ok(());
+    }
+    let no_data = |result| matches!(result, Err(VfsError::Other(LinuxError::ENODATA)));
+    let list = || -> VfsResult<Vec<String>> {
+        let mut names = cx.list_xattr("/file")?;
+        names.sort();
+        Ok(names)
+    };
+
+    assert!(list()?.is_empty());
+    cx.set_xattr("/file", "user.a", b"first", XattrFlags::empty())?;
+    cx.set_xattr("/file", "trusted.b", b"b", XattrFlags::empty())?;
+    assert_eq!(cx.get_xattr("/file", "user.a")?, b"first");
+    assert_eq!(cx.get_xattr("/file", "trusted.b")?, b"b");
+    assert_eq!(list()?, ["trusted.b", "user.a"]);
+    cx.set_xattr("/file", "user.a", b"second", XattrFlags::empty())?;
+    assert_eq!(cx.get_xattr("/file", "user.a")?, b"second");
+
+    // `CREATE` and `REPLACE` check whether the attribute exists.
     assert!(matches!(
-        cx.rename("temp2", "temp"),
-        Err(VfsError::ENOTEMPTY)
+        cx.set_xattr("/file", "user.a", b"x", XattrFlags::CREATE),
+        Err(VfsError::AlreadyExists)
+    ));
+    assert!(no_data(cx.set_xattr(
+        "/file",
+        "user.c",
+        b"x",
+        XattrFlags::REPLACE
+    )));
+    cx.set_xattr("/file", "user.a", b"third", XattrFlags::REPLACE)?;
+    cx.set_xattr("/file", "user.c", b"x", XattrFlags::CREATE)?;
+    assert_eq!(cx.get_xattr("/file", "user.a")?, b"third");
+    assert!(matches!(
+        cx.set_xattr(
+            "/file",
+            "user.a",
+            b"x",
+            XattrFlags::CREATE | XattrFlags::REPLACE
+        ),
+        Err(VfsError::InvalidInput)
     ));
 
-    cx.write("/test.txt", "hello world".as_bytes())?;
-    assert_eq!(cx.read_to_string("/test.txt")?, "hello world");
+    cx.remove_xattr("/file", "user.c")?;
+    assert!(no_data(cx.get_xattr("/file", "user.c").map(|_| ())));
+    assert!(no_data(cx.remove_xattr("/file", "user.c")));
+    assert_eq!(list()?, ["trusted.b", "user.a"]);
 
-    cx.create_dir("test_dir", NodePermission::from_bits_truncate(0o755))?;
-    cx.rename("test_dir", "test")?;
-    cx.remove_dir("test")?;
+    // Names must be in a known namespace, and neither names nor values may
+    // be too long.
+    assert!(matches!(
+        cx.set_xattr("/file", "other.a", b"", XattrFlags::empty()),
+        Err(VfsError::Unsupported)
+    ));
+    assert!(matches!(
+        cx.get_xattr("/file", "a"),
+        Err(VfsError::Unsupported)
+    ));
+    let erange = |result| matches!(result, Err(VfsError::Other(LinuxError::ERANGE)));
+    assert!(erange(cx.set_xattr("/file", "", b"", XattrFlags::empty())));
+    let long_name = format!("user.{}", "n".repeat(XATTR_NAME_MAX));
+    assert!(erange(cx.set_xattr(
+        "/file",
+        &long_name,
+        b"",
+        XattrFlags::empty()
+    )));
+    assert!(matches!(
+        cx.set_xattr(
+            "/file",
+            "user.big",
+            &vec![0; XATTR_SIZE_MAX + 1],
+            XattrFlags::empty()
+        ),
+        Err(VfsError::Other(LinuxError::E2BIG))
+    ));
+    assert_eq!(list()?, ["trusted.b", "user.a"]);
 
-    println!("---------------------");
+    // Directories have attributes too.
+    cx.create_dir("/dir", dir_mode())?;
+    cx.set_xattr("/dir", "user.dir", b"d", XattrFlags::empty())?;
+    assert_eq!(cx.get_xattr("/dir", "user.dir")?, b"d");
+    assert!(no_data(cx.get_xattr("/file", "user.dir").map(|_| ())));
+    Ok(())
+}
 
-    if cx.link("/test.txt", "/test_link").is_ok() {
-        assert_eq!(cx.read_to_string("/test_link")?, "hello world");
-    }
-    if cx.symlink("/test.txt", "/test_symlink").is_ok() {
-        assert_eq!(cx.read_to_string("/test_symlink")?, "hello world");
+fn test_readdir_offsets(cx: &FsContext) -> VfsResult<()> {
+    cx.create_dir("/many", dir_mode())?;
+    let names = (0..100)
+        .map(|i| format!("entry-with-a-long-name-{i}"))
+        .collect::<BTreeSet<_>>();
+    for name in &names {
+        write_file(cx, &format!("/many/{name}"), b"")?;
     }
 
-    // FAT has errornous rename implementation
-    if fs.name() != "vfat" {
-        cx.write("rename1", "hello world".as_bytes())?;
-        cx.write("rename2", "hello world2".as_bytes())?;
-        cx.rename("rename1", "rename2")?;
-        assert_eq!(cx.read_to_string("rename2")?, "hello world");
-    }
+    let entries = cx.read_dir("/many")?.collect::<VfsResult<Vec<_>>>()?;
+    let listed = entries
+        .iter()
+        .map(|it| it.name.clone())
+        .filter(|name| name != "." && name != "..")
+        .collect::<BTreeSet<_>>();
+    assert_eq!(listed, names);
 
+    let dir = cx.resolve("/many")?;
+    for entry in &entries {
+        assert_eq!(dir.lookup_no_follow(&entry.name)?.inode(), entry.ino);
+    }
+    // Resuming from the offset of an entry yields exactly the ones after it.
+    for (i, entry) in entries.iter().enumerate() {
+        let mut rest = Vec::new();
+        dir.read_dir(
+            entry.offset,
+            &mut |name: &str, _ino: u64, _node_type: NodeType, _offset: u64| {
+                rest.push(name.to_owned());
+                true
+            },
+        )?;
+        let expected = entries[i + 1..]
+            .iter()
+            .map(|it| it.name.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(rest, expected);
+    }
     Ok(())
 }
 
-fn test_fs_full(fs: Filesystem<RawMutex>) -> VfsResult<()> {
-    let mut thrds = vec![];
-    for _ in 0..1 {
-        let fs = fs.clone();
-        thrds.push(std::thread::spawn(move || test_fs_read(&fs)));
-    }
-    for th in thrds {
-        th.join().unwrap()?;
+/// Runs the conformance suite on new filesystems made by `new_fs`.
+fn run_suite(new_fs: impl Fn() -> Filesystem, features: Features) {
+    common::init();
+    let tests: &[(&str, &dyn Fn(&FsContext) -> VfsResult<()>)] = &[
+        ("create", &test_create),
+        ("unlink", &test_unlink),
+        ("rename", &test_rename),
+        ("links", &|cx| test_links(cx, features)),
+        ("truncate", &test_truncate),
+        ("large_file", &test_large_file),
+        ("sparse", &|cx| test_sparse(cx, features)),
+        ("readdir_offsets", &test_readdir_offsets),
+        ("xattr", &|cx| test_xattr(cx, features)),
+    ];
+    for (name, test) in tests {
+        let fs = new_fs();
+        let cx = common::context(&fs);
+        if let Err(err) = test(&cx) {
+            panic!("{} failed on {}: {err:?}", name, fs.name());
+        }
+        fs.flush().unwrap();
     }
-    test_fs_write(&fs)?;
+}
+
+/// Checks that the page cache and the filesystem see the same data, whether
+/// accessed through cached files, direct I/O or the node itself.
+fn test_coherence(fs: Filesystem) -> VfsResult<()> {
+    #[repr(align(4096))]
+    struct Aligned([u8; 4096]);
+
+    let cx = common::context(&fs);
+    write_file(&cx, "/file", &pattern(8192, 3))?;
+    let cached = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&cx, "/file")?
+        .into_file()?;
+    let node = cached.location().entry().as_file()?.clone();
+
+    // Dirty pages reach the node once synced.
+    cached.write_at(&mut &[0xaa; 100][..], 0)?;
+    cached.sync(false)?;
+    let mut buf = [0; 100];
+    node.read_at(&mut buf, 0)?;
+    assert_eq!(buf, [0xaa; 100]);
+
+    let direct = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .direct(true)
+        .open(&cx, "/file")?
+        .into_file()?;
+
+    // Direct reads see the dirty pages of the cache.
+    cached.write_at(&mut &[0xbb; 100][..], 0)?;
+    let mut aligned = Aligned([0; 4096]);
+    direct.read_at(&mut &mut aligned.0[..], 0)?;
+    assert_eq!(aligned.0[..100], [0xbb; 100]);
+
+    // Direct writes are seen through the cache.
+    read_all(&cached)?;
+    aligned.0.fill(0xcc);
+    direct.write_at(&mut &aligned.0[..], 4096)?;
+    let data = read_all(&cached)?;
+    assert!(data[4096..].iter().all(|&b| b == 0xcc));
+
+    // Misaligned direct I/O is rejected.
+    assert!(matches!(
+        direct.read_at(&mut &mut aligned.0[..100], 0),
+        Err(VfsError::InvalidInput)
+    ));
+    assert!(matches!(
+        direct.write_at(&mut &aligned.0[..], 1),
+        Err(VfsError::InvalidInput)
+    ));
+    assert!(matches!(
+        direct.read_at(&mut &mut aligned.0[1..DIRECT_IO_ALIGN + 1], 0),
+        Err(VfsError::InvalidInput)
+    ));
     Ok(())
 }
 
 #[test]
+fn test_tmpfs() {
+    run_suite(common::tmpfs, ALL);
+}
+
 #[cfg(feature = "fat")]
-fn test_fatfs() {
-    for path in ["resources/fat16.img", "resources/fat32.img"] {
-        let data = std::fs::read(path).unwrap();
-        let disk = RamDisk::from(&data);
-        let fs = fs::fat::FatFilesystem::<RawMutex>::new(disk);
-        test_fs_full(fs).unwrap();
-    }
+const FAT: Features = Features {
+    hard_links: false,
+    symlinks: false,
+    holes: false,
+    xattrs: false,
+};
+
+#[test]
+#[cfg(feature = "fat")]
+fn test_fat12() {
+    run_suite(|| common::fat(common::FatType::Fat12), FAT);
+}
+
+#[test]
+#[cfg(feature = "fat")]
+fn test_fat16() {
+    run_suite(|| common::fat(common::FatType::Fat16), FAT);
+}
+
+#[test]
+#[cfg(feature = "fat")]
+fn test_fat32() {
+    run_suite(|| common::fat(common::FatType::Fat32), FAT);
+}
+
+#[test]
+#[cfg(feature = "fat")]
+fn test_fat_unix() {
+    run_suite(
+        || common::fat_unix(common::FatType::Fat16),
+        Features {
+            symlinks: true,
+            ..FAT
+        },
+    );
+}
+
+#[test]
+#[cfg(feature = "fat")]
+fn test_fat_unix_attrs() {
+    use axfs_ng::fs::fat::{FatOptions, unix::SYMLINK_MAGIC};
+
+    common::init();
+    let dev = common::fat_disk(common::FatType::Fat16);
+    let open = || {
+        FatOptions::new()
+            .emulate_unix(true)
+            .open(dev.clone())
+            .unwrap()
+    };
+    let fs = open();
+    let cx = common::context(&fs);
+    let mode = |bits| NodePermission::from_bits_truncate(bits);
+    cx.create_dir("/dir", mode(0o700)).unwrap();
+    write_file(&cx, "/dir/file", b"data").unwrap();
+    cx.update_metadata(
+        "/dir/file",
+        axfs_ng_vfs::MetadataUpdate {
+            mode: Some(mode(0o640)),
+            owner: Some((1000, 100)),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    let metadata = cx.metadata("/dir").unwrap();
+    assert_eq!(metadata.mode, mode(0o700));
+    let metadata = cx.metadata("/dir/file").unwrap();
+    assert_eq!(metadata.mode, mode(0o640));
+    assert_eq!((metadata.uid, metadata.gid), (1000, 100));
+    // The sidecar holding them is hidden.
+    assert_eq!(
+        list_dir(&cx, "/dir").unwrap(),
+        BTreeSet::from(["file".to_owned()])
+    );
+    assert!(cx.resolve("/dir/.axfs-unix").is_err());
+
+    // Attributes follow renamed files.
+    cx.rename("/dir/file", "/moved").unwrap();
+    let metadata = cx.metadata("/moved").unwrap();
+    assert_eq!(metadata.mode, mode(0o640));
+    assert_eq!((metadata.uid, metadata.gid), (1000, 100));
+    assert_eq!(read_file(&cx, "/moved").unwrap(), b"data");
+
+    // Symlinks read as their target only.
+    cx.symlink("moved", "/link").unwrap();
+    assert_eq!(cx.metadata("/link").unwrap().size, 4);
+    assert_eq!(cx.resolve_no_follow("/link").unwrap().len().unwrap(), 5);
+    // Files merely holding what symlinks do stay files.
+    write_file(&cx, "/forged", &[SYMLINK_MAGIC, b"/dir"].concat()).unwrap();
+
+    cx.remove_dir("/dir").unwrap();
+    fs.flush().unwrap();
+    drop((cx, fs));
+    let fs = open();
+    let cx = common::context(&fs);
+    let node_type = |path| cx.resolve_no_follow(path).unwrap().node_type();
+    assert_eq!(node_type("/link"), NodeType::Symlink);
+    assert_eq!(node_type("/forged"), NodeType::RegularFile);
+    assert_eq!(cx.metadata("/moved").unwrap().mode, mode(0o640));
 }
 
 #[test]
 #[cfg(feature = "ext4")]
 fn test_ext4() {
-    let data = std::fs::read("resources/ext4.img").unwrap();
-    let disk = RamDisk::from(&data);
-    let fs = fs::ext4::Ext4Filesystem::<RawMutex>::new(disk).unwrap();
-    test_fs_full(fs).unwrap();
+    run_suite(
+        || common::ext4(32 * 1024 * 1024),
+        Features {
+            holes: false,
+            ..ALL
+        },
+    );
 }
 
 #[test]
-#[cfg(all(feature = "ext4", feature = "fat"))]
-fn test_mount() {
-    env_logger::init();
-    let disk = RamDisk::from(&std::fs::read("resources/ext4.img").unwrap());
-    let fs = fs::ext4::Ext4Filesystem::<RawMutex>::new(disk).unwrap();
+#[cfg(feature = "fat")]
+fn test_fat_coherence() {
+    common::init();
+    test_coherence(common::fat(common::FatType::Fat32)).unwrap();
+}
 
-    let disk = RamDisk::from(&std::fs::read("resources/fat16.img").unwrap());
-    let sub_fs = fs::fat::FatFilesystem::<RawMutex>::new(disk);
+#[test]
+#[cfg(feature = "ext4")]
+fn test_ext4_coherence() {
+    common::init();
+    test_coherence(common::ext4(32 * 1024 * 1024)).unwrap();
+}
 
-    let mount = Mountpoint::new(&fs, None);
-    let cx = FsContext::new(mount.root_location());
-    cx.resolve("a").unwrap().mount(&sub_fs);
+#[test]
+#[cfg(all(feature = "ext4", feature = "fat"))]
+fn test_mount() {
+    common::init();
+    let fs = common::ext4(32 * 1024 * 1024);
+    let sub_fs = common::fat(common::FatType::Fat16);
+    let cx = common::context(&fs);
+    cx.create_dir("/a", dir_mode()).unwrap();
+    cx.mount("fat16", "/a", &sub_fs, axfs_ng::MountFlags::empty())
+        .unwrap();
 
     let mt = cx.resolve("a").unwrap();
     assert!(!mt.is_mountpoint() && mt.is_root_of_mount());
     assert_eq!(mt.filesystem().name(), "vfat");
     assert_eq!(mt.absolute_path().unwrap().to_string(), "/a");
 
+    write_file(&cx, "/a/file.txt", b"Rust is cool!\n").unwrap();
     assert_eq!(
-        cx.read_to_string("/a/../a/very-long-dir-name/very-long-file-name.txt")
-            .unwrap(),
-        "Rust is cool!\n"
+        read_file(&cx, "/a/../a/file.txt").unwrap(),
+        b"Rust is cool!\n"
     );
+    cx.umount("/a").unwrap();
+    assert!(cx.resolve("/a/file.txt").is_err());
+}
+
+#[test]
+#[cfg(feature = "fat")]
+fn test_remount() {
+    use axfs_ng::MountFlags;
+
+    common::init();
+    let fs = common::tmpfs();
+    let sub_fs = common::fat(common::FatType::Fat16);
+    let cx = common::context(&fs);
+    cx.create_dir("/mnt", dir_mode()).unwrap();
+    cx.mount("fat16", "/mnt", &sub_fs, MountFlags::empty())
+        .unwrap();
+    cx.create_dir("/mnt/dir", dir_mode()).unwrap();
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(&cx, "/mnt/file")
+        .and_then(|it| it.into_file())
+        .unwrap();
+    let node = file.location().entry().as_file().unwrap().clone();
+    let read_node = |len| {
+        let mut buf = vec![0; len];
+        node.read_at(&mut buf, 0).unwrap();
+        buf
+    };
+    let erofs = |result: VfsResult<()>| matches!(result, Err(VfsError::Other(LinuxError::EROFS)));
+
+    // Becoming read-only writes the dirty pages back.
+    file.write_at(&mut &b"dirty"[..], 0).unwrap();
+    cx.remount("/mnt", MountFlags::READ_ONLY).unwrap();
+    assert_eq!(read_node(5), b"dirty");
+
+    assert!(erofs(file.write_at(&mut &b"more"[..], 0).map(|_| ())));
+    assert!(erofs(write_file(&cx, "/mnt/new", b"")));
+    assert!(erofs(
+        OpenOptions::new()
+            .write(true)
+            .open(&cx, "/mnt/file")
+            .map(|_| ())
+    ));
+    assert!(erofs(cx.create_dir("/mnt/new", dir_mode()).map(|_| ())));
+    assert!(erofs(cx.rename("/mnt/file", "/mnt/dir/file")));
+    assert!(erofs(cx.remove_file("/mnt/file")));
+    assert!(erofs(cx.remove_dir("/mnt/dir")));
+    // Reading still works, and so do modifications outside of the mount.
+    assert_eq!(read_file(&cx, "/mnt/file").unwrap(), b"dirty");
+    write_file(&cx, "/new", b"").unwrap();
+
+    // Unmounting writes the dirty pages back as well.
+    cx.remount("/mnt", MountFlags::empty()).unwrap();
+    file.write_at(&mut &b"clean"[..], 0).unwrap();
+    cx.umount("/mnt").unwrap();
+    assert_eq!(read_node(5), b"clean");
+}
+
+#[test]
+fn test_xattr_read_only() {
+    use axfs_ng::{MountFlags, XattrFlags};
+
+    common::init();
+    let fs = common::tmpfs();
+    let sub_fs = common::tmpfs();
+    let cx = common::context(&fs);
+    cx.create_dir("/mnt", dir_mode()).unwrap();
+    cx.mount("tmpfs", "/mnt", &sub_fs, MountFlags::empty())
+        .unwrap();
+    write_file(&cx, "/mnt/file", b"").unwrap();
+    cx.set_xattr("/mnt/file", "user.a", b"1", XattrFlags::empty())
+        .unwrap();
+    cx.remount("/mnt", MountFlags::READ_ONLY).unwrap();
+    let erofs = |result| matches!(result, Err(VfsError::Other(LinuxError::EROFS)));
+
+    assert!(erofs(cx.set_xattr(
+        "/mnt/file",
+        "user.b",
+        b"2",
+        XattrFlags::empty()
+    )));
+    assert!(erofs(cx.remove_xattr("/mnt/file", "user.a")));
+    // Reading stays possible.
+    assert_eq!(cx.get_xattr("/mnt/file", "user.a").unwrap(), b"1");
+    assert_eq!(cx.list_xattr("/mnt/file").unwrap(), ["user.a"]);
+}
+
+#[test]
+#[cfg(feature = "ext4")]
+fn test_ext4_journal() {
+    use axfs_ng::fs::ext4::Ext4Filesystem;
+
+    common::init();
+    let dev = common::ext4_disk(32 * 1024 * 1024);
+    let fs = Ext4Filesystem::new(dev.clone()).unwrap();
+    let cx = common::context(&fs);
+    cx.create_dir("/dir", dir_mode()).unwrap();
+    let data = pattern(300_000, 5);
+    let file = axfs_ng::File::create(&cx, "/dir/file").unwrap();
+    file.write_at(&mut &data[..], 0).unwrap();
+    file.sync(false).unwrap();
+    drop(file);
+    fs.flush().unwrap();
+
+    // Committed transactions are in place for the next mount.
+    let fs = Ext4Filesystem::new(dev).unwrap();
+    let cx = common::context(&fs);
+    assert_eq!(
+        list_dir(&cx, "/").unwrap(),
+        ["dir", "lost+found"].map(String::from).into()
+    );
+    assert!(read_file(&cx, "/dir/file").unwrap() == data);
+    cx.remove_dir("/lost+found").unwrap();
+    fs.flush().unwrap();
+}
+
+#[test]
+#[cfg(feature = "ext4")]
+fn test_ext4_journal_large_write() {
+    use axfs_ng::fs::ext4::{Ext4Filesystem, Ext4MkfsOptions};
+
+    common::init();
+    let mut dev = common::disk(32 * 1024 * 1024);
+    Ext4MkfsOptions::new()
+        .journal_blocks(1024)
+        .format(&mut dev)
+        .unwrap();
+    let fs = Ext4Filesystem::new(dev.clone()).unwrap();
+    let cx = common::context(&fs);
+    let file = axfs_ng::File::create(&cx, "/file").unwrap();
+    let node = file.location().entry().as_file().unwrap().clone();
+
+    // Single writes several times the size of the 1 MiB journal.
+    let data = pattern(3 * 1024 * 1024, 7);
+    assert_eq!(node.write_at(&data, 0).unwrap(), data.len());
+    let tail = &data[..1024 * 1024 + 1];
+    assert_eq!(
+        node.append(tail).unwrap(),
+        (tail.len(), (data.len() + tail.len()) as u64)
+    );
+    drop(file);
+    fs.flush().unwrap();
+
+    let fs = Ext4Filesystem::new(dev).unwrap();
+    let cx = common::context(&fs);
+    let read = read_file(&cx, "/file").unwrap();
+    assert_eq!(read.len(), data.len() + tail.len());
+    assert!(read[..data.len()] == data[..]);
+    assert!(read[data.len()..] == *tail);
+}
+
+#[test]
+#[cfg(feature = "ext4")]
+fn test_ext4_journal_replay() {
+    use axfs_ng::fs::ext4::Ext4Filesystem;
+
+    let be32 =
+        |buf: &[u8], offset: usize| u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap());
+    let le32 =
+        |buf: &[u8], offset: usize| u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap());
+    let put_be32 = |buf: &mut [u8], offset: usize, value: u32| {
+        buf[offset..offset + 4].copy_from_slice(&value.to_be_bytes())
+    };
+    let put_header = |buf: &mut [u8], block_type: u32, sequence: u32| {
+        put_be32(buf, 0, 0xc03b_3998);
+        put_be32(buf, 4, block_type);
+        put_be32(buf, 8, sequence);
+    };
+
+    common::init();
+    let mut dev = common::ext4_disk(32 * 1024 * 1024);
+    let mut sb = [0; 1024];
+    common::read_raw(&mut dev, 1024, &mut sb);
+    let block_size = 1024 << le32(&sb, 24);
+    let blocks_count = le32(&sb, 4) as u64;
+    let inode_size = u16::from_le_bytes([sb[88], sb[89]]) as u64;
+    let offset = |block: u64| block * block_size as u64;
+
+    // The journal is inode 8, whose first extent holds the log.
+    let mut desc = [0; 32];
+    common::read_raw(&mut dev, offset(le32(&sb, 20) as u64 + 1), &mut desc);
+    let mut inode = vec![0; inode_size as usize];
+    let inode_table = offset(le32(&desc, 8) as u64);
+    common::read_raw(&mut dev, inode_table + 7 * inode_size, &mut inode);
+    let journal = le32(&inode, 60) as u64;
+    let mut jsb = vec![0; block_size];
+    common::read_raw(&mut dev, offset(journal), &mut jsb);
+    assert_eq!(be32(&jsb, 0), 0xc03b_3998);
+    let first = be32(&jsb, 20) as u64;
+    let sequence = be32(&jsb, 24);
+    assert_eq!(be32(&jsb, 28), 0);
+    assert_eq!(be32(&jsb, 40) & !0x1, 0);
+
+    // A transaction committed but never written in place, logging two free
+    // blocks, the second of which looks like a journal block.
+    let targets = [blocks_count - 2, blocks_count - 1];
+    let data = pattern(block_size, 3);
+    let mut magic = pattern(block_size, 4);
+    put_header(&mut magic, 1, sequence);
+    let mut descriptor = vec![0; block_size];
+    put_header(&mut descriptor, 1, sequence);
+    put_be32(&mut descriptor, 12, targets[0] as u32);
+    put_be32(&mut descriptor, 36, targets[1] as u32);
+    // SAME_UUID, LAST_TAG and ESCAPE; the first tag is followed by the UUID.
+    descriptor[43] = 0x2 | 0x8 | 0x1;
+    let mut escaped = magic.clone();
+    put_be32(&mut escaped, 0, 0);
+    let mut commit = vec![0; block_size];
+    put_header(&mut commit, 2, sequence);
+    let log = offset(journal + first);
+    common::write_raw(&mut dev, log, &descriptor);
+    common::write_raw(&mut dev, log + offset(1), &data);
+    common::write_raw(&mut dev, log + offset(2), &escaped);
+    common::write_raw(&mut dev, log + offset(3), &commit);
+    put_be32(&mut jsb, 28, first as u32);
+    common::write_raw(&mut dev, offset(journal), &jsb);
+    // Flag the filesystem as needing recovery, as a crash would leave it.
+    sb[96] |= 0x4;
+    common::write_raw(&mut dev, 1024, &sb);
+
+    let fs = Ext4Filesystem::new(dev.clone()).unwrap();
+    let mut block = vec![0; block_size];
+    common::read_raw(&mut dev, offset(targets[0]), &mut block);
+    assert!(block == data);
+    common::read_raw(&mut dev, offset(targets[1]), &mut block);
+    assert!(block == magic);
+    // The log is empty again.
+    common::read_raw(&mut dev, offset(journal), &mut jsb);
+    assert_eq!(be32(&jsb, 28), 0);
+    assert_eq!(be32(&jsb, 24), sequence.wrapping_add(1));
+    common::read_raw(&mut dev, 1024, &mut sb);
+    assert_eq!(sb[96] & 0x4, 0);
+
+    let cx = common::context(&fs);
+    assert_eq!(
+        list_dir(&cx, "/").unwrap(),
+        ["lost+found"].map(String::from).into()
+    );
+}
+
+/// Computes the CRC32C of `data` as ext4 does, without the final inversion.
+#[cfg(feature = "ext4")]
+fn crc32c(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0x82f6_3b78 & (crc & 1).wrapping_neg());
+        }
+    }
+    crc
+}
+
+#[test]
+#[cfg(feature = "ext4")]
+fn test_ext4_metadata_csum() {
+    use axfs_ng::fs::ext4::{Ext4Filesystem, Ext4Options};
+
+    common::init();
+    let mut dev = common::ext4_disk(32 * 1024 * 1024);
+    let mut sb = [0; 1024];
+    common::read_raw(&mut dev, 1024, &mut sb);
+    // Turn on metadata_csum, with a valid superblock checksum.
+    sb[101] |= 0x4;
+    let checksum = crc32c(!0, &sb[..0x3fc]);
+    sb[0x3fc..].copy_from_slice(&checksum.to_le_bytes());
+    common::write_raw(&mut dev, 1024, &sb);
+
+    assert!(matches!(
+        Ext4Filesystem::new(dev.clone()),
+        Err(VfsError::Unsupported)
+    ));
+    let fs = Ext4Options::new()
+        .read_only(true)
+        .open(dev.clone())
+        .unwrap();
+    let cx = common::context(&fs);
+    assert_eq!(
+        list_dir(&cx, "/").unwrap(),
+        ["lost+found"].map(String::from).into()
+    );
+    // Changes are refused rather than lost.
+    let erofs = |result: VfsResult<()>| matches!(result, Err(VfsError::Other(LinuxError::EROFS)));
+    assert!(erofs(write_file(&cx, "/file", b"data")));
+    assert!(erofs(cx.create_dir("/dir", dir_mode()).map(|_| ())));
+    assert!(erofs(cx.rename("/lost+found", "/found")));
+    assert!(erofs(cx.remove_dir("/lost+found")));
+    assert!(erofs(cx.update_metadata(
+        "/lost+found",
+        axfs_ng_vfs::MetadataUpdate {
+            mode: Some(dir_mode()),
+            ..Default::default()
+        }
+    )));
+    assert_eq!(
+        list_dir(&cx, "/").unwrap(),
+        ["lost+found"].map(String::from).into()
+    );
+    fs.flush().unwrap();
+    let mut after = [0; 1024];
+    common::read_raw(&mut dev, 1024, &mut after);
+    assert_eq!(sb, after);
+}
+
+/// Appends a `newc` record to a cpio archive.
+fn cpio_entry(archive: &mut Vec<u8>, ino: u32, mode: u32, nlink: u32, name: &str, data: &[u8]) {
+    let name_size = name.len() as u32 + 1;
+    let fields = [
+        ino,
+        mode,
+        1000,
+        100,
+        nlink,
+        1_700_000_000,
+        data.len() as u32,
+        0,
+        0,
+        0,
+        0,
+        name_size,
+        0,
+    ];
+    archive.extend_from_slice(b"070701");
+    for field in fields {
+        archive.extend_from_slice(format!("{field:08X}").as_bytes());
+    }
+    archive.extend_from_slice(name.as_bytes());
+    archive.push(0);
+    archive.resize(archive.len().next_multiple_of(4), 0);
+    archive.extend_from_slice(data);
+    archive.resize(archive.len().next_multiple_of(4), 0);
+}
+
+#[test]
+fn test_cpio() {
+    use std::time::Duration;
+
+    use axfs_ng::unpack_cpio;
+
+    common::init();
+    let mut archive = Vec::new();
+    cpio_entry(&mut archive, 1, 0o040755, 2, ".", b"");
+    cpio_entry(&mut archive, 2, 0o040750, 2, "bin", b"");
+    cpio_entry(&mut archive, 3, 0o100755, 1, "bin/hello", b"hello\n");
+    cpio_entry(&mut archive, 4, 0o120777, 1, "bin/link", b"hello");
+    // Hard links carry their data with the last one.
+    cpio_entry(&mut archive, 5, 0o100644, 2, "a", b"");
+    cpio_entry(&mut archive, 5, 0o100644, 2, "b", b"shared");
+    // Device nodes come from devfs instead.
+    cpio_entry(&mut archive, 6, 0o020666, 1, "null", b"");
+    let entries = archive.len();
+    cpio_entry(&mut archive, 0, 0, 1, "TRAILER!!!", b"");
+    // Anything after the trailer is ignored, like padding.
+    archive.extend_from_slice(b"garbage");
+
+    let fs = common::tmpfs();
+    let cx = common::context(&fs);
+    unpack_cpio(&cx, &archive).unwrap();
+    assert_eq!(
+        list_dir(&cx, "/").unwrap(),
+        BTreeSet::from(["a", "b", "bin"].map(String::from))
+    );
+    assert_eq!(cx.resolve("/bin").unwrap().node_type(), NodeType::Directory);
+    let metadata = cx.metadata("/bin").unwrap();
+    assert_eq!(metadata.mode, NodePermission::from_bits_truncate(0o750));
+    let metadata = cx.metadata("/bin/hello").unwrap();
+    assert_eq!(metadata.mode, NodePermission::from_bits_truncate(0o755));
+    assert_eq!((metadata.uid, metadata.gid), (1000, 100));
+    assert_eq!(metadata.mtime, Duration::from_secs(1_700_000_000));
+    assert_eq!(read_file(&cx, "/bin/hello").unwrap(), b"hello\n");
+    let link = cx.resolve_no_follow("/bin/link").unwrap();
+    assert_eq!(link.node_type(), NodeType::Symlink);
+    assert_eq!(link.read_link().unwrap(), "hello");
+    assert_eq!(read_file(&cx, "/bin/link").unwrap(), b"hello\n");
+    assert_eq!(read_file(&cx, "/a").unwrap(), b"shared");
+    assert_eq!(
+        cx.resolve("/a").unwrap().inode(),
+        cx.resolve("/b").unwrap().inode()
+    );
+
+    // Malformed archives are rejected, wherever they end.
+    let unpack = |data: &[u8]| unpack_cpio(&common::context(&common::tmpfs()), data);
+    let invalid = |result| matches!(result, Err(VfsError::InvalidData));
+    let mut bad_magic = archive.clone();
+    bad_magic[..6].copy_from_slice(b"070707");
+    assert!(invalid(unpack(&bad_magic)));
+    let mut bad_field = archive.clone();
+    bad_field[6] = b'x';
+    assert!(invalid(unpack(&bad_field)));
+    // A truncated header, and a missing trailer.
+    assert!(invalid(unpack(&archive[..50])));
+    assert!(invalid(unpack(&archive[..entries])));
+    // A name, then data, running past the end.
+    let mut record = Vec::new();
+    cpio_entry(&mut record, 7, 0o100644, 1, "file", b"data");
+    assert!(invalid(unpack(&record[..112])));
+    assert!(invalid(unpack(&record[..118])));
+    let mut huge = record.clone();
+    huge[6 + 11 * 8..6 + 12 * 8].copy_from_slice(b"FFFFFFFF");
+    assert!(invalid(unpack(&huge)));
+    let mut huge = record;
+    huge[6 + 6 * 8..6 + 7 * 8].copy_from_slice(b"FFFFFFFF");
+    assert!(invalid(unpack(&huge)));
+}
+
+#[test]
+fn test_loop_device() {
+    use axdriver::prelude::{BlockDriverOps, DevError};
+    use axfs_ng::loop_device::LoopOptions;
+
+    common::init();
+    let fs = common::tmpfs();
+    let cx = common::context(&fs);
+    let data = pattern(10000, 5);
+    write_file(&cx, "/image", &data).unwrap();
+    let image = |write| {
+        OpenOptions::new()
+            .read(true)
+            .write(write)
+            .open(&cx, "/image")
+            .and_then(|it| it.into_file())
+            .unwrap()
+    };
+    let invalid_param = |result| matches!(result, Err(DevError::InvalidParam));
+
+    // The device covers whole blocks of the part of the file it is given.
+    let mut dev = LoopOptions::new()
+        .offset(1000)
+        .size_limit(Some(5000))
+        .open(image(true))
+        .unwrap();
+    assert_eq!((dev.block_size(), dev.num_blocks()), (512, 9));
+    assert!(!dev.is_read_only());
+    let mut block = [0; 512];
+    dev.read_block(8, &mut block).unwrap();
+    assert_eq!(block, data[1000 + 8 * 512..1000 + 9 * 512]);
+    dev.write_block(1, &[0xaa; 512]).unwrap();
+    dev.flush().unwrap();
+    assert!(read_file(&cx, "/image").unwrap()[1512..2024] == [0xaa; 512]);
+    // Accesses past the end, or of partial blocks, are rejected.
+    assert!(invalid_param(dev.read_block(9, &mut block)));
+    assert!(invalid_param(dev.read_block(8, &mut [0; 1024])));
+    assert!(invalid_param(dev.read_block(u64::MAX, &mut block)));
+    assert!(invalid_param(dev.read_block(0, &mut [0; 100])));
+    assert!(invalid_param(dev.write_block(9, &block)));
+    assert!(invalid_param(dev.write_block(0, &[0; 100])));
+    drop(dev);
+
+    // A limit past the end of the file is that of the file.
+    let dev = LoopOptions::new()
+        .offset(1000)
+        .size_limit(Some(1 << 40))
+        .block_size(1024)
+        .open(image(true))
+        .unwrap();
+    assert_eq!((dev.block_size(), dev.num_blocks()), (1024, 8));
+    drop(dev);
+    for block_size in [0, 256, 1000, 8192] {
+        let result = LoopOptions::new().block_size(block_size).open(image(true));
+        assert!(matches!(result, Err(VfsError::InvalidInput)));
+    }
+    let result = LoopOptions::new().offset(10001).open(image(true));
+    assert!(matches!(result, Err(VfsError::InvalidInput)));
+
+    // Writes are rejected on read-only devices, as on read-only files.
+    let mut dev = LoopOptions::new()
+        .read_only(true)
+        .open(image(true))
+        .unwrap();
+    assert!(dev.is_read_only());
+    assert!(matches!(
+        dev.write_block(0, &block),
+        Err(DevError::Unsupported)
+    ));
+    let mut dev = LoopOptions::new().open(image(false)).unwrap();
+    assert!(dev.is_read_only());
+    assert!(matches!(
+        dev.write_block(0, &block),
+        Err(DevError::Unsupported)
+    ));
+    dev.read_block(0, &mut block).unwrap();
+    assert_eq!(block, data[..512]);
+    assert!(read_file(&cx, "/image").unwrap()[512..1024] == data[512..1024]);
+}
+
+/// Sets the `i`-th entry of the MBR or EBR in `sector`.
+fn mbr_entry(sector: &mut [u8], i: usize, kind: u8, start: u32, len: u32) {
+    let entry = &mut sector[446 + i * 16..446 + (i + 1) * 16];
+    entry[4] = kind;
+    entry[8..12].copy_from_slice(&start.to_le_bytes());
+    entry[12..16].copy_from_slice(&len.to_le_bytes());
+}
+
+/// Returns the index, first block and number of blocks of the partitions
+/// found on `dev`.
+fn partition_layout(dev: &axfs_ng::partition::Partition) -> Vec<(usize, u64, u64)> {
+    dev.partitions()
+        .unwrap()
+        .iter()
+        .map(|p| (p.info().index, p.info().start, p.info().num_blocks))
+        .collect()
+}
+
+#[test]
+fn test_mbr_partitions() {
+    use axfs_ng::partition::{PartitionSelector, select};
+
+    common::init();
+    let mut dev = common::disk(8 * 1024 * 1024);
+    // Not partitioned: the whole disk.
+    assert_eq!(partition_layout(&dev), [(0, 0, 16384)]);
+
+    let mut mbr = [0; 512];
+    mbr[440..444].copy_from_slice(&0x1234abcd_u32.to_le_bytes());
+    mbr[510..512].copy_from_slice(&[0x55, 0xaa]);
+    mbr_entry(&mut mbr, 0, 0x83, 2048, 2048);
+    mbr_entry(&mut mbr, 1, 0x05, 8192, 8192);
+    // Logical partitions are relative to their record, and links to the
+    // next record relative to the extended partition.
+    let mut ebr = [0; 512];
+    ebr[510..512].copy_from_slice(&[0x55, 0xaa]);
+    mbr_entry(&mut ebr, 0, 0x83, 2048, 1024);
+    mbr_entry(&mut ebr, 1, 0x05, 4096, 4096);
+    let mut last = ebr;
+    mbr_entry(&mut last, 1, 0, 0, 0);
+    common::write_raw(&mut dev, 0, &mbr);
+    common::write_raw(&mut dev, 8192 * 512, &ebr);
+    common::write_raw(&mut dev, 12288 * 512, &last);
+    let layout = [(1, 2048, 2048), (5, 10240, 1024), (6, 14336, 1024)];
+    assert_eq!(partition_layout(&dev), layout);
+
+    let partitions = dev.partitions().unwrap();
+    let selected = |selector: &str| {
+        let parsed = selector.parse::<PartitionSelector>().unwrap();
+        assert_eq!(parsed.to_string(), selector);
+        select(&partitions, Some(&parsed)).map(|it| it.info().index)
+    };
+    assert_eq!(select(&partitions, None).unwrap().info().index, 1);
+    assert_eq!(selected("5").unwrap(), 5);
+    assert_eq!(selected("PARTUUID=1234ABCD-06").unwrap(), 6);
+    assert!(matches!(selected("2"), Err(VfsError::NotFound)));
+    assert!(matches!(
+        selected("PARTLABEL=root"),
+        Err(VfsError::NotFound)
+    ));
+    assert!(matches!(
+        "root".parse::<PartitionSelector>(),
+        Err(VfsError::InvalidInput)
+    ));
+    assert_eq!(partitions[2].info().uuid.as_deref(), Some("1234abcd-06"));
+
+    // A chain of records coming back on itself ends there, even when they
+    // hold no partition.
+    mbr_entry(&mut last, 1, 0x05, 4096, 4096);
+    common::write_raw(&mut dev, 12288 * 512, &last);
+    assert_eq!(partition_layout(&dev), layout);
+    mbr_entry(&mut last, 0, 0, 0, 0);
+    common::write_raw(&mut dev, 12288 * 512, &last);
+    assert_eq!(partition_layout(&dev), layout[..2]);
+}
+
+#[test]
+fn test_gpt_partitions() {
+    use axdriver::prelude::DevError;
+    use axfs_ng::partition::{PartitionSelector, select};
+
+    common::init();
+    let mut dev = common::disk(8 * 1024 * 1024);
+    let mut mbr = [0; 512];
+    mbr[510..512].copy_from_slice(&[0x55, 0xaa]);
+    mbr_entry(&mut mbr, 0, 0xee, 1, 16383);
+    common::write_raw(&mut dev, 0, &mbr);
+
+    let entry = |guid: u8, first: u64, last: u64, name: &str| {
+        let mut entry = [0; 128];
+        entry[..16].fill(0xaf);
+        for (i, byte) in entry[16..32].iter_mut().enumerate() {
+            *byte = guid + i as u8;
+        }
+        entry[32..40].copy_from_slice(&first.to_le_bytes());
+        entry[40..48].copy_from_slice(&last.to_le_bytes());
+        for (i, c) in name.encode_utf16().enumerate() {
+            entry[56 + i * 2..58 + i * 2].copy_from_slice(&c.to_le_bytes());
+        }
+        entry
+    };
+    let mut entries = [0; 4 * 128];
+    entries[..128].copy_from_slice(&entry(1, 2048, 4095, "boot"));
+    // Unused entries keep their index.
+    entries[256..384].copy_from_slice(&entry(0x41, 4096, 8191, "root"));
+    // Partitions past the end of the disk are left out.
+    entries[384..].copy_from_slice(&entry(0x61, 8192, 20000, "big"));
+    common::write_raw(&mut dev, 2 * 512, &entries);
+    let header = |entries_lba: u64, num_entries: u32, entry_size: u32| {
+        let mut header = [0; 92];
+        header[..8].copy_from_slice(b"EFI PART");
+        header[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+        header[80..84].copy_from_slice(&num_entries.to_le_bytes());
+        header[84..88].copy_from_slice(&entry_size.to_le_bytes());
+        header
+    };
+    common::write_raw(&mut dev, 512, &header(2, 4, 128));
+    assert_eq!(partition_layout(&dev), [(1, 2048, 2048), (3, 4096, 4096)]);
+
+    let partitions = dev.partitions().unwrap();
+    assert_eq!(partitions[0].info().label.as_deref(), Some("boot"));
+    assert_eq!(
+        partitions[0].info().uuid.as_deref(),
+        Some("04030201-0605-0807-090a-0b0c0d0e0f10")
+    );
+    let selected = |selector: &str| {
+        let selector = selector.parse::<PartitionSelector>().unwrap();
+        select(&partitions, Some(&selector)).map(|it| it.info().index)
+    };
+    assert_eq!(selected("PARTLABEL=root").unwrap(), 3);
+    assert_eq!(
+        selected("PARTUUID=04030201-0605-0807-090A-0B0C0D0E0F10").unwrap(),
+        1
+    );
+    assert!(matches!(selected("PARTLABEL=big"), Err(VfsError::NotFound)));
+
+    // Headers asking for odd or huge tables are rejected.
+    for (entries_lba, num_entries, entry_size) in [
+        (2, 4, 100),
+        (2, 4, 96),
+        (2, 4, 8192),
+        (2, 1 << 20, 128),
+        (2, u32::MAX, 4096),
+        (u64::MAX / 256, 4, 128),
+    ] {
+        common::write_raw(&mut dev, 512, &header(entries_lba, num_entries, entry_size));
+        assert!(matches!(dev.partitions(), Err(DevError::InvalidParam)));
+    }
+}
+
+#[test]
+fn test_mkfs() {
+    use axfs_ng::fs::{FsType, mkfs, probe};
+
+    common::init();
+    let mut dev = common::disk(8 * 1024 * 1024);
+    assert_eq!(probe(&mut dev).unwrap(), None);
+    for (fs_type, enabled) in [
+        (FsType::Ext4, cfg!(feature = "ext4")),
+        (FsType::Fat, cfg!(feature = "fat")),
+    ] {
+        let result = mkfs(fs_type, &mut dev);
+        if enabled {
+            result.unwrap();
+            assert_eq!(probe(&mut dev).unwrap(), Some(fs_type));
+        } else {
+            assert!(matches!(result, Err(VfsError::Unsupported)));
+        }
+    }
+}
+
+#[test]
+fn test_tree() {
+    common::init();
+    let fs = common::tmpfs();
+    let cx = common::context(&fs);
+    cx.create_dir_all("/a/b/c", dir_mode()).unwrap();
+    cx.create_dir_all("/a/./b/../d", dir_mode()).unwrap();
+    write_file(&cx, "/a/b/c/file.txt", b"data").unwrap();
+    write_file(&cx, "/a/d/.hidden", b"").unwrap();
+    cx.symlink("/a", "/a/d/up").unwrap();
+    assert!(cx.create_dir_all("/a/b/c/file.txt/e", dir_mode()).is_err());
+
+    let walk = |options: &axfs_ng::WalkOptions| {
+        options
+            .walk(&cx, "/a")
+            .unwrap()
+            .map(|it| it.map(|it| it.path))
+            .collect::<Vec<_>>()
+    };
+    let paths = walk(&axfs_ng::WalkOptions::new())
+        .into_iter()
+        .collect::<VfsResult<BTreeSet<_>>>()
+        .unwrap();
+    let expected = [
+        "/a",
+        "/a/b",
+        "/a/b/c",
+        "/a/b/c/file.txt",
+        "/a/d",
+        "/a/d/.hidden",
+        "/a/d/up",
+    ];
+    assert_eq!(paths, expected.map(String::from).into());
+    let shallow = walk(axfs_ng::WalkOptions::new().max_depth(1))
+        .into_iter()
+        .collect::<VfsResult<BTreeSet<_>>>()
+        .unwrap();
+    assert_eq!(shallow, ["/a", "/a/b", "/a/d"].map(String::from).into());
+    let breadth = walk(axfs_ng::WalkOptions::new().order(axfs_ng::WalkOrder::BreadthFirst));
+    let depths = breadth
+        .iter()
+        .map(|it| it.as_ref().unwrap().matches('/').count())
+        .collect::<Vec<_>>();
+    assert!(depths.is_sorted());
+    // Following `up` leads back to `/a`.
+    let followed = walk(axfs_ng::WalkOptions::new().follow_symlinks(true));
+    assert!(
+        followed
+            .iter()
+            .any(|it| matches!(it, Err(VfsError::FilesystemLoop)))
+    );
+
+    assert_eq!(cx.glob("/a/*/c/*.txt").unwrap(), ["/a/b/c/file.txt"]);
+    assert_eq!(cx.glob("/a/[b-c]").unwrap(), ["/a/b"]);
+    assert_eq!(cx.glob("/a/d/*").unwrap(), ["/a/d/up"]);
+    assert_eq!(cx.glob("/a/**/*.txt").unwrap(), ["/a/b/c/file.txt"]);
+    assert!(cx.glob("/a/*.rs").unwrap().is_empty());
+
+    cx.copy_tree("/a", "/copy").unwrap();
+    assert_eq!(read_file(&cx, "/copy/b/c/file.txt").unwrap(), b"data");
+    let up = cx.resolve_no_follow("/copy/d/up").unwrap();
+    assert_eq!(up.read_link().unwrap(), "/a");
+    assert!(matches!(
+        cx.copy_tree("/a", "/a/b/inner"),
+        Err(VfsError::InvalidInput)
+    ));
+
+    cx.remove_dir_all("/a").unwrap();
+    assert!(cx.resolve("/a").is_err());
+    assert!(cx.resolve("/copy/b/c/file.txt").is_ok());
+}
+
+#[test]
+fn test_watch() {
+    use axfs_ng::{WatchEvent, WatchMask, Watcher};
+    use axfs_ng_vfs::MetadataUpdate;
+
+    common::init();
+    let fs = common::tmpfs();
+    let cx = common::context(&fs);
+    cx.create_dir("/dir", dir_mode()).unwrap();
+    let watcher = Watcher::new();
+    let dir = watcher
+        .add_watch(&cx.resolve("/dir").unwrap(), WatchMask::ALL_EVENTS)
+        .unwrap();
+    let events = |watcher: &Watcher| {
+        core::iter::from_fn(|| watcher.read_event())
+            .map(|event| {
+                assert!(event.mask.intersects(WatchMask::MOVE) || event.cookie == 0);
+                (event.wd, event.mask, event.name)
+            })
+            .collect::<Vec<_>>()
+    };
+    let event = |wd, mask, name: Option<&str>| (wd, mask, name.map(String::from));
+    let open_write = |path: &str| {
+        OpenOptions::new()
+            .write(true)
+            .open(&cx, path)
+            .and_then(|it| it.into_file())
+            .unwrap()
+    };
+
+    // Events on entries of a directory carry their name. The truncation and
+    // the write are coalesced, being the same event.
+    write_file(&cx, "/dir/file", b"data").unwrap();
+    assert_eq!(
+        events(&watcher),
+        [
+            event(dir, WatchMask::CREATE, Some("file")),
+            event(dir, WatchMask::MODIFY, Some("file")),
+            event(dir, WatchMask::CLOSE_WRITE, Some("file")),
+        ]
+    );
+    // Watches of a file see its events without a name, and only those in
+    // their mask.
+    let file = watcher
+        .add_watch(&cx.resolve("/dir/file").unwrap(), WatchMask::ATTRIB)
+        .unwrap();
+    cx.update_metadata(
+        "/dir/file",
+        MetadataUpdate {
+            mode: Some(NodePermission::from_bits_truncate(0o600)),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let handle = open_write("/dir/file");
+    handle.write_at(&mut &b"more"[..], 4).unwrap();
+    handle.write_at(&mut &b"more"[..], 8).unwrap();
+    drop(handle);
+    assert_eq!(
+        events(&watcher),
+        [
+            event(file, WatchMask::ATTRIB, None),
+            event(dir, WatchMask::ATTRIB, Some("file")),
+            event(dir, WatchMask::MODIFY, Some("file")),
+            event(dir, WatchMask::CLOSE_WRITE, Some("file")),
+        ]
+    );
+    watcher.remove_watch(file).unwrap();
+    assert_eq!(events(&watcher), [event(file, WatchMask::IGNORED, None)]);
+    assert!(matches!(
+        watcher.remove_watch(file),
+        Err(VfsError::InvalidInput)
+    ));
+    cx.update_metadata("/dir/file", MetadataUpdate::default())
+        .unwrap();
+    assert_eq!(
+        events(&watcher),
+        [event(dir, WatchMask::ATTRIB, Some("file"))]
+    );
+
+    // Both events of a rename share a cookie.
+    cx.create_dir("/dir/sub", dir_mode()).unwrap();
+    cx.rename("/dir/file", "/dir/moved").unwrap();
+    cx.rename("/dir/sub", "/dir/sub2").unwrap();
+    let moves = core::iter::from_fn(|| watcher.read_event()).collect::<Vec<_>>();
+    let [created, from_file, to_file, from_dir, to_dir] = &moves[..] else {
+        panic!("unexpected events {moves:?}");
+    };
+    let dir_mask = |mask: WatchMask| mask | WatchMask::IS_DIR;
+    assert_eq!(created.mask, dir_mask(WatchMask::CREATE));
+    assert_eq!(created.name.as_deref(), Some("sub"));
+    for (from, to, is_dir, names) in [
+        (from_file, to_file, false, ("file", "moved")),
+        (from_dir, to_dir, true, ("sub", "sub2")),
+    ] {
+        let flags = if is_dir {
+            WatchMask::IS_DIR
+        } else {
+            WatchMask::empty()
+        };
+        assert_eq!(from.mask, WatchMask::MOVED_FROM | flags);
+        assert_eq!(to.mask, WatchMask::MOVED_TO | flags);
+        assert_eq!(
+            (from.name.as_deref(), to.name.as_deref()),
+            (Some(names.0), Some(names.1))
+        );
+        assert_ne!(from.cookie, 0);
+        assert_eq!(from.cookie, to.cookie);
+    }
+    assert_ne!(from_file.cookie, from_dir.cookie);
+    cx.remove_file("/dir/moved").unwrap();
+    cx.remove_dir("/dir/sub2").unwrap();
+    assert_eq!(
+        events(&watcher),
+        [
+            event(dir, WatchMask::DELETE, Some("moved")),
+            event(dir, dir_mask(WatchMask::DELETE), Some("sub2")),
+        ]
+    );
+
+    // A full queue ends with a single overflow event.
+    let other = Watcher::new();
+    let other_dir = other
+        .add_watch(&cx.resolve("/dir").unwrap(), WatchMask::MODIFY)
+        .unwrap();
+    write_file(&cx, "/dir/a", b"").unwrap();
+    write_file(&cx, "/dir/b", b"").unwrap();
+    let (a, b) = (open_write("/dir/a"), open_write("/dir/b"));
+    let _ = events(&other);
+    for i in 0..10000 {
+        a.write_at(&mut &b"a"[..], i).unwrap();
+        b.write_at(&mut &b"b"[..], i).unwrap();
+    }
+    let overflowed = core::iter::from_fn(|| other.read_event()).collect::<Vec<_>>();
+    assert_eq!(overflowed.len(), 16385);
+    assert_eq!(
+        overflowed.last(),
+        Some(&WatchEvent {
+            wd: u32::MAX,
+            mask: WatchMask::Q_OVERFLOW,
+            cookie: 0,
+            name: None,
+        })
+    );
+    assert!(
+        overflowed[..16384]
+            .iter()
+            .all(|it| it.wd == other_dir && it.mask == WatchMask::MODIFY)
+    );
+    let _ = events(&watcher);
+
+    // Dropping a watcher leaves the watches of others in place.
+    drop(other);
+    a.write_at(&mut &b"a"[..], 0).unwrap();
+    assert_eq!(events(&watcher), [event(dir, WatchMask::MODIFY, Some("a"))]);
+    drop(watcher);
+    b.write_at(&mut &b"b"[..], 0).unwrap();
+}
+
+#[test]
+fn test_overlay() {
+    use axfs_ng::{File, fs::overlayfs::OverlayFilesystem};
+    use axfs_ng_vfs::MetadataUpdate;
+
+    common::init();
+    let (upper, lower) = (common::tmpfs(), common::tmpfs());
+    let upper_cx = common::context(&upper);
+    let lower_cx = common::context(&lower);
+    lower_cx.create_dir_all("/dir/sub", dir_mode()).unwrap();
+    lower_cx.create_dir("/opaque", dir_mode()).unwrap();
+    write_file(&lower_cx, "/dir/a", b"lower a").unwrap();
+    write_file(&lower_cx, "/dir/b", b"lower b").unwrap();
+    write_file(&lower_cx, "/dir/sub/x", b"x").unwrap();
+    write_file(&lower_cx, "/opaque/old", b"old").unwrap();
+    write_file(&lower_cx, "/file", b"lower").unwrap();
+    write_file(&lower_cx, "/gone", b"gone").unwrap();
+    let overlay = OverlayFilesystem::new(&upper, &lower);
+    let cx = common::context(&overlay);
+    // Unlike `list_dir`, keeps duplicates.
+    let names = |path: &str| {
+        let mut names = cx
+            .read_dir(path)
+            .unwrap()
+            .map(|entry| entry.unwrap().name)
+            .filter(|name| name != "." && name != "..")
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    };
+
+    // Writes copy the file up, contents first, and leave the lower one alone.
+    let file = OpenOptions::new()
+        .write(true)
+        .open(&cx, "/file")
+        .and_then(|it| it.into_file())
+        .unwrap();
+    file.write_at(&mut &b"UP"[..], 0).unwrap();
+    assert_eq!(read_file(&cx, "/file").unwrap(), b"UPwer");
+    // Cached writes only reach the overlay node on write-back.
+    file.sync(false).unwrap();
+    assert_eq!(read_file(&upper_cx, "/file").unwrap(), b"UPwer");
+    assert_eq!(read_file(&lower_cx, "/file").unwrap(), b"lower");
+    // So do metadata changes, along with the parents.
+    let mode = NodePermission::from_bits_truncate(0o600);
+    cx.update_metadata(
+        "/dir/b",
+        MetadataUpdate {
+            mode: Some(mode),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    assert_eq!(cx.metadata("/dir/b").unwrap().mode, mode);
+    assert_eq!(upper_cx.metadata("/dir/b").unwrap().mode, mode);
+    assert_eq!(read_file(&upper_cx, "/dir/b").unwrap(), b"lower b");
+    assert_ne!(lower_cx.metadata("/dir/b").unwrap().mode, mode);
+    assert!(matches!(
+        upper_cx.metadata("/dir/a"),
+        Err(VfsError::NotFound)
+    ));
+
+    // Removing a lower file leaves a whiteout, which is never listed.
+    cx.remove_file("/gone").unwrap();
+    assert!(matches!(cx.metadata("/gone"), Err(VfsError::NotFound)));
+    assert!(matches!(
+        File::open(&cx, "/.wh.gone"),
+        Err(VfsError::NotFound)
+    ));
+    assert!(upper_cx.metadata("/.wh.gone").is_ok());
+    assert_eq!(read_file(&lower_cx, "/gone").unwrap(), b"gone");
+    assert_eq!(names("/"), ["dir", "file", "opaque"]);
+    // Recreating it drops the whiteout, and none of the lower file remains.
+    write_file(&cx, "/gone", b"new").unwrap();
+    assert_eq!(read_file(&cx, "/gone").unwrap(), b"new");
+    assert!(upper_cx.metadata("/.wh.gone").is_err());
+    assert_eq!(names("/"), ["dir", "file", "gone", "opaque"]);
+
+    // A directory recreated over a lower one does not show its entries.
+    cx.remove_file("/opaque/old").unwrap();
+    cx.remove_dir("/opaque").unwrap();
+    cx.create_dir("/opaque", dir_mode()).unwrap();
+    assert!(names("/opaque").is_empty());
+    assert!(matches!(
+        cx.metadata("/opaque/old"),
+        Err(VfsError::NotFound)
+    ));
+    assert!(upper_cx.metadata("/opaque/.wh..wh..opq").is_ok());
+    write_file(&cx, "/opaque/new", b"").unwrap();
+    assert_eq!(names("/opaque"), ["new"]);
+
+    // Merged directories list the entries of both layers once.
+    write_file(&cx, "/dir/c", b"upper c").unwrap();
+    write_file(&cx, "/dir/a", b"new a").unwrap();
+    assert_eq!(names("/dir"), ["a", "b", "c", "sub"]);
+    cx.remove_file("/dir/b").unwrap();
+    assert_eq!(names("/dir"), ["a", "c", "sub"]);
+    assert_eq!(read_file(&lower_cx, "/dir/a").unwrap(), b"lower a");
+
+    // Directories with lower entries are not renamed, but others are.
+    assert!(matches!(
+        cx.rename("/dir", "/moved"),
+        Err(VfsError::CrossesDevices)
+    ));
+    assert!(matches!(
+        cx.rename("/dir/sub", "/sub"),
+        Err(VfsError::CrossesDevices)
+    ));
+    cx.rename("/opaque", "/moved").unwrap();
+    assert_eq!(names("/moved"), ["new"]);
+    assert_eq!(names("/"), ["dir", "file", "gone", "moved"]);
+}
+
+#[test]
+fn test_copy_file_range() {
+    use axfs_ng::{File, copy_file_range, send_file};
+
+    common::init();
+    let fs = common::tmpfs();
+    let cx = common::context(&fs);
+    let data = pattern(10000, 3);
+    write_file(&cx, "/src", &data).unwrap();
+    let src = File::open(&cx, "/src").unwrap();
+    let dst = File::create(&cx, "/dst").unwrap();
+    let read_rest = |file: &File| {
+        let mut buf = vec![0; 20000];
+        let read = file.read(&mut &mut buf[..]).unwrap();
+        buf.truncate(read);
+        buf
+    };
+
+    // Without offsets, the positions of both files move.
+    assert_eq!(copy_file_range(&src, None, &dst, None, 500).unwrap(), 500);
+    assert_eq!(read_rest(&src), data[500..]);
+    // With offsets, only the offsets move.
+    let (mut src_offset, mut dst_offset) = (1000, 500);
+    assert_eq!(
+        copy_file_range(
+            &src,
+            Some(&mut src_offset),
+            &dst,
+            Some(&mut dst_offset),
+            1500
+        )
+        .unwrap(),
+        1500
+    );
+    assert_eq!((src_offset, dst_offset), (2500, 2000));
+    assert_eq!(read_rest(&src), []);
+    assert_eq!(copy_file_range(&src, None, &dst, None, 100).unwrap(), 0);
+    // The position of `dst` is still where the first copy left it.
+    assert_eq!(
+        copy_file_range(&src, Some(&mut 0), &dst, None, 10).unwrap(),
+        10
+    );
+    let copied = read_file(&cx, "/dst").unwrap();
+    assert_eq!(copied[..500], data[..500]);
+    assert_eq!(copied[500..510], data[..10]);
+    assert_eq!(copied[510..2000], data[1010..2500]);
+
+    // Copies stop at the end of `src`.
+    let mut src_offset = 9900;
+    assert_eq!(
+        copy_file_range(&src, Some(&mut src_offset), &dst, Some(&mut 0), 1000).unwrap(),
+        100
+    );
+    assert_eq!(src_offset, 10000);
+    assert_eq!(
+        copy_file_range(&src, Some(&mut src_offset), &dst, Some(&mut 0), 1000).unwrap(),
+        0
+    );
+
+    // Ranges of one file must not overlap.
+    let both = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&cx, "/src")
+        .and_then(|it| it.into_file())
+        .unwrap();
+    assert!(matches!(
+        copy_file_range(&both, Some(&mut 0), &both, Some(&mut 50), 100),
+        Err(VfsError::InvalidInput)
+    ));
+    assert_eq!(
+        copy_file_range(&both, Some(&mut 0), &both, Some(&mut 100), 100).unwrap(),
+        100
+    );
+    let copied = read_file(&cx, "/src").unwrap();
+    assert_eq!(copied[100..200], data[..100]);
+
+    let append = OpenOptions::new()
+        .write(true)
+        .append(true)
+        .open(&cx, "/dst")
+        .and_then(|it| it.into_file())
+        .unwrap();
+    assert!(matches!(
+        copy_file_range(&src, Some(&mut 0), &append, None, 10),
+        Err(VfsError::BadFileDescriptor)
+    ));
+
+    // `send_file` feeds the contents to `send` in pieces it accepts.
+    let src = File::open(&cx, "/dst").unwrap();
+    let expected = read_file(&cx, "/dst").unwrap();
+    let mut sent = Vec::new();
+    let result = send_file(&src, None, usize::MAX, |buf| {
+        let len = buf.len().min(300);
+        sent.extend_from_slice(&buf[..len]);
+        *buf = &buf[len..];
+        Ok(len)
+    });
+    assert_eq!(result.unwrap(), expected.len());
+    assert_eq!(sent, expected);
+    // A full non-blocking socket ends the transfer early, with the offset
+    // moved past what was sent only.
+    let mut offset = 0;
+    let mut room = 1000;
+    let result = send_file(&src, Some(&mut offset), 5000, |buf| {
+        if room == 0 {
+            return Err(VfsError::WouldBlock);
+        }
+        let len = buf.len().min(room);
+        room -= len;
+        *buf = &buf[len..];
+        Ok(len)
+    });
+    assert_eq!((result.unwrap(), offset), (1000, 1000));
+    let result = send_file(&src, Some(&mut offset), 5000, |_| Err(VfsError::WouldBlock));
+    assert!(matches!(result, Err(VfsError::WouldBlock)));
+    assert_eq!(offset, 1000);
+}
+
+#[test]
+fn test_permissions() {
+    use axfs_ng::Credentials;
+    use axfs_ng_vfs::MetadataUpdate;
+
+    common::init();
+    let fs = common::tmpfs();
+    let cx = common::context(&fs);
+    let mode = |bits| NodePermission::from_bits_truncate(bits);
+    let user = |uid| {
+        let mut cx = cx.clone();
+        cx.set_credentials(Credentials::new(uid, uid, vec![]));
+        cx
+    };
+    fn denied<T>(result: VfsResult<T>) -> bool {
+        matches!(result, Err(VfsError::PermissionDenied))
+    }
+    fn not_permitted<T>(result: VfsResult<T>) -> bool {
+        matches!(result, Err(VfsError::OperationNotPermitted))
+    }
+
+    cx.create_dir("/home", dir_mode()).unwrap();
+    cx.create_dir("/home/alice", mode(0o700)).unwrap();
+    cx.update_metadata(
+        "/home/alice",
+        MetadataUpdate {
+            owner: Some((1000, 1000)),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    write_file(&cx, "/secret", b"root").unwrap();
+    let alice = user(1000);
+    let bob = user(1001);
+    write_file(&alice, "/home/alice/file", b"data").unwrap();
+    assert_eq!(alice.metadata("/home/alice/file").unwrap().uid, 1000);
+
+    // Directories on the way need search permission, and listing one needs
+    // read permission.
+    assert!(denied(bob.metadata("/home/alice/file")));
+    assert!(denied(read_file(&bob, "/home/alice/file")));
+    alice
+        .update_metadata(
+            "/home/alice",
+            MetadataUpdate {
+                mode: Some(mode(0o711)),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    assert_eq!(read_file(&bob, "/home/alice/file").unwrap(), b"data");
+    assert!(denied(bob.read_dir("/home/alice")));
+    // Only the owner may change the metadata.
+    assert!(not_permitted(bob.update_metadata(
+        "/home/alice/file",
+        MetadataUpdate {
+            mode: Some(mode(0o777)),
+            ..Default::default()
+        },
+    )));
+
+    // Creating and removing entries needs write permission on the parent.
+    assert!(denied(write_file(&bob, "/home/bob", b"")));
+    assert!(denied(bob.create_dir("/home/alice/dir", dir_mode())));
+    assert!(denied(bob.remove_file("/home/alice/file")));
+    assert!(denied(bob.rename("/home/alice/file", "/file")));
+
+    // In a sticky directory, like the root of a tmpfs, entries may only be
+    // removed or replaced by their owner.
+    write_file(&alice, "/a", b"").unwrap();
+    write_file(&bob, "/b", b"").unwrap();
+    assert!(not_permitted(bob.remove_file("/a")));
+    assert!(not_permitted(bob.remove_file("/secret")));
+    assert!(not_permitted(bob.rename("/a", "/c")));
+    assert!(not_permitted(bob.rename("/b", "/a")));
+    bob.rename("/b", "/c").unwrap();
+    alice.remove_file("/a").unwrap();
+    cx.remove_file("/c").unwrap();
+    assert_eq!(
+        list_dir(&cx, "/").unwrap(),
+        BTreeSet::from(["home".to_owned(), "secret".to_owned()])
+    );
+
+    // The umask clears permissions of new files and directories.
+    let mut alice = alice;
+    assert_eq!(alice.set_umask(0o077), 0);
+    let dir = alice
+        .create_dir("/home/alice/private", mode(0o777))
+        .unwrap();
+    assert_eq!(dir.metadata().unwrap().mode, mode(0o700));
+    write_file(&alice, "/home/alice/private/file", b"").unwrap();
+    let metadata = alice.metadata("/home/alice/private/file").unwrap();
+    assert_eq!(metadata.mode, mode(0o600));
+    assert_eq!((metadata.uid, metadata.gid), (1000, 1000));
+
+    // `..` never leaves the root of a context, which only the superuser may
+    // change.
+    let mut bob = bob;
+    assert!(not_permitted(bob.chroot("/home")));
+    let mut jail = cx.clone();
+    jail.chroot("/home").unwrap();
+    let home = BTreeSet::from(["alice".to_owned()]);
+    assert_eq!(list_dir(&jail, "/").unwrap(), home);
+    assert_eq!(list_dir(&jail, "..").unwrap(), home);
+    assert_eq!(list_dir(&jail, "/alice/../../..").unwrap(), home);
+    assert!(matches!(jail.resolve("../secret"), Err(VfsError::NotFound)));
+}
+
+#[test]
+fn test_flock() {
+    use axfs_ng::{File, LockType};
+
+    common::init();
+    let fs = common::tmpfs();
+    let cx = common::context(&fs);
+    write_file(&cx, "/file", b"data").unwrap();
+    let a = File::open(&cx, "/file").unwrap();
+    let b = File::open(&cx, "/file").unwrap();
+    let would_block = |result| matches!(result, Err(VfsError::WouldBlock));
+
+    // Shared locks go together, and keep exclusive ones out.
+    a.lock_file(LockType::Shared, false).unwrap();
+    b.lock_file(LockType::Shared, false).unwrap();
+    assert!(would_block(b.lock_file(LockType::Exclusive, false)));
+    // Locks are converted in place.
+    a.unlock_file().unwrap();
+    b.lock_file(LockType::Exclusive, false).unwrap();
+    assert!(would_block(a.lock_file(LockType::Shared, false)));
+    b.lock_file(LockType::Shared, false).unwrap();
+    a.lock_file(LockType::Shared, false).unwrap();
+    assert!(would_block(a.lock_file(LockType::Exclusive, false)));
+
+    // Dropping a `File` releases its lock.
+    drop(b);
+    a.lock_file(LockType::Exclusive, false).unwrap();
+}
+
+#[test]
+fn test_record_locks() {
+    use axfs_ng::LockType::{Exclusive, Shared};
+
+    common::init();
+    let fs = common::tmpfs();
+    let cx = common::context(&fs);
+    write_file(&cx, "/file", b"data").unwrap();
+    let open = || {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&cx, "/file")
+            .and_then(|it| it.into_file())
+            .unwrap()
+    };
+    let file = open();
+    let lock = |owner, kind, range| file.set_record_lock(owner, Some(kind), range, false);
+    let conflict = |file: &axfs_ng::File, owner, kind, range| {
+        let lock = file.get_record_lock(owner, kind, range).unwrap()?;
+        Some((lock.owner, lock.kind, lock.range))
+    };
+
+    // Adjacent locks of the same type merge.
+    lock(1, Exclusive, 0..10).unwrap();
+    lock(1, Exclusive, 10..20).unwrap();
+    assert_eq!(
+        conflict(&file, 2, Shared, 5..15),
+        Some((1, Exclusive, 0..20))
+    );
+    // Unlocking the middle of a lock splits it.
+    file.set_record_lock(1, None, 8..12, false).unwrap();
+    assert_eq!(conflict(&file, 2, Shared, 8..12), None);
+    assert_eq!(conflict(&file, 2, Shared, 0..9), Some((1, Exclusive, 0..8)));
+    assert_eq!(
+        conflict(&file, 2, Shared, 11..30),
+        Some((1, Exclusive, 12..20))
+    );
+    // Locking part of a lock with another type splits it as well.
+    lock(1, Shared, 14..16).unwrap();
+    assert_eq!(conflict(&file, 2, Shared, 14..16), None);
+    assert_eq!(
+        conflict(&file, 2, Exclusive, 14..16),
+        Some((1, Shared, 14..16))
+    );
+    assert_eq!(
+        conflict(&file, 2, Shared, 13..15),
+        Some((1, Exclusive, 12..14))
+    );
+
+    // Other owners only get what is left.
+    assert!(matches!(lock(2, Shared, 0..1), Err(VfsError::WouldBlock)));
+    lock(2, Shared, 8..12).unwrap();
+    lock(2, Shared, 14..16).unwrap();
+    assert!(matches!(
+        lock(1, Exclusive, 8..9),
+        Err(VfsError::WouldBlock)
+    ));
+
+    // The locks of all owners are released with the `File`.
+    drop(file);
+    let file = open();
+    assert_eq!(conflict(&file, 3, Exclusive, 0..u64::MAX), None);
 }
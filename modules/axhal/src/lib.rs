@@ -76,9 +76,57 @@ pub mod console {
 
 /// CPU power management.
 pub mod power {
+    use core::sync::atomic::{AtomicBool, Ordering};
+
     #[cfg(feature = "smp")]
     pub use axplat::power::cpu_boot;
-    pub use axplat::power::system_off;
+    use kspin::SpinNoIrq;
+
+    /// Maximum number of hooks registered by [`register_shutdown_hook`].
+    const MAX_SHUTDOWN_HOOKS: usize = 8;
+
+    static SHUTDOWN_HOOKS: SpinNoIrq<heapless::Vec<fn(), MAX_SHUTDOWN_HOOKS>> =
+        SpinNoIrq::new(heapless::Vec::new());
+
+    /// Registers a function to run before the system is powered off, e.g. to
+    /// write cached data back to disks.
+    ///
+    /// Hooks may block, and are never run on panic.
+    ///
+    /// Returns `false` if there are too many hooks already.
+    pub fn register_shutdown_hook(hook: fn()) -> bool {
+        SHUTDOWN_HOOKS.lock().push(hook).is_ok()
+    }
+
+    /// Runs the shutdown hooks in registration order, unless they already
+    /// ran.
+    ///
+    /// Callers of [`system_off`] with IRQs or preemption disabled call this
+    /// first, where the hooks can block.
+    pub fn run_shutdown_hooks() {
+        static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+        // A hook powering off would come back here.
+        if SHUTTING_DOWN.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let hooks = SHUTDOWN_HOOKS.lock().clone();
+        for hook in hooks {
+            hook();
+        }
+    }
+
+    /// Runs the shutdown hooks, unless they already ran, then powers off the
+    /// system.
+    pub fn system_off() -> ! {
+        run_shutdown_hooks();
+        axplat::power::system_off()
+    }
+
+    /// Powers off the system without running the shutdown hooks, e.g. on
+    /// panic, when the locks they take may be held.
+    pub fn system_off_now() -> ! {
+        axplat::power::system_off()
+    }
 }
 
 /// Trap handling.
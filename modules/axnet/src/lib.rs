@@ -25,6 +25,7 @@ mod device;
 mod general;
 mod listen_table;
 pub mod options;
+mod procfs;
 mod router;
 mod service;
 mod socket;
@@ -110,6 +111,8 @@ pub fn init_network(mut net_devs: AxDeviceContainer<AxNetDevice>) {
 
     SOCKET_SET.init_once(SocketSetWrapper::new());
     LISTEN_TABLE.init_once(ListenTable::new());
+
+    procfs::register_entries();
 }
 
 pub fn poll_interfaces() {
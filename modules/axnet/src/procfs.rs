@@ -0,0 +1,64 @@
+//! Network entries of procfs.
+
+use alloc::string::{String, ToString};
+use core::fmt::Write;
+
+use axfs_ng::fs::procfs::register_entry;
+use smoltcp::socket::Socket;
+
+use crate::{SERVICE, SOCKET_SET};
+
+fn interfaces() -> String {
+    let service = SERVICE.lock();
+    let mut out = String::new();
+    for name in service.device_names() {
+        let _ = writeln!(out, "{name}");
+    }
+    out
+}
+
+fn addresses() -> String {
+    let service = SERVICE.lock();
+    let mut out = String::new();
+    for addr in service.iface.ip_addrs() {
+        let _ = writeln!(out, "{addr}");
+    }
+    out
+}
+
+fn sockets() -> String {
+    let mut out = String::from("Proto Local Remote State\n");
+    for (_, socket) in SOCKET_SET.inner.lock().iter() {
+        match socket {
+            Socket::Tcp(s) => {
+                let endpoint = |it: Option<_>| it.map_or("*".to_string(), |it| it.to_string());
+                let _ = writeln!(
+                    out,
+                    "tcp {} {} {}",
+                    endpoint(s.local_endpoint()),
+                    endpoint(s.remote_endpoint()),
+                    s.state()
+                );
+            }
+            Socket::Udp(s) => {
+                let _ = writeln!(out, "udp {} * -", s.endpoint());
+            }
+            #[allow(unreachable_patterns)]
+            _ => {}
+        }
+    }
+    out
+}
+
+/// Registers `net/dev`, `net/addr` and `net/sockets` in procfs.
+pub(crate) fn register_entries() {
+    for (path, generator) in [
+        ("net/dev", interfaces as fn() -> String),
+        ("net/addr", addresses),
+        ("net/sockets", sockets),
+    ] {
+        if let Err(err) = register_entry(path, generator) {
+            warn!("Failed to register /proc/{path}: {err:?}");
+        }
+    }
+}
@@ -38,6 +38,10 @@ impl Service {
         self.router.dispatch(timestamp)
     }
 
+    pub fn device_names(&self) -> impl Iterator<Item = &str> {
+        self.router.devices.iter().map(|dev| dev.name())
+    }
+
     pub fn get_source_address(&self, dst_addr: &IpAddress) -> IpAddress {
         let Some(rule) = self.router.table.lookup(dst_addr) else {
             panic!("no route to destination: {dst_addr}");
@@ -7,6 +7,7 @@ use core::{
 };
 
 use axerrno::{AxError, AxResult, LinuxError};
+use axfs_ng::File;
 use axio::{Buf, BufMut, IoEvents, Pollable};
 use bitflags::bitflags;
 use enum_dispatch::enum_dispatch;
@@ -149,6 +150,29 @@ pub enum Socket {
     Unix(UnixSocket),
 }
 
+impl Socket {
+    /// Sends up to `count` bytes of `file`, like `sendfile`.
+    ///
+    /// The file is read at `offset` if given, or else at its position, which
+    /// is moved past the bytes sent. Like [`send`](SocketOps::send), this
+    /// blocks until all is sent unless the socket is non-blocking, in which
+    /// case it returns once the socket is full.
+    ///
+    /// Returns the number of bytes sent, which is less than `count` only if
+    /// the end of the file is reached, or if sending stops after some bytes
+    /// were sent.
+    pub fn send_file(
+        &self,
+        file: &File,
+        offset: Option<&mut u64>,
+        count: usize,
+    ) -> AxResult<usize> {
+        axfs_ng::send_file(file, offset, count, |data| {
+            self.send(data, SendOptions::default())
+        })
+    }
+}
+
 impl Pollable for Socket {
     fn poll(&self) -> IoEvents {
         match self {
@@ -0,0 +1,16 @@
+use std::{env, fs, path::PathBuf};
+
+fn main() {
+    // The archive linked into the kernel as the built-in initramfs. An empty
+    // file means there is none.
+    let out = PathBuf::from(env::var("OUT_DIR").unwrap()).join("initramfs.cpio");
+    println!("cargo:rerun-if-env-changed=AX_INITRAMFS");
+    match env::var("AX_INITRAMFS") {
+        Ok(path) if !path.is_empty() => {
+            println!("cargo:rerun-if-changed={path}");
+            fs::copy(&path, &out)
+                .unwrap_or_else(|err| panic!("failed to read initramfs {path}: {err}"));
+        }
+        _ => fs::write(&out, []).unwrap(),
+    }
+}
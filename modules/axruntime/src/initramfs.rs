@@ -0,0 +1,204 @@
+//! Initial RAM filesystem, used as the root filesystem when there is no disk.
+//!
+//! Two `newc` cpio archives are looked for, and both are unpacked if present:
+//!
+//! - the built-in one, linked into the kernel image from the path in the
+//!   `AX_INITRAMFS` environment variable at build time;
+//! - the one loaded by the bootloader, found via the `linux,initrd-start` and
+//!   `linux,initrd-end` properties of the `/chosen` node in the device tree.
+//!
+//! The memory holding the latter is kept away from the allocator until it has
+//! been unpacked.
+
+use core::{
+    ops::Range,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use axfs_ng::{FsContext, MountFlags, fs::tmpfs::TmpFilesystem, unpack_cpio};
+use axhal::mem::{MemRegionFlags, memory_regions, phys_to_virt};
+
+static BUILTIN: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/initramfs.cpio"));
+
+/// Physical address range of the archive loaded by the bootloader.
+static INITRD_START: AtomicUsize = AtomicUsize::new(0);
+static INITRD_END: AtomicUsize = AtomicUsize::new(0);
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+
+const PAGE_SIZE: usize = 0x1000;
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+fn be32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn cstr(data: &[u8], offset: usize) -> Option<&[u8]> {
+    let data = data.get(offset..)?;
+    Some(&data[..data.iter().position(|&b| b == 0)?])
+}
+
+/// Reads an address stored as one or two cells.
+fn address(value: &[u8]) -> Option<usize> {
+    match value.len() {
+        4 => Some(u32::from_be_bytes(value.try_into().ok()?) as usize),
+        8 => Some(u64::from_be_bytes(value.try_into().ok()?) as usize),
+        _ => None,
+    }
+}
+
+/// Finds the initrd range in the `/chosen` node of a flattened device tree.
+fn find_initrd(dtb: &[u8]) -> Option<Range<usize>> {
+    let struct_offset = be32(dtb, 8)? as usize;
+    let strings_offset = be32(dtb, 12)? as usize;
+
+    let mut offset = struct_offset;
+    let mut depth = 0;
+    let mut in_chosen = false;
+    let (mut start, mut end) = (None, None);
+    loop {
+        let token = be32(dtb, offset)?;
+        offset += 4;
+        match token {
+            FDT_BEGIN_NODE => {
+                let name = cstr(dtb, offset)?;
+                offset = align4(offset + name.len() + 1);
+                depth += 1;
+                if depth == 2 {
+                    in_chosen = name == b"chosen" || name.starts_with(b"chosen@");
+                }
+            }
+            FDT_END_NODE => {
+                if in_chosen && depth == 2 {
+                    break;
+                }
+                depth -= 1;
+            }
+            FDT_PROP => {
+                let len = be32(dtb, offset)? as usize;
+                let name = cstr(dtb, strings_offset + be32(dtb, offset + 4)? as usize)?;
+                let value = dtb.get(offset + 8..offset + 8 + len)?;
+                offset = align4(offset + 8 + len);
+                if in_chosen && depth == 2 {
+                    match name {
+                        b"linux,initrd-start" => start = address(value),
+                        b"linux,initrd-end" => end = address(value),
+                        _ => {}
+                    }
+                }
+            }
+            FDT_NOP => {}
+            // `FDT_END`, or a malformed tree.
+            _ => break,
+        }
+    }
+    let (start, end) = (start?, end?);
+    (start < end).then_some(start..end)
+}
+
+/// Looks for an archive loaded by the bootloader in the device tree at `dtb`.
+///
+/// Must be called before the allocator is initialized.
+pub(crate) fn probe(dtb: usize) {
+    if dtb == 0 {
+        return;
+    }
+    let header = phys_to_virt(dtb.into()).as_ptr();
+    // SAFETY: the bootloader passes a valid device tree, and the magic number
+    // is checked before the size is trusted.
+    let dtb = unsafe {
+        let header = core::slice::from_raw_parts(header, 8);
+        if be32(header, 0) != Some(FDT_MAGIC) {
+            return;
+        }
+        core::slice::from_raw_parts(header.as_ptr(), be32(header, 4).unwrap() as usize)
+    };
+    if let Some(range) = find_initrd(dtb) {
+        info!("Found initrd at [{:#x}, {:#x})", range.start, range.end);
+        INITRD_START.store(range.start, Ordering::Release);
+        INITRD_END.store(range.end, Ordering::Release);
+    }
+}
+
+/// Returns the pages holding the archive loaded by the bootloader.
+fn reserved() -> Range<usize> {
+    let start = INITRD_START.load(Ordering::Acquire);
+    let end = INITRD_END.load(Ordering::Acquire);
+    (start & !(PAGE_SIZE - 1))..(end.div_ceil(PAGE_SIZE) * PAGE_SIZE)
+}
+
+/// Splits the physical memory `range` into the parts before and after the
+/// archive loaded by the bootloader, either of which may be empty.
+pub(crate) fn exclude_reserved(range: Range<usize>) -> [Range<usize>; 2] {
+    let reserved = reserved();
+    if reserved.is_empty() || reserved.end <= range.start || reserved.start >= range.end {
+        return [range, 0..0];
+    }
+    [
+        range.start..reserved.start.max(range.start),
+        reserved.end.min(range.end)..range.end,
+    ]
+}
+
+/// Returns the archive loaded by the bootloader.
+fn boot_archive() -> Option<&'static [u8]> {
+    let start = INITRD_START.load(Ordering::Acquire);
+    let end = INITRD_END.load(Ordering::Acquire);
+    if start == end {
+        return None;
+    }
+    // SAFETY: the range is reserved from the allocator until `release`.
+    Some(unsafe { core::slice::from_raw_parts(phys_to_virt(start.into()).as_ptr(), end - start) })
+}
+
+/// Gives the memory of the archive loaded by the bootloader to the allocator,
+/// once the root filesystem is ready.
+pub(crate) fn release() {
+    let reserved = reserved();
+    INITRD_START.store(0, Ordering::Release);
+    INITRD_END.store(0, Ordering::Release);
+    for r in memory_regions() {
+        if !r.flags.contains(MemRegionFlags::FREE) {
+            continue;
+        }
+        let start = reserved.start.max(r.paddr.as_usize());
+        let end = reserved.end.min(r.paddr.as_usize() + r.size);
+        if start < end {
+            axalloc::global_add_memory(phys_to_virt(start.into()).as_usize(), end - start)
+                .expect("add initrd memory failed");
+        }
+    }
+}
+
+/// Unpacks the initramfs archives into a tmpfs, and returns the context with
+/// it as the root directory.
+///
+/// # Panics
+///
+/// Panics if there is no archive at all.
+pub(crate) fn mount_root() -> FsContext {
+    let archives = [(!BUILTIN.is_empty()).then_some(BUILTIN), boot_archive()];
+    if archives.iter().all(Option::is_none) {
+        panic!("No block device or initramfs found!");
+    }
+
+    let fs = TmpFilesystem::new();
+    let root = axfs_ng::mount_root("rootfs", &fs, MountFlags::empty());
+    let cx = FsContext::new(root);
+    for archive in archives.into_iter().flatten() {
+        info!("Unpacking initramfs ({} bytes)...", archive.len());
+        if let Err(err) = unpack_cpio(&cx, archive) {
+            warn!("Failed to unpack initramfs: {err:?}");
+        }
+    }
+    cx
+}
@@ -10,7 +10,8 @@
 //! - `irq`: Enable interrupt handling support.
 //! - `multitask`: Enable multi-threading support.
 //! - `smp`: Enable SMP (symmetric multiprocessing) support.
-//! - `fs`: Enable filesystem support.
+//! - `fs`: Enable filesystem support. Without a disk, the root filesystem is
+//!   unpacked from an initramfs instead.
 //! - `net`: Enable networking support.
 //! - `display`: Enable graphics support.
 //!
@@ -22,9 +23,15 @@
 #[macro_use]
 extern crate axlog;
 
+#[cfg(feature = "fs")]
+extern crate alloc;
+
 #[cfg(all(target_os = "none", not(test)))]
 mod lang_items;
 
+#[cfg(feature = "fs")]
+mod initramfs;
+
 #[cfg(feature = "smp")]
 mod mp;
 
@@ -156,6 +163,9 @@ pub fn rust_main(cpu_id: usize, arg: usize) -> ! {
         );
     }
 
+    #[cfg(feature = "fs")]
+    initramfs::probe(arg);
+
     #[cfg(feature = "alloc")]
     init_allocator();
 
@@ -200,19 +210,42 @@ pub fn rust_main(cpu_id: usize, arg: usize) -> ! {
 
         #[cfg(feature = "fs")]
         {
+            use alloc::string::ToString;
+
             #[allow(unused_imports)]
             use axdriver::prelude::BaseDriverOps;
 
-            axfs_ng::ROOT_FS_CONTEXT.call_once(|| {
-                let dev = all_devices
-                    .block
-                    .take_one()
-                    .expect("No block device found!");
-                info!("Block device: {}", dev.device_name());
-                let fs = axfs_ng::fs::new_default(dev).expect("Failed to initialize filesystem");
-                let mount = axfs_ng_vfs::Mountpoint::new_root(&fs);
-                axfs_ng::FsContext::new(mount.root_location())
+            // Partitions of the disk holding the root filesystem, if any.
+            let mut root_disk = None;
+            axfs_ng::ROOT_FS_CONTEXT.call_once(|| match all_devices.block.take_one() {
+                Some(dev) => {
+                    let selector = option_env!("AX_ROOT")
+                        .filter(|it| !it.is_empty())
+                        .map(|it| it.parse().expect("Invalid root partition"));
+                    let partitions =
+                        axfs_ng::partition::scan(dev).expect("Failed to read partition table");
+                    let dev = axfs_ng::partition::select(&partitions, selector.as_ref())
+                        .expect("Root partition not found");
+                    root_disk = Some(partitions);
+                    let source = dev.device_name().to_string();
+                    info!("Block device: {} (partition {})", source, dev.info().index);
+                    let fs =
+                        axfs_ng::fs::new_default(dev).expect("Failed to initialize filesystem");
+                    let root = axfs_ng::mount_root(&source, &fs, axfs_ng::MountFlags::empty());
+                    axfs_ng::FsContext::new(root)
+                }
+                None => {
+                    info!("No block device found, using initramfs as root");
+                    initramfs::mount_root()
+                }
             });
+            initramfs::release();
+            init_devfs(root_disk, &mut all_devices.block);
+            init_procfs();
+            #[cfg(all(feature = "multitask", feature = "irq"))]
+            init_writeback();
+            axhal::power::register_shutdown_hook(axfs_ng::sync_all);
+            axalloc::global_allocator().set_shrinker(axfs_ng::shrink_page_cache);
         }
 
         #[cfg(feature = "net")]
@@ -223,6 +256,16 @@ pub fn rust_main(cpu_id: usize, arg: usize) -> ! {
 
         #[cfg(feature = "input")]
         axinput::init_input(all_devices.input);
+
+        #[cfg(all(feature = "fs", feature = "display"))]
+        if let Err(err) = axfs_ng::fs::devfs::register_display_devices() {
+            warn!("Failed to register display devices: {err:?}");
+        }
+
+        #[cfg(all(feature = "fs", feature = "input"))]
+        if let Err(err) = axfs_ng::fs::devfs::register_input_devices() {
+            warn!("Failed to register input devices: {err:?}");
+        }
     }
 
     #[cfg(feature = "smp")]
@@ -265,27 +308,111 @@ fn init_allocator() {
     info!("Initialize global memory allocator...");
     info!("  use {} allocator.", axalloc::global_allocator().name());
 
-    let mut max_region_size = 0;
-    let mut max_region_paddr = 0.into();
-    for r in memory_regions() {
-        if r.flags.contains(MemRegionFlags::FREE) && r.size > max_region_size {
-            max_region_size = r.size;
-            max_region_paddr = r.paddr;
-            // use lowest addr region
-            break;
-        }
+    // Keep the initramfs loaded by the bootloader until it is unpacked.
+    #[cfg(feature = "fs")]
+    let split = initramfs::exclude_reserved;
+    #[cfg(not(feature = "fs"))]
+    let split = |range: core::ops::Range<usize>| [range, 0..0];
+
+    let mut free_regions = memory_regions()
+        .filter(|r| r.flags.contains(MemRegionFlags::FREE))
+        .flat_map(|r| split(r.paddr.as_usize()..r.paddr.as_usize() + r.size))
+        .filter(|r| !r.is_empty());
+
+    // use lowest addr region
+    let first = free_regions.next().expect("no free memory region");
+    axalloc::global_init(phys_to_virt(first.start.into()).as_usize(), first.len());
+    for r in free_regions {
+        axalloc::global_add_memory(phys_to_virt(r.start.into()).as_usize(), r.len())
+            .expect("add heap memory region failed");
     }
-    for r in memory_regions() {
-        if r.flags.contains(MemRegionFlags::FREE) && r.paddr == max_region_paddr {
-            axalloc::global_init(phys_to_virt(r.paddr).as_usize(), r.size);
-            break;
-        }
+}
+
+#[cfg(feature = "fs")]
+fn init_devfs(
+    root_disk: Option<alloc::vec::Vec<axfs_ng::partition::Partition>>,
+    block_devs: &mut axdriver::AxDeviceContainer<axdriver::AxBlockDevice>,
+) {
+    use axfs_ng::fs::devfs;
+
+    if let Err(err) = devfs::register_builtin_devices() {
+        warn!("Failed to register builtin devices: {err:?}");
+    }
+    // The disk of the root filesystem comes first, shared with it.
+    let mut index = 0;
+    let registered = |result: axfs_ng_vfs::VfsResult<_>| match result {
+        Ok(name) => info!("Block device: /dev/{name}"),
+        Err(err) => warn!("Failed to register block device: {err:?}"),
+    };
+    if let Some(partitions) = root_disk {
+        registered(devfs::register_disk(index, partitions));
+        index += 1;
+    }
+    while let Some(dev) = block_devs.take_one() {
+        registered(devfs::register_block_device(index, dev));
+        index += 1;
     }
-    for r in memory_regions() {
-        if r.flags.contains(MemRegionFlags::FREE) && r.paddr != max_region_paddr {
-            axalloc::global_add_memory(phys_to_virt(r.paddr).as_usize(), r.size)
-                .expect("add heap memory region failed");
+
+    let cx = axfs_ng::ROOT_FS_CONTEXT.get().unwrap();
+    let fs = devfs::DevFilesystem::new();
+    match cx.mount("devfs", "/dev", &fs, axfs_ng::MountFlags::empty()) {
+        Ok(_) => info!("Mounted devfs at /dev"),
+        Err(axfs_ng_vfs::VfsError::NotFound) => info!("No /dev directory, devfs not mounted"),
+        Err(err) => warn!("Failed to mount devfs: {err:?}"),
+    }
+}
+
+#[cfg(all(feature = "fs", feature = "multitask", feature = "irq"))]
+fn init_writeback() {
+    axtask::spawn(
+        || loop {
+            let interval = axfs_ng::writeback_config().interval;
+            axtask::future::block_on(axtask::future::sleep(interval));
+            axfs_ng::writeback_expired();
+        },
+        "writeback".into(),
+    );
+}
+
+#[cfg(feature = "fs")]
+fn init_procfs() {
+    use alloc::string::String;
+    use core::fmt::Write;
+
+    use axfs_ng::fs::procfs;
+
+    if let Err(err) = procfs::register_builtin_entries() {
+        warn!("Failed to register procfs entries: {err:?}");
+    }
+    let _ = procfs::register_entry("cpuinfo", || {
+        let mut out = String::new();
+        for cpu in 0..axconfig::plat::CPU_NUM {
+            let _ = writeln!(out, "processor\t: {cpu}");
         }
+        out
+    });
+    #[cfg(feature = "multitask")]
+    let _ = procfs::register_entry("tasks", || {
+        let mut out = alloc::format!("{:>6} {:<8} {:>4} NAME\n", "ID", "STATE", "CPU");
+        for task in axtask::all_tasks() {
+            let _ = writeln!(
+                out,
+                "{:>6} {:<8} {:>4} {}",
+                task.id().as_u64(),
+                alloc::format!("{:?}", task.state()),
+                task.cpu_id(),
+                task.name()
+            );
+        }
+        out
+    });
+
+    let cx = axfs_ng::ROOT_FS_CONTEXT.get().unwrap();
+    let fs = procfs::ProcFilesystem::new();
+    match cx.mount("proc", "/proc", &fs, axfs_ng::MountFlags::empty()) {
+        Ok(_) => info!("Mounted procfs at /proc"),
+        Err(axfs_ng_vfs::VfsError::NotFound) => info!("No /proc directory, procfs not mounted"),
+        Err(err) => warn!("Failed to mount procfs: {err:?}"),
     }
 }
 
@@ -8,7 +8,7 @@ use alloc::{
 use kernel_guard::NoPreemptIrqSave;
 
 pub(crate) use crate::run_queue::{current_run_queue, select_run_queue};
-pub use crate::task::{CurrentTask, TaskId, TaskInner, TaskState};
+pub use crate::task::{CurrentTask, TaskId, TaskInner, TaskState, all_tasks};
 #[cfg(feature = "task-ext")]
 pub use crate::task::{TaskExt, TaskExtProxy};
 #[cfg(feature = "irq")]
@@ -187,6 +187,11 @@ pub fn yield_now() {
 
 /// Exits the current task.
 pub fn exit(exit_code: i32) -> ! {
+    if current().is_init() {
+        // The system is powered off with IRQs and preemption disabled, under
+        // which the shutdown hooks can't block.
+        axhal::power::run_shutdown_hooks();
+    }
     current_run_queue::<NoPreemptIrqSave>().exit_current(exit_code)
 }
 
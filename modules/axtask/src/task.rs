@@ -1,4 +1,4 @@
-use alloc::{boxed::Box, string::String, sync::Arc};
+use alloc::{boxed::Box, collections::BTreeMap, string::String, sync::Arc, vec::Vec};
 #[cfg(feature = "preempt")]
 use core::sync::atomic::AtomicUsize;
 use core::{
@@ -19,7 +19,19 @@ use futures::task::AtomicWaker;
 use kspin::SpinNoIrq;
 use memory_addr::{VirtAddr, align_up_4k};
 
-use crate::{AxCpuMask, AxTask, AxTaskRef, future::block_on};
+use crate::{AxCpuMask, AxTask, AxTaskRef, WeakAxTaskRef, future::block_on};
+
+/// All tasks that have been spawned and not dropped yet, indexed by their IDs.
+static TASK_TABLE: SpinNoIrq<BTreeMap<u64, WeakAxTaskRef>> = SpinNoIrq::new(BTreeMap::new());
+
+/// Returns all live tasks, ordered by their IDs.
+pub fn all_tasks() -> Vec<AxTaskRef> {
+    TASK_TABLE
+        .lock()
+        .values()
+        .filter_map(WeakAxTaskRef::upgrade)
+        .collect()
+}
 
 /// A unique identifier for a thread.
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
@@ -290,7 +302,10 @@ impl TaskInner {
     }
 
     pub(crate) fn into_arc(self) -> AxTaskRef {
-        Arc::new(AxTask::new(self))
+        let id = self.id.as_u64();
+        let task = Arc::new(AxTask::new(self));
+        TASK_TABLE.lock().insert(id, Arc::downgrade(&task));
+        task
     }
 
     #[inline]
@@ -457,6 +472,7 @@ impl fmt::Debug for TaskInner {
 impl Drop for TaskInner {
     fn drop(&mut self) {
         debug!("task drop: {}", self.id_name());
+        TASK_TABLE.lock().remove(&self.id.as_u64());
     }
 }
 
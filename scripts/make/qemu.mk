@@ -52,6 +52,10 @@ qemu_args-$(BLK) += \
   -device virtio-blk-$(vdev-suffix),drive=disk0 \
   -drive id=disk0,if=none,format=raw,file=$(DISK_IMG)
 
+ifneq ($(INITRD),)
+  qemu_args-y += -initrd $(INITRD)
+endif
+
 qemu_args-$(ICOUNT) += -icount shift=1
 
 qemu_args-$(NET) += \